@@ -0,0 +1,69 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+};
+
+use ipnet::IpNet;
+
+/// Whether `addr` falls into any of `nets`.
+pub fn in_any(nets: &[IpNet], addr: IpAddr) -> bool {
+    nets.iter().any(|net| net.contains(&addr))
+}
+
+/// Enforce a user's address allowlist.  Users without one may connect from
+/// anywhere; users with one must come from a listed network, and a request
+/// whose address is unknown is refused.
+pub fn user_ip_allowed(allowed: Option<&Vec<IpNet>>, addr: Option<IpAddr>) -> bool {
+    let Some(allowed) = allowed else { return true };
+    addr.is_some_and(|addr| in_any(allowed, addr))
+}
+
+/// Running totals of requests refused because of their address, keyed by
+/// username.  Hits on the global deny list happen before authentication and
+/// are counted under `None`.
+#[derive(Clone, Default)]
+pub struct Denials {
+    counts: Arc<Mutex<HashMap<Option<String>, u64>>>,
+}
+
+impl Denials {
+    /// Count a denial and return the new total for that user.
+    pub fn record(&self, username: Option<&str>) -> u64 {
+        let mut counts = self.counts.lock().unwrap();
+        let n = counts.entry(username.map(str::to_string)).or_default();
+        *n += 1;
+        *n
+    }
+
+    #[cfg(test)]
+    pub fn count(&self, username: Option<&str>) -> u64 {
+        let counts = self.counts.lock().unwrap();
+        counts.get(&username.map(str::to_string)).copied().unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allowlists() {
+        let nets: Vec<IpNet> = vec!["10.1.0.0/16".parse().unwrap(), "2001:db8::/32".parse().unwrap()];
+        assert!(user_ip_allowed(None, None));
+        assert!(user_ip_allowed(Some(&nets), Some("10.1.2.3".parse().unwrap())));
+        assert!(user_ip_allowed(Some(&nets), Some("2001:db8::5".parse().unwrap())));
+        assert!(!user_ip_allowed(Some(&nets), Some("10.2.0.1".parse().unwrap())));
+        assert!(!user_ip_allowed(Some(&nets), None));
+    }
+
+    #[test]
+    fn counts_denials_per_user() {
+        let denials = Denials::default();
+        assert_eq!(denials.record(Some("alice")), 1);
+        assert_eq!(denials.record(Some("alice")), 2);
+        assert_eq!(denials.record(None), 1);
+        assert_eq!(denials.count(Some("alice")), 2);
+        assert_eq!(denials.count(Some("bob")), 0);
+    }
+}
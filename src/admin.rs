@@ -0,0 +1,648 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    net::SocketAddr,
+    time::Duration,
+};
+
+use axum::{
+    Json, Router,
+    body::Body,
+    extract::{ConnectInfo, Path, Query, Request, State},
+    http::{Response, StatusCode},
+    middleware::{self, Next},
+    response::IntoResponse,
+    routing::{delete, get, post, put},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::auth::{Source, authenticate};
+use crate::coalesce::{Coalescer, RouteStats};
+use crate::config::{self, ADMIN_ROLE};
+use crate::policy::Policy;
+use crate::state::AppState;
+use crate::usage::UserUsage;
+
+/// Routes of the admin API, served on their own listener.  Every route
+/// requires the bearer key (or client certificate) of a user with the
+/// `admin` role.  Changes are written to the SQLite key store and picked up
+/// by the running proxy before the response is sent.
+pub fn router(state: AppState) -> Router {
+    Router::new()
+        .route("/admin/users", get(list_users).post(create_user))
+        .route("/admin/users/{username}", get(get_user).delete(delete_user))
+        .route("/admin/users/{username}/disable", post(disable_user))
+        .route("/admin/users/{username}/enable", post(enable_user))
+        .route("/admin/users/{username}/rotate", post(rotate_key))
+        .route("/admin/users/{username}/role", put(set_role))
+        .route("/admin/users/{username}/quota", put(set_quota))
+        .route("/admin/users/{username}/origins", put(set_origins))
+        .route("/admin/users/{username}/ips", put(set_ips))
+        .route("/admin/users/{username}/policy", put(set_user_policy))
+        .route("/admin/policies", get(list_policies))
+        .route("/admin/policies/models/{pattern}", put(set_model_policy).delete(delete_model_policy))
+        .route("/admin/usage", get(usage))
+        .route("/admin/coalescing", get(coalescing))
+        .route("/admin/bans", get(list_bans).delete(clear_bans))
+        .route("/admin/bans/{ip}", delete(unban))
+        .route_layer(middleware::from_fn_with_state(state.clone(), require_admin))
+        .with_state(state)
+}
+
+/// An error answered as `{"error": "..."}` with the given status.
+struct ApiError(StatusCode, String);
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response<Body> {
+        (self.0, Json(json!({ "error": self.1 }))).into_response()
+    }
+}
+
+type ApiResult = Result<Response<Body>, ApiError>;
+
+fn error(status: StatusCode, message: impl Into<String>) -> ApiError {
+    ApiError(status, message.into())
+}
+
+fn internal(e: anyhow::Error) -> ApiError {
+    error(StatusCode::INTERNAL_SERVER_ERROR, format!("{e:#}"))
+}
+
+async fn require_admin(State(state): State<AppState>, req: Request, next: Next) -> Response<Body> {
+    let peer = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+    let banned = match peer {
+        Some(addr) => state.guard.ban_remaining(addr).await.is_some(),
+        None => false,
+    };
+    if banned {
+        return error(StatusCode::TOO_MANY_REQUESTS, "too many failed authentication attempts").into_response();
+    }
+    let Some(identity) = authenticate(&state, req.headers(), req.extensions()) else {
+        if let Some(addr) = peer {
+            let failure = state.guard.record_failure(addr).await;
+            tokio::time::sleep(failure.delay).await;
+        }
+        return error(StatusCode::UNAUTHORIZED, "unauthorized").into_response();
+    };
+    // scoped tokens are for the proxy routes only, and an SSO name may
+    // merely coincide with a local admin's
+    let is_admin = matches!(identity.source, Source::Key | Source::Certificate)
+        && identity
+            .username
+            .as_deref()
+            .is_some_and(|user| state.directory().users.role(user) == ADMIN_ROLE);
+    if !is_admin {
+        return error(StatusCode::FORBIDDEN, "admin role required").into_response();
+    }
+    next.run(req).await
+}
+
+/// The key store; configuration loading guarantees there is one whenever
+/// the admin listener runs.
+fn store(state: &AppState) -> Result<&str, ApiError> {
+    state
+        .sqlite_path
+        .as_deref()
+        .ok_or_else(|| error(StatusCode::SERVICE_UNAVAILABLE, "no SQLite key store configured"))
+}
+
+/// Run key store work on the blocking pool, off the async workers.
+async fn blocking<T: Send + 'static>(work: impl FnOnce() -> Result<T, ApiError> + Send + 'static) -> Result<T, ApiError> {
+    tokio::task::spawn_blocking(work).await.map_err(|e| internal(e.into()))?
+}
+
+/// Apply a change to the key store and reload the directory from it, both
+/// on the blocking pool.
+async fn update<T: Send + 'static>(
+    state: &AppState,
+    change: impl FnOnce(&str) -> Result<T, ApiError> + Send + 'static,
+) -> Result<T, ApiError> {
+    let path = store(state)?.to_string();
+    let state = state.clone();
+    blocking(move || {
+        let changed = change(&path)?;
+        state.reload_directory().map_err(internal)?;
+        Ok(changed)
+    })
+    .await
+}
+
+fn require_user(state: &AppState, username: &str) -> Result<(), ApiError> {
+    if state.directory().users.contains(username) {
+        Ok(())
+    } else {
+        Err(error(StatusCode::NOT_FOUND, format!("no such user '{username}'")))
+    }
+}
+
+/// An undefined role allows nothing, so a typo would lock the user out.
+fn require_role(state: &AppState, role: &str) -> Result<(), ApiError> {
+    if state.roles.is_defined(role) {
+        Ok(())
+    } else {
+        Err(error(StatusCode::BAD_REQUEST, format!("no such role '{role}'")))
+    }
+}
+
+/// Everything known about a user except their key.
+#[derive(Serialize)]
+struct UserView {
+    username: String,
+    role: String,
+    disabled: bool,
+    daily_quota: Option<u64>,
+    origins: Vec<String>,
+    ips: Vec<String>,
+    /// Expiry times of keys still valid after a rotation.
+    old_keys_expire_at: Vec<u64>,
+    policy: Option<Policy>,
+    usage: UserUsage,
+}
+
+fn user_view(state: &AppState, username: &str) -> UserView {
+    let directory = state.directory();
+    let users = &directory.users;
+    UserView {
+        username: username.to_string(),
+        role: users.role(username).to_string(),
+        disabled: users.is_disabled(username),
+        daily_quota: users.quotas.get(username).copied(),
+        origins: users.origins.get(username).cloned().unwrap_or_default(),
+        ips: users
+            .ips
+            .get(username)
+            .map(|nets| nets.iter().map(|n| n.to_string()).collect())
+            .unwrap_or_default(),
+        old_keys_expire_at: {
+            let mut expiries: Vec<u64> = users
+                .extra_keys
+                .values()
+                .filter(|k| k.username == username)
+                .filter_map(|k| k.expires_at)
+                .collect();
+            expiries.sort();
+            expiries
+        },
+        policy: users.policies.users.get(username).cloned(),
+        usage: state.usage.get(username),
+    }
+}
+
+async fn list_users(State(state): State<AppState>) -> Json<Vec<UserView>> {
+    let mut names: Vec<String> = state.directory().users.keys.keys().cloned().collect();
+    names.sort();
+    Json(names.iter().map(|name| user_view(&state, name)).collect())
+}
+
+async fn get_user(State(state): State<AppState>, Path(username): Path<String>) -> ApiResult {
+    require_user(&state, &username)?;
+    Ok(Json(user_view(&state, &username)).into_response())
+}
+
+#[derive(Deserialize)]
+struct NewUser {
+    username: String,
+    /// Generated when absent.
+    key: Option<String>,
+    role: Option<String>,
+    daily_quota: Option<u64>,
+}
+
+async fn create_user(State(state): State<AppState>, Json(new): Json<NewUser>) -> ApiResult {
+    store(&state)?;
+    if new.username.is_empty() {
+        return Err(error(StatusCode::BAD_REQUEST, "username must not be empty"));
+    }
+    if state.directory().users.contains(&new.username) {
+        return Err(error(StatusCode::CONFLICT, format!("user '{}' already exists", new.username)));
+    }
+    if let Some(role) = &new.role {
+        require_role(&state, role)?;
+    }
+    let key = match new.key {
+        Some(key) => key,
+        None => config::generate_key().map_err(internal)?,
+    };
+    if state.directory().valid_keys.contains(&key) {
+        return Err(error(StatusCode::CONFLICT, "key already in use"));
+    }
+    let (user, stored_key) = (new.username.clone(), key.clone());
+    update(&state, move |path| {
+        config::add_key_to_sqlite(path, &user, &stored_key).map_err(internal)?;
+        if let Some(role) = &new.role {
+            config::set_user_role(path, &user, role).map_err(internal)?;
+        }
+        if new.daily_quota.is_some() {
+            config::set_user_quota(path, &user, new.daily_quota).map_err(internal)?;
+        }
+        Ok(())
+    })
+    .await?;
+    let mut body = serde_json::to_value(user_view(&state, &new.username)).unwrap_or_default();
+    body["key"] = json!(key);
+    Ok((StatusCode::CREATED, Json(body)).into_response())
+}
+
+async fn delete_user(State(state): State<AppState>, Path(username): Path<String>) -> ApiResult {
+    require_user(&state, &username)?;
+    update(&state, move |path| config::remove_key_from_sqlite(path, &username).map_err(internal)).await?;
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+async fn disable_user(State(state): State<AppState>, Path(username): Path<String>) -> ApiResult {
+    set_disabled(&state, username, true).await
+}
+
+async fn enable_user(State(state): State<AppState>, Path(username): Path<String>) -> ApiResult {
+    set_disabled(&state, username, false).await
+}
+
+async fn set_disabled(state: &AppState, username: String, disabled: bool) -> ApiResult {
+    require_user(state, &username)?;
+    let user = username.clone();
+    update(state, move |path| config::set_user_disabled(path, &user, disabled).map_err(internal)).await?;
+    Ok(Json(user_view(state, &username)).into_response())
+}
+
+#[derive(Deserialize)]
+struct RotateQuery {
+    /// How long the old key keeps working, e.g. `7d`; immediately invalid
+    /// when absent.
+    grace: Option<String>,
+}
+
+async fn rotate_key(
+    State(state): State<AppState>,
+    Path(username): Path<String>,
+    Query(query): Query<RotateQuery>,
+) -> ApiResult {
+    require_user(&state, &username)?;
+    let grace = match &query.grace {
+        Some(grace) => config::parse_duration(grace)
+            .map_err(|e| error(StatusCode::BAD_REQUEST, format!("{e:#}")))?,
+        None => Duration::ZERO,
+    };
+    let key = config::generate_key().map_err(internal)?;
+    let (user, new_key) = (username.clone(), key.clone());
+    update(&state, move |path| config::rotate_user_key(path, &user, &new_key, grace).map_err(internal)).await?;
+    Ok(Json(json!({ "username": username, "key": key })).into_response())
+}
+
+#[derive(Deserialize)]
+struct RoleBody {
+    role: String,
+}
+
+async fn set_role(
+    State(state): State<AppState>,
+    Path(username): Path<String>,
+    Json(body): Json<RoleBody>,
+) -> ApiResult {
+    require_user(&state, &username)?;
+    require_role(&state, &body.role)?;
+    let user = username.clone();
+    update(&state, move |path| config::set_user_role(path, &user, &body.role).map_err(internal)).await?;
+    Ok(Json(user_view(&state, &username)).into_response())
+}
+
+#[derive(Deserialize)]
+struct QuotaBody {
+    /// `null` removes the limit.
+    daily_quota: Option<u64>,
+}
+
+async fn set_quota(
+    State(state): State<AppState>,
+    Path(username): Path<String>,
+    Json(body): Json<QuotaBody>,
+) -> ApiResult {
+    require_user(&state, &username)?;
+    let user = username.clone();
+    update(&state, move |path| config::set_user_quota(path, &user, body.daily_quota).map_err(internal)).await?;
+    Ok(Json(user_view(&state, &username)).into_response())
+}
+
+#[derive(Deserialize)]
+struct OriginsBody {
+    origins: Vec<String>,
+}
+
+async fn set_origins(
+    State(state): State<AppState>,
+    Path(username): Path<String>,
+    Json(body): Json<OriginsBody>,
+) -> ApiResult {
+    require_user(&state, &username)?;
+    let user = username.clone();
+    update(&state, move |path| config::set_user_origins(path, &user, &body.origins).map_err(internal)).await?;
+    Ok(Json(user_view(&state, &username)).into_response())
+}
+
+#[derive(Deserialize)]
+struct IpsBody {
+    ips: Vec<String>,
+}
+
+async fn set_ips(
+    State(state): State<AppState>,
+    Path(username): Path<String>,
+    Json(body): Json<IpsBody>,
+) -> ApiResult {
+    require_user(&state, &username)?;
+    let user = username.clone();
+    update(&state, move |path| {
+        config::set_user_ips(path, &user, &body.ips)
+            .map_err(|e| error(StatusCode::BAD_REQUEST, format!("{e:#}")))
+    })
+    .await?;
+    Ok(Json(user_view(&state, &username)).into_response())
+}
+
+/// `null` removes the user's policy.
+async fn set_user_policy(
+    State(state): State<AppState>,
+    Path(username): Path<String>,
+    Json(policy): Json<Option<Policy>>,
+) -> ApiResult {
+    require_user(&state, &username)?;
+    let user = username.clone();
+    update(&state, move |path| config::set_user_policy(path, &user, policy.as_ref()).map_err(internal)).await?;
+    Ok(Json(user_view(&state, &username)).into_response())
+}
+
+async fn list_policies(State(state): State<AppState>) -> ApiResult {
+    let policies = state.directory().users.policies.clone();
+    let users: BTreeMap<_, _> = policies.users.into_iter().collect();
+    Ok(Json(json!({ "users": users, "models": policies.models })).into_response())
+}
+
+async fn set_model_policy(
+    State(state): State<AppState>,
+    Path(pattern): Path<String>,
+    Json(policy): Json<Policy>,
+) -> ApiResult {
+    let stored = policy.clone();
+    update(&state, move |path| config::set_model_policy(path, &pattern, Some(&stored)).map_err(internal)).await?;
+    Ok(Json(policy).into_response())
+}
+
+async fn delete_model_policy(State(state): State<AppState>, Path(pattern): Path<String>) -> ApiResult {
+    if !state.directory().users.policies.models.contains_key(&pattern) {
+        return Err(error(StatusCode::NOT_FOUND, format!("no policy for model '{pattern}'")));
+    }
+    update(&state, move |path| config::set_model_policy(path, &pattern, None).map_err(internal)).await?;
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+async fn usage(State(state): State<AppState>) -> Json<HashMap<String, UserUsage>> {
+    Json(state.usage.all())
+}
+
+async fn coalescing(State(state): State<AppState>) -> Json<BTreeMap<String, RouteStats>> {
+    Json(state.coalescer.as_ref().map(Coalescer::stats).unwrap_or_default())
+}
+
+/// Bans held by this process merged with the store's, which also has those
+/// of other processes sharing it.
+async fn list_bans(State(state): State<AppState>) -> ApiResult {
+    let mut bans: BTreeMap<String, u64> = state
+        .guard
+        .active()
+        .into_iter()
+        .map(|(ip, until)| (ip.to_string(), until))
+        .collect();
+    if let Some(path) = state.sqlite_path.clone() {
+        for (ip, until) in blocking(move || config::list_bans(&path).map_err(internal)).await? {
+            let entry = bans.entry(ip).or_insert(until);
+            *entry = (*entry).max(until);
+        }
+    }
+    let mut bans: Vec<_> = bans.into_iter().collect();
+    bans.sort_by(|a, b| (a.1, &a.0).cmp(&(b.1, &b.0)));
+    let bans: Vec<_> = bans
+        .into_iter()
+        .map(|(ip, until)| json!({ "ip": ip, "until": until }))
+        .collect();
+    Ok(Json(bans).into_response())
+}
+
+async fn clear_bans(State(state): State<AppState>) -> ApiResult {
+    let mut lifted: BTreeSet<String> = state.guard.active().into_iter().map(|(ip, _)| ip.to_string()).collect();
+    if let Some(path) = state.sqlite_path.clone() {
+        let stored = blocking(move || {
+            let bans = config::list_bans(&path).map_err(internal)?;
+            config::remove_bans(&path, None).map_err(internal)?;
+            Ok(bans)
+        })
+        .await?;
+        lifted.extend(stored.into_iter().map(|(ip, _)| ip));
+    }
+    state.guard.clear(None);
+    Ok(Json(json!({ "removed": lifted.len() })).into_response())
+}
+
+async fn unban(State(state): State<AppState>, Path(ip): Path<String>) -> ApiResult {
+    let addr = ip
+        .parse()
+        .map_err(|_| error(StatusCode::BAD_REQUEST, format!("invalid address '{ip}'")))?;
+    let removed = match state.sqlite_path.clone() {
+        Some(path) => {
+            let ip = ip.clone();
+            blocking(move || config::remove_bans(&path, Some(&ip)).map_err(internal)).await?
+        }
+        None => 0,
+    };
+    if !state.guard.clear(Some(addr)) && removed == 0 {
+        return Err(error(StatusCode::NOT_FOUND, format!("'{ip}' is not banned")));
+    }
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bans::BanConfig;
+    use crate::jwt::{JwtConfig, JwtVerifier};
+    use axum::http::Request;
+    use tempfile::NamedTempFile;
+    use tower::ServiceExt;
+
+    struct Admin {
+        app: Router,
+        state: AppState,
+        _db: NamedTempFile,
+    }
+
+    fn admin() -> Admin {
+        let db = NamedTempFile::new().unwrap();
+        let path = db.path().to_str().unwrap().to_string();
+        config::add_key_to_sqlite(&path, "root", "root-key").unwrap();
+        config::set_user_role(&path, "root", ADMIN_ROLE).unwrap();
+        config::add_key_to_sqlite(&path, "alice", "alice-key").unwrap();
+
+        let mut state = AppState::for_tests("http://localhost", &[]);
+        state.sqlite_path = Some(path);
+        state.reload_directory().unwrap();
+        Admin {
+            app: router(state.clone()),
+            state,
+            _db: db,
+        }
+    }
+
+    impl Admin {
+        async fn call(&self, method: &str, uri: &str, key: &str, body: Option<serde_json::Value>) -> (StatusCode, serde_json::Value) {
+            let req = Request::builder()
+                .method(method)
+                .uri(uri)
+                .header("authorization", format!("Bearer {key}"))
+                .header("content-type", "application/json");
+            let req = req
+                .body(body.map_or_else(Body::empty, |b| Body::from(b.to_string())))
+                .unwrap();
+            let resp = self.app.clone().oneshot(req).await.unwrap();
+            let status = resp.status();
+            let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+            (status, serde_json::from_slice(&bytes).unwrap_or_default())
+        }
+    }
+
+    #[tokio::test]
+    async fn requires_admin_role() {
+        let admin = admin();
+        assert_eq!(admin.call("GET", "/admin/users", "nope", None).await.0, StatusCode::UNAUTHORIZED);
+        assert_eq!(admin.call("GET", "/admin/users", "alice-key", None).await.0, StatusCode::FORBIDDEN);
+        // a token minted by an admin does not open the admin API
+        let claims = admin.state.tokens.claims(Some("root".into()), None, vec![], vec![]).unwrap();
+        let token = admin.state.tokens.mint(&claims);
+        assert_eq!(admin.call("GET", "/admin/users", &token, None).await.0, StatusCode::FORBIDDEN);
+        let (status, users) = admin.call("GET", "/admin/users", "root-key", None).await;
+        assert_eq!(status, StatusCode::OK);
+        let names: Vec<_> = users.as_array().unwrap().iter().map(|u| u["username"].clone()).collect();
+        assert_eq!(names, vec!["alice", "root"]);
+        assert!(users[0].get("key").is_none());
+    }
+
+    #[tokio::test]
+    async fn refuses_jwts() {
+        use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD as B64};
+        use ring::signature::{Ed25519KeyPair, KeyPair};
+        use std::io::Write;
+        use std::time::{SystemTime, UNIX_EPOCH};
+
+        let der = Ed25519KeyPair::generate_pkcs8(&ring::rand::SystemRandom::new()).unwrap();
+        let key = Ed25519KeyPair::from_pkcs8(der.as_ref()).unwrap();
+        let mut jwks = NamedTempFile::new().unwrap();
+        let x = B64.encode(key.public_key().as_ref());
+        write!(jwks, r#"{{"keys":[{{"kty":"OKP","crv":"Ed25519","x":"{x}"}}]}}"#).unwrap();
+
+        let mut admin = admin();
+        let verifier = JwtVerifier::new(JwtConfig::new(jwks.path().to_str().unwrap().into()));
+        verifier.reload(&reqwest::Client::new()).await.unwrap();
+        admin.state.jwt = Some(verifier);
+        admin.app = router(admin.state.clone());
+
+        // an SSO subject that happens to match a local admin's name
+        let exp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() + 60;
+        let header = B64.encode(br#"{"alg":"EdDSA","typ":"JWT"}"#);
+        let claims = B64.encode(format!(r#"{{"sub":"root","exp":{exp}}}"#));
+        let input = format!("{header}.{claims}");
+        let jwt = format!("{input}.{}", B64.encode(key.sign(input.as_bytes())));
+        assert_eq!(admin.call("GET", "/admin/users", &jwt, None).await.0, StatusCode::FORBIDDEN);
+        assert_eq!(admin.call("GET", "/admin/users", "root-key", None).await.0, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn rejects_undefined_roles() {
+        let admin = admin();
+        let (status, _) = admin
+            .call("PUT", "/admin/users/alice/role", "root-key", Some(json!({ "role": "readonly" })))
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, body) = admin
+            .call("PUT", "/admin/users/alice/role", "root-key", Some(json!({ "role": "reaodnly" })))
+            .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body.to_string().contains("no such role"));
+        assert_eq!(admin.state.directory().users.role("alice"), "readonly");
+        let (status, _) = admin
+            .call("POST", "/admin/users", "root-key", Some(json!({ "username": "bob", "role": "amdin" })))
+            .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(!admin.state.directory().users.contains("bob"));
+    }
+
+    #[tokio::test]
+    async fn lists_bans_from_memory_and_store() {
+        let admin = admin();
+        // banned in this process only, as without a key store
+        for _ in 0..BanConfig::default().threshold {
+            admin.state.guard.record_failure("192.0.2.7".parse().unwrap()).await;
+        }
+        let path = admin.state.sqlite_path.clone().unwrap();
+        config::record_ban(&path, "2001:db8::9", u64::MAX >> 2).unwrap();
+
+        let (status, bans) = admin.call("GET", "/admin/bans", "root-key", None).await;
+        assert_eq!(status, StatusCode::OK);
+        let ips: Vec<_> = bans.as_array().unwrap().iter().map(|b| b["ip"].clone()).collect();
+        assert_eq!(ips, vec!["192.0.2.7", "2001:db8::9"]);
+
+        let (status, _) = admin.call("DELETE", "/admin/bans/192.0.2.7", "root-key", None).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert!(admin.state.guard.ban_remaining("192.0.2.7".parse().unwrap()).await.is_none());
+        let (_, cleared) = admin.call("DELETE", "/admin/bans", "root-key", None).await;
+        assert_eq!(cleared["removed"], 1);
+        assert_eq!(admin.call("GET", "/admin/bans", "root-key", None).await.1, json!([]));
+    }
+
+    #[tokio::test]
+    async fn user_lifecycle_applies_live() {
+        let admin = admin();
+        let (status, created) = admin
+            .call("POST", "/admin/users", "root-key", Some(json!({ "username": "bob", "daily_quota": 5 })))
+            .await;
+        assert_eq!(status, StatusCode::CREATED);
+        let key = created["key"].as_str().unwrap().to_string();
+        assert!(key.starts_with("sk-") && key.len() == 51);
+        assert_eq!(created["daily_quota"], 5);
+        assert!(admin.state.directory().valid_keys.contains(&key));
+
+        let (status, _) = admin
+            .call("POST", "/admin/users", "root-key", Some(json!({ "username": "bob" })))
+            .await;
+        assert_eq!(status, StatusCode::CONFLICT);
+
+        let (_, bob) = admin.call("POST", "/admin/users/bob/disable", "root-key", None).await;
+        assert_eq!(bob["disabled"], true);
+        assert!(admin.state.directory().users.is_disabled("bob"));
+
+        let (_, rotated) = admin.call("POST", "/admin/users/bob/rotate", "root-key", None).await;
+        let new_key = rotated["key"].as_str().unwrap().to_string();
+        assert_ne!(new_key, key);
+        assert!(!admin.state.directory().valid_keys.contains(&key));
+
+        let (_, rotated) = admin.call("POST", "/admin/users/bob/rotate?grace=1d", "root-key", None).await;
+        assert_ne!(rotated["key"], json!(new_key));
+        assert!(admin.state.directory().valid_keys.contains(&new_key));
+        let (_, bob) = admin.call("GET", "/admin/users/bob", "root-key", None).await;
+        assert_eq!(bob["old_keys_expire_at"].as_array().unwrap().len(), 1);
+
+        let (status, bob) = admin
+            .call("PUT", "/admin/users/bob/ips", "root-key", Some(json!({ "ips": ["10.0.0.0/8"] })))
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(bob["ips"], json!(["10.0.0.0/8"]));
+        let (status, _) = admin
+            .call("PUT", "/admin/users/bob/ips", "root-key", Some(json!({ "ips": ["nonsense"] })))
+            .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, _) = admin.call("DELETE", "/admin/users/bob", "root-key", None).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert!(!admin.state.directory().users.contains("bob"));
+        assert_eq!(admin.call("GET", "/admin/users/bob", "root-key", None).await.0, StatusCode::NOT_FOUND);
+    }
+}
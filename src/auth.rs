@@ -0,0 +1,277 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use axum::http::{Extensions, HeaderMap};
+
+use crate::state::{AppState, Directory};
+use crate::jwt::looks_like_jwt;
+use crate::tokens::{Claims, TOKEN_PREFIX};
+
+/// Names from a client certificate verified during the TLS handshake,
+/// attached to requests by `tls::ClientCertAcceptor`.
+#[derive(Clone, Debug, Default)]
+pub struct ClientCert {
+    pub names: Vec<String>,
+}
+
+/// How a caller proved who they are.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Source {
+    Key,
+    Token,
+    /// An SSO name, which may coincide with a key-store user's.
+    Jwt,
+    Certificate,
+    ForwardAuth,
+}
+
+/// The caller a request was authenticated as.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Identity {
+    /// User from the key store, when the credential maps to one.  Keys
+    /// supplied via `API_KEYS` or a keys file have no user attached.
+    pub username: Option<String>,
+    /// Set when the request used a key that is only valid until this time
+    /// (seconds since the Unix epoch), typically the previous key during a
+    /// rotation grace period.
+    pub old_key_expires: Option<u64>,
+    /// Set when the request used a short-lived token rather than a key; the
+    /// token may only be used within these limits.
+    pub scope: Option<Claims>,
+    pub source: Source,
+}
+
+/// Work out who is calling.  A bearer key is checked first; failing that, a
+/// verified client certificate whose subject CN or SAN names a user in the
+/// key store authenticates as that user, exactly as if their key had been
+/// presented.  Disabled users are refused either way.  Tokens minted from a
+/// key are checked by signature alone, but their user must still exist.
+/// JWTs, when configured, name their user in a claim; SSO users need not
+/// be in the key store at all.
+pub fn authenticate(state: &AppState, headers: &HeaderMap, extensions: &Extensions) -> Option<Identity> {
+    let directory = state.directory();
+    let identity = resolve(state, &directory, headers, extensions)?;
+    match &identity.username {
+        Some(user) if directory.users.is_disabled(user) => None,
+        _ => Some(identity),
+    }
+}
+
+fn resolve(state: &AppState, directory: &Directory, headers: &HeaderMap, extensions: &Extensions) -> Option<Identity> {
+    let bearer = headers
+        .get("authorization")
+        .and_then(|auth| auth.to_str().ok())
+        .and_then(|auth| auth.strip_prefix("Bearer "));
+    let claims = bearer
+        .filter(|b| b.starts_with(TOKEN_PREFIX))
+        .and_then(|token| state.tokens.verify(token));
+    if let Some(claims) = claims {
+        if claims.user.as_ref().is_some_and(|u| !directory.users.contains(u)) {
+            return None;
+        }
+        return Some(Identity {
+            username: claims.user.clone(),
+            old_key_expires: None,
+            scope: Some(claims),
+            source: Source::Token,
+        });
+    }
+    if let (Some(jwt), Some(token)) = (&state.jwt, bearer.filter(|b| looks_like_jwt(b))) {
+        match jwt.verify(token) {
+            Ok(username) => {
+                return Some(Identity {
+                    username: Some(username),
+                    old_key_expires: None,
+                    scope: None,
+                    source: Source::Jwt,
+                });
+            }
+            Err(e) => eprintln!("rejected JWT: {e:#}"),
+        }
+    }
+    if let Some(key) = bearer {
+        // check every key so the time taken does not reveal which one, or
+        // how much of it, matched
+        let valid = directory
+            .valid_keys
+            .iter()
+            .fold(false, |found, k| found | constant_time_eq(k.as_bytes(), key.as_bytes()));
+        if valid {
+            let username = directory.users.user_for_key(key).map(str::to_string);
+            let old_key_expires = directory.users.key_expiry(key);
+            // the directory is only reloaded when the store changes, not when
+            // a grace period ends, so expiry is checked here
+            if old_key_expires.is_some_and(|t| t <= unix_now()) {
+                return None;
+            }
+            return Some(Identity {
+                username,
+                old_key_expires,
+                scope: None,
+                source: Source::Key,
+            });
+        }
+    }
+
+    let cert = extensions.get::<ClientCert>()?;
+    cert.names
+        .iter()
+        .find(|name| directory.users.contains(name))
+        .map(|name| Identity {
+            username: Some(name.clone()),
+            old_key_expires: None,
+            scope: None,
+            source: Source::Certificate,
+        })
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Compare two secrets in time that depends only on their lengths.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    let mut diff = a.len() ^ b.len();
+    for i in 0..a.len().max(b.len()) {
+        let x = a.get(i).copied().unwrap_or(0);
+        let y = b.get(i).copied().unwrap_or(0);
+        diff |= usize::from(x ^ y);
+    }
+    std::hint::black_box(diff) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ExtraKey;
+    use crate::jwt::{JwtConfig, JwtVerifier};
+
+    fn state() -> AppState {
+        let state = AppState::for_tests(
+            "http://localhost",
+            &["k-alice", "anonymous", "k-bob", "k-alice-old", "k-alice-expired"],
+        );
+        {
+            let mut dir = state.directory.write().unwrap();
+            for (key, expires_at) in [("k-alice-old", unix_now() + 60), ("k-alice-expired", unix_now() - 1)] {
+                dir.users.extra_keys.insert(
+                    key.into(),
+                    ExtraKey {
+                        username: "alice".into(),
+                        expires_at: Some(expires_at),
+                    },
+                );
+            }
+            dir.users.keys.insert("alice".into(), "k-alice".into());
+            dir.users.keys.insert("bob".into(), "k-bob".into());
+            dir.users.disabled.insert("bob".into());
+        }
+        state
+    }
+
+    fn bearer(key: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("authorization", format!("Bearer {key}").parse().unwrap());
+        headers
+    }
+
+    fn cert(names: &[&str]) -> Extensions {
+        let mut ext = Extensions::new();
+        ext.insert(ClientCert {
+            names: names.iter().map(|n| n.to_string()).collect(),
+        });
+        ext
+    }
+
+    #[test]
+    fn bearer_keys() {
+        let state = state();
+        let alice = authenticate(&state, &bearer("k-alice"), &Extensions::new()).unwrap();
+        assert_eq!(alice.username.as_deref(), Some("alice"));
+        assert_eq!(alice.old_key_expires, None);
+        let old = authenticate(&state, &bearer("k-alice-old"), &Extensions::new()).unwrap();
+        assert_eq!(old.username.as_deref(), Some("alice"));
+        assert!(old.old_key_expires.is_some());
+        assert!(authenticate(&state, &bearer("k-alice-expired"), &Extensions::new()).is_none());
+        let anon = authenticate(&state, &bearer("anonymous"), &Extensions::new()).unwrap();
+        assert_eq!(anon.username, None);
+        assert!(authenticate(&state, &bearer("wrong"), &Extensions::new()).is_none());
+        assert!(authenticate(&state, &HeaderMap::new(), &Extensions::new()).is_none());
+        // disabled users are refused by key and by certificate
+        assert!(authenticate(&state, &bearer("k-bob"), &Extensions::new()).is_none());
+        assert!(authenticate(&state, &HeaderMap::new(), &cert(&["bob"])).is_none());
+    }
+
+    #[test]
+    fn scoped_tokens() {
+        let state = state();
+        let mint = |user: &str| {
+            let claims = state
+                .tokens
+                .claims(Some(user.into()), None, vec!["llama3".into()], vec![])
+                .unwrap();
+            state.tokens.mint(&claims)
+        };
+        let id = authenticate(&state, &bearer(&mint("alice")), &Extensions::new()).unwrap();
+        assert_eq!(id.username.as_deref(), Some("alice"));
+        assert_eq!(id.scope.unwrap().models, vec!["llama3"]);
+        // deleted and disabled users lose their tokens too
+        assert!(authenticate(&state, &bearer(&mint("carol")), &Extensions::new()).is_none());
+        assert!(authenticate(&state, &bearer(&mint("bob")), &Extensions::new()).is_none());
+        assert!(authenticate(&state, &bearer("st.forged.token"), &Extensions::new()).is_none());
+    }
+
+    #[tokio::test]
+    async fn jwt_users() {
+        use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD as B64};
+        use ring::signature::{Ed25519KeyPair, KeyPair};
+        use std::io::Write;
+
+        let der = Ed25519KeyPair::generate_pkcs8(&ring::rand::SystemRandom::new()).unwrap();
+        let key = Ed25519KeyPair::from_pkcs8(der.as_ref()).unwrap();
+        let mut jwks = tempfile::NamedTempFile::new().unwrap();
+        let x = B64.encode(key.public_key().as_ref());
+        write!(jwks, r#"{{"keys":[{{"kty":"OKP","crv":"Ed25519","x":"{x}"}}]}}"#).unwrap();
+
+        let mut state = state();
+        let verifier = JwtVerifier::new(JwtConfig::new(jwks.path().to_str().unwrap().into()));
+        verifier.reload(&reqwest::Client::new()).await.unwrap();
+        state.jwt = Some(verifier);
+
+        let jwt = |sub: &str| {
+            let header = B64.encode(br#"{"alg":"EdDSA","typ":"JWT"}"#);
+            let claims = B64.encode(format!(r#"{{"sub":"{sub}","exp":{}}}"#, unix_now() + 60));
+            let input = format!("{header}.{claims}");
+            format!("{input}.{}", B64.encode(key.sign(input.as_bytes())))
+        };
+        // SSO users need no key
+        let id = authenticate(&state, &bearer(&jwt("dave")), &Extensions::new()).unwrap();
+        assert_eq!(id.username.as_deref(), Some("dave"));
+        assert_eq!(id.source, Source::Jwt);
+        // but can still be disabled locally
+        assert!(authenticate(&state, &bearer(&jwt("bob")), &Extensions::new()).is_none());
+        assert!(authenticate(&state, &bearer("k-alice"), &Extensions::new()).is_some());
+    }
+
+    #[test]
+    fn compares_secrets() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+        assert!(!constant_time_eq(b"", b"x"));
+        assert!(constant_time_eq(b"", b""));
+    }
+
+    #[test]
+    fn client_certificate_maps_to_user() {
+        let state = state();
+        let id = authenticate(&state, &HeaderMap::new(), &cert(&["svc-a", "alice"])).unwrap();
+        assert_eq!(id.username.as_deref(), Some("alice"));
+        // an invalid bearer key does not block a valid certificate
+        assert!(authenticate(&state, &bearer("wrong"), &cert(&["alice"])).is_some());
+        assert!(authenticate(&state, &HeaderMap::new(), &cert(&["mallory"])).is_none());
+        assert!(authenticate(&state, &HeaderMap::new(), &cert(&[])).is_none());
+    }
+}
@@ -0,0 +1,338 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::config;
+
+/// Brute-force protection settings.
+#[derive(Clone, Debug)]
+pub struct BanConfig {
+    /// Failed authentications from one address before it is banned; `0`
+    /// disables bans (delays still apply).
+    pub threshold: u32,
+    /// Failures older than this are forgotten.
+    pub window: Duration,
+    pub ban_duration: Duration,
+    /// Delay before answering the first failure; doubled for each further
+    /// failure within the window.
+    pub delay_step: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for BanConfig {
+    fn default() -> Self {
+        BanConfig {
+            threshold: 10,
+            window: Duration::from_secs(600),
+            ban_duration: Duration::from_secs(900),
+            delay_step: Duration::from_millis(200),
+            max_delay: Duration::from_secs(3),
+        }
+    }
+}
+
+/// How long a still-banned address may go before the SQLite store is asked
+/// again whether the ban was lifted with `sql unban`.
+const STORE_RECHECK: Duration = Duration::from_secs(5);
+
+/// Tracked addresses are pruned once there are this many, so clients
+/// rotating through addresses cannot grow the table without bound.
+const PRUNE_AT: usize = 10_000;
+
+#[derive(Debug)]
+struct Entry {
+    failures: u32,
+    first_failure: SystemTime,
+    banned_until: Option<SystemTime>,
+    checked_store: SystemTime,
+}
+
+/// Outcome of a failed authentication.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Failure {
+    /// How long to hold the 401 back.
+    pub delay: Duration,
+    /// Set when this failure got the address banned.
+    pub banned_for: Option<Duration>,
+}
+
+/// Failed-authentication tracking per client address, in the spirit of
+/// fail2ban.  When the keys live in SQLite, bans are also written there so
+/// they survive restarts and can be listed and lifted with `sql bans` and
+/// `sql unban` while the server runs.
+#[derive(Clone, Default)]
+pub struct AuthGuard {
+    config: BanConfig,
+    store: Option<String>,
+    entries: Arc<Mutex<HashMap<IpAddr, Entry>>>,
+}
+
+impl AuthGuard {
+    /// Create a guard, picking up unexpired bans from `store`.
+    pub fn new(config: BanConfig, store: Option<String>) -> Self {
+        let guard = AuthGuard {
+            config,
+            store,
+            entries: Arc::default(),
+        };
+        if let Some(path) = &guard.store {
+            match config::list_bans(path) {
+                Ok(bans) => {
+                    let now = SystemTime::now();
+                    let mut entries = guard.entries.lock().unwrap();
+                    for (ip, until) in bans {
+                        let Ok(ip) = ip.parse() else { continue };
+                        entries.insert(
+                            ip,
+                            Entry {
+                                failures: 0,
+                                first_failure: now,
+                                banned_until: Some(UNIX_EPOCH + Duration::from_secs(until)),
+                                checked_store: now,
+                            },
+                        );
+                    }
+                }
+                Err(e) => eprintln!("failed to load bans: {e:#}"),
+            }
+        }
+        guard
+    }
+
+    /// Time left on `addr`'s ban, if it has one.  The store is read on the
+    /// blocking pool, without holding the lock.
+    pub async fn ban_remaining(&self, addr: IpAddr) -> Option<Duration> {
+        let now = SystemTime::now();
+        let (until, recheck) = {
+            let mut entries = self.entries.lock().unwrap();
+            let entry = entries.get_mut(&addr)?;
+            let until = entry.banned_until?;
+            if until <= now {
+                entries.remove(&addr);
+                return None;
+            }
+            let recheck = self.store.is_some() && entry.checked_store + STORE_RECHECK <= now;
+            if recheck {
+                entry.checked_store = now;
+            }
+            (until, recheck)
+        };
+        if let (true, Some(path)) = (recheck, self.store.clone()) {
+            let ip = addr.to_string();
+            let exists = tokio::task::spawn_blocking(move || config::ban_exists(&path, &ip)).await;
+            // keep the ban if the store cannot be read
+            if matches!(exists, Ok(Ok(false))) {
+                let mut entries = self.entries.lock().unwrap();
+                if entries.get(&addr).is_some_and(|e| e.banned_until == Some(until)) {
+                    entries.remove(&addr);
+                }
+                return None;
+            }
+        }
+        until.duration_since(now).ok()
+    }
+
+    /// Count a failed authentication from `addr`.  A resulting ban is
+    /// written to the store on the blocking pool, after the lock is released.
+    pub async fn record_failure(&self, addr: IpAddr) -> Failure {
+        let now = SystemTime::now();
+        let failures = {
+            let mut entries = self.entries.lock().unwrap();
+            if entries.len() >= PRUNE_AT {
+                let window = self.config.window;
+                entries.retain(|_, e| match e.banned_until {
+                    Some(until) => until > now,
+                    None => e.first_failure + window > now,
+                });
+            }
+            let entry = entries.entry(addr).or_insert(Entry {
+                failures: 0,
+                first_failure: now,
+                banned_until: None,
+                checked_store: now,
+            });
+            if entry.first_failure + self.config.window <= now {
+                entry.failures = 0;
+                entry.first_failure = now;
+            }
+            entry.failures += 1;
+            let failures = entry.failures;
+            if self.config.threshold > 0 && failures >= self.config.threshold {
+                entry.banned_until = Some(now + self.config.ban_duration);
+                entry.failures = 0;
+                entry.checked_store = now;
+            }
+            failures
+        };
+
+        let exponent = (failures - 1).min(16);
+        let delay = self
+            .config
+            .delay_step
+            .saturating_mul(1 << exponent)
+            .min(self.config.max_delay);
+
+        let mut banned_for = None;
+        if self.config.threshold > 0 && failures >= self.config.threshold {
+            banned_for = Some(self.config.ban_duration);
+            if let Some(path) = self.store.clone() {
+                let until = (now + self.config.ban_duration)
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs();
+                let ip = addr.to_string();
+                let stored = tokio::task::spawn_blocking(move || config::record_ban(&path, &ip, until)).await;
+                match stored {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => eprintln!("failed to store ban for {addr}: {e:#}"),
+                    Err(e) => eprintln!("failed to store ban for {addr}: {e}"),
+                }
+            }
+        }
+        Failure { delay, banned_for }
+    }
+
+    /// Bans held in this process as `(ip, until)` pairs, soonest expiry
+    /// first; without a store these are the only record of them.
+    pub fn active(&self) -> Vec<(IpAddr, u64)> {
+        let now = SystemTime::now();
+        let mut bans: Vec<_> = self
+            .entries
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|(ip, e)| {
+                let until = e.banned_until.filter(|&until| until > now)?;
+                Some((*ip, until.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()))
+            })
+            .collect();
+        bans.sort_by_key(|&(ip, until)| (until, ip));
+        bans
+    }
+
+    /// Lift the ban on `addr`, or on every address when `None`, in this
+    /// process.  Returns whether anything was banned.
+    pub fn clear(&self, addr: Option<IpAddr>) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+        match addr {
+            Some(addr) => entries
+                .remove(&addr)
+                .is_some_and(|e| e.banned_until.is_some()),
+            None => {
+                let any = entries.values().any(|e| e.banned_until.is_some());
+                entries.clear();
+                any
+            }
+        }
+    }
+
+    /// Forget earlier failures after a successful authentication.
+    pub fn record_success(&self, addr: IpAddr) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.get(&addr).is_some_and(|e| e.banned_until.is_none()) {
+            entries.remove(&addr);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    fn config() -> BanConfig {
+        BanConfig {
+            threshold: 3,
+            delay_step: Duration::from_millis(100),
+            max_delay: Duration::from_millis(250),
+            ..Default::default()
+        }
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn progressive_delay_then_ban() {
+        let guard = AuthGuard::new(config(), None);
+        let addr = ip("192.0.2.1");
+        assert_eq!(guard.record_failure(addr).await.delay, Duration::from_millis(100));
+        assert_eq!(guard.record_failure(addr).await.delay, Duration::from_millis(200));
+        assert!(guard.ban_remaining(addr).await.is_none());
+        let third = guard.record_failure(addr).await;
+        assert_eq!(third.delay, Duration::from_millis(250));
+        assert_eq!(third.banned_for, Some(Duration::from_secs(900)));
+        assert!(guard.ban_remaining(addr).await.is_some());
+        // other addresses are unaffected
+        assert!(guard.ban_remaining(ip("192.0.2.2")).await.is_none());
+        assert_eq!(guard.active().iter().map(|b| b.0).collect::<Vec<_>>(), vec![addr]);
+    }
+
+    #[tokio::test]
+    async fn success_resets_failures() {
+        let guard = AuthGuard::new(config(), None);
+        let addr = ip("192.0.2.1");
+        guard.record_failure(addr).await;
+        guard.record_failure(addr).await;
+        guard.record_success(addr);
+        assert_eq!(guard.record_failure(addr).await.delay, Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn forgets_stale_addresses() {
+        let guard = AuthGuard::new(
+            BanConfig {
+                window: Duration::from_secs(60),
+                ..config()
+            },
+            None,
+        );
+        guard.record_failure(ip("192.0.2.1")).await;
+        for _ in 0..3 {
+            guard.record_failure(ip("192.0.2.2")).await;
+        }
+        for entry in guard.entries.lock().unwrap().values_mut() {
+            entry.first_failure -= Duration::from_secs(120);
+        }
+        for i in 0..PRUNE_AT as u32 {
+            guard.record_failure(IpAddr::from((0x2001_0db8u128 << 96 | i as u128).to_be_bytes())).await;
+        }
+        let entries = guard.entries.lock().unwrap();
+        // the stale failure went, the ban stayed
+        assert!(!entries.contains_key(&ip("192.0.2.1")));
+        assert!(entries.contains_key(&ip("192.0.2.2")));
+    }
+
+    #[tokio::test]
+    async fn bans_are_shared_through_sqlite() {
+        let tmp = NamedTempFile::new().unwrap();
+        let path = tmp.path().to_str().unwrap().to_string();
+        let guard = AuthGuard::new(config(), Some(path.clone()));
+        let addr = ip("2001:db8::1");
+        for _ in 0..3 {
+            guard.record_failure(addr).await;
+        }
+        let bans = config::list_bans(&path).unwrap();
+        assert_eq!(bans.len(), 1);
+        assert_eq!(bans[0].0, "2001:db8::1");
+
+        // a restarted server still knows about the ban
+        let restarted = AuthGuard::new(config(), Some(path.clone()));
+        assert!(restarted.ban_remaining(addr).await.is_some());
+
+        // `sql unban` lifts it once the store is consulted again
+        assert_eq!(config::remove_bans(&path, Some("2001:db8::1")).unwrap(), 1);
+        restarted
+            .entries
+            .lock()
+            .unwrap()
+            .get_mut(&addr)
+            .unwrap()
+            .checked_store -= STORE_RECHECK;
+        assert!(restarted.ban_remaining(addr).await.is_none());
+    }
+}
@@ -0,0 +1,236 @@
+use std::{
+    collections::HashMap,
+    ops::Range,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use axum::http::HeaderMap;
+use serde_json::Value;
+use tokio::sync::oneshot;
+
+use crate::embeddings::{Embedded, Failure, embed};
+use crate::forwarded::FORWARDING_HEADERS;
+use crate::state::AppState;
+
+/// Settings for combining small embeddings requests into larger ones.
+#[derive(Clone, Debug)]
+pub struct BatchConfig {
+    /// How long the first request of a batch waits for company.
+    pub window: Duration,
+    /// A batch is sent as soon as it holds this many inputs.
+    pub max_inputs: usize,
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        BatchConfig {
+            window: Duration::from_millis(10),
+            max_inputs: 64,
+        }
+    }
+}
+
+struct Waiter {
+    range: Range<usize>,
+    reply: oneshot::Sender<Result<Embedded, Failure>>,
+}
+
+/// Inputs collected for one upstream call.
+struct Pending {
+    id: u64,
+    /// The first caller's request, whose parameters every member shares.
+    request: Value,
+    /// The first caller's headers, sent without forwarding headers when
+    /// the batch serves anyone else too.
+    headers: HeaderMap,
+    inputs: Vec<String>,
+    waiters: Vec<Waiter>,
+}
+
+/// Micro-batcher for `/v1/embeddings`.  Requests with the same model and
+/// parameters arriving within a short window are sent upstream as one call,
+/// and each caller gets back its own vectors and its share of the tokens.
+#[derive(Clone)]
+pub struct EmbeddingBatcher {
+    config: BatchConfig,
+    pending: Arc<Mutex<HashMap<String, Pending>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl EmbeddingBatcher {
+    pub fn new(config: BatchConfig) -> Self {
+        EmbeddingBatcher {
+            config,
+            pending: Arc::default(),
+            next_id: Arc::default(),
+        }
+    }
+
+    /// Embed `inputs` as part of the next batch for `request`'s model and
+    /// parameters.
+    pub async fn embed(
+        &self,
+        state: &AppState,
+        headers: HeaderMap,
+        request: Value,
+        inputs: Vec<String>,
+    ) -> Result<Embedded, Failure> {
+        let group = group_key(&request);
+        let (reply, result) = oneshot::channel();
+        let full = {
+            let mut pending = self.pending.lock().unwrap();
+            let batch = pending.entry(group.clone()).or_insert_with(|| {
+                let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+                self.schedule(state, group.clone(), id);
+                Pending {
+                    id,
+                    request,
+                    headers,
+                    inputs: Vec::new(),
+                    waiters: Vec::new(),
+                }
+            });
+            let start = batch.inputs.len();
+            batch.inputs.extend(inputs);
+            let range = start..batch.inputs.len();
+            batch.waiters.push(Waiter { range, reply });
+            if batch.inputs.len() >= self.config.max_inputs {
+                pending.remove(&group)
+            } else {
+                None
+            }
+        };
+        if let Some(batch) = full {
+            tokio::spawn(send(state.clone(), batch));
+        }
+        result
+            .await
+            .unwrap_or_else(|_| Err(Failure::bad_gateway("Embedding batch was dropped")))
+    }
+
+    /// Send the batch once its window closes, unless it filled up first.
+    fn schedule(&self, state: &AppState, group: String, id: u64) {
+        let (state, pending, window) = (state.clone(), self.pending.clone(), self.config.window);
+        tokio::spawn(async move {
+            tokio::time::sleep(window).await;
+            let batch = {
+                let mut pending = pending.lock().unwrap();
+                match pending.get(&group) {
+                    Some(batch) if batch.id == id => pending.remove(&group),
+                    _ => None,
+                }
+            };
+            if let Some(batch) = batch {
+                send(state, batch).await;
+            }
+        });
+    }
+}
+
+async fn send(state: AppState, batch: Pending) {
+    let mut headers = batch.headers;
+    // they would name only the first of several callers
+    if batch.waiters.len() > 1 {
+        for name in FORWARDING_HEADERS {
+            headers.remove(name);
+        }
+    }
+    let result = embed(&state, headers, batch.request, &batch.inputs).await;
+    for waiter in batch.waiters {
+        let share = match &result {
+            Ok(embedded) => Ok(embedded.slice(waiter.range)),
+            Err(failure) => Err(failure.clone()),
+        };
+        // the caller may have gone away; its share is simply dropped
+        let _ = waiter.reply.send(share);
+    }
+}
+
+/// Requests batch together when everything but their `input` (and the
+/// end-user `user` tag) matches.
+fn group_key(request: &Value) -> String {
+    let mut request = request.clone();
+    if let Some(fields) = request.as_object_mut() {
+        fields.remove("input");
+        fields.remove("user");
+    }
+    // serde_json's map is ordered by key, so this is canonical
+    request.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use httpmock::MockServer;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn combines_concurrent_requests() {
+        let server = MockServer::start_async().await;
+        let mock = server.mock(|when, then| {
+            when.method("POST")
+                .path("/v1/embeddings")
+                .body_includes(r#""input":["aa","b","cccc"]"#)
+                .header_missing("x-forwarded-for");
+            then.status(200).header("content-type", "application/json").body(
+                r#"{"object":"list","model":"nomic","usage":{"prompt_tokens":7,"total_tokens":7},
+                    "data":[{"index":0,"embedding":[1]},{"index":1,"embedding":[2]},{"index":2,"embedding":[3]}]}"#,
+            );
+        });
+        let state = AppState::for_tests(&server.url(""), &[]);
+        let batcher = EmbeddingBatcher::new(BatchConfig {
+            window: Duration::from_millis(50),
+            max_inputs: 100,
+        });
+        let request = |user: &str| json!({ "model": "nomic", "user": user });
+        let forwarded = |addr: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert("x-forwarded-for", addr.parse().unwrap());
+            headers
+        };
+        let (first, second) = tokio::join!(
+            batcher.embed(&state, forwarded("192.0.2.1"), request("x"), vec!["aa".into(), "b".into()]),
+            batcher.embed(&state, forwarded("192.0.2.2"), request("y"), vec!["cccc".into()]),
+        );
+        mock.assert_calls(1);
+        let (first, second) = (first.unwrap(), second.unwrap());
+        assert_eq!(first.vectors, vec![json!([1]), json!([2])]);
+        assert_eq!(second.vectors, vec![json!([3])]);
+        assert_eq!(first.tokens.iter().sum::<u64>() + second.tokens.iter().sum::<u64>(), 7);
+        assert_eq!(second.tokens, vec![4]);
+    }
+
+    #[tokio::test]
+    async fn sends_full_batches_early_and_splits_by_model() {
+        let server = MockServer::start_async().await;
+        let mock = server.mock(|when, then| {
+            // a lone caller's forwarding headers are kept
+            when.method("POST").path("/v1/embeddings").header("x-forwarded-for", "192.0.2.1");
+            then.status(200).header("content-type", "application/json").body(
+                r#"{"usage":{"prompt_tokens":2},"data":[{"index":0,"embedding":[1]},{"index":1,"embedding":[2]}]}"#,
+            );
+        });
+        let state = AppState::for_tests(&server.url(""), &[]);
+        let batcher = EmbeddingBatcher::new(BatchConfig {
+            window: Duration::from_secs(60),
+            max_inputs: 2,
+        });
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "192.0.2.1".parse().unwrap());
+        let result = batcher
+            .embed(&state, headers, json!({ "model": "a" }), vec!["x".into(), "y".into()])
+            .await;
+        assert_eq!(result.unwrap().tokens, vec![1, 1]);
+        mock.assert_calls(1);
+
+        assert_ne!(group_key(&json!({ "model": "a" })), group_key(&json!({ "model": "b" })));
+        assert_eq!(
+            group_key(&json!({ "model": "a", "input": "x" })),
+            group_key(&json!({ "input": ["y"], "model": "a", "user": "z" }))
+        );
+    }
+}
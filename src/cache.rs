@@ -0,0 +1,541 @@
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use axum::{
+    body::{Body, Bytes},
+    http::{HeaderMap, HeaderValue, Response, StatusCode, header},
+};
+use futures_util::{Stream, StreamExt, stream};
+use hyper::Method;
+use ring::digest;
+use rusqlite::{Connection, OptionalExtension, params};
+use serde_json::Value;
+use tokio::task::JoinHandle;
+
+/// Response header reporting whether a cacheable request was served from
+/// the cache.
+pub const CACHE_HEADER: &str = "x-shim-cache";
+
+/// Rows read per eviction query once SQLite is over its size cap.
+const EVICT_BATCH: i64 = 32;
+
+/// Settings for the response cache.
+#[derive(Clone, Debug)]
+pub struct CacheConfig {
+    /// Routes below `/v1/` whose `POST` responses may be cached.
+    pub routes: Vec<String>,
+    pub ttl: Duration,
+    /// Entries kept in memory, least recently used evicted first.
+    pub max_entries: usize,
+    /// Responses larger than this are passed through without being stored.
+    pub max_entry_bytes: usize,
+    /// SQLite file backing the in-memory cache, so entries survive restarts.
+    pub sqlite: Option<String>,
+    /// Total response bytes kept in SQLite.
+    pub sqlite_max_bytes: u64,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            routes: vec!["chat/completions".to_string(), "completions".to_string()],
+            ttl: Duration::from_secs(3600),
+            max_entries: 1000,
+            max_entry_bytes: 1024 * 1024,
+            sqlite: None,
+            sqlite_max_bytes: 256 * 1024 * 1024,
+        }
+    }
+}
+
+/// A stored upstream response.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CachedResponse {
+    pub content_type: Option<String>,
+    pub body: Bytes,
+}
+
+struct MemoryEntry {
+    response: CachedResponse,
+    stored_at: SystemTime,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct Memory {
+    entries: HashMap<String, MemoryEntry>,
+    clock: u64,
+}
+
+/// What the client asked of the cache with `Cache-Control`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Directives {
+    /// Do not answer from the cache (but do store the fresh response).
+    pub no_cache: bool,
+    /// Neither answer from nor write to the cache.
+    pub no_store: bool,
+}
+
+pub fn directives(headers: &HeaderMap) -> Directives {
+    let mut d = Directives::default();
+    for value in headers.get_all(header::CACHE_CONTROL) {
+        for token in value.to_str().unwrap_or_default().split(',') {
+            match token.trim().to_ascii_lowercase().as_str() {
+                "no-cache" => d.no_cache = true,
+                "no-store" => d.no_store = true,
+                _ => {}
+            }
+        }
+    }
+    d
+}
+
+/// Cache for responses to deterministic requests (`temperature: 0`).  The
+/// key is a digest of the route, model and canonicalized JSON body, so
+/// requests differing only in key order or whitespace share an entry.
+/// Streaming and non-streaming requests are cached separately, and a
+/// streamed response is replayed event by event.
+#[derive(Clone)]
+pub struct ResponseCache {
+    config: CacheConfig,
+    memory: Arc<Mutex<Memory>>,
+}
+
+impl ResponseCache {
+    pub fn new(config: CacheConfig) -> Self {
+        ResponseCache {
+            config,
+            memory: Arc::default(),
+        }
+    }
+
+    /// Cache key for a request, or `None` when it must not be cached.
+    /// Requests with a different `scope`, such as the per-user native
+    /// options applied upstream, never share an entry.
+    pub fn key(&self, scope: Option<&str>, method: &Method, path: &str, body: &[u8]) -> Option<String> {
+        if method != Method::POST || !self.config.routes.iter().any(|r| r == path) {
+            return None;
+        }
+        let value: Value = serde_json::from_slice(body).ok()?;
+        if value.get("temperature").and_then(Value::as_f64) != Some(0.0) {
+            return None;
+        }
+        let model = value.get("model").and_then(Value::as_str).unwrap_or_default();
+        // serde_json's map is ordered by key, so this is canonical
+        let canonical = serde_json::to_vec(&value).ok()?;
+        let mut ctx = digest::Context::new(&digest::SHA256);
+        for part in [scope.unwrap_or_default().as_bytes(), path.as_bytes(), model.as_bytes(), &canonical] {
+            ctx.update(part);
+            ctx.update(b"\0");
+        }
+        Some(ctx.finish().as_ref().iter().map(|b| format!("{b:02x}")).collect())
+    }
+
+    pub async fn get(&self, key: &str) -> Option<CachedResponse> {
+        let now = SystemTime::now();
+        {
+            let mut memory = self.memory.lock().unwrap();
+            memory.clock += 1;
+            let clock = memory.clock;
+            match memory.entries.get_mut(key) {
+                Some(entry) if entry.stored_at + self.config.ttl > now => {
+                    entry.last_used = clock;
+                    return Some(entry.response.clone());
+                }
+                Some(_) => {
+                    memory.entries.remove(key);
+                }
+                None => {}
+            }
+        }
+        let path = self.config.sqlite.clone()?;
+        let (owned_key, ttl) = (key.to_string(), self.config.ttl);
+        let found = tokio::task::spawn_blocking(move || sqlite_get(&path, &owned_key, ttl)).await;
+        match found {
+            Ok(Ok(Some((response, stored_at)))) => {
+                self.remember(key, response.clone(), stored_at);
+                Some(response)
+            }
+            Ok(Ok(None)) => None,
+            Ok(Err(e)) => {
+                eprintln!("response cache lookup failed: {e:#}");
+                None
+            }
+            Err(e) => {
+                eprintln!("response cache lookup failed: {e}");
+                None
+            }
+        }
+    }
+
+    /// Keep `response` in memory right away and write it to SQLite on the
+    /// blocking pool, returning the write's handle.
+    fn store(&self, key: &str, response: CachedResponse) -> Option<JoinHandle<()>> {
+        if response.body.len() > self.config.max_entry_bytes {
+            return None;
+        }
+        self.remember(key, response.clone(), SystemTime::now());
+        let path = self.config.sqlite.clone()?;
+        let (key, max_bytes) = (key.to_string(), self.config.sqlite_max_bytes);
+        Some(tokio::task::spawn_blocking(move || {
+            if let Err(e) = sqlite_put(&path, &key, &response, max_bytes) {
+                eprintln!("failed to store cached response: {e:#}");
+            }
+        }))
+    }
+
+    fn remember(&self, key: &str, response: CachedResponse, stored_at: SystemTime) {
+        if self.config.max_entries == 0 {
+            return;
+        }
+        let mut memory = self.memory.lock().unwrap();
+        memory.clock += 1;
+        let last_used = memory.clock;
+        memory.entries.insert(
+            key.to_string(),
+            MemoryEntry {
+                response,
+                stored_at,
+                last_used,
+            },
+        );
+        while memory.entries.len() > self.config.max_entries {
+            let oldest = memory
+                .entries
+                .iter()
+                .min_by_key(|(_, e)| e.last_used)
+                .map(|(k, _)| k.clone());
+            match oldest {
+                Some(k) => memory.entries.remove(&k),
+                None => break,
+            };
+        }
+    }
+
+    /// Pass `resp` through to the client, storing its body under `key` once
+    /// it has been sent in full.  Only successful responses are stored.
+    pub fn store_on_completion(&self, key: String, resp: Response<Body>) -> Response<Body> {
+        let (mut parts, body) = resp.into_parts();
+        parts.headers.insert(CACHE_HEADER, HeaderValue::from_static("MISS"));
+        if parts.status != StatusCode::OK {
+            return Response::from_parts(parts, body);
+        }
+        let content_type = parts
+            .headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string);
+        let recording = Recording {
+            inner: Box::pin(body.into_data_stream()),
+            buf: Vec::new(),
+            overflowed: false,
+            finished: false,
+            limit: self.config.max_entry_bytes,
+            on_complete: Some(Box::new({
+                let cache = self.clone();
+                move |body: Vec<u8>| {
+                    let response = CachedResponse {
+                        content_type,
+                        body: body.into(),
+                    };
+                    // the write finishes on its own; the stream must not wait
+                    cache.store(&key, response);
+                }
+            })),
+        };
+        Response::from_parts(parts, Body::from_stream(recording))
+    }
+}
+
+/// A response for a cache hit.  Server-sent events go out one event per
+/// chunk, as they did from upstream.
+pub fn replay(cached: CachedResponse) -> Response<Body> {
+    let is_sse = cached
+        .content_type
+        .as_deref()
+        .is_some_and(|ct| ct.starts_with("text/event-stream"));
+    let body = if is_sse {
+        let events: Vec<Result<Bytes, std::convert::Infallible>> = split_events(&cached.body)
+            .into_iter()
+            .map(Ok)
+            .collect();
+        Body::from_stream(stream::iter(events))
+    } else {
+        Body::from(cached.body)
+    };
+    let mut resp = Response::new(body);
+    if let Some(Ok(ct)) = cached.content_type.map(HeaderValue::try_from) {
+        resp.headers_mut().insert(header::CONTENT_TYPE, ct);
+    }
+    resp.headers_mut().insert(CACHE_HEADER, HeaderValue::from_static("HIT"));
+    resp
+}
+
+/// Split an SSE body after each blank line, keeping the separators.
+fn split_events(body: &Bytes) -> Vec<Bytes> {
+    let mut events = Vec::new();
+    let mut start = 0;
+    let mut i = 0;
+    while i + 1 < body.len() {
+        if body[i] == b'\n' && body[i + 1] == b'\n' {
+            events.push(body.slice(start..i + 2));
+            start = i + 2;
+            i += 2;
+        } else {
+            i += 1;
+        }
+    }
+    if start < body.len() {
+        events.push(body.slice(start..));
+    }
+    events
+}
+
+type DataStream = Pin<Box<dyn Stream<Item = Result<Bytes, axum::Error>> + Send>>;
+
+/// Relays a body while keeping a copy, handed to `on_complete` when the
+/// body ends cleanly and stayed within `limit`.
+struct Recording {
+    inner: DataStream,
+    buf: Vec<u8>,
+    overflowed: bool,
+    finished: bool,
+    limit: usize,
+    on_complete: Option<Box<dyn FnOnce(Vec<u8>) + Send>>,
+}
+
+impl Stream for Recording {
+    type Item = Result<Bytes, axum::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.finished {
+            return Poll::Ready(None);
+        }
+        let item = self.inner.poll_next_unpin(cx);
+        match &item {
+            Poll::Ready(Some(Ok(chunk))) if !self.overflowed => {
+                if self.buf.len() + chunk.len() > self.limit {
+                    self.overflowed = true;
+                    self.buf = Vec::new();
+                } else {
+                    self.buf.extend_from_slice(chunk);
+                }
+            }
+            Poll::Ready(Some(Err(_))) => self.overflowed = true,
+            Poll::Ready(None) => {
+                self.finished = true;
+                if !self.overflowed {
+                    if let Some(done) = self.on_complete.take() {
+                        done(std::mem::take(&mut self.buf));
+                    }
+                }
+            }
+            _ => {}
+        }
+        item
+    }
+}
+
+fn ensure_table(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS response_cache(
+            key TEXT PRIMARY KEY,
+            content_type TEXT,
+            body BLOB NOT NULL,
+            created_at INTEGER NOT NULL,
+            last_used INTEGER NOT NULL
+        )",
+        [],
+    )?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS response_cache_last_used ON response_cache(last_used)",
+        [],
+    )?;
+    Ok(())
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn sqlite_get(path: &str, key: &str, ttl: Duration) -> Result<Option<(CachedResponse, SystemTime)>> {
+    let conn = Connection::open(path)?;
+    ensure_table(&conn)?;
+    let now = unix_now();
+    let row = conn
+        .query_row(
+            "SELECT content_type, body, created_at FROM response_cache WHERE key = ?1",
+            [key],
+            |row| {
+                Ok((
+                    row.get::<_, Option<String>>(0)?,
+                    row.get::<_, Vec<u8>>(1)?,
+                    row.get::<_, i64>(2)? as u64,
+                ))
+            },
+        )
+        .optional()?;
+    let Some((content_type, body, created_at)) = row else {
+        return Ok(None);
+    };
+    if created_at + ttl.as_secs() <= now {
+        conn.execute("DELETE FROM response_cache WHERE key = ?1", [key])?;
+        return Ok(None);
+    }
+    conn.execute(
+        "UPDATE response_cache SET last_used = ?1 WHERE key = ?2",
+        params![now as i64, key],
+    )?;
+    let response = CachedResponse {
+        content_type,
+        body: body.into(),
+    };
+    Ok(Some((response, UNIX_EPOCH + Duration::from_secs(created_at))))
+}
+
+fn sqlite_put(path: &str, key: &str, response: &CachedResponse, max_bytes: u64) -> Result<()> {
+    let mut conn = Connection::open(path)?;
+    ensure_table(&conn)?;
+    let tx = conn.transaction()?;
+    let now = unix_now() as i64;
+    tx.execute(
+        "INSERT OR REPLACE INTO response_cache(key, content_type, body, created_at, last_used)
+         VALUES (?1, ?2, ?3, ?4, ?4)",
+        params![key, response.content_type, &response.body[..], now],
+    )?;
+    // evict least recently used rows beyond the size cap, a few at a time
+    let mut total: u64 = tx.query_row("SELECT COALESCE(SUM(length(body)), 0) FROM response_cache", [], |row| {
+        row.get::<_, i64>(0)
+    })? as u64;
+    while total > max_bytes {
+        let oldest: Vec<(String, u64)> = tx
+            .prepare("SELECT key, length(body) FROM response_cache ORDER BY last_used, rowid LIMIT ?1")?
+            .query_map([EVICT_BATCH], |row| Ok((row.get(0)?, row.get::<_, i64>(1)? as u64)))?
+            .collect::<Result<_, _>>()?;
+        if oldest.is_empty() {
+            break;
+        }
+        for (row_key, len) in oldest {
+            if total <= max_bytes {
+                break;
+            }
+            tx.execute("DELETE FROM response_cache WHERE key = ?1", [row_key])?;
+            total -= len;
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    /// Store and wait for the SQLite write.
+    async fn put(cache: &ResponseCache, key: &str, response: CachedResponse) {
+        if let Some(write) = cache.store(key, response) {
+            write.await.unwrap();
+        }
+    }
+
+    fn cached(body: &'static str) -> CachedResponse {
+        CachedResponse {
+            content_type: Some("application/json".into()),
+            body: Bytes::from_static(body.as_bytes()),
+        }
+    }
+
+    #[test]
+    fn keys_deterministic_requests_only() {
+        let cache = ResponseCache::new(CacheConfig::default());
+        let key = |path: &str, body: &str| cache.key(None, &Method::POST, path, body.as_bytes());
+        let a = key("chat/completions", r#"{"model":"llama3","temperature":0,"messages":[]}"#);
+        let b = key("chat/completions", r#"{ "messages": [], "temperature": 0, "model": "llama3" }"#);
+        assert!(a.is_some());
+        assert_eq!(a, b);
+        assert_ne!(a, key("chat/completions", r#"{"model":"mistral","temperature":0,"messages":[]}"#));
+        assert_ne!(a, key("completions", r#"{"model":"llama3","temperature":0,"messages":[]}"#));
+        assert!(key("chat/completions", r#"{"model":"llama3","temperature":0.7}"#).is_none());
+        assert!(key("chat/completions", r#"{"model":"llama3"}"#).is_none());
+        assert!(key("embeddings", r#"{"model":"llama3","temperature":0}"#).is_none());
+        assert!(cache.key(None, &Method::GET, "chat/completions", b"{\"temperature\":0}").is_none());
+        let scoped = cache.key(
+            Some(r#"{"num_ctx":8192}"#),
+            &Method::POST,
+            "chat/completions",
+            br#"{"model":"llama3","temperature":0,"messages":[]}"#,
+        );
+        assert!(scoped.is_some());
+        assert_ne!(a, scoped);
+    }
+
+    #[tokio::test]
+    async fn memory_lru_and_sqlite_backing() {
+        let tmp = NamedTempFile::new().unwrap();
+        let config = CacheConfig {
+            max_entries: 2,
+            sqlite: Some(tmp.path().to_str().unwrap().to_string()),
+            sqlite_max_bytes: 4,
+            ..Default::default()
+        };
+        let cache = ResponseCache::new(config.clone());
+        put(&cache, "a", cached("aaaa")).await;
+        put(&cache, "b", cached("bbbb")).await;
+        assert!(cache.get("a").await.is_some());
+        put(&cache, "c", cached("cccc")).await;
+        // `b` was least recently used in memory, and SQLite only has room
+        // for the newest entry
+        assert_eq!(cache.memory.lock().unwrap().entries.len(), 2);
+        assert!(!cache.memory.lock().unwrap().entries.contains_key("b"));
+        assert!(cache.get("b").await.is_none());
+
+        // a fresh process finds entries in SQLite
+        let restarted = ResponseCache::new(config);
+        assert_eq!(restarted.get("c").await, Some(cached("cccc")));
+        assert!(restarted.get("a").await.is_none());
+
+        // eviction works through the table in batches
+        let path = tmp.path().to_str().unwrap();
+        for i in 0..100 {
+            sqlite_put(path, &format!("k{i}"), &cached("xx"), u64::MAX).unwrap();
+        }
+        sqlite_put(path, "last", &cached("yy"), 4).unwrap();
+        let rows: i64 = Connection::open(path)
+            .unwrap()
+            .query_row("SELECT COUNT(*) FROM response_cache", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(rows, 2);
+
+        let expired = ResponseCache::new(CacheConfig {
+            ttl: Duration::ZERO,
+            ..Default::default()
+        });
+        put(&expired, "a", cached("aaaa")).await;
+        assert!(expired.get("a").await.is_none());
+    }
+
+    #[test]
+    fn cache_control_and_sse_replay() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("max-age=0, No-Cache"));
+        assert_eq!(
+            directives(&headers),
+            Directives {
+                no_cache: true,
+                no_store: false
+            }
+        );
+        let events = split_events(&Bytes::from_static(b"data: 1\n\ndata: 2\n\ndata: [DONE]\n\n"));
+        assert_eq!(events, vec!["data: 1\n\n", "data: 2\n\n", "data: [DONE]\n\n"]);
+    }
+}
@@ -0,0 +1,347 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    body::{Body, Bytes},
+    http::{HeaderMap, HeaderValue, Response, StatusCode},
+};
+use futures_util::{
+    StreamExt,
+    future::{BoxFuture, FutureExt, Shared},
+    stream,
+};
+use hyper::Method;
+use ring::digest;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::proxy::send_upstream;
+use crate::state::AppState;
+
+/// Header set on responses that were shared with an identical request
+/// already in flight.
+pub const COALESCED_HEADER: &str = "x-shim-coalesced";
+
+/// Largest upstream answer buffered for sharing.  A longer one goes to the
+/// caller that started the call as it arrives, and callers that joined it
+/// make their own upstream calls.
+const MAX_SHARED_BYTES: usize = 8 * 1024 * 1024;
+
+/// Settings for single-flight de-duplication of identical requests.
+#[derive(Clone, Debug)]
+pub struct CoalesceConfig {
+    /// Routes below `/v1/` whose `POST` requests may share an upstream call.
+    pub routes: Vec<String>,
+}
+
+impl Default for CoalesceConfig {
+    fn default() -> Self {
+        CoalesceConfig {
+            routes: vec!["chat/completions".into(), "completions".into(), "embeddings".into()],
+        }
+    }
+}
+
+/// Upstream calls made and saved on one route.
+#[derive(Clone, Copy, Debug, Default, Serialize, PartialEq, Eq)]
+pub struct RouteStats {
+    /// Requests that went upstream.
+    pub upstream_calls: u64,
+    /// Requests answered by joining an identical call already in flight.
+    pub coalesced: u64,
+}
+
+/// An upstream answer, buffered so every waiting caller gets a copy.
+struct Buffered {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+/// How an upstream call ended, as seen by everyone waiting on it.
+enum Outcome {
+    Shared(Buffered),
+    /// Too long to buffer; held for the caller that started the call.
+    Oversized(Mutex<Option<Response<Body>>>),
+}
+
+type Flight = Shared<BoxFuture<'static, Arc<Outcome>>>;
+
+/// Who a request may share an answer with: the same key-store user, or for
+/// callers without one, the same credential.  Only a digest of the
+/// credential is kept.
+pub fn scope(user: Option<&str>, headers: &HeaderMap) -> String {
+    if let Some(user) = user {
+        return format!("user:{user}");
+    }
+    let credential = headers.get("authorization").map_or(&[][..], |v| v.as_bytes());
+    let digest = digest::digest(&digest::SHA256, credential);
+    format!("key:{}", digest.as_ref().iter().map(|b| format!("{b:02x}")).collect::<String>())
+}
+
+/// Shares one upstream call between identical non-streaming requests that
+/// arrive while it is in flight.  Requests only match within one
+/// [`scope`], so answers never cross key boundaries.
+#[derive(Clone)]
+pub struct Coalescer {
+    config: CoalesceConfig,
+    inflight: Arc<Mutex<HashMap<String, Flight>>>,
+    stats: Arc<Mutex<BTreeMap<String, RouteStats>>>,
+}
+
+impl Coalescer {
+    pub fn new(config: CoalesceConfig) -> Self {
+        Coalescer {
+            config,
+            inflight: Arc::default(),
+            stats: Arc::default(),
+        }
+    }
+
+    /// Key for a request from `scope`, or `None` when it must go upstream on
+    /// its own: other routes and methods, streaming requests and non-JSON
+    /// bodies.
+    pub fn key(&self, scope: &str, method: &Method, path: &str, body: &[u8]) -> Option<String> {
+        if method != Method::POST || !self.config.routes.iter().any(|r| r == path) {
+            return None;
+        }
+        let value: Value = serde_json::from_slice(body).ok()?;
+        if value.get("stream").and_then(Value::as_bool) == Some(true) {
+            return None;
+        }
+        let model = value.get("model").and_then(Value::as_str).unwrap_or_default();
+        // serde_json's map is ordered by key, so this is canonical
+        let canonical = serde_json::to_vec(&value).ok()?;
+        let mut ctx = digest::Context::new(&digest::SHA256);
+        for part in [scope.as_bytes(), path.as_bytes(), model.as_bytes(), &canonical] {
+            ctx.update(part);
+            ctx.update(b"\0");
+        }
+        Some(ctx.finish().as_ref().iter().map(|b| format!("{b:02x}")).collect())
+    }
+
+    /// Forward the request, or wait for the identical one already in flight.
+    /// The upstream call runs to completion even if the caller that started
+    /// it goes away, as long as anyone is still waiting.
+    pub async fn forward(
+        &self,
+        state: &AppState,
+        key: String,
+        user: Option<String>,
+        path: String,
+        headers: HeaderMap,
+        body: Bytes,
+    ) -> Response<Body> {
+        let (flight, joined) = {
+            let mut inflight = self.inflight.lock().unwrap();
+            match inflight.get(&key) {
+                Some(flight) => (flight.clone(), true),
+                None => {
+                    let flight = self.start(
+                        state,
+                        key.clone(),
+                        user.clone(),
+                        path.clone(),
+                        headers.clone(),
+                        body.clone(),
+                    );
+                    inflight.insert(key, flight.clone());
+                    (flight, false)
+                }
+            }
+        };
+        {
+            let mut stats = self.stats.lock().unwrap();
+            let route = stats.entry(path.clone()).or_default();
+            if joined {
+                route.coalesced += 1;
+            } else {
+                route.upstream_calls += 1;
+            }
+        }
+
+        let outcome = flight.await;
+        let buffered = match &*outcome {
+            Outcome::Shared(buffered) => buffered,
+            Outcome::Oversized(resp) => {
+                if let (false, Some(resp)) = (joined, resp.lock().unwrap().take()) {
+                    return resp;
+                }
+                return send_upstream(state, user.as_deref(), Method::POST, path, headers, body).await;
+            }
+        };
+        let mut resp = Response::new(Body::from(buffered.body.clone()));
+        *resp.status_mut() = buffered.status;
+        *resp.headers_mut() = buffered.headers.clone();
+        if joined {
+            resp.headers_mut()
+                .insert(COALESCED_HEADER, HeaderValue::from_static("1"));
+        }
+        resp
+    }
+
+    fn start(
+        &self,
+        state: &AppState,
+        key: String,
+        user: Option<String>,
+        path: String,
+        headers: HeaderMap,
+        body: Bytes,
+    ) -> Flight {
+        let state = state.clone();
+        let inflight = self.inflight.clone();
+        async move {
+            let resp = send_upstream(&state, user.as_deref(), Method::POST, path, headers, body).await;
+            let outcome = buffer(resp).await;
+            inflight.lock().unwrap().remove(&key);
+            Arc::new(outcome)
+        }
+        .boxed()
+        .shared()
+    }
+
+    /// Per-route counters since the proxy started.
+    pub fn stats(&self) -> BTreeMap<String, RouteStats> {
+        self.stats.lock().unwrap().clone()
+    }
+}
+
+/// Read `resp` for sharing, up to [`MAX_SHARED_BYTES`].
+async fn buffer(resp: Response<Body>) -> Outcome {
+    let (parts, body) = resp.into_parts();
+    let mut chunks = body.into_data_stream();
+    let mut read = Vec::new();
+    while let Some(chunk) = chunks.next().await {
+        let Ok(chunk) = chunk else {
+            return Outcome::Shared(Buffered {
+                status: StatusCode::BAD_GATEWAY,
+                headers: HeaderMap::new(),
+                body: Bytes::from_static(b"Upstream request failed"),
+            });
+        };
+        read.extend_from_slice(&chunk);
+        if read.len() > MAX_SHARED_BYTES {
+            let rest = stream::once(async { Ok(Bytes::from(read)) }).chain(chunks);
+            let resp = Response::from_parts(parts, Body::from_stream(rest));
+            return Outcome::Oversized(Mutex::new(Some(resp)));
+        }
+    }
+    Outcome::Shared(Buffered {
+        status: parts.status,
+        headers: parts.headers,
+        body: read.into(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body;
+    use httpmock::MockServer;
+    use std::time::Duration;
+
+    #[test]
+    fn keys_identical_non_streaming_requests() {
+        let coalescer = Coalescer::new(CoalesceConfig::default());
+        let key = |scope, path, body: &str| coalescer.key(scope, &Method::POST, path, body.as_bytes());
+        let a = key("user:alice", "chat/completions", r#"{"model":"m","messages":[]}"#);
+        assert!(a.is_some());
+        assert_eq!(a, key("user:alice", "chat/completions", r#"{ "messages":[], "model":"m" }"#));
+        assert_ne!(a, key("user:bob", "chat/completions", r#"{"model":"m","messages":[]}"#));
+        assert_eq!(key("user:alice", "chat/completions", r#"{"model":"m","stream":true}"#), None);
+        assert_eq!(key("user:alice", "models", "{}"), None);
+        assert_eq!(coalescer.key("", &Method::GET, "embeddings", b"{}"), None);
+    }
+
+    #[test]
+    fn keys_without_a_user_are_kept_apart() {
+        let bearer = |key: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert("authorization", format!("Bearer {key}").parse().unwrap());
+            headers
+        };
+        let first = scope(None, &bearer("key-one"));
+        assert_eq!(first, scope(None, &bearer("key-one")));
+        assert_ne!(first, scope(None, &bearer("key-two")));
+        assert!(!first.contains("key-one"));
+        assert_eq!(scope(Some("alice"), &bearer("key-one")), scope(Some("alice"), &bearer("key-two")));
+    }
+
+    #[tokio::test]
+    async fn shares_one_upstream_call() {
+        let server = MockServer::start_async().await;
+        let mock = server.mock(|when, then| {
+            when.method("POST").path("/v1/chat/completions");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(r#"{"choices":[]}"#)
+                .delay(Duration::from_millis(200));
+        });
+        let state = AppState::for_tests(&server.url(""), &[]);
+        let coalescer = Coalescer::new(CoalesceConfig::default());
+        let body = r#"{"model":"m","messages":[]}"#;
+        let key = coalescer
+            .key("", &Method::POST, "chat/completions", body.as_bytes())
+            .unwrap();
+
+        let calls = (0..5).map(|_| {
+            coalescer.forward(
+                &state,
+                key.clone(),
+                None,
+                "chat/completions".into(),
+                HeaderMap::new(),
+                Bytes::from(body),
+            )
+        });
+        let responses = futures_util::future::join_all(calls).await;
+        mock.assert_calls(1);
+        let mut joined = 0;
+        for resp in responses {
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert_eq!(resp.headers()["content-type"], "application/json");
+            joined += resp.headers().contains_key(COALESCED_HEADER) as u64;
+            let body = body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+            assert_eq!(body, r#"{"choices":[]}"#);
+        }
+        assert_eq!(joined, 4);
+        let stats = coalescer.stats()["chat/completions"];
+        assert_eq!(stats, RouteStats { upstream_calls: 1, coalesced: 4 });
+
+        // once finished, the next identical request goes upstream again
+        coalescer
+            .forward(&state, key, None, "chat/completions".into(), HeaderMap::new(), Bytes::from(body))
+            .await;
+        mock.assert_calls(2);
+    }
+
+    #[tokio::test]
+    async fn oversized_answers_are_not_shared() {
+        let server = MockServer::start_async().await;
+        let long = "x".repeat(MAX_SHARED_BYTES + 1);
+        let mock = server.mock(|when, then| {
+            when.method("POST").path("/v1/embeddings");
+            then.status(200).body(&long).delay(Duration::from_millis(200));
+        });
+        let state = AppState::for_tests(&server.url(""), &[]);
+        let coalescer = Coalescer::new(CoalesceConfig::default());
+        let body = r#"{"model":"m","input":"hi"}"#;
+        let key = coalescer.key("", &Method::POST, "embeddings", body.as_bytes()).unwrap();
+
+        let calls = (0..3).map(|_| {
+            coalescer.forward(&state, key.clone(), None, "embeddings".into(), HeaderMap::new(), Bytes::from(body))
+        });
+        let responses = futures_util::future::join_all(calls).await;
+        // the callers that joined went upstream on their own
+        mock.assert_calls(3);
+        for resp in responses {
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert!(!resp.headers().contains_key(COALESCED_HEADER));
+            let body = body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+            assert_eq!(body.len(), long.len());
+        }
+    }
+}
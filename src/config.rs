@@ -1,16 +1,164 @@
-use std::{env, fs, net::SocketAddr};
+use std::{
+    collections::{HashMap, HashSet},
+    env, fs,
+    net::SocketAddr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use anyhow::{Context, Result};
+use ipnet::IpNet;
 use rusqlite::Connection;
 
+use crate::bans::BanConfig;
+use crate::batch::BatchConfig;
+use crate::cache::CacheConfig;
+use crate::coalesce::CoalesceConfig;
+use crate::config_file::{
+    AccessSection, AdminSection, BansSection, BreakerSection, CacheSection, CoalesceSection,
+    ContextSection, CorsSection, EmbeddingBatchSection, EmbeddingCacheSection, FileConfig,
+    ForwardAuthSection, ForwardedSection, JwtSection, KeysSection, NativeChatSection, RetrySection,
+    SchemaSection, ServerSection, ThinkSection, TlsSection, TokensSection, ToolsSection,
+    mask_secret,
+};
+use crate::context::{ContextConfig, Overflow};
+use crate::cors::CorsConfig;
+use crate::embeddings::EmbeddingCacheConfig;
+use crate::forward_auth::ForwardAuthConfig;
+use crate::forwarded::{ForwardedConfig, parse_cidrs};
+use crate::jwt::JwtConfig;
+use crate::native::NativeChatConfig;
+use crate::policy::{Policies, Policy};
+use crate::retry::{BreakerConfig, RetryPolicy};
+use crate::roles::Roles;
+use crate::schema::SchemaConfig;
+use crate::think::{ThinkConfig, ThinkMode};
+use crate::tls::TlsConfig;
+use crate::tokens::{MIN_SECRET_LEN, TokenConfig};
+use crate::tools::{ToolMode, ToolsConfig};
+
 /// Application configuration, loaded at startup.
 pub struct AppConfig {
     /// List of valid API keys.
     pub valid_keys: Vec<String>,
+    /// Per-user data; only populated from SQLite databases that have a
+    /// `username` column.
+    pub users: UserDirectory,
     /// Base URL for the Ollama service (no trailing slash).
     pub ollama_url: String,
     /// Address on which the proxy should listen.
     pub proxy_addr: SocketAddr,
+    /// Retry behaviour for failed upstream requests.
+    pub retry: RetryPolicy,
+    /// Circuit breaker thresholds applied per backend.
+    pub breaker: BreakerConfig,
+    /// HTTPS settings; `None` serves plain HTTP.
+    pub tls: Option<TlsConfig>,
+    /// Cross-origin settings for browser clients; `None` disables CORS.
+    pub cors: Option<CorsConfig>,
+    /// Trusted reverse proxies and the forwarding headers sent upstream.
+    pub forwarded: ForwardedConfig,
+    /// Networks refused outright, before authentication.
+    pub denied_ips: Vec<IpNet>,
+    /// Brute-force protection for failed authentications.
+    pub bans: BanConfig,
+    /// SQLite key database in use, if any; other per-user state such as bans
+    /// is kept alongside the keys.
+    pub sqlite_path: Option<String>,
+    /// Address of the admin API listener; `None` disables it.
+    pub admin_addr: Option<SocketAddr>,
+    /// Routes and methods each role may use.
+    pub roles: Roles,
+    /// Signing settings for short-lived scoped tokens.
+    pub tokens: TokenConfig,
+    /// External authorization service; when set it decides access instead
+    /// of the shim's own keys.
+    pub forward_auth: Option<ForwardAuthConfig>,
+    /// OIDC JWT validation; `None` accepts API keys only.
+    pub jwt: Option<JwtConfig>,
+    /// Response cache; `None` when disabled (the default).
+    pub cache: Option<CacheConfig>,
+    /// Per-input `/v1/embeddings` cache; `None` when disabled.
+    pub embedding_cache: Option<EmbeddingCacheConfig>,
+    /// Micro-batching of embeddings requests; `None` when disabled.
+    pub embedding_batch: Option<BatchConfig>,
+    /// Single-flight de-duplication; `None` when disabled (the default).
+    pub coalesce: Option<CoalesceConfig>,
+    /// Chat completions through native `/api/chat`; `None` when disabled.
+    pub native_chat: Option<NativeChatConfig>,
+    /// Context windows to enforce; `None` when no limits are configured.
+    pub context: Option<ContextConfig>,
+    /// Per-model handling of `<think>` blocks; empty leaves answers alone
+    /// unless a request asks with `X-Shim-Think`.
+    pub think: ThinkConfig,
+    /// Structured output validation; `None` when disabled (the default).
+    pub schema: Option<SchemaConfig>,
+    /// Per-model tool calling emulation; empty passes `tools` through.
+    pub tools: ToolsConfig,
+}
+
+/// Role that grants access to the admin API.
+pub const ADMIN_ROLE: &str = "admin";
+
+/// Everything the SQLite key database records about named users.
+#[derive(Clone, Debug, Default)]
+pub struct UserDirectory {
+    /// Username → API key.
+    pub keys: HashMap<String, String>,
+    /// Username → origins the user's key may be used from.  Users without
+    /// an entry are not restricted.
+    pub origins: HashMap<String, Vec<String>>,
+    /// Username → networks the user may connect from.  Users without an
+    /// entry are not restricted.
+    pub ips: HashMap<String, Vec<IpNet>>,
+    /// Username → role; users without an entry have the role `user`.
+    pub roles: HashMap<String, String>,
+    /// Users whose credentials are currently refused.
+    pub disabled: HashSet<String>,
+    /// Username → maximum requests per UTC day.
+    pub quotas: HashMap<String, u64>,
+    /// Keys beyond each user's main one, such as keys kept valid for a grace
+    /// period after rotation.
+    pub extra_keys: HashMap<String, ExtraKey>,
+    /// Request policies per user and per model pattern.
+    pub policies: Policies,
+}
+
+/// An additional key for a user.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExtraKey {
+    pub username: String,
+    /// Seconds since the Unix epoch after which the key stops working;
+    /// `None` keeps it valid indefinitely.
+    pub expires_at: Option<u64>,
+}
+
+impl UserDirectory {
+    pub fn role(&self, username: &str) -> &str {
+        self.roles.get(username).map_or("user", String::as_str)
+    }
+
+    pub fn is_disabled(&self, username: &str) -> bool {
+        self.disabled.contains(username)
+    }
+
+    pub fn contains(&self, username: &str) -> bool {
+        self.keys.contains_key(username)
+    }
+
+    /// Find the user owning `key`, whether it is their main key or an
+    /// extra one.
+    pub fn user_for_key(&self, key: &str) -> Option<&str> {
+        self.keys
+            .iter()
+            .find(|(_, k)| k.as_str() == key)
+            .map(|(user, _)| user.as_str())
+            .or_else(|| self.extra_keys.get(key).map(|k| k.username.as_str()))
+    }
+
+    /// When `key` stops working, if it is an expiring extra key.
+    pub fn key_expiry(&self, key: &str) -> Option<u64> {
+        self.extra_keys.get(key).and_then(|k| k.expires_at)
+    }
 }
 
 impl AppConfig {
@@ -22,44 +170,619 @@ impl AppConfig {
     /// 2. `API_KEYS_FILE` pointing at a newline- or comma-separated file.
     /// 3. `API_KEYS` environment variable containing comma-separated keys.
     pub fn load() -> Result<Self> {
-        let ollama_url = env::var("OLLAMA_URL").unwrap_or_else(|_| {
-            // default to localhost port used previously
-            "http://127.0.0.1:11434".to_string()
-        });
+        Self::load_with_file(FileConfig::default())
+    }
+
+    /// Like [`AppConfig::load`], but with a TOML configuration file as the
+    /// lowest layer: every environment variable that is set wins over the
+    /// corresponding file setting.
+    pub fn load_from(path: Option<&str>) -> Result<Self> {
+        match path {
+            Some(path) => Self::load_with_file(FileConfig::read(path)?),
+            None => Self::load(),
+        }
+    }
+
+    fn load_with_file(file: FileConfig) -> Result<Self> {
+        let ollama_url = env::var("OLLAMA_URL")
+            .ok()
+            .or(file.ollama_url)
+            .unwrap_or_else(|| {
+                // default to localhost port used previously
+                "http://127.0.0.1:11434".to_string()
+            });
 
         // default listening address for the proxy
-        let proxy_host = env::var("PROXY_HOST").unwrap_or_else(|_| "0.0.0.0".to_string());
+        let proxy_host = env::var("PROXY_HOST")
+            .ok()
+            .or(file.server.host)
+            .unwrap_or_else(|| "0.0.0.0".to_string());
         let proxy_port: u16 = env::var("PROXY_PORT")
             .ok()
             .and_then(|p| p.parse().ok())
+            .or(file.server.port)
             .unwrap_or(3000);
         let proxy_addr = format!("{}:{}", proxy_host, proxy_port)
             .parse()
             .context("failed to parse PROXY_HOST:PROXY_PORT into SocketAddr")?;
 
-        let valid_keys = if let Ok(sqlite_path) = env::var("API_KEYS_SQLITE") {
-            load_keys_from_sqlite(&sqlite_path)?
+        // a key source set in the environment replaces the file's entirely,
+        // mirroring how the CLI flags replace both
+        let env_keys_set = ["API_KEYS_SQLITE", "API_KEYS_FILE", "API_KEYS"]
+            .iter()
+            .any(|name| env::var(name).is_ok());
+        let mut users = UserDirectory::default();
+        let mut sqlite_path = None;
+        let valid_keys = if let Ok(path) = env::var("API_KEYS_SQLITE") {
+            users = load_users_from_sqlite(&path)?;
+            let keys = load_keys_from_sqlite(&path)?;
+            sqlite_path = Some(path);
+            keys
         } else if let Ok(file_path) = env::var("API_KEYS_FILE") {
             load_keys_from_file(&file_path)?
-        } else {
+        } else if env_keys_set || file.keys.is_empty() {
             let keys = env::var("API_KEYS").unwrap_or_default();
             keys.split(',')
                 .map(|s| s.trim().to_string())
                 .filter(|s| !s.is_empty())
                 .collect()
+        } else if let Some(path) = &file.keys.sqlite {
+            users = load_users_from_sqlite(path)?;
+            sqlite_path = Some(path.clone());
+            load_keys_from_sqlite(path)?
+        } else if let Some(file_path) = &file.keys.file {
+            load_keys_from_file(file_path)?
+        } else {
+            file.keys.keys.unwrap_or_default()
+        };
+
+        let mut retry = RetryPolicy::default();
+        if let Some(n) = env_parse("RETRY_MAX_ATTEMPTS")?.or(file.retry.max_attempts) {
+            retry.max_attempts = n;
+        }
+        if let Some(ms) = env_parse("RETRY_BASE_DELAY_MS")?.or(file.retry.base_delay_ms) {
+            retry.base_delay = Duration::from_millis(ms);
+        }
+        if let Some(ms) = env_parse("RETRY_MAX_DELAY_MS")?.or(file.retry.max_delay_ms) {
+            retry.max_delay = Duration::from_millis(ms);
+        }
+        if let Some(routes) = env_list("RETRY_SAFE_ROUTES").or(file.retry.safe_routes) {
+            retry.safe_routes = routes;
+        }
+
+        let mut breaker = BreakerConfig::default();
+        if let Some(n) = env_parse("BREAKER_FAILURE_THRESHOLD")?.or(file.breaker.failure_threshold) {
+            breaker.failure_threshold = n;
+        }
+        if let Some(secs) = env_parse("BREAKER_COOLDOWN_SECS")?.or(file.breaker.cooldown_secs) {
+            breaker.cooldown = Duration::from_secs(secs);
+        }
+
+        let file_tls = file.tls.unwrap_or_default();
+        let cert = env::var("TLS_CERT").ok().or(file_tls.cert);
+        let key = env::var("TLS_KEY").ok().or(file_tls.key);
+        let tls = match (cert, key) {
+            (Some(cert_path), Some(key_path)) => Some(TlsConfig {
+                cert_path,
+                key_path,
+                http_port: env_parse("TLS_HTTP_PORT")?.or(file_tls.http_port),
+                http_redirect: env_flag("TLS_HTTP_REDIRECT")
+                    .or(file_tls.http_redirect)
+                    .unwrap_or(false),
+                reload_interval: Duration::from_secs(
+                    env_parse("TLS_RELOAD_SECS")?
+                        .or(file_tls.reload_secs)
+                        .unwrap_or(60),
+                ),
+                client_ca: env::var("TLS_CLIENT_CA").ok().or(file_tls.client_ca),
+                client_cert_required: env_flag("TLS_CLIENT_CERT_REQUIRED")
+                    .or(file_tls.client_cert_required)
+                    .unwrap_or(false),
+            }),
+            (None, None) => None,
+            _ => anyhow::bail!("TLS certificate and key must be set together (TLS_CERT/TLS_KEY or [tls] cert/key)"),
+        };
+
+        let mut cors = CorsConfig::default();
+        let file_cors = file.cors;
+        if let Some(origins) = env_list("CORS_ALLOWED_ORIGINS").or(file_cors.allowed_origins) {
+            cors.allowed_origins = origins;
+        }
+        if let Some(headers) = env_list("CORS_ALLOWED_HEADERS").or(file_cors.allowed_headers) {
+            cors.allowed_headers = headers;
+        }
+        if let Some(methods) = env_list("CORS_ALLOWED_METHODS").or(file_cors.allowed_methods) {
+            cors.allowed_methods = methods;
+        }
+        if let Some(secs) = env_parse("CORS_MAX_AGE_SECS")?.or(file_cors.max_age_secs) {
+            cors.max_age_secs = Some(secs);
+        }
+        if let Some(credentials) = env_flag("CORS_ALLOW_CREDENTIALS").or(file_cors.allow_credentials) {
+            cors.allow_credentials = credentials;
+        }
+        let cors = (!cors.allowed_origins.is_empty()).then_some(cors);
+
+        let file_forwarded = file.forwarded;
+        let mut forwarded = ForwardedConfig::default();
+        if let Some(proxies) = env_list("TRUSTED_PROXIES").or(file_forwarded.trusted_proxies) {
+            forwarded.trusted_proxies = parse_cidrs(&proxies).context("invalid trusted proxies")?;
+        }
+        if let Some(mode) = env::var("FORWARDED_HEADERS").ok().or(file_forwarded.headers) {
+            forwarded.headers = mode.parse()?;
+        }
+
+        let denied_ips = match env_list("DENIED_IPS").or(file.access.denied_ips) {
+            Some(nets) => parse_cidrs(&nets).context("invalid denied IPs")?,
+            None => Vec::new(),
+        };
+
+        let admin_addr = match env_parse::<u16>("ADMIN_PORT")?.or(file.admin.port) {
+            Some(port) => {
+                let host = env::var("ADMIN_HOST")
+                    .ok()
+                    .or(file.admin.host)
+                    .unwrap_or_else(|| "127.0.0.1".to_string());
+                Some(
+                    format!("{}:{}", host, port)
+                        .parse()
+                        .context("failed to parse ADMIN_HOST:ADMIN_PORT into SocketAddr")?,
+                )
+            }
+            None => None,
+        };
+
+        if admin_addr.is_some() && sqlite_path.is_none() {
+            anyhow::bail!("the admin API needs a SQLite key store (API_KEYS_SQLITE or [keys] sqlite)");
+        }
+
+        let roles = Roles::with_custom(&file.roles)?;
+
+        let mut bans = BanConfig::default();
+        if let Some(n) = env_parse("BAN_THRESHOLD")?.or(file.bans.threshold) {
+            bans.threshold = n;
+        }
+        if let Some(secs) = env_parse("BAN_WINDOW_SECS")?.or(file.bans.window_secs) {
+            bans.window = Duration::from_secs(secs);
+        }
+        if let Some(secs) = env_parse("BAN_DURATION_SECS")?.or(file.bans.duration_secs) {
+            bans.ban_duration = Duration::from_secs(secs);
+        }
+        if let Some(ms) = env_parse("AUTH_DELAY_STEP_MS")?.or(file.bans.delay_step_ms) {
+            bans.delay_step = Duration::from_millis(ms);
+        }
+        if let Some(ms) = env_parse("AUTH_MAX_DELAY_MS")?.or(file.bans.max_delay_ms) {
+            bans.max_delay = Duration::from_millis(ms);
+        }
+
+        let mut tokens = TokenConfig {
+            secret: env::var("TOKEN_SECRET").ok().or(file.tokens.secret),
+            ..Default::default()
+        };
+        if let Some(secs) = env_parse("TOKEN_DEFAULT_TTL_SECS")?.or(file.tokens.default_ttl_secs) {
+            tokens.default_ttl = Duration::from_secs(secs);
+        }
+        if let Some(secs) = env_parse("TOKEN_MAX_TTL_SECS")?.or(file.tokens.max_ttl_secs) {
+            tokens.max_ttl = Duration::from_secs(secs);
+        }
+        if tokens.secret.as_ref().is_some_and(|s| s.len() < MIN_SECRET_LEN) {
+            anyhow::bail!("TOKEN_SECRET must be at least {MIN_SECRET_LEN} characters");
+        }
+        if tokens.default_ttl.is_zero() || tokens.default_ttl > tokens.max_ttl {
+            anyhow::bail!("the default token lifetime must be between 1 second and the maximum");
+        }
+
+        let file_forward_auth = file.forward_auth.unwrap_or_default();
+        let forward_auth = match env::var("FORWARD_AUTH_URL").ok().or(file_forward_auth.url) {
+            Some(url) => {
+                let mut fa = ForwardAuthConfig::new(url);
+                if let Some(secs) = env_parse("FORWARD_AUTH_CACHE_SECS")?.or(file_forward_auth.cache_secs) {
+                    fa.cache_ttl = Duration::from_secs(secs);
+                }
+                if let Some(header) = env::var("FORWARD_AUTH_USER_HEADER").ok().or(file_forward_auth.user_header) {
+                    fa.user_header = header.to_ascii_lowercase();
+                }
+                if let Some(ms) = env_parse("FORWARD_AUTH_TIMEOUT_MS")?.or(file_forward_auth.timeout_ms) {
+                    fa.timeout = Duration::from_millis(ms);
+                }
+                Some(fa)
+            }
+            None => None,
+        };
+
+        let file_jwt = file.jwt.unwrap_or_default();
+        let jwt = match env::var("JWT_JWKS").ok().or(file_jwt.jwks) {
+            Some(jwks) => {
+                let mut jwt = JwtConfig::new(jwks);
+                jwt.issuer = env::var("JWT_ISSUER").ok().or(file_jwt.issuer);
+                jwt.audience = env::var("JWT_AUDIENCE").ok().or(file_jwt.audience);
+                if let Some(claim) = env::var("JWT_USERNAME_CLAIM").ok().or(file_jwt.username_claim) {
+                    jwt.username_claim = claim;
+                }
+                if let Some(secs) = env_parse("JWT_JWKS_RELOAD_SECS")?.or(file_jwt.reload_secs) {
+                    jwt.reload_interval = Duration::from_secs(secs.max(1));
+                }
+                if let Some(secs) = env_parse("JWT_LEEWAY_SECS")?.or(file_jwt.leeway_secs) {
+                    jwt.leeway = Duration::from_secs(secs);
+                }
+                Some(jwt)
+            }
+            None => None,
+        };
+
+        let file_cache = file.cache;
+        let cache = match env_flag("RESPONSE_CACHE").or(file_cache.enabled) {
+            Some(true) => {
+                let mut cache = CacheConfig::default();
+                if let Some(routes) = env_list("RESPONSE_CACHE_ROUTES").or(file_cache.routes) {
+                    cache.routes = routes;
+                }
+                if let Some(secs) = env_parse("RESPONSE_CACHE_TTL_SECS")?.or(file_cache.ttl_secs) {
+                    cache.ttl = Duration::from_secs(secs);
+                }
+                if let Some(n) = env_parse("RESPONSE_CACHE_MAX_ENTRIES")?.or(file_cache.max_entries) {
+                    cache.max_entries = n;
+                }
+                if let Some(n) = env_parse("RESPONSE_CACHE_MAX_ENTRY_BYTES")?.or(file_cache.max_entry_bytes) {
+                    cache.max_entry_bytes = n;
+                }
+                cache.sqlite = env::var("RESPONSE_CACHE_SQLITE").ok().or(file_cache.sqlite);
+                if let Some(mb) = env_parse::<u64>("RESPONSE_CACHE_SQLITE_MAX_MB")?.or(file_cache.sqlite_max_mb) {
+                    cache.sqlite_max_bytes = mb * 1024 * 1024;
+                }
+                Some(cache)
+            }
+            _ => None,
+        };
+
+        let file_embedding_cache = file.embedding_cache.unwrap_or_default();
+        let embedding_cache_mb = env_parse::<u64>("EMBEDDING_CACHE_MAX_MB")?
+            .or(file_embedding_cache.max_mb)
+            .unwrap_or(512);
+        let embedding_cache = env::var("EMBEDDING_CACHE_SQLITE")
+            .ok()
+            .or(file_embedding_cache.sqlite)
+            .map(|sqlite| EmbeddingCacheConfig {
+                sqlite,
+                max_bytes: embedding_cache_mb * 1024 * 1024,
+            });
+
+        let file_batch = file.embedding_batch.unwrap_or_default();
+        let batch_max_inputs = env_parse::<usize>("EMBEDDING_BATCH_MAX_INPUTS")?.or(file_batch.max_inputs);
+        let embedding_batch = env_parse::<u64>("EMBEDDING_BATCH_WINDOW_MS")?
+            .or(file_batch.window_ms)
+            .map(|ms| {
+                let mut batch = BatchConfig {
+                    window: Duration::from_millis(ms),
+                    ..Default::default()
+                };
+                if let Some(n) = batch_max_inputs {
+                    batch.max_inputs = n.max(1);
+                }
+                batch
+            });
+
+        let file_coalesce = file.coalesce;
+        let coalesce = match env_flag("COALESCE").or(file_coalesce.enabled) {
+            Some(true) => {
+                let mut coalesce = CoalesceConfig::default();
+                if let Some(routes) = env_list("COALESCE_ROUTES").or(file_coalesce.routes) {
+                    coalesce.routes = routes;
+                }
+                Some(coalesce)
+            }
+            _ => None,
+        };
+
+        let file_native = file.native_chat;
+        let native_chat = match env_flag("NATIVE_CHAT").or(file_native.enabled) {
+            Some(true) => {
+                let options = match env::var("NATIVE_CHAT_OPTIONS") {
+                    Ok(json) => serde_json::from_str(&json)
+                        .context("NATIVE_CHAT_OPTIONS must be a JSON object")?,
+                    Err(_) => file_native.options.unwrap_or_default(),
+                };
+                Some(NativeChatConfig {
+                    options,
+                    models: file_native.models,
+                    users: file_native.users,
+                })
+            }
+            _ => None,
+        };
+
+        let file_context = file.context;
+        let mut context = ContextConfig::default();
+        match env_list("CONTEXT_LIMITS") {
+            Some(entries) => {
+                for entry in entries {
+                    let limit = entry
+                        .split_once('=')
+                        .and_then(|(pattern, n)| Some((pattern.trim().to_string(), n.trim().parse().ok()?)));
+                    let Some((pattern, n)) = limit else {
+                        anyhow::bail!("invalid CONTEXT_LIMITS entry '{entry}' (expected model=tokens)");
+                    };
+                    context.limits.insert(pattern, n);
+                }
+            }
+            None => context.limits = file_context.limits.unwrap_or_default(),
+        }
+        if let Some(mode) = env::var("CONTEXT_OVERFLOW").ok().or(file_context.overflow) {
+            context.overflow = mode.parse()?;
+        }
+        if let Some(ratio) = env_parse::<f64>("CONTEXT_CHARS_PER_TOKEN")?.or(file_context.chars_per_token) {
+            if ratio <= 0.0 {
+                anyhow::bail!("CONTEXT_CHARS_PER_TOKEN must be positive");
+            }
+            context.chars_per_token = ratio;
+        }
+        let context = (!context.limits.is_empty()).then_some(context);
+
+        let mut think = ThinkConfig::default();
+        match env_list("THINK_MODELS") {
+            Some(entries) => {
+                for entry in entries {
+                    let (pattern, mode) = entry.split_once('=').unwrap_or((&entry, "separate"));
+                    think.models.insert(pattern.trim().to_string(), mode.parse()?);
+                }
+            }
+            None => {
+                for (pattern, mode) in file.think.models.unwrap_or_default() {
+                    think.models.insert(pattern, mode.parse()?);
+                }
+            }
+        }
+
+        let file_schema = file.schema;
+        let schema = match env_flag("SCHEMA_VALIDATION").or(file_schema.enabled) {
+            Some(true) => {
+                let mut schema = SchemaConfig::default();
+                if let Some(retries) = env_parse("SCHEMA_RETRIES")?.or(file_schema.retries) {
+                    schema.retries = retries;
+                }
+                Some(schema)
+            }
+            _ => None,
         };
 
+        let mut tools = ToolsConfig::default();
+        match env_list("TOOL_EMULATION") {
+            Some(entries) => {
+                for entry in entries {
+                    let (pattern, mode) = entry.split_once('=').unwrap_or((&entry, "emulate"));
+                    tools.models.insert(pattern.trim().to_string(), mode.parse()?);
+                }
+            }
+            None => {
+                for (pattern, mode) in file.tools.models.unwrap_or_default() {
+                    tools.models.insert(pattern, mode.parse()?);
+                }
+            }
+        }
+
         Ok(AppConfig {
             valid_keys,
+            users,
             ollama_url,
             proxy_addr,
+            retry,
+            breaker,
+            tls,
+            cors,
+            forwarded,
+            denied_ips,
+            bans,
+            sqlite_path,
+            admin_addr,
+            roles,
+            tokens,
+            forward_auth,
+            jwt,
+            cache,
+            embedding_cache,
+            embedding_batch,
+            coalesce,
+            native_chat,
+            context,
+            think,
+            schema,
+            tools,
         })
     }
+
+    /// The effective configuration in configuration-file form, with API keys
+    /// masked, for `config check`.
+    pub fn to_file_config(&self) -> FileConfig {
+        FileConfig {
+            ollama_url: Some(self.ollama_url.clone()),
+            server: ServerSection {
+                host: Some(self.proxy_addr.ip().to_string()),
+                port: Some(self.proxy_addr.port()),
+            },
+            keys: KeysSection {
+                sqlite: self.sqlite_path.clone(),
+                keys: Some(self.valid_keys.iter().map(|k| mask_secret(k)).collect()),
+                ..Default::default()
+            },
+            retry: RetrySection {
+                max_attempts: Some(self.retry.max_attempts),
+                base_delay_ms: Some(self.retry.base_delay.as_millis() as u64),
+                max_delay_ms: Some(self.retry.max_delay.as_millis() as u64),
+                safe_routes: Some(self.retry.safe_routes.clone()),
+            },
+            breaker: BreakerSection {
+                failure_threshold: Some(self.breaker.failure_threshold),
+                cooldown_secs: Some(self.breaker.cooldown.as_secs()),
+            },
+            tls: self.tls.as_ref().map(|tls| TlsSection {
+                cert: Some(tls.cert_path.clone()),
+                key: Some(tls.key_path.clone()),
+                http_port: tls.http_port,
+                http_redirect: Some(tls.http_redirect),
+                reload_secs: Some(tls.reload_interval.as_secs()),
+                client_ca: tls.client_ca.clone(),
+                client_cert_required: Some(tls.client_cert_required),
+            }),
+            cors: self
+                .cors
+                .as_ref()
+                .map(|cors| CorsSection {
+                    allowed_origins: Some(cors.allowed_origins.clone()),
+                    allowed_headers: Some(cors.allowed_headers.clone()),
+                    allowed_methods: Some(cors.allowed_methods.clone()),
+                    max_age_secs: cors.max_age_secs,
+                    allow_credentials: Some(cors.allow_credentials),
+                })
+                .unwrap_or_default(),
+            forwarded: ForwardedSection {
+                trusted_proxies: Some(
+                    self.forwarded.trusted_proxies.iter().map(|n| n.to_string()).collect(),
+                ),
+                headers: Some(self.forwarded.headers.to_string()),
+            },
+            access: AccessSection {
+                denied_ips: Some(self.denied_ips.iter().map(|n| n.to_string()).collect()),
+            },
+            admin: AdminSection {
+                host: self.admin_addr.map(|a| a.ip().to_string()),
+                port: self.admin_addr.map(|a| a.port()),
+            },
+            roles: self.roles.to_rules(),
+            bans: BansSection {
+                threshold: Some(self.bans.threshold),
+                window_secs: Some(self.bans.window.as_secs()),
+                duration_secs: Some(self.bans.ban_duration.as_secs()),
+                delay_step_ms: Some(self.bans.delay_step.as_millis() as u64),
+                max_delay_ms: Some(self.bans.max_delay.as_millis() as u64),
+            },
+            tokens: TokensSection {
+                secret: self.tokens.secret.as_deref().map(mask_secret),
+                default_ttl_secs: Some(self.tokens.default_ttl.as_secs()),
+                max_ttl_secs: Some(self.tokens.max_ttl.as_secs()),
+            },
+            forward_auth: self.forward_auth.as_ref().map(|fa| ForwardAuthSection {
+                url: Some(fa.url.clone()),
+                cache_secs: Some(fa.cache_ttl.as_secs()),
+                user_header: Some(fa.user_header.clone()),
+                timeout_ms: Some(fa.timeout.as_millis() as u64),
+            }),
+            jwt: self.jwt.as_ref().map(|jwt| JwtSection {
+                jwks: Some(jwt.jwks.clone()),
+                issuer: jwt.issuer.clone(),
+                audience: jwt.audience.clone(),
+                username_claim: Some(jwt.username_claim.clone()),
+                reload_secs: Some(jwt.reload_interval.as_secs()),
+                leeway_secs: Some(jwt.leeway.as_secs()),
+            }),
+            cache: match &self.cache {
+                Some(cache) => CacheSection {
+                    enabled: Some(true),
+                    routes: Some(cache.routes.clone()),
+                    ttl_secs: Some(cache.ttl.as_secs()),
+                    max_entries: Some(cache.max_entries),
+                    max_entry_bytes: Some(cache.max_entry_bytes),
+                    sqlite: cache.sqlite.clone(),
+                    sqlite_max_mb: Some(cache.sqlite_max_bytes / (1024 * 1024)),
+                },
+                None => CacheSection {
+                    enabled: Some(false),
+                    ..Default::default()
+                },
+            },
+            embedding_cache: self.embedding_cache.as_ref().map(|ec| EmbeddingCacheSection {
+                sqlite: Some(ec.sqlite.clone()),
+                max_mb: Some(ec.max_bytes / (1024 * 1024)),
+            }),
+            embedding_batch: self.embedding_batch.as_ref().map(|batch| EmbeddingBatchSection {
+                window_ms: Some(batch.window.as_millis() as u64),
+                max_inputs: Some(batch.max_inputs),
+            }),
+            coalesce: CoalesceSection {
+                enabled: Some(self.coalesce.is_some()),
+                routes: self.coalesce.as_ref().map(|c| c.routes.clone()),
+            },
+            context: match &self.context {
+                Some(context) => ContextSection {
+                    limits: Some(context.limits.clone()),
+                    overflow: Some(context.overflow.as_str().to_string()),
+                    chars_per_token: Some(context.chars_per_token),
+                },
+                None => ContextSection::default(),
+            },
+            think: ThinkSection {
+                models: Some(
+                    self.think
+                        .models
+                        .iter()
+                        .map(|(pattern, mode)| (pattern.clone(), mode.as_str().to_string()))
+                        .collect(),
+                ),
+            },
+            schema: SchemaSection {
+                enabled: Some(self.schema.is_some()),
+                retries: self.schema.as_ref().map(|s| s.retries),
+            },
+            tools: ToolsSection {
+                models: Some(
+                    self.tools
+                        .models
+                        .iter()
+                        .map(|(pattern, mode)| (pattern.clone(), mode.as_str().to_string()))
+                        .collect(),
+                ),
+            },
+            native_chat: match &self.native_chat {
+                Some(native) => NativeChatSection {
+                    enabled: Some(true),
+                    options: Some(native.options.clone()),
+                    models: native.models.clone(),
+                    users: native.users.clone(),
+                },
+                None => NativeChatSection {
+                    enabled: Some(false),
+                    ..Default::default()
+                },
+            },
+        }
+    }
+}
+
+/// Interpret an environment variable as a boolean switch (`1`, `true`,
+/// `yes` or `on`, case-insensitive); `None` when it is unset.
+fn env_flag(name: &str) -> Option<bool> {
+    env::var(name)
+        .ok()
+        .map(|v| matches!(v.trim().to_ascii_lowercase().as_str(), "1" | "true" | "yes" | "on"))
+}
+
+/// Read an environment variable and parse it; `None` when it is unset.  A
+/// malformed value is an error, as it is in the configuration file.
+fn env_parse<T: std::str::FromStr>(name: &str) -> Result<Option<T>> {
+    match env::var(name) {
+        Ok(v) => match v.trim().parse() {
+            Ok(parsed) => Ok(Some(parsed)),
+            Err(_) => anyhow::bail!("invalid value for {name}: '{v}'"),
+        },
+        Err(_) => Ok(None),
+    }
+}
+
+/// Read a comma-separated list from the environment; `None` when unset.
+fn env_list(name: &str) -> Option<Vec<String>> {
+    env::var(name).ok().map(|v| split_list(&v))
+}
+
+/// Split a comma-separated list, trimming whitespace and dropping empties.
+fn split_list(s: &str) -> Vec<String> {
+    s.split(',')
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect()
 }
 
 /// Values that can be supplied via command-line flags; the loader reads
 /// environment variables, but these overrides allow the CLI to take
 /// precedence.
+#[derive(Default)]
 pub struct ConfigOverrides {
     pub ollama_url: Option<String>,
     pub proxy_host: Option<String>,
@@ -69,19 +792,61 @@ pub struct ConfigOverrides {
     pub api_keys_sqlite: Option<String>,
     pub api_keys_file: Option<String>,
     pub api_keys: Option<Vec<String>>,
-}
 
-impl Default for ConfigOverrides {
-    fn default() -> Self {
-        ConfigOverrides {
-            ollama_url: None,
-            proxy_host: None,
-            proxy_port: None,
-            api_keys_sqlite: None,
-            api_keys_file: None,
-            api_keys: None,
-        }
-    }
+    pub retry_max_attempts: Option<u32>,
+    pub retry_safe_routes: Option<Vec<String>>,
+
+    // TLS; the certificate and key have to be given together unless the
+    // environment already provided the other half.
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+    pub http_port: Option<u16>,
+    pub http_redirect: Option<bool>,
+    pub tls_client_ca: Option<String>,
+    pub tls_client_cert_required: Option<bool>,
+
+    /// Replaces the allowed CORS origins; an empty list disables CORS.
+    pub cors_allowed_origins: Option<Vec<String>>,
+
+    /// Replaces the trusted proxy CIDRs.
+    pub trusted_proxies: Option<Vec<String>>,
+    /// Replaces the global deny list.
+    pub denied_ips: Option<Vec<String>>,
+
+    /// Enables the admin API on this port (host from the environment or
+    /// file, else loopback).
+    pub admin_port: Option<u16>,
+
+    /// Delegates authentication to this URL.
+    pub forward_auth_url: Option<String>,
+
+    /// Enables the response cache with its default settings.
+    pub response_cache: Option<bool>,
+
+    /// Enables the embedding cache in this SQLite database.
+    pub embedding_cache_sqlite: Option<String>,
+
+    /// Enables embedding micro-batching with this window.
+    pub embedding_batch_window_ms: Option<u64>,
+
+    /// Enables coalescing of identical requests on the default routes.
+    pub coalesce: Option<bool>,
+
+    /// Serves chat completions through native `/api/chat`.
+    pub native_chat: Option<bool>,
+
+    /// What to do with conversations longer than the context window.
+    pub context_overflow: Option<Overflow>,
+
+    /// How to handle `<think>` blocks from every model not configured
+    /// otherwise.
+    pub think: Option<ThinkMode>,
+
+    /// Enables structured output validation with this many retries.
+    pub schema_retries: Option<u32>,
+
+    /// Model patterns whose tool calling is emulated.
+    pub emulate_tools: Option<Vec<String>>,
 }
 
 impl AppConfig {
@@ -104,12 +869,133 @@ impl AppConfig {
                 .expect("valid socket addr from overrides");
         }
 
+        if let Some(n) = overrides.retry_max_attempts {
+            self.retry.max_attempts = n;
+        }
+        if let Some(routes) = &overrides.retry_safe_routes {
+            self.retry.safe_routes = routes.clone();
+        }
+
+        match (&overrides.tls_cert, &overrides.tls_key, &mut self.tls) {
+            (None, None, _) => {}
+            (cert, key, Some(tls)) => {
+                if let Some(cert) = cert {
+                    tls.cert_path = cert.clone();
+                }
+                if let Some(key) = key {
+                    tls.key_path = key.clone();
+                }
+            }
+            (Some(cert), Some(key), None) => {
+                self.tls = Some(TlsConfig {
+                    cert_path: cert.clone(),
+                    key_path: key.clone(),
+                    http_port: None,
+                    http_redirect: false,
+                    reload_interval: Duration::from_secs(60),
+                    client_ca: None,
+                    client_cert_required: false,
+                });
+            }
+            _ => anyhow::bail!("--tls-cert and --tls-key must be given together"),
+        }
+        if let Some(tls) = &mut self.tls {
+            if let Some(port) = overrides.http_port {
+                tls.http_port = Some(port);
+            }
+            if let Some(redirect) = overrides.http_redirect {
+                tls.http_redirect = redirect;
+            }
+            if let Some(ca) = &overrides.tls_client_ca {
+                tls.client_ca = Some(ca.clone());
+            }
+            if let Some(required) = overrides.tls_client_cert_required {
+                tls.client_cert_required = required;
+            }
+        }
+
+        if let Some(origins) = &overrides.cors_allowed_origins {
+            if origins.is_empty() {
+                self.cors = None;
+            } else {
+                self.cors.get_or_insert_with(CorsConfig::default).allowed_origins = origins.clone();
+            }
+        }
+
+        if let Some(proxies) = &overrides.trusted_proxies {
+            self.forwarded.trusted_proxies = parse_cidrs(proxies).context("invalid --trusted-proxies")?;
+        }
+        if let Some(nets) = &overrides.denied_ips {
+            self.denied_ips = parse_cidrs(nets).context("invalid --denied-ips")?;
+        }
+
+        if let Some(port) = overrides.admin_port {
+            let ip = self
+                .admin_addr
+                .map_or_else(|| [127, 0, 0, 1].into(), |a| a.ip());
+            self.admin_addr = Some(SocketAddr::new(ip, port));
+        }
+
+        if let Some(url) = &overrides.forward_auth_url {
+            match &mut self.forward_auth {
+                Some(fa) => fa.url = url.clone(),
+                None => self.forward_auth = Some(ForwardAuthConfig::new(url.clone())),
+            }
+        }
+
+        if overrides.response_cache == Some(true) && self.cache.is_none() {
+            self.cache = Some(CacheConfig::default());
+        }
+
+        if let Some(sqlite) = &overrides.embedding_cache_sqlite {
+            match &mut self.embedding_cache {
+                Some(ec) => ec.sqlite = sqlite.clone(),
+                None => {
+                    self.embedding_cache = Some(EmbeddingCacheConfig {
+                        sqlite: sqlite.clone(),
+                        max_bytes: 512 * 1024 * 1024,
+                    })
+                }
+            }
+        }
+
+        if let Some(ms) = overrides.embedding_batch_window_ms {
+            self.embedding_batch.get_or_insert_with(BatchConfig::default).window = Duration::from_millis(ms);
+        }
+
+        if overrides.coalesce == Some(true) && self.coalesce.is_none() {
+            self.coalesce = Some(CoalesceConfig::default());
+        }
+
+        if overrides.native_chat == Some(true) && self.native_chat.is_none() {
+            self.native_chat = Some(NativeChatConfig::default());
+        }
+
+        if let (Some(overflow), Some(context)) = (overrides.context_overflow, &mut self.context) {
+            context.overflow = overflow;
+        }
+
+        if let Some(mode) = overrides.think {
+            self.think.models.entry("*".to_string()).or_insert(mode);
+        }
+
+        if let Some(retries) = overrides.schema_retries {
+            self.schema.get_or_insert_with(SchemaConfig::default).retries = retries;
+        }
+
+        for pattern in overrides.emulate_tools.iter().flatten() {
+            self.tools.models.insert(pattern.clone(), ToolMode::Emulate);
+        }
+
         // handle api key overrides
         if overrides.api_keys_sqlite.is_some()
             || overrides.api_keys_file.is_some()
             || overrides.api_keys.is_some()
         {
+            self.users = UserDirectory::default();
+            self.sqlite_path = overrides.api_keys_sqlite.clone();
             let keys = if let Some(path) = &overrides.api_keys_sqlite {
+                self.users = load_users_from_sqlite(path)?;
                 load_keys_from_sqlite(path)?
             } else if let Some(path) = &overrides.api_keys_file {
                 load_keys_from_file(path)?
@@ -121,10 +1007,15 @@ impl AppConfig {
             self.valid_keys = keys;
         }
 
+        if self.admin_addr.is_some() && self.sqlite_path.is_none() {
+            anyhow::bail!("the admin API needs a SQLite key store");
+        }
+
         Ok(())
     }
 }
 
+#[allow(clippy::manual_pattern_char_comparison)]
 fn load_keys_from_file(path: &str) -> Result<Vec<String>> {
     let content = fs::read_to_string(path)
         .with_context(|| format!("failed to read API keys file '{}'", path))?;
@@ -150,9 +1041,164 @@ fn load_keys_from_sqlite(path: &str) -> Result<Vec<String>> {
     for key in keys_iter {
         keys.push(key?);
     }
+
+    if has_table(&conn, "user_keys")? {
+        let mut stmt = conn
+            .prepare("SELECT key FROM user_keys WHERE expires_at IS NULL OR expires_at > ?1")
+            .context("failed to prepare select statement")?;
+        let rows = stmt
+            .query_map([unix_now() as i64], |row| row.get(0))
+            .context("query execution failed")?;
+        for key in rows {
+            keys.push(key?);
+        }
+    }
     Ok(keys)
 }
 
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Load the per-user data.  Legacy databases without a `username` column
+/// yield an empty directory.
+fn load_users_from_sqlite(path: &str) -> Result<UserDirectory> {
+    let conn = Connection::open(path)
+        .with_context(|| format!("failed to open sqlite database '{}'", path))?;
+    if !has_column(&conn, "username")? {
+        return Ok(UserDirectory::default());
+    }
+    let mut stmt = conn
+        .prepare("SELECT username, key FROM api_keys")
+        .context("failed to prepare select statement")?;
+    let rows = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+        .context("query execution failed")?;
+
+    let mut users = UserDirectory::default();
+    for row in rows {
+        let (username, key) = row?;
+        users.keys.insert(username, key);
+    }
+
+    if has_table(&conn, "user_origins")? {
+        let mut stmt = conn
+            .prepare("SELECT username, origin FROM user_origins")
+            .context("failed to prepare select statement")?;
+        let rows = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .context("query execution failed")?;
+        for row in rows {
+            let (username, origin): (String, String) = row?;
+            users.origins.entry(username).or_default().push(origin);
+        }
+    }
+
+    if has_table(&conn, "user_ips")? {
+        let mut stmt = conn
+            .prepare("SELECT username, cidr FROM user_ips")
+            .context("failed to prepare select statement")?;
+        let rows = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .context("query execution failed")?;
+        for row in rows {
+            let (username, cidr): (String, String) = row?;
+            let net = parse_cidrs(&[cidr])
+                .with_context(|| format!("bad address allowlist entry for user '{}'", username))?;
+            users.ips.entry(username).or_default().extend(net);
+        }
+    }
+
+    if has_table(&conn, "user_keys")? {
+        let mut stmt = conn
+            .prepare(
+                "SELECT key, username, expires_at FROM user_keys
+                 WHERE expires_at IS NULL OR expires_at > ?1",
+            )
+            .context("failed to prepare select statement")?;
+        let rows = stmt
+            .query_map([unix_now() as i64], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get::<_, Option<i64>>(2)?))
+            })
+            .context("query execution failed")?;
+        for row in rows {
+            let (key, username, expires_at) = row?;
+            users.extra_keys.insert(
+                key,
+                ExtraKey {
+                    username,
+                    expires_at: expires_at.map(|t| t.max(0) as u64),
+                },
+            );
+        }
+    }
+
+    if has_table(&conn, "user_settings")? {
+        let mut stmt = conn
+            .prepare("SELECT username, role, disabled, daily_quota FROM user_settings")
+            .context("failed to prepare select statement")?;
+        let rows = stmt
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, bool>(2)?,
+                    row.get::<_, Option<i64>>(3)?,
+                ))
+            })
+            .context("query execution failed")?;
+        for row in rows {
+            let (username, role, disabled, quota) = row?;
+            if disabled {
+                users.disabled.insert(username.clone());
+            }
+            if let Some(quota) = quota {
+                users.quotas.insert(username.clone(), quota.max(0) as u64);
+            }
+            users.roles.insert(username, role);
+        }
+    }
+
+    if has_table(&conn, "policies")? {
+        let mut stmt = conn
+            .prepare("SELECT scope, name, policy FROM policies")
+            .context("failed to prepare select statement")?;
+        let rows = stmt
+            .query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get::<_, String>(2)?)))
+            .context("query execution failed")?;
+        for row in rows {
+            let (scope, name, policy) = row?;
+            let policy: Policy = serde_json::from_str(&policy)
+                .with_context(|| format!("bad request policy for {scope} '{name}'"))?;
+            match scope.as_str() {
+                "user" => users.policies.users.insert(name, policy),
+                _ => users.policies.models.insert(name, policy),
+            };
+        }
+    }
+    Ok(users)
+}
+
+/// Reload keys and users from the SQLite store, e.g. after the admin API
+/// changed it.
+pub fn load_directory_from_sqlite(path: &str) -> Result<(Vec<String>, UserDirectory)> {
+    Ok((load_keys_from_sqlite(path)?, load_users_from_sqlite(path)?))
+}
+
+fn has_table(conn: &Connection, table: &str) -> Result<bool> {
+    let n: i64 = conn
+        .query_row(
+            "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = ?1",
+            [table],
+            |row| row.get(0),
+        )
+        .context("failed to query sqlite schema")?;
+    Ok(n > 0)
+}
+
 // helpers used by the new `sql` command-line subcommands
 fn ensure_sqlite(path: &str) -> Result<Connection> {
     let conn = Connection::open(path)
@@ -174,6 +1220,55 @@ fn ensure_sqlite(path: &str) -> Result<Connection> {
         "CREATE UNIQUE INDEX IF NOT EXISTS idx_api_keys_key ON api_keys(key)",
         [],
     )?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS user_origins(
+            username TEXT NOT NULL,
+            origin TEXT NOT NULL,
+            PRIMARY KEY (username, origin)
+        )",
+        [],
+    )?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS user_keys(
+            key TEXT PRIMARY KEY,
+            username TEXT NOT NULL,
+            expires_at INTEGER
+        )",
+        [],
+    )?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS user_settings(
+            username TEXT PRIMARY KEY,
+            role TEXT NOT NULL DEFAULT 'user',
+            disabled INTEGER NOT NULL DEFAULT 0,
+            daily_quota INTEGER
+        )",
+        [],
+    )?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS bans(
+            ip TEXT PRIMARY KEY,
+            until INTEGER NOT NULL
+        )",
+        [],
+    )?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS policies(
+            scope TEXT NOT NULL,
+            name TEXT NOT NULL,
+            policy TEXT NOT NULL,
+            PRIMARY KEY (scope, name)
+        )",
+        [],
+    )?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS user_ips(
+            username TEXT NOT NULL,
+            cidr TEXT NOT NULL,
+            PRIMARY KEY (username, cidr)
+        )",
+        [],
+    )?;
     Ok(conn)
 }
 
@@ -222,6 +1317,12 @@ pub fn add_key_to_sqlite(path: &str, username: &str, key: &str) -> Result<()> {
 pub fn remove_key_from_sqlite(path: &str, username: &str) -> Result<bool> {
     let conn = ensure_sqlite(path)?;
     let n = if has_column(&conn, "username")? {
+        for table in ["user_origins", "user_ips", "user_settings", "user_keys"] {
+            conn.execute(&format!("DELETE FROM {table} WHERE username = ?1"), [username])
+                .context("failed to delete user data from sqlite database")?;
+        }
+        conn.execute("DELETE FROM policies WHERE scope = 'user' AND name = ?1", [username])
+            .context("failed to delete user data from sqlite database")?;
         conn.execute("DELETE FROM api_keys WHERE username = ?1", [username])
     } else {
         conn.execute("DELETE FROM api_keys WHERE key = ?1", [username])
@@ -230,37 +1331,284 @@ pub fn remove_key_from_sqlite(path: &str, username: &str) -> Result<bool> {
     Ok(n > 0)
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::io::Write;
-    use tempfile::NamedTempFile;
-    use std::sync::Mutex;
+/// Allow `username`'s key to be used from browser `origin`.  Once a user has
+/// at least one origin, requests with their key must come from one of them.
+pub fn add_user_origin(path: &str, username: &str, origin: &str) -> Result<()> {
+    let conn = ensure_sqlite(path)?;
+    conn.execute(
+        "INSERT OR IGNORE INTO user_origins(username, origin) VALUES (?1, ?2)",
+        [username, origin],
+    )
+    .context("failed to insert origin into sqlite database")?;
+    Ok(())
+}
 
-    // guard around tests that touch the process environment; tests may run in
-    // parallel threads, so we serialize access to avoid races and flakiness.
-    static ENV_LOCK: Mutex<()> = Mutex::new(());
+/// Remove a previously allowed origin.  Returns `true` if a row was deleted.
+pub fn remove_user_origin(path: &str, username: &str, origin: &str) -> Result<bool> {
+    let conn = ensure_sqlite(path)?;
+    let n = conn
+        .execute(
+            "DELETE FROM user_origins WHERE username = ?1 AND origin = ?2",
+            [username, origin],
+        )
+        .context("failed to delete origin from sqlite database")?;
+    Ok(n > 0)
+}
 
-    #[test]
-    fn env_fallback() {
-        let _guard = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
-        // clear any previous configuration
-        unsafe {
-            env::remove_var("API_KEYS_SQLITE");
-            env::remove_var("API_KEYS_FILE");
-            env::remove_var("API_KEYS");
-            env::remove_var("OLLAMA_URL");
-            env::remove_var("PROXY_HOST");
-            env::remove_var("PROXY_PORT");
-        }
-        unsafe {
-            env::set_var("API_KEYS", "a,b, c");
-        }
-        let cfg = AppConfig::load().expect("load");
-        assert_eq!(cfg.valid_keys, vec!["a", "b", "c"]);
-    }
+/// Only accept `username`'s credentials from `cidr` (a network or a single
+/// address).  Once a user has at least one entry, connections from anywhere
+/// else are refused.  The value is stored in canonical form so that
+/// `remove_user_ip` matches however it was typed.
+pub fn add_user_ip(path: &str, username: &str, cidr: &str) -> Result<String> {
+    let net = parse_cidrs(&[cidr.to_string()])?[0].to_string();
+    let conn = ensure_sqlite(path)?;
+    conn.execute(
+        "INSERT OR IGNORE INTO user_ips(username, cidr) VALUES (?1, ?2)",
+        [username, &net],
+    )
+    .context("failed to insert address into sqlite database")?;
+    Ok(net)
+}
 
-    #[test]
+/// Remove a previously allowed network.  Returns `true` if a row was deleted.
+pub fn remove_user_ip(path: &str, username: &str, cidr: &str) -> Result<bool> {
+    let net = parse_cidrs(&[cidr.to_string()])?[0].to_string();
+    let conn = ensure_sqlite(path)?;
+    let n = conn
+        .execute(
+            "DELETE FROM user_ips WHERE username = ?1 AND cidr = ?2",
+            [username, &net],
+        )
+        .context("failed to delete address from sqlite database")?;
+    Ok(n > 0)
+}
+
+/// Give `username` the new main key `key`.  The old key keeps working for
+/// `grace` (not at all when it is zero), so deployed clients can be moved
+/// over before it is cut off.  Returns `false` if there is no such user.
+pub fn rotate_user_key(path: &str, username: &str, key: &str, grace: Duration) -> Result<bool> {
+    let mut conn = ensure_sqlite(path)?;
+    let tx = conn.transaction()?;
+    let old: Option<String> = match tx.query_row(
+        "SELECT key FROM api_keys WHERE username = ?1",
+        [username],
+        |row| row.get(0),
+    ) {
+        Ok(key) => Some(key),
+        Err(rusqlite::Error::QueryReturnedNoRows) => None,
+        Err(e) => return Err(e).context("failed to look up user in sqlite database"),
+    };
+    let Some(old) = old else { return Ok(false) };
+
+    tx.execute("UPDATE api_keys SET key = ?2 WHERE username = ?1", [username, key])
+        .context("failed to update key in sqlite database")?;
+    if !grace.is_zero() {
+        let expires_at = unix_now().saturating_add(grace.as_secs()) as i64;
+        tx.execute(
+            "INSERT OR REPLACE INTO user_keys(key, username, expires_at) VALUES (?1, ?2, ?3)",
+            rusqlite::params![old, username, expires_at],
+        )
+        .context("failed to keep old key in sqlite database")?;
+    }
+    tx.commit()?;
+    Ok(true)
+}
+
+/// A fresh random API key.
+pub fn generate_key() -> Result<String> {
+    let mut bytes = [0u8; 24];
+    getrandom::getrandom(&mut bytes).map_err(|e| anyhow::anyhow!("no randomness available: {e}"))?;
+    Ok(format!(
+        "sk-{}",
+        bytes.iter().map(|b| format!("{b:02x}")).collect::<String>()
+    ))
+}
+
+/// Parse a duration such as `7d`, `12h`, `30m`, `45s` or a plain number of
+/// seconds.
+pub fn parse_duration(s: &str) -> Result<Duration> {
+    let s = s.trim();
+    let (digits, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+    let n: u64 = digits
+        .parse()
+        .with_context(|| format!("invalid duration '{s}'"))?;
+    let secs = match unit {
+        "" | "s" => n,
+        "m" => n * 60,
+        "h" => n * 3600,
+        "d" => n * 86_400,
+        "w" => n * 7 * 86_400,
+        _ => anyhow::bail!("invalid duration '{s}' (use s, m, h, d or w)"),
+    };
+    Ok(Duration::from_secs(secs))
+}
+
+fn upsert_setting(path: &str, username: &str, column: &str, value: rusqlite::types::Value) -> Result<()> {
+    let conn = ensure_sqlite(path)?;
+    conn.execute(
+        &format!(
+            "INSERT INTO user_settings(username, {column}) VALUES (?1, ?2)
+             ON CONFLICT(username) DO UPDATE SET {column} = excluded.{column}"
+        ),
+        rusqlite::params![username, value],
+    )
+    .with_context(|| format!("failed to update {column} in sqlite database"))?;
+    Ok(())
+}
+
+/// Give `username` a role, e.g. `admin` for access to the admin API.
+pub fn set_user_role(path: &str, username: &str, role: &str) -> Result<()> {
+    upsert_setting(path, username, "role", role.to_string().into())
+}
+
+/// Refuse (or accept again) `username`'s credentials without deleting them.
+pub fn set_user_disabled(path: &str, username: &str, disabled: bool) -> Result<()> {
+    upsert_setting(path, username, "disabled", i64::from(disabled).into())
+}
+
+/// Limit `username` to `quota` requests per UTC day; `None` lifts the limit.
+pub fn set_user_quota(path: &str, username: &str, quota: Option<u64>) -> Result<()> {
+    let value = quota.map_or(rusqlite::types::Value::Null, |q| (q.min(i64::MAX as u64) as i64).into());
+    upsert_setting(path, username, "daily_quota", value)
+}
+
+/// Set the request policy for `username`; `None` removes it.
+pub fn set_user_policy(path: &str, username: &str, policy: Option<&Policy>) -> Result<()> {
+    set_policy(path, "user", username, policy)
+}
+
+/// Set the request policy for models matching `pattern` (a name or `*`
+/// glob); `None` removes it.
+pub fn set_model_policy(path: &str, pattern: &str, policy: Option<&Policy>) -> Result<()> {
+    set_policy(path, "model", pattern, policy)
+}
+
+fn set_policy(path: &str, scope: &str, name: &str, policy: Option<&Policy>) -> Result<()> {
+    let conn = ensure_sqlite(path)?;
+    match policy {
+        Some(policy) => conn.execute(
+            "INSERT OR REPLACE INTO policies(scope, name, policy) VALUES (?1, ?2, ?3)",
+            [scope, name, &serde_json::to_string(policy)?],
+        ),
+        None => conn.execute("DELETE FROM policies WHERE scope = ?1 AND name = ?2", [scope, name]),
+    }
+    .context("failed to update policy in sqlite database")?;
+    Ok(())
+}
+
+/// Replace `username`'s allowed origins.  An empty list lifts the restriction.
+pub fn set_user_origins(path: &str, username: &str, origins: &[String]) -> Result<()> {
+    let mut conn = ensure_sqlite(path)?;
+    let tx = conn.transaction()?;
+    tx.execute("DELETE FROM user_origins WHERE username = ?1", [username])?;
+    for origin in origins {
+        tx.execute(
+            "INSERT OR IGNORE INTO user_origins(username, origin) VALUES (?1, ?2)",
+            [username, origin],
+        )?;
+    }
+    tx.commit().context("failed to update origins in sqlite database")
+}
+
+/// Replace `username`'s allowed networks.  An empty list lifts the
+/// restriction.
+pub fn set_user_ips(path: &str, username: &str, cidrs: &[String]) -> Result<()> {
+    let nets = parse_cidrs(cidrs)?;
+    let mut conn = ensure_sqlite(path)?;
+    let tx = conn.transaction()?;
+    tx.execute("DELETE FROM user_ips WHERE username = ?1", [username])?;
+    for net in nets {
+        tx.execute(
+            "INSERT OR IGNORE INTO user_ips(username, cidr) VALUES (?1, ?2)",
+            [username, &net.to_string()],
+        )?;
+    }
+    tx.commit().context("failed to update addresses in sqlite database")
+}
+
+/// Ban `ip` until `until` (seconds since the Unix epoch).
+pub fn record_ban(path: &str, ip: &str, until: u64) -> Result<()> {
+    let conn = ensure_sqlite(path)?;
+    conn.execute(
+        "INSERT OR REPLACE INTO bans(ip, until) VALUES (?1, ?2)",
+        rusqlite::params![ip, until as i64],
+    )
+    .context("failed to insert ban into sqlite database")?;
+    Ok(())
+}
+
+/// Active bans as `(ip, until)` pairs, soonest expiry first.  Expired rows
+/// are pruned on the way.
+pub fn list_bans(path: &str) -> Result<Vec<(String, u64)>> {
+    let conn = ensure_sqlite(path)?;
+    conn.execute("DELETE FROM bans WHERE until <= ?1", [unix_now() as i64])
+        .context("failed to prune expired bans")?;
+    let mut stmt = conn
+        .prepare("SELECT ip, until FROM bans ORDER BY until")
+        .context("failed to prepare select statement")?;
+    let rows = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get::<_, i64>(1)? as u64)))
+        .context("query execution failed")?;
+    Ok(rows.collect::<rusqlite::Result<_>>()?)
+}
+
+/// Whether `ip` still has a ban row.
+pub fn ban_exists(path: &str, ip: &str) -> Result<bool> {
+    let conn = Connection::open(path)
+        .with_context(|| format!("failed to open sqlite database '{}'", path))?;
+    if !has_table(&conn, "bans")? {
+        return Ok(false);
+    }
+    let n: i64 = conn
+        .query_row("SELECT COUNT(*) FROM bans WHERE ip = ?1", [ip], |row| row.get(0))
+        .context("failed to query bans")?;
+    Ok(n > 0)
+}
+
+/// Lift the ban on `ip`, or every ban when `ip` is `None`.  Returns the
+/// number of bans removed.
+pub fn remove_bans(path: &str, ip: Option<&str>) -> Result<usize> {
+    let conn = ensure_sqlite(path)?;
+    let n = match ip {
+        Some(ip) => conn.execute("DELETE FROM bans WHERE ip = ?1", [ip]),
+        None => conn.execute("DELETE FROM bans", []),
+    }
+    .context("failed to delete bans from sqlite database")?;
+    Ok(n)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+    use std::sync::Mutex;
+    use crate::forwarded::ForwardedHeaders;
+
+    // guard around tests that touch the process environment; tests may run in
+    // parallel threads, so we serialize access to avoid races and flakiness.
+    static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+    #[test]
+    fn env_fallback() {
+        let _guard = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        // clear any previous configuration
+        unsafe {
+            env::remove_var("API_KEYS_SQLITE");
+            env::remove_var("API_KEYS_FILE");
+            env::remove_var("API_KEYS");
+            env::remove_var("OLLAMA_URL");
+            env::remove_var("PROXY_HOST");
+            env::remove_var("PROXY_PORT");
+        }
+        unsafe {
+            env::set_var("API_KEYS", "a,b, c");
+        }
+        let cfg = AppConfig::load().expect("load");
+        assert_eq!(cfg.valid_keys, vec!["a", "b", "c"]);
+    }
+
+    #[test]
     fn file_keys() {
         let _guard = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
         // clear any previous configuration
@@ -357,6 +1705,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::field_reassign_with_default)]
     fn apply_overrides_test() {
         let _guard = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
         unsafe {
@@ -378,6 +1727,115 @@ mod tests {
         assert_eq!(cfg.ollama_url, "http://foo");
     }
 
+    #[test]
+    fn retry_settings_from_env_and_overrides() {
+        let _guard = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        unsafe {
+            env::remove_var("API_KEYS_SQLITE");
+            env::remove_var("API_KEYS_FILE");
+            env::remove_var("API_KEYS");
+            env::set_var("RETRY_MAX_ATTEMPTS", "5");
+            env::set_var("RETRY_BASE_DELAY_MS", "10");
+            env::set_var("RETRY_SAFE_ROUTES", "embeddings, chat/completions");
+            env::set_var("BREAKER_FAILURE_THRESHOLD", "7");
+            env::set_var("BREAKER_COOLDOWN_SECS", "bogus");
+        }
+        // malformed values are refused rather than silently ignored
+        let err = AppConfig::load().err().expect("malformed value accepted");
+        assert!(err.to_string().contains("BREAKER_COOLDOWN_SECS"));
+        unsafe {
+            env::remove_var("BREAKER_COOLDOWN_SECS");
+        }
+        let mut cfg = AppConfig::load().expect("load");
+        unsafe {
+            env::remove_var("RETRY_MAX_ATTEMPTS");
+            env::remove_var("RETRY_BASE_DELAY_MS");
+            env::remove_var("RETRY_SAFE_ROUTES");
+            env::remove_var("BREAKER_FAILURE_THRESHOLD");
+            env::remove_var("BREAKER_COOLDOWN_SECS");
+        }
+        assert_eq!(cfg.retry.max_attempts, 5);
+        assert_eq!(cfg.retry.base_delay, Duration::from_millis(10));
+        assert_eq!(cfg.retry.safe_routes, vec!["embeddings", "chat/completions"]);
+        assert_eq!(cfg.breaker.failure_threshold, 7);
+        assert_eq!(cfg.breaker.cooldown, Duration::from_secs(30));
+
+        let overrides = ConfigOverrides {
+            retry_max_attempts: Some(1),
+            ..Default::default()
+        };
+        cfg.apply_overrides(&overrides).unwrap();
+        assert_eq!(cfg.retry.max_attempts, 1);
+        assert_eq!(cfg.retry.safe_routes, vec!["embeddings", "chat/completions"]);
+    }
+
+    #[test]
+    fn tls_settings() {
+        let _guard = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        unsafe {
+            env::remove_var("API_KEYS_SQLITE");
+            env::remove_var("API_KEYS_FILE");
+            env::remove_var("API_KEYS");
+            env::remove_var("TLS_CERT");
+            env::remove_var("TLS_KEY");
+            env::set_var("TLS_HTTP_PORT", "8080");
+        }
+        let mut cfg = AppConfig::load().expect("load");
+        assert!(cfg.tls.is_none());
+
+        // a lone certificate without its key is rejected
+        let half = ConfigOverrides {
+            tls_cert: Some("/tmp/cert.pem".into()),
+            ..Default::default()
+        };
+        assert!(cfg.apply_overrides(&half).is_err());
+
+        let overrides = ConfigOverrides {
+            tls_cert: Some("/tmp/cert.pem".into()),
+            tls_key: Some("/tmp/key.pem".into()),
+            http_port: Some(80),
+            http_redirect: Some(true),
+            tls_client_ca: Some("/tmp/ca.pem".into()),
+            ..Default::default()
+        };
+        cfg.apply_overrides(&overrides).unwrap();
+        let tls = cfg.tls.as_ref().unwrap();
+        assert_eq!(tls.key_path, "/tmp/key.pem");
+        assert_eq!(tls.http_port, Some(80));
+        assert!(tls.http_redirect);
+        assert_eq!(tls.client_ca.as_deref(), Some("/tmp/ca.pem"));
+        assert!(!tls.client_cert_required);
+
+        unsafe {
+            env::set_var("TLS_CERT", "/etc/cert.pem");
+            env::set_var("TLS_KEY", "/etc/key.pem");
+            env::set_var("TLS_HTTP_REDIRECT", "true");
+        }
+        let mut cfg = AppConfig::load().expect("load");
+        // the flag replaces only the half it names
+        let overrides = ConfigOverrides {
+            tls_cert: Some("/tmp/cert.pem".into()),
+            ..Default::default()
+        };
+        cfg.apply_overrides(&overrides).unwrap();
+        unsafe {
+            env::remove_var("TLS_CERT");
+            env::set_var("TLS_KEY", "/etc/key.pem");
+        }
+        let only_key = AppConfig::load();
+        unsafe {
+            env::remove_var("TLS_KEY");
+            env::remove_var("TLS_HTTP_PORT");
+            env::remove_var("TLS_HTTP_REDIRECT");
+        }
+        let tls = cfg.tls.as_ref().unwrap();
+        assert_eq!(tls.cert_path, "/tmp/cert.pem");
+        assert_eq!(tls.key_path, "/etc/key.pem");
+        assert_eq!(tls.http_port, Some(8080));
+        assert!(tls.http_redirect);
+        assert!(only_key.is_err());
+    }
+
     #[test]
     fn sqlite_add_remove_key() {
         let _guard = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
@@ -402,6 +1860,8 @@ mod tests {
         add_key_to_sqlite(path, "alice", "key1").unwrap(); // duplicate ignored
         let cfg2 = AppConfig::load().expect("load");
         assert_eq!(cfg2.valid_keys, vec!["key1", "key2"]);
+        assert_eq!(cfg2.users.keys.get("bob").map(String::as_str), Some("key2"));
+        assert_eq!(cfg2.users.user_for_key("key2"), Some("bob"));
         // removal by user name
         assert!(remove_key_from_sqlite(path, "alice").unwrap());
         assert!(!remove_key_from_sqlite(path, "alice").unwrap());
@@ -423,9 +1883,676 @@ mod tests {
         unsafe { env::set_var("API_KEYS_SQLITE", path); }
         let cfg = AppConfig::load().expect("load");
         assert_eq!(cfg.valid_keys, vec!["legacykey"]);
+        assert!(cfg.users.keys.is_empty());
         // removal should treat the provided username as the key
         assert!(remove_key_from_sqlite(path, "legacykey").unwrap());
         let cfg2 = AppConfig::load().expect("load");
         assert_eq!(cfg2.valid_keys, Vec::<String>::new());
     }
+
+    fn clear_env() {
+        unsafe {
+            for name in [
+                "API_KEYS_SQLITE",
+                "API_KEYS_FILE",
+                "API_KEYS",
+                "OLLAMA_URL",
+                "PROXY_HOST",
+                "PROXY_PORT",
+                "RETRY_MAX_ATTEMPTS",
+                "TLS_CERT",
+                "TLS_KEY",
+                "TRUSTED_PROXIES",
+                "FORWARDED_HEADERS",
+                "DENIED_IPS",
+                "ADMIN_PORT",
+                "ADMIN_HOST",
+                "TOKEN_SECRET",
+                "TOKEN_DEFAULT_TTL_SECS",
+                "TOKEN_MAX_TTL_SECS",
+                "FORWARD_AUTH_URL",
+                "FORWARD_AUTH_CACHE_SECS",
+                "FORWARD_AUTH_USER_HEADER",
+                "JWT_JWKS",
+                "JWT_ISSUER",
+                "JWT_AUDIENCE",
+                "JWT_USERNAME_CLAIM",
+                "RESPONSE_CACHE",
+                "RESPONSE_CACHE_TTL_SECS",
+                "RESPONSE_CACHE_SQLITE",
+                "EMBEDDING_CACHE_SQLITE",
+                "EMBEDDING_CACHE_MAX_MB",
+                "EMBEDDING_BATCH_WINDOW_MS",
+                "EMBEDDING_BATCH_MAX_INPUTS",
+                "COALESCE",
+                "COALESCE_ROUTES",
+                "NATIVE_CHAT",
+                "NATIVE_CHAT_OPTIONS",
+                "CONTEXT_LIMITS",
+                "THINK_MODELS",
+                "SCHEMA_VALIDATION",
+                "SCHEMA_RETRIES",
+                "TOOL_EMULATION",
+                "CONTEXT_OVERFLOW",
+                "CONTEXT_CHARS_PER_TOKEN",
+            ] {
+                env::remove_var(name);
+            }
+        }
+    }
+
+    #[test]
+    fn cors_settings() {
+        let _guard = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        clear_env();
+        let cfg = AppConfig::load().expect("load");
+        assert!(cfg.cors.is_none());
+
+        let mut file = NamedTempFile::new().unwrap();
+        writeln!(
+            file,
+            "[cors]\nallowed_origins = [\"https://chat.example.com\"]\nmax_age_secs = 60"
+        )
+        .unwrap();
+        unsafe {
+            env::set_var("CORS_ALLOW_CREDENTIALS", "true");
+        }
+        let mut cfg = AppConfig::load_from(Some(file.path().to_str().unwrap())).expect("load");
+        unsafe {
+            env::remove_var("CORS_ALLOW_CREDENTIALS");
+        }
+        let cors = cfg.cors.as_ref().unwrap();
+        assert_eq!(cors.allowed_origins, vec!["https://chat.example.com"]);
+        assert_eq!(cors.max_age_secs, Some(60));
+        assert!(cors.allow_credentials);
+
+        cfg.apply_overrides(&ConfigOverrides {
+            cors_allowed_origins: Some(vec![]),
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(cfg.cors.is_none());
+    }
+
+    #[test]
+    fn forwarded_settings() {
+        let _guard = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        clear_env();
+        let cfg = AppConfig::load().expect("load");
+        assert!(cfg.forwarded.trusted_proxies.is_empty());
+        assert_eq!(cfg.forwarded.headers, ForwardedHeaders::XForwarded);
+
+        let mut file = NamedTempFile::new().unwrap();
+        writeln!(file, "[forwarded]\ntrusted_proxies = [\"10.0.0.0/8\"]\nheaders = \"both\"").unwrap();
+        unsafe {
+            env::set_var("TRUSTED_PROXIES", "127.0.0.1, fd00::/8");
+        }
+        let mut cfg = AppConfig::load_from(Some(file.path().to_str().unwrap())).expect("load");
+        unsafe {
+            env::set_var("TRUSTED_PROXIES", "not-an-ip");
+        }
+        let err = AppConfig::load().err().unwrap();
+        clear_env();
+        assert!(format!("{err:#}").contains("not-an-ip"), "{err:#}");
+        let nets: Vec<_> = cfg.forwarded.trusted_proxies.iter().map(|n| n.to_string()).collect();
+        assert_eq!(nets, vec!["127.0.0.1/32", "fd00::/8"]);
+        assert_eq!(cfg.forwarded.headers, ForwardedHeaders::Both);
+
+        cfg.apply_overrides(&ConfigOverrides {
+            trusted_proxies: Some(vec!["192.168.0.0/16".into()]),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(cfg.forwarded.trusted_proxies[0].to_string(), "192.168.0.0/16");
+    }
+
+    #[test]
+    fn sqlite_user_origins() {
+        let _guard = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        clear_env();
+        let tmp = NamedTempFile::new().unwrap();
+        let path = tmp.path().to_str().unwrap();
+        add_key_to_sqlite(path, "web", "webkey").unwrap();
+        add_user_origin(path, "web", "https://chat.example.com").unwrap();
+        add_user_origin(path, "web", "https://chat.example.com").unwrap();
+        add_user_origin(path, "web", "https://*.example.org").unwrap();
+
+        let users = load_users_from_sqlite(path).unwrap();
+        let mut origins = users.origins["web"].clone();
+        origins.sort();
+        assert_eq!(origins, vec!["https://*.example.org", "https://chat.example.com"]);
+
+        assert!(remove_user_origin(path, "web", "https://*.example.org").unwrap());
+        assert!(!remove_user_origin(path, "web", "https://*.example.org").unwrap());
+        let users = load_users_from_sqlite(path).unwrap();
+        assert_eq!(users.origins["web"], vec!["https://chat.example.com"]);
+    }
+
+    #[test]
+    fn admin_listener_needs_sqlite() {
+        let _guard = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        clear_env();
+        let tmp = NamedTempFile::new().unwrap();
+        let path = tmp.path().to_str().unwrap();
+        add_key_to_sqlite(path, "root", "root-key").unwrap();
+        set_user_role(path, "root", ADMIN_ROLE).unwrap();
+        set_user_quota(path, "root", Some(100)).unwrap();
+        set_user_disabled(path, "root", true).unwrap();
+
+        unsafe {
+            env::set_var("ADMIN_PORT", "9090");
+        }
+        let err = AppConfig::load().err().unwrap();
+        assert!(format!("{err:#}").contains("SQLite"), "{err:#}");
+        unsafe {
+            env::set_var("API_KEYS_SQLITE", path);
+        }
+        let cfg = AppConfig::load();
+        clear_env();
+        let cfg = cfg.unwrap();
+        assert_eq!(cfg.admin_addr, Some("127.0.0.1:9090".parse().unwrap()));
+        assert_eq!(cfg.sqlite_path.as_deref(), Some(path));
+        assert_eq!(cfg.users.role("root"), ADMIN_ROLE);
+        assert_eq!(cfg.users.quotas["root"], 100);
+        assert!(cfg.users.is_disabled("root"));
+
+        // deleting a user takes their settings along
+        assert!(remove_key_from_sqlite(path, "root").unwrap());
+        add_key_to_sqlite(path, "root", "root-key").unwrap();
+        let users = load_users_from_sqlite(path).unwrap();
+        assert_eq!(users.role("root"), "user");
+        assert!(!users.is_disabled("root"));
+    }
+
+    #[test]
+    fn token_settings() {
+        let _guard = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        clear_env();
+        unsafe {
+            env::set_var("TOKEN_SECRET", "too-short");
+        }
+        let err = AppConfig::load().err().unwrap();
+        assert!(format!("{err:#}").contains("TOKEN_SECRET"), "{err:#}");
+        unsafe {
+            env::set_var("TOKEN_SECRET", "a-long-enough-signing-secret");
+            env::set_var("TOKEN_MAX_TTL_SECS", "60");
+        }
+        let err = AppConfig::load().err().unwrap();
+        assert!(format!("{err:#}").contains("default token lifetime"), "{err:#}");
+        unsafe {
+            env::set_var("TOKEN_DEFAULT_TTL_SECS", "30");
+        }
+        let cfg = AppConfig::load();
+        clear_env();
+        let cfg = cfg.unwrap();
+        assert_eq!(cfg.tokens.default_ttl, Duration::from_secs(30));
+        assert_eq!(cfg.tokens.max_ttl, Duration::from_secs(60));
+        let masked = cfg.to_file_config().tokens.secret.unwrap();
+        assert!(!masked.contains("signing"), "{masked}");
+    }
+
+    #[test]
+    fn forward_auth_settings() {
+        let _guard = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        clear_env();
+        assert!(AppConfig::load().unwrap().forward_auth.is_none());
+        unsafe {
+            env::set_var("FORWARD_AUTH_URL", "http://127.0.0.1:9000/auth");
+            env::set_var("FORWARD_AUTH_CACHE_SECS", "3");
+            env::set_var("FORWARD_AUTH_USER_HEADER", "X-Auth-User");
+        }
+        let cfg = AppConfig::load();
+        clear_env();
+        let mut cfg = cfg.unwrap();
+        let fa = cfg.forward_auth.as_ref().unwrap();
+        assert_eq!(fa.url, "http://127.0.0.1:9000/auth");
+        assert_eq!(fa.cache_ttl, Duration::from_secs(3));
+        assert_eq!(fa.user_header, "x-auth-user");
+
+        cfg.apply_overrides(&ConfigOverrides {
+            forward_auth_url: Some("http://auth.internal/check".into()),
+            ..Default::default()
+        })
+        .unwrap();
+        let fa = cfg.forward_auth.unwrap();
+        assert_eq!(fa.url, "http://auth.internal/check");
+        assert_eq!(fa.cache_ttl, Duration::from_secs(3));
+    }
+
+    #[test]
+    fn jwt_settings() {
+        let _guard = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        clear_env();
+        let mut file = NamedTempFile::new().unwrap();
+        write!(
+            file,
+            "[jwt]\njwks = \"https://sso.example.com/jwks.json\"\nissuer = \"https://sso.example.com\"\nusername_claim = \"email\"\n"
+        )
+        .unwrap();
+        unsafe {
+            env::set_var("JWT_AUDIENCE", "ollama");
+        }
+        let cfg = AppConfig::load_from(file.path().to_str());
+        clear_env();
+        let jwt = cfg.unwrap().jwt.unwrap();
+        assert_eq!(jwt.jwks, "https://sso.example.com/jwks.json");
+        assert_eq!(jwt.issuer.as_deref(), Some("https://sso.example.com"));
+        assert_eq!(jwt.audience.as_deref(), Some("ollama"));
+        assert_eq!(jwt.username_claim, "email");
+        assert_eq!(jwt.reload_interval, Duration::from_secs(300));
+    }
+
+    #[test]
+    fn response_cache_settings() {
+        let _guard = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        clear_env();
+        let mut cfg = AppConfig::load().unwrap();
+        assert!(cfg.cache.is_none());
+        cfg.apply_overrides(&ConfigOverrides {
+            response_cache: Some(true),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(cfg.cache.unwrap().ttl, Duration::from_secs(3600));
+
+        unsafe {
+            env::set_var("RESPONSE_CACHE", "1");
+            env::set_var("RESPONSE_CACHE_TTL_SECS", "60");
+            env::set_var("RESPONSE_CACHE_SQLITE", "/tmp/cache.db");
+        }
+        let cfg = AppConfig::load();
+        clear_env();
+        let cache = cfg.unwrap().cache.unwrap();
+        assert_eq!(cache.ttl, Duration::from_secs(60));
+        assert_eq!(cache.sqlite.as_deref(), Some("/tmp/cache.db"));
+    }
+
+    #[test]
+    fn embedding_cache_settings() {
+        let _guard = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        clear_env();
+        let mut cfg = AppConfig::load().unwrap();
+        assert!(cfg.embedding_cache.is_none());
+        cfg.apply_overrides(&ConfigOverrides {
+            embedding_cache_sqlite: Some("/tmp/cli.db".into()),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(cfg.embedding_cache.unwrap().max_bytes, 512 * 1024 * 1024);
+
+        unsafe {
+            env::set_var("EMBEDDING_CACHE_SQLITE", "/tmp/embeddings.db");
+            env::set_var("EMBEDDING_CACHE_MAX_MB", "8");
+        }
+        let cfg = AppConfig::load();
+        clear_env();
+        let ec = cfg.unwrap().embedding_cache.unwrap();
+        assert_eq!(ec.sqlite, "/tmp/embeddings.db");
+        assert_eq!(ec.max_bytes, 8 * 1024 * 1024);
+    }
+
+    #[test]
+    fn embedding_batch_settings() {
+        let _guard = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        clear_env();
+        let mut cfg = AppConfig::load().unwrap();
+        assert!(cfg.embedding_batch.is_none());
+        cfg.apply_overrides(&ConfigOverrides {
+            embedding_batch_window_ms: Some(5),
+            ..Default::default()
+        })
+        .unwrap();
+        let batch = cfg.embedding_batch.unwrap();
+        assert_eq!(batch.window, Duration::from_millis(5));
+        assert_eq!(batch.max_inputs, 64);
+
+        unsafe {
+            env::set_var("EMBEDDING_BATCH_WINDOW_MS", "20");
+            env::set_var("EMBEDDING_BATCH_MAX_INPUTS", "16");
+        }
+        let cfg = AppConfig::load();
+        clear_env();
+        let batch = cfg.unwrap().embedding_batch.unwrap();
+        assert_eq!(batch.window, Duration::from_millis(20));
+        assert_eq!(batch.max_inputs, 16);
+    }
+
+    #[test]
+    fn native_chat_settings() {
+        let _guard = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        clear_env();
+        let mut cfg = AppConfig::load().unwrap();
+        assert!(cfg.native_chat.is_none());
+        cfg.apply_overrides(&ConfigOverrides {
+            native_chat: Some(true),
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(cfg.native_chat.unwrap().options.is_empty());
+
+        unsafe {
+            env::set_var("NATIVE_CHAT", "1");
+            env::set_var("NATIVE_CHAT_OPTIONS", r#"{"num_ctx": 8192}"#);
+        }
+        let cfg = AppConfig::load();
+        unsafe { env::set_var("NATIVE_CHAT_OPTIONS", "[1]") };
+        let bad = AppConfig::load();
+        clear_env();
+        assert_eq!(cfg.unwrap().native_chat.unwrap().options["num_ctx"], 8192);
+        assert!(bad.is_err());
+
+        let file = FileConfig::parse(
+            r#"
+            [native_chat]
+            enabled = true
+            options = { keep_alive = "30m" }
+            [native_chat.models."llama3*"]
+            num_ctx = 16384
+            [native_chat.users.alice]
+            num_gpu = 0
+            "#,
+        )
+        .unwrap();
+        assert_eq!(file.native_chat.models["llama3*"]["num_ctx"], 16384);
+        assert_eq!(file.native_chat.users["alice"]["num_gpu"], 0);
+    }
+
+    #[test]
+    fn context_settings() {
+        let _guard = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        clear_env();
+        assert!(AppConfig::load().unwrap().context.is_none());
+
+        unsafe {
+            env::set_var("CONTEXT_LIMITS", "llama3*=8192, *=2048");
+            env::set_var("CONTEXT_OVERFLOW", "Truncate");
+        }
+        let mut cfg = AppConfig::load().unwrap();
+        let context = cfg.context.clone().unwrap();
+        assert_eq!(context.limits["llama3*"], 8192);
+        assert_eq!(context.limits["*"], 2048);
+        assert_eq!(context.overflow, Overflow::Truncate);
+        cfg.apply_overrides(&ConfigOverrides {
+            context_overflow: Some(Overflow::Reject),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(cfg.context.unwrap().overflow, Overflow::Reject);
+
+        unsafe { env::set_var("CONTEXT_LIMITS", "llama3") };
+        let bad_limits = AppConfig::load();
+        unsafe {
+            env::set_var("CONTEXT_LIMITS", "llama3=1");
+            env::set_var("CONTEXT_OVERFLOW", "drop");
+        }
+        let bad_mode = AppConfig::load();
+        clear_env();
+        assert!(bad_limits.is_err());
+        assert!(bad_mode.is_err());
+    }
+
+    #[test]
+    fn think_settings() {
+        let _guard = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        clear_env();
+        let mut cfg = AppConfig::load().unwrap();
+        assert!(cfg.think.models.is_empty());
+        cfg.apply_overrides(&ConfigOverrides {
+            think: Some(ThinkMode::Strip),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(cfg.think.models["*"], ThinkMode::Strip);
+
+        unsafe { env::set_var("THINK_MODELS", "deepseek-r1*, qwq*=strip") };
+        let cfg = AppConfig::load().unwrap();
+        assert_eq!(cfg.think.models["deepseek-r1*"], ThinkMode::Separate);
+        assert_eq!(cfg.think.models["qwq*"], ThinkMode::Strip);
+        unsafe { env::set_var("THINK_MODELS", "qwq*=hide") };
+        let bad = AppConfig::load();
+        clear_env();
+        assert!(bad.is_err());
+
+        let file = FileConfig::parse("[think.models]\n\"deepseek-r1*\" = \"strip\"\n").unwrap();
+        assert_eq!(file.think.models.unwrap()["deepseek-r1*"], "strip");
+    }
+
+    #[test]
+    fn schema_settings() {
+        let _guard = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        clear_env();
+        let mut cfg = AppConfig::load().unwrap();
+        assert!(cfg.schema.is_none());
+        cfg.apply_overrides(&ConfigOverrides {
+            schema_retries: Some(0),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(cfg.schema.unwrap().retries, 0);
+
+        unsafe { env::set_var("SCHEMA_RETRIES", "5") };
+        let ignored = AppConfig::load().unwrap().schema.is_none();
+        unsafe { env::set_var("SCHEMA_VALIDATION", "1") };
+        let cfg = AppConfig::load().unwrap();
+        clear_env();
+        assert!(ignored);
+        assert_eq!(cfg.schema.unwrap().retries, 5);
+    }
+
+    #[test]
+    fn tool_emulation_settings() {
+        let _guard = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        clear_env();
+        unsafe { env::set_var("TOOL_EMULATION", "gemma*, gemma3*=native") };
+        let mut cfg = AppConfig::load().unwrap();
+        unsafe { env::set_var("TOOL_EMULATION", "phi3=sometimes") };
+        let bad = AppConfig::load();
+        clear_env();
+        assert!(bad.is_err());
+        assert_eq!(cfg.tools.models["gemma*"], ToolMode::Emulate);
+        assert_eq!(cfg.tools.models["gemma3*"], ToolMode::Native);
+        cfg.apply_overrides(&ConfigOverrides {
+            emulate_tools: Some(vec!["phi3*".into()]),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(cfg.tools.models["phi3*"], ToolMode::Emulate);
+
+        let file = FileConfig::parse("[tools.models]\n\"phi3*\" = \"emulate\"\n").unwrap();
+        assert_eq!(file.tools.models.unwrap()["phi3*"], "emulate");
+    }
+
+    #[test]
+    fn coalesce_settings() {
+        let _guard = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        clear_env();
+        let mut cfg = AppConfig::load().unwrap();
+        assert!(cfg.coalesce.is_none());
+        cfg.apply_overrides(&ConfigOverrides {
+            coalesce: Some(true),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(cfg.coalesce.unwrap().routes.len(), 3);
+
+        unsafe {
+            env::set_var("COALESCE", "yes");
+            env::set_var("COALESCE_ROUTES", "chat/completions");
+        }
+        let cfg = AppConfig::load();
+        clear_env();
+        assert_eq!(cfg.unwrap().coalesce.unwrap().routes, vec!["chat/completions"]);
+    }
+
+    #[test]
+    fn policies_in_sqlite() {
+        let tmp = NamedTempFile::new().unwrap();
+        let path = tmp.path().to_str().unwrap();
+        add_key_to_sqlite(path, "alice", "k1").unwrap();
+        let policy = Policy {
+            max_tokens: Some(512),
+            ..Default::default()
+        };
+        set_user_policy(path, "alice", Some(&policy)).unwrap();
+        set_model_policy(path, "llama3*", Some(&policy)).unwrap();
+        let (_, users) = load_directory_from_sqlite(path).unwrap();
+        assert_eq!(users.policies.users["alice"], policy);
+        assert_eq!(users.policies.models["llama3*"], policy);
+
+        set_model_policy(path, "llama3*", None).unwrap();
+        remove_key_from_sqlite(path, "alice").unwrap();
+        let (_, users) = load_directory_from_sqlite(path).unwrap();
+        assert!(users.policies.is_empty());
+    }
+
+    #[test]
+    fn key_rotation_with_grace() {
+        let _guard = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        clear_env();
+        let tmp = NamedTempFile::new().unwrap();
+        let path = tmp.path().to_str().unwrap();
+        add_key_to_sqlite(path, "alice", "old").unwrap();
+
+        assert!(rotate_user_key(path, "alice", "new", parse_duration("7d").unwrap()).unwrap());
+        assert!(!rotate_user_key(path, "nobody", "x", Duration::ZERO).unwrap());
+        let (mut keys, users) = load_directory_from_sqlite(path).unwrap();
+        keys.sort();
+        assert_eq!(keys, vec!["new", "old"]);
+        assert_eq!(users.user_for_key("old"), Some("alice"));
+        assert_eq!(users.user_for_key("new"), Some("alice"));
+        let expiry = users.key_expiry("old").unwrap();
+        assert!(expiry > unix_now() + 6 * 86_400);
+        assert_eq!(users.key_expiry("new"), None);
+
+        // without grace the previous key is gone at once; the one still in
+        // its grace period stays until it expires
+        assert!(rotate_user_key(path, "alice", "newer", Duration::ZERO).unwrap());
+        let (mut keys, _) = load_directory_from_sqlite(path).unwrap();
+        keys.sort();
+        assert_eq!(keys, vec!["newer", "old"]);
+
+        assert!(remove_key_from_sqlite(path, "alice").unwrap());
+        assert!(load_keys_from_sqlite(path).unwrap().is_empty());
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("7d").unwrap(), Duration::from_secs(7 * 86_400));
+        assert_eq!(parse_duration("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("2h").unwrap(), Duration::from_secs(7200));
+        assert!(parse_duration("7 days").is_err());
+        assert!(parse_duration("d").is_err());
+    }
+
+    #[test]
+    fn sqlite_user_ips() {
+        let _guard = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        clear_env();
+        let tmp = NamedTempFile::new().unwrap();
+        let path = tmp.path().to_str().unwrap();
+        add_key_to_sqlite(path, "ci", "cikey").unwrap();
+        assert_eq!(add_user_ip(path, "ci", "10.1.0.0/16").unwrap(), "10.1.0.0/16");
+        assert_eq!(add_user_ip(path, "ci", "192.0.2.7").unwrap(), "192.0.2.7/32");
+        assert!(add_user_ip(path, "ci", "10.1.0.0/99").is_err());
+
+        let users = load_users_from_sqlite(path).unwrap();
+        assert_eq!(users.ips["ci"].len(), 2);
+        assert!(!users.ips.contains_key("other"));
+
+        assert!(remove_user_ip(path, "ci", "192.0.2.7").unwrap());
+        assert!(!remove_user_ip(path, "ci", "192.0.2.7/32").unwrap());
+        let users = load_users_from_sqlite(path).unwrap();
+        assert_eq!(users.ips["ci"], vec!["10.1.0.0/16".parse::<IpNet>().unwrap()]);
+    }
+
+    #[test]
+    fn config_file_layering() {
+        let _guard = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        clear_env();
+        let mut file = NamedTempFile::new().unwrap();
+        writeln!(
+            file,
+            r#"
+ollama_url = "http://from-file:11434"
+
+[server]
+host = "127.0.0.1"
+port = 4000
+
+[keys]
+keys = ["file-key-1", "file-key-2"]
+
+[retry]
+max_attempts = 6
+"#
+        )
+        .unwrap();
+        let path = file.path().to_str().unwrap();
+
+        // file only
+        let cfg = AppConfig::load_from(Some(path)).expect("load");
+        assert_eq!(cfg.ollama_url, "http://from-file:11434");
+        assert_eq!(cfg.proxy_addr, "127.0.0.1:4000".parse().unwrap());
+        assert_eq!(cfg.valid_keys, vec!["file-key-1", "file-key-2"]);
+        assert_eq!(cfg.retry.max_attempts, 6);
+
+        // environment beats the file, key sources are replaced wholesale
+        unsafe {
+            env::set_var("OLLAMA_URL", "http://from-env:11434");
+            env::set_var("PROXY_PORT", "5000");
+            env::set_var("API_KEYS", "env-key");
+        }
+        let mut cfg = AppConfig::load_from(Some(path)).expect("load");
+        clear_env();
+        assert_eq!(cfg.ollama_url, "http://from-env:11434");
+        assert_eq!(cfg.proxy_addr, "127.0.0.1:5000".parse().unwrap());
+        assert_eq!(cfg.valid_keys, vec!["env-key"]);
+        assert_eq!(cfg.retry.max_attempts, 6);
+
+        // and the CLI beats both
+        let overrides = ConfigOverrides {
+            ollama_url: Some("http://from-cli:11434".into()),
+            retry_max_attempts: Some(2),
+            ..Default::default()
+        };
+        cfg.apply_overrides(&overrides).unwrap();
+        assert_eq!(cfg.ollama_url, "http://from-cli:11434");
+        assert_eq!(cfg.retry.max_attempts, 2);
+    }
+
+    #[test]
+    fn config_file_errors() {
+        let _guard = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        clear_env();
+        let mut file = NamedTempFile::new().unwrap();
+        writeln!(file, "[server]\nport = 4000\n\n[tls]\ncert = \"/tmp/c.pem\"").unwrap();
+        let path = file.path().to_str().unwrap();
+        let err = AppConfig::load_from(Some(path)).err().unwrap();
+        assert!(format!("{err:#}").contains("must be set together"), "{err:#}");
+
+        let mut file = NamedTempFile::new().unwrap();
+        writeln!(file, "[server]\nport = 70000").unwrap();
+        let path = file.path().to_str().unwrap();
+        let err = format!("{:#}", AppConfig::load_from(Some(path)).err().unwrap());
+        assert!(err.contains(path) && err.contains("line 2"), "{err}");
+
+        assert!(AppConfig::load_from(Some("/nonexistent/shim.toml")).is_err());
+    }
+
+    #[test]
+    fn effective_config_masks_keys() {
+        let _guard = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        clear_env();
+        unsafe {
+            env::set_var("API_KEYS", "sk-secret-1,sk-secret-2");
+        }
+        let cfg = AppConfig::load().expect("load");
+        clear_env();
+        let rendered = cfg.to_file_config().to_toml().unwrap();
+        assert!(!rendered.contains("secret"), "{rendered}");
+        assert!(rendered.contains("sk****"), "{rendered}");
+        // the rendered output is itself a valid configuration file
+        let reparsed = FileConfig::parse(&rendered).unwrap();
+        assert_eq!(reparsed.server.port, Some(3000));
+    }
 }
@@ -0,0 +1,363 @@
+use std::{collections::BTreeMap, fs};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// Contents of the optional TOML configuration file (`--config shim.toml`).
+///
+/// Every field is optional; anything left out falls through to the
+/// environment variable of the same meaning and then to the built-in
+/// default.  Unknown keys are rejected so typos do not go unnoticed.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileConfig {
+    /// Base URL for the Ollama service.
+    pub ollama_url: Option<String>,
+    #[serde(default)]
+    pub server: ServerSection,
+    #[serde(default)]
+    pub keys: KeysSection,
+    #[serde(default)]
+    pub retry: RetrySection,
+    #[serde(default)]
+    pub breaker: BreakerSection,
+    pub tls: Option<TlsSection>,
+    #[serde(default)]
+    pub cors: CorsSection,
+    #[serde(default)]
+    pub forwarded: ForwardedSection,
+    #[serde(default)]
+    pub access: AccessSection,
+    #[serde(default)]
+    pub bans: BansSection,
+    #[serde(default)]
+    pub admin: AdminSection,
+    #[serde(default)]
+    pub tokens: TokensSection,
+    pub forward_auth: Option<ForwardAuthSection>,
+    pub jwt: Option<JwtSection>,
+    #[serde(default)]
+    pub cache: CacheSection,
+    pub embedding_cache: Option<EmbeddingCacheSection>,
+    pub embedding_batch: Option<EmbeddingBatchSection>,
+    #[serde(default)]
+    pub coalesce: CoalesceSection,
+    #[serde(default)]
+    pub native_chat: NativeChatSection,
+    #[serde(default)]
+    pub context: ContextSection,
+    #[serde(default)]
+    pub think: ThinkSection,
+    #[serde(default)]
+    pub schema: SchemaSection,
+    #[serde(default)]
+    pub tools: ToolsSection,
+    /// Extra roles (or replacements for built-in ones) mapping to
+    /// `"METHOD route"` rules, e.g. `dashboard = ["GET models"]`.
+    #[serde(default)]
+    pub roles: BTreeMap<String, Vec<String>>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServerSection {
+    pub host: Option<String>,
+    pub port: Option<u16>,
+}
+
+/// API key sources; as on the command line, `sqlite` beats `file` beats an
+/// inline `keys` list.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct KeysSection {
+    pub sqlite: Option<String>,
+    pub file: Option<String>,
+    pub keys: Option<Vec<String>>,
+}
+
+impl KeysSection {
+    pub fn is_empty(&self) -> bool {
+        self.sqlite.is_none() && self.file.is_none() && self.keys.is_none()
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RetrySection {
+    pub max_attempts: Option<u32>,
+    pub base_delay_ms: Option<u64>,
+    pub max_delay_ms: Option<u64>,
+    pub safe_routes: Option<Vec<String>>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct BreakerSection {
+    pub failure_threshold: Option<u32>,
+    pub cooldown_secs: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsSection {
+    pub cert: Option<String>,
+    pub key: Option<String>,
+    pub http_port: Option<u16>,
+    pub http_redirect: Option<bool>,
+    pub reload_secs: Option<u64>,
+    pub client_ca: Option<String>,
+    pub client_cert_required: Option<bool>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct CorsSection {
+    pub allowed_origins: Option<Vec<String>>,
+    pub allowed_headers: Option<Vec<String>>,
+    pub allowed_methods: Option<Vec<String>>,
+    pub max_age_secs: Option<u64>,
+    pub allow_credentials: Option<bool>,
+}
+
+/// Reverse proxies in front of the shim and the forwarding headers sent on
+/// to Ollama (`x-forwarded`, `forwarded`, `both` or `none`).
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ForwardedSection {
+    pub trusted_proxies: Option<Vec<String>>,
+    pub headers: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccessSection {
+    /// Networks refused before authentication.
+    pub denied_ips: Option<Vec<String>>,
+}
+
+/// Brute-force protection: delays and temporary bans per client address.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct BansSection {
+    pub threshold: Option<u32>,
+    pub window_secs: Option<u64>,
+    pub duration_secs: Option<u64>,
+    pub delay_step_ms: Option<u64>,
+    pub max_delay_ms: Option<u64>,
+}
+
+/// Listener for the admin API; it is only started when a port is set.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdminSection {
+    pub host: Option<String>,
+    pub port: Option<u16>,
+}
+
+/// Short-lived scoped tokens minted with `POST /v1/shim/tokens`.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct TokensSection {
+    pub secret: Option<String>,
+    pub default_ttl_secs: Option<u64>,
+    pub max_ttl_secs: Option<u64>,
+}
+
+/// External authorization service asked about every request; setting `url`
+/// replaces the shim's own key checks.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ForwardAuthSection {
+    pub url: Option<String>,
+    pub cache_secs: Option<u64>,
+    pub user_header: Option<String>,
+    pub timeout_ms: Option<u64>,
+}
+
+/// OIDC JWTs accepted alongside API keys; enabled by `jwks`.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct JwtSection {
+    /// Path or URL of the JWKS.
+    pub jwks: Option<String>,
+    pub issuer: Option<String>,
+    pub audience: Option<String>,
+    pub username_claim: Option<String>,
+    pub reload_secs: Option<u64>,
+    pub leeway_secs: Option<u64>,
+}
+
+/// Response cache for deterministic (`temperature: 0`) requests.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct CacheSection {
+    pub enabled: Option<bool>,
+    pub routes: Option<Vec<String>>,
+    pub ttl_secs: Option<u64>,
+    pub max_entries: Option<usize>,
+    pub max_entry_bytes: Option<usize>,
+    pub sqlite: Option<String>,
+    pub sqlite_max_mb: Option<u64>,
+}
+
+/// Per-input embedding cache; enabled by `sqlite`.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct EmbeddingCacheSection {
+    pub sqlite: Option<String>,
+    pub max_mb: Option<u64>,
+}
+
+/// Micro-batching of `/v1/embeddings` requests; enabled by `window_ms`.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct EmbeddingBatchSection {
+    pub window_ms: Option<u64>,
+    pub max_inputs: Option<usize>,
+}
+
+/// Sharing of one upstream call between identical in-flight requests.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct CoalesceSection {
+    pub enabled: Option<bool>,
+    pub routes: Option<Vec<String>>,
+}
+
+/// Serving chat completions through Ollama's native `/api/chat`.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct NativeChatSection {
+    pub enabled: Option<bool>,
+    /// Native options for every model, e.g. `num_ctx = 8192`.
+    pub options: Option<serde_json::Map<String, serde_json::Value>>,
+    /// Options per model name or `*` glob.
+    #[serde(default)]
+    pub models: BTreeMap<String, serde_json::Map<String, serde_json::Value>>,
+    /// Options per user.
+    #[serde(default)]
+    pub users: BTreeMap<String, serde_json::Map<String, serde_json::Value>>,
+}
+
+/// Per-model context windows checked before forwarding.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ContextSection {
+    /// Model name or `*` glob → context length in tokens.
+    pub limits: Option<BTreeMap<String, usize>>,
+    /// `reject` (the default) or `truncate`.
+    pub overflow: Option<String>,
+    pub chars_per_token: Option<f64>,
+}
+
+/// Handling of `<think>` blocks in reasoning models' answers.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ThinkSection {
+    /// Model name or `*` glob → `separate`, `strip` or `keep`.
+    pub models: Option<BTreeMap<String, String>>,
+}
+
+/// Validation of structured chat output against the request's schema.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct SchemaSection {
+    pub enabled: Option<bool>,
+    /// Extra upstream calls after an answer fails validation.
+    pub retries: Option<u32>,
+}
+
+/// Tool calling emulation for models without native tool support.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ToolsSection {
+    /// Model name or `*` glob → `emulate` or `native`.
+    pub models: Option<BTreeMap<String, String>>,
+}
+
+impl FileConfig {
+    /// Read and parse `path`.  Syntax and type errors carry the line and
+    /// column reported by the TOML parser.
+    pub fn read(path: &str) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("failed to read config file '{}'", path))?;
+        Self::parse(&content).with_context(|| format!("invalid config file '{}'", path))
+    }
+
+    pub fn parse(content: &str) -> Result<Self> {
+        Ok(toml::from_str(content)?)
+    }
+
+    /// Render as TOML, e.g. for `config check`.
+    pub fn to_toml(&self) -> Result<String> {
+        toml::to_string(self).context("failed to render configuration")
+    }
+}
+
+/// Hide most of a secret while keeping enough to tell values apart.
+pub fn mask_secret(secret: &str) -> String {
+    let visible: String = secret.chars().take(2).collect();
+    if secret.chars().count() <= 4 {
+        "****".to_string()
+    } else {
+        format!("{visible}****")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sections() {
+        let cfg = FileConfig::parse(
+            r#"
+            ollama_url = "http://gpu:11434"
+
+            [server]
+            port = 8080
+
+            [keys]
+            keys = ["a", "b"]
+
+            [retry]
+            safe_routes = ["embeddings"]
+
+            [tls]
+            cert = "/etc/cert.pem"
+            key = "/etc/key.pem"
+
+            [roles]
+            dashboard = ["GET models"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(cfg.ollama_url.as_deref(), Some("http://gpu:11434"));
+        assert_eq!(cfg.server.port, Some(8080));
+        assert!(cfg.server.host.is_none());
+        assert_eq!(cfg.keys.keys, Some(vec!["a".into(), "b".into()]));
+        assert!(!cfg.keys.is_empty());
+        assert_eq!(cfg.retry.safe_routes, Some(vec!["embeddings".into()]));
+        assert_eq!(cfg.tls.unwrap().cert.as_deref(), Some("/etc/cert.pem"));
+        assert_eq!(cfg.roles["dashboard"], vec!["GET models"]);
+    }
+
+    #[test]
+    fn errors_carry_line_numbers() {
+        let err = FileConfig::parse("ollama_url = \"x\"\n[server]\nport = \"eighty\"\n").unwrap_err();
+        assert!(err.to_string().contains("line 3"), "{err}");
+
+        let err = FileConfig::parse("[server]\nprot = 80\n").unwrap_err();
+        let msg = err.to_string();
+        assert!(msg.contains("line 2") && msg.contains("unknown field"), "{msg}");
+
+        let err = FileConfig::parse("[server\n").unwrap_err();
+        assert!(err.to_string().contains("line 1"), "{err}");
+    }
+
+    #[test]
+    fn masks_secrets() {
+        assert_eq!(mask_secret("sk-123456"), "sk****");
+        assert_eq!(mask_secret("abc"), "****");
+    }
+}
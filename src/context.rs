@@ -0,0 +1,286 @@
+use std::collections::BTreeMap;
+
+use anyhow::bail;
+use axum::{
+    Json,
+    body::Body,
+    http::{Response, StatusCode},
+    response::IntoResponse,
+};
+use serde_json::{Value, json};
+
+use crate::roles::glob_match;
+
+/// Header telling the client how many messages were dropped to fit.
+pub const TRUNCATED_HEADER: &str = "x-shim-truncated";
+
+/// Tokens counted per message for role markers and separators, on top of
+/// the content.
+const MESSAGE_OVERHEAD: usize = 4;
+
+/// What to do with a conversation that does not fit the context window.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Overflow {
+    /// Answer `400 context_length_exceeded`.
+    #[default]
+    Reject,
+    /// Drop the oldest non-system messages until it fits.
+    Truncate,
+}
+
+impl std::str::FromStr for Overflow {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "reject" => Ok(Overflow::Reject),
+            "truncate" => Ok(Overflow::Truncate),
+            _ => bail!("invalid context overflow mode '{s}' (use reject or truncate)"),
+        }
+    }
+}
+
+impl Overflow {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Overflow::Reject => "reject",
+            Overflow::Truncate => "truncate",
+        }
+    }
+}
+
+/// Per-model context windows checked before requests are forwarded.
+#[derive(Clone, Debug)]
+pub struct ContextConfig {
+    /// Model name or `*` glob → context length in tokens; the longest
+    /// matching pattern wins.
+    pub limits: BTreeMap<String, usize>,
+    pub overflow: Overflow,
+    /// Characters per token assumed when estimating prompt size.
+    pub chars_per_token: f64,
+}
+
+impl Default for ContextConfig {
+    fn default() -> Self {
+        ContextConfig {
+            limits: BTreeMap::new(),
+            overflow: Overflow::Reject,
+            chars_per_token: 4.0,
+        }
+    }
+}
+
+/// Outcome of checking a request against its model's context window.
+#[derive(Debug, PartialEq)]
+pub enum Fit {
+    /// Fits as sent, or no limit applies.
+    Fits,
+    /// Fits after dropping this many messages; the body was rewritten.
+    Truncated(usize),
+    /// Cannot be made to fit; `tokens` counts the prompt and the space
+    /// reserved for the completion.
+    Exceeded { limit: usize, tokens: usize },
+}
+
+impl ContextConfig {
+    fn limit(&self, model: &str) -> Option<usize> {
+        self.limits
+            .iter()
+            .filter(|(pattern, _)| glob_match(pattern, model))
+            .max_by_key(|(pattern, _)| pattern.len())
+            .map(|(_, limit)| *limit)
+    }
+
+    fn tokens(&self, text: &str) -> usize {
+        (text.chars().count() as f64 / self.chars_per_token).ceil() as usize
+    }
+
+    fn message_tokens(&self, message: &Value) -> usize {
+        let content = match message.get("content") {
+            Some(Value::String(text)) => self.tokens(text),
+            Some(Value::Array(parts)) => parts
+                .iter()
+                .filter_map(|part| part.get("text").and_then(Value::as_str))
+                .map(|text| self.tokens(text))
+                .sum(),
+            _ => 0,
+        };
+        let tool_calls = message
+            .get("tool_calls")
+            .map_or(0, |calls| self.tokens(&calls.to_string()));
+        MESSAGE_OVERHEAD + content + tool_calls
+    }
+
+    /// Check a `chat/completions` or `completions` request, dropping the
+    /// oldest messages when configured to.  The space asked for with
+    /// `max_tokens` is kept free for the answer.
+    pub fn fit(&self, path: &str, request: &mut Value) -> Fit {
+        let Some(model) = request.get("model").and_then(Value::as_str) else { return Fit::Fits };
+        let Some(limit) = self.limit(model) else { return Fit::Fits };
+        let reserved = ["max_tokens", "max_completion_tokens"]
+            .iter()
+            .filter_map(|f| request.get(*f).and_then(Value::as_u64))
+            .max()
+            .unwrap_or(0) as usize;
+        let budget = limit.saturating_sub(reserved);
+
+        if path != "chat/completions" {
+            let tokens = match request.get("prompt") {
+                Some(Value::String(prompt)) => self.tokens(prompt),
+                Some(Value::Array(prompts)) => prompts
+                    .iter()
+                    .filter_map(Value::as_str)
+                    .map(|p| self.tokens(p))
+                    .max()
+                    .unwrap_or(0),
+                _ => 0,
+            };
+            return if tokens > budget {
+                Fit::Exceeded { limit, tokens: tokens + reserved }
+            } else {
+                Fit::Fits
+            };
+        }
+
+        let Some(Value::Array(messages)) = request.get_mut("messages") else { return Fit::Fits };
+        let sizes: Vec<usize> = messages.iter().map(|m| self.message_tokens(m)).collect();
+        let total: usize = sizes.iter().sum();
+        if total <= budget {
+            return Fit::Fits;
+        }
+        if self.overflow == Overflow::Reject {
+            return Fit::Exceeded { limit, tokens: total + reserved };
+        }
+
+        // never drop system messages or the latest message
+        let is_system = |m: &Value| m.get("role").and_then(Value::as_str) == Some("system");
+        let last = messages.len().saturating_sub(1);
+        let mut keep = vec![true; messages.len()];
+        let mut remaining = total;
+        let mut i = 0;
+        while remaining > budget && i < last {
+            if !is_system(&messages[i]) {
+                keep[i] = false;
+                remaining -= sizes[i];
+            }
+            i += 1;
+        }
+        // tool results whose call was dropped would confuse the model
+        while i < last && messages[i].get("role").and_then(Value::as_str) == Some("tool") {
+            keep[i] = false;
+            remaining -= sizes[i];
+            i += 1;
+        }
+        if remaining > budget {
+            return Fit::Exceeded { limit, tokens: total + reserved };
+        }
+        let mut index = 0;
+        messages.retain(|_| {
+            index += 1;
+            keep[index - 1]
+        });
+        Fit::Truncated(keep.iter().filter(|k| !**k).count())
+    }
+}
+
+/// OpenAI's answer for a prompt that is too long.
+pub fn exceeded_response(limit: usize, tokens: usize) -> Response<Body> {
+    let message = format!(
+        "This model's maximum context length is {limit} tokens. However, your request \
+         needs about {tokens} tokens, including the space reserved for the completion. \
+         Please shorten the messages or lower max_tokens."
+    );
+    (
+        StatusCode::BAD_REQUEST,
+        Json(json!({
+            "error": {
+                "message": message,
+                "type": "invalid_request_error",
+                "param": "messages",
+                "code": "context_length_exceeded",
+            }
+        })),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(overflow: Overflow) -> ContextConfig {
+        ContextConfig {
+            limits: BTreeMap::from([("*".to_string(), 1000), ("tiny*".to_string(), 30)]),
+            overflow,
+            chars_per_token: 1.0,
+        }
+    }
+
+    fn message(role: &str, chars: usize) -> Value {
+        json!({ "role": role, "content": "x".repeat(chars) })
+    }
+
+    #[test]
+    fn rejects_or_truncates() {
+        let mut request = json!({
+            "model": "tiny:1b",
+            "messages": [message("system", 6), message("user", 6), message("assistant", 6), message("user", 6)],
+        });
+        // 4 messages of 10 tokens each against a limit of 30
+        assert_eq!(
+            config(Overflow::Reject).fit("chat/completions", &mut request.clone()),
+            Fit::Exceeded { limit: 30, tokens: 40 }
+        );
+        assert_eq!(config(Overflow::Truncate).fit("chat/completions", &mut request), Fit::Truncated(1));
+        let roles: Vec<&str> = request["messages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|m| m["role"].as_str().unwrap())
+            .collect();
+        assert_eq!(roles, ["system", "assistant", "user"]);
+
+        // the latest message alone is too long
+        let mut request = json!({ "model": "tiny", "messages": [message("system", 6), message("user", 60)] });
+        assert_eq!(
+            config(Overflow::Truncate).fit("chat/completions", &mut request),
+            Fit::Exceeded { limit: 30, tokens: 74 }
+        );
+
+        let mut request = json!({ "model": "big", "messages": [message("user", 600)] });
+        assert_eq!(config(Overflow::Reject).fit("chat/completions", &mut request), Fit::Fits);
+        request["max_tokens"] = json!(500);
+        // the reported size includes the space kept for the answer
+        assert_eq!(
+            config(Overflow::Reject).fit("chat/completions", &mut request),
+            Fit::Exceeded { limit: 1000, tokens: 1104 }
+        );
+    }
+
+    #[test]
+    fn drops_orphaned_tool_results_and_checks_prompts() {
+        let mut request = json!({
+            "model": "tiny",
+            "messages": [
+                { "role": "assistant", "content": "", "tool_calls": [{ "id": "1" }] },
+                message("tool", 6),
+                message("user", 6),
+            ],
+        });
+        assert_eq!(config(Overflow::Truncate).fit("chat/completions", &mut request), Fit::Truncated(2));
+        assert_eq!(request["messages"].as_array().unwrap().len(), 1);
+
+        let mut request = json!({ "model": "tiny", "prompt": "x".repeat(31) });
+        assert_eq!(
+            config(Overflow::Truncate).fit("completions", &mut request),
+            Fit::Exceeded { limit: 30, tokens: 31 }
+        );
+        assert_eq!(config(Overflow::Reject).fit("completions", &mut json!({ "model": "tiny", "prompt": "hi" })), Fit::Fits);
+        let mut request = json!({ "model": "tiny", "prompt": "hi", "max_tokens": 29 });
+        assert_eq!(
+            config(Overflow::Reject).fit("completions", &mut request),
+            Fit::Exceeded { limit: 30, tokens: 31 }
+        );
+        assert_eq!(config(Overflow::Reject).fit("chat/completions", &mut json!({ "messages": [] })), Fit::Fits);
+    }
+}
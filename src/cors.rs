@@ -0,0 +1,243 @@
+use axum::{
+    body::Body,
+    http::{HeaderMap, HeaderValue, Response, StatusCode, header},
+};
+use hyper::Method;
+
+/// Cross-origin settings for browser clients.  CORS handling is enabled as
+/// soon as at least one origin is allowed.
+#[derive(Clone, Debug)]
+pub struct CorsConfig {
+    /// Allowed origins: exact values such as `https://chat.example.com`,
+    /// subdomain wildcards such as `https://*.example.com`, or `*`.
+    pub allowed_origins: Vec<String>,
+    /// Request headers a browser may send; defaults cover bearer auth and JSON.
+    pub allowed_headers: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    /// How long browsers may cache a preflight answer.
+    pub max_age_secs: Option<u64>,
+    /// Whether cookies and other credentials may accompany requests.
+    pub allow_credentials: bool,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        CorsConfig {
+            allowed_origins: Vec::new(),
+            allowed_headers: vec!["authorization".into(), "content-type".into()],
+            allowed_methods: vec!["GET".into(), "POST".into(), "OPTIONS".into()],
+            max_age_secs: Some(600),
+            allow_credentials: false,
+        }
+    }
+}
+
+/// Whether `origin` matches a single allowed-origin pattern.
+pub fn origin_matches(pattern: &str, origin: &str) -> bool {
+    if pattern == "*" {
+        return true;
+    }
+    let pattern = pattern.trim_end_matches('/').to_ascii_lowercase();
+    let origin = origin.to_ascii_lowercase();
+    match pattern.split_once("://*.") {
+        Some((scheme, domain)) => origin
+            .strip_prefix(scheme)
+            .and_then(|rest| rest.strip_prefix("://"))
+            .is_some_and(|host| host.ends_with(&format!(".{domain}"))),
+        None => pattern == origin,
+    }
+}
+
+/// A preflight is an `OPTIONS` request carrying both `Origin` and
+/// `Access-Control-Request-Method`; browsers never attach credentials to it.
+pub fn is_preflight(method: &Method, headers: &HeaderMap) -> bool {
+    method == Method::OPTIONS
+        && headers.contains_key(header::ORIGIN)
+        && headers.contains_key(header::ACCESS_CONTROL_REQUEST_METHOD)
+}
+
+/// Enforce a user's own origin allowlist.  Users without one may call from
+/// anywhere; users with one must send an `Origin` header that matches, so a
+/// key published in a web page only works from that page's site.
+pub fn user_origin_allowed(allowed: Option<&Vec<String>>, headers: &HeaderMap) -> bool {
+    let Some(allowed) = allowed else { return true };
+    headers
+        .get(header::ORIGIN)
+        .and_then(|o| o.to_str().ok())
+        .is_some_and(|origin| allowed.iter().any(|p| origin_matches(p, origin)))
+}
+
+impl CorsConfig {
+    pub fn origin_allowed(&self, origin: &str) -> bool {
+        self.allowed_origins.iter().any(|p| origin_matches(p, origin))
+    }
+
+    /// Answer a preflight request.  Disallowed origins get a 403 without any
+    /// CORS headers, which makes the browser block the real request.
+    pub fn preflight(&self, headers: &HeaderMap) -> Response<Body> {
+        let origin = headers.get(header::ORIGIN).cloned();
+        let allowed = origin
+            .as_ref()
+            .and_then(|o| o.to_str().ok())
+            .is_some_and(|o| self.origin_allowed(o));
+        if !allowed {
+            return Response::builder()
+                .status(StatusCode::FORBIDDEN)
+                .body(Body::from("CORS origin not allowed"))
+                .unwrap();
+        }
+
+        let mut resp = Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(Body::empty())
+            .unwrap();
+        self.apply(origin.as_ref(), &mut resp);
+        let h = resp.headers_mut();
+        if let Ok(v) = HeaderValue::from_str(&self.allowed_methods.join(", ")) {
+            h.insert(header::ACCESS_CONTROL_ALLOW_METHODS, v);
+        }
+        if let Ok(v) = HeaderValue::from_str(&self.allowed_headers.join(", ")) {
+            h.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, v);
+        }
+        if let Some(max_age) = self.max_age_secs {
+            h.insert(header::ACCESS_CONTROL_MAX_AGE, HeaderValue::from(max_age));
+        }
+        resp
+    }
+
+    /// Put the CORS headers on a response for a request from `origin`,
+    /// replacing any that Ollama itself added.
+    pub fn apply(&self, origin: Option<&HeaderValue>, resp: &mut Response<Body>) {
+        let h = resp.headers_mut();
+        let upstream: Vec<_> = h
+            .keys()
+            .filter(|name| name.as_str().starts_with("access-control-"))
+            .cloned()
+            .collect();
+        for name in upstream {
+            h.remove(name);
+        }
+        h.append(header::VARY, HeaderValue::from_static("origin"));
+
+        let Some(origin) = origin else { return };
+        if !origin.to_str().is_ok_and(|o| self.origin_allowed(o)) {
+            return;
+        }
+        // a literal `*` cannot be combined with credentials, so echo the
+        // origin instead whenever credentials are enabled
+        let wildcard_only = self.allowed_origins.iter().all(|p| p == "*");
+        if wildcard_only && !self.allow_credentials {
+            h.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("*"));
+        } else {
+            h.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin.clone());
+        }
+        if self.allow_credentials {
+            h.insert(
+                header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                HeaderValue::from_static("true"),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cors(origins: &[&str]) -> CorsConfig {
+        CorsConfig {
+            allowed_origins: origins.iter().map(|o| o.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut h = HeaderMap::new();
+        for (k, v) in pairs {
+            h.insert(*k, HeaderValue::from_static(v));
+        }
+        h
+    }
+
+    #[test]
+    fn matching_origins() {
+        assert!(origin_matches("https://chat.example.com", "https://chat.example.com"));
+        assert!(origin_matches("https://chat.example.com/", "https://CHAT.example.com"));
+        assert!(!origin_matches("https://chat.example.com", "http://chat.example.com"));
+        assert!(origin_matches("https://*.example.com", "https://a.b.example.com"));
+        assert!(!origin_matches("https://*.example.com", "https://example.com"));
+        assert!(!origin_matches("https://*.example.com", "https://evilexample.com"));
+        assert!(!origin_matches("https://*.example.com", "http://a.example.com"));
+        assert!(origin_matches("*", "http://anything"));
+    }
+
+    #[test]
+    fn per_user_origins() {
+        let allowed = vec!["https://chat.example.com".to_string()];
+        let from_chat = headers(&[("origin", "https://chat.example.com")]);
+        let from_evil = headers(&[("origin", "https://evil.example")]);
+        assert!(user_origin_allowed(None, &from_evil));
+        assert!(user_origin_allowed(None, &HeaderMap::new()));
+        assert!(user_origin_allowed(Some(&allowed), &from_chat));
+        assert!(!user_origin_allowed(Some(&allowed), &from_evil));
+        assert!(!user_origin_allowed(Some(&allowed), &HeaderMap::new()));
+    }
+
+    #[test]
+    fn detects_preflight() {
+        let h = headers(&[
+            ("origin", "https://chat.example.com"),
+            ("access-control-request-method", "POST"),
+        ]);
+        assert!(is_preflight(&Method::OPTIONS, &h));
+        assert!(!is_preflight(&Method::POST, &h));
+        assert!(!is_preflight(&Method::OPTIONS, &headers(&[("origin", "x")])));
+    }
+
+    #[test]
+    fn preflight_answers() {
+        let cfg = CorsConfig {
+            allow_credentials: true,
+            ..cors(&["https://chat.example.com"])
+        };
+        let resp = cfg.preflight(&headers(&[
+            ("origin", "https://chat.example.com"),
+            ("access-control-request-method", "POST"),
+        ]));
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        let h = resp.headers();
+        assert_eq!(h["access-control-allow-origin"], "https://chat.example.com");
+        assert_eq!(h["access-control-allow-credentials"], "true");
+        assert_eq!(h["access-control-allow-methods"], "GET, POST, OPTIONS");
+        assert_eq!(h["access-control-allow-headers"], "authorization, content-type");
+        assert_eq!(h["access-control-max-age"], "600");
+
+        let resp = cfg.preflight(&headers(&[
+            ("origin", "https://evil.example"),
+            ("access-control-request-method", "POST"),
+        ]));
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        assert!(!resp.headers().contains_key("access-control-allow-origin"));
+    }
+
+    #[test]
+    fn apply_replaces_upstream_headers() {
+        let cfg = cors(&["*"]);
+        let mut resp = Response::builder()
+            .header("access-control-allow-origin", "http://localhost")
+            .header("access-control-allow-methods", "PUT")
+            .body(Body::empty())
+            .unwrap();
+        cfg.apply(Some(&HeaderValue::from_static("https://x.test")), &mut resp);
+        let h = resp.headers();
+        assert_eq!(h["access-control-allow-origin"], "*");
+        assert!(!h.contains_key("access-control-allow-methods"));
+        assert_eq!(h["vary"], "origin");
+
+        // disallowed origins get no allow header at all
+        let cfg = cors(&["https://chat.example.com"]);
+        let mut resp = Response::new(Body::empty());
+        cfg.apply(Some(&HeaderValue::from_static("https://x.test")), &mut resp);
+        assert!(!resp.headers().contains_key("access-control-allow-origin"));
+    }
+}
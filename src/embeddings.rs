@@ -0,0 +1,450 @@
+use std::{
+    collections::HashMap,
+    ops::Range,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use axum::{
+    Json,
+    body::{self, Body, Bytes},
+    http::{HeaderMap, HeaderValue, Response, StatusCode},
+    response::IntoResponse,
+};
+use hyper::Method;
+use ring::digest;
+use rusqlite::{Connection, OptionalExtension, params};
+use serde_json::{Value, json};
+
+use crate::cache::CACHE_HEADER;
+use crate::proxy::forward_request;
+use crate::state::AppState;
+
+/// Route whose requests are served through the embedding cache and batcher.
+pub const EMBEDDINGS_PATH: &str = "embeddings";
+
+/// Rows read per eviction query once the cache is over its size cap.
+const EVICT_BATCH: i64 = 32;
+
+/// Settings for the per-input embedding cache.
+#[derive(Clone, Debug)]
+pub struct EmbeddingCacheConfig {
+    pub sqlite: String,
+    /// Total size of stored vectors; least recently used are evicted first.
+    pub max_bytes: u64,
+}
+
+/// Persistent `(model, text)` → vector cache.  Array inputs are split so
+/// unchanged strings are served from the cache and only new ones are
+/// embedded upstream, in a single batch; see [`handle`].
+#[derive(Clone, Debug)]
+pub struct EmbeddingCache {
+    config: EmbeddingCacheConfig,
+}
+
+/// A cached vector, kept as the JSON Ollama returned so hits are
+/// byte-for-byte what a miss would have produced.
+#[derive(Clone, Debug, PartialEq)]
+struct Entry {
+    embedding: Value,
+    tokens: u64,
+}
+
+/// The parts of an embeddings request the cache understands.
+struct Request {
+    /// `model`, plus `dimensions` when given, since it changes the vector.
+    cache_model: String,
+    inputs: Vec<String>,
+}
+
+/// Vectors for a list of inputs, in input order, with each input's share
+/// of the prompt tokens.
+#[derive(Clone, Debug)]
+pub struct Embedded {
+    pub vectors: Vec<Value>,
+    pub tokens: Vec<u64>,
+    pub model: Option<Value>,
+}
+
+impl Embedded {
+    /// The vectors and tokens of `range`, for one caller of a batch.
+    pub fn slice(&self, range: Range<usize>) -> Embedded {
+        Embedded {
+            vectors: self.vectors[range.clone()].to_vec(),
+            tokens: self.tokens[range].to_vec(),
+            model: self.model.clone(),
+        }
+    }
+}
+
+/// An upstream error, buffered so it can be passed to every caller that
+/// shared the request.
+#[derive(Clone, Debug)]
+pub struct Failure {
+    pub status: StatusCode,
+    pub headers: Box<HeaderMap>,
+    pub body: Bytes,
+}
+
+impl Failure {
+    pub fn bad_gateway(message: &'static str) -> Self {
+        Failure {
+            status: StatusCode::BAD_GATEWAY,
+            headers: Box::default(),
+            body: Bytes::from_static(message.as_bytes()),
+        }
+    }
+}
+
+impl IntoResponse for Failure {
+    fn into_response(self) -> Response<Body> {
+        let mut resp = Response::new(Body::from(self.body));
+        *resp.status_mut() = self.status;
+        *resp.headers_mut() = *self.headers;
+        resp
+    }
+}
+
+/// Answer an embeddings request through the embedding cache and the
+/// batcher, whichever are enabled, or `None` when its shape is not one they
+/// handle (token arrays, base64 output, invalid JSON) and it should be
+/// forwarded as usual.
+pub async fn handle(state: &AppState, headers: HeaderMap, body: &[u8]) -> Option<Response<Body>> {
+    let request: Value = serde_json::from_slice(body).ok()?;
+    let parsed = parse_request(&request)?;
+
+    let cached = match &state.embedding_cache {
+        Some(cache) => match cache.lookup(&parsed.cache_model, &parsed.inputs).await {
+            Ok(cached) => cached,
+            Err(e) => {
+                eprintln!("embedding cache lookup failed: {e:#}");
+                return None;
+            }
+        },
+        None => HashMap::new(),
+    };
+    // each distinct missing text is embedded once
+    let mut misses: Vec<String> = Vec::new();
+    for text in &parsed.inputs {
+        if !cached.contains_key(text) && !misses.contains(text) {
+            misses.push(text.clone());
+        }
+    }
+
+    let mut fresh = HashMap::new();
+    let mut upstream_model = None;
+    if !misses.is_empty() {
+        let embedded = match &state.batcher {
+            Some(batcher) => batcher.embed(state, headers, request.clone(), misses.clone()).await,
+            None => embed(state, headers, request.clone(), &misses).await,
+        };
+        let embedded = match embedded {
+            Ok(embedded) => embedded,
+            Err(failure) => return Some(failure.into_response()),
+        };
+        upstream_model = embedded.model;
+        for ((text, embedding), tokens) in misses.iter().zip(embedded.vectors).zip(embedded.tokens) {
+            fresh.insert(text.clone(), Entry { embedding, tokens });
+        }
+        if let Some(cache) = &state.embedding_cache {
+            if let Err(e) = cache.store(&parsed.cache_model, &fresh).await {
+                eprintln!("failed to store embeddings: {e:#}");
+            }
+        }
+    }
+
+    let mut data = Vec::with_capacity(parsed.inputs.len());
+    let mut prompt_tokens = 0;
+    for (index, text) in parsed.inputs.iter().enumerate() {
+        let entry = cached.get(text).or_else(|| fresh.get(text))?;
+        prompt_tokens += entry.tokens;
+        data.push(json!({ "object": "embedding", "embedding": entry.embedding, "index": index }));
+    }
+    let model = upstream_model.unwrap_or_else(|| request["model"].clone());
+    let mut resp = Json(json!({
+        "object": "list",
+        "data": data,
+        "model": model,
+        "usage": { "prompt_tokens": prompt_tokens, "total_tokens": prompt_tokens },
+    }))
+    .into_response();
+    if state.embedding_cache.is_some() {
+        let flag = if misses.is_empty() {
+            "HIT"
+        } else if cached.is_empty() {
+            "MISS"
+        } else {
+            "PARTIAL"
+        };
+        resp.headers_mut().insert(CACHE_HEADER, HeaderValue::from_static(flag));
+    }
+    Some(resp)
+}
+
+/// Embed `inputs` upstream in one call, with the other parameters of
+/// `request`.  Token counts are apportioned over the inputs.
+pub async fn embed(state: &AppState, headers: HeaderMap, mut request: Value, inputs: &[String]) -> Result<Embedded, Failure> {
+    request["input"] = json!(inputs);
+    let body = serde_json::to_vec(&request).map_err(|_| Failure::bad_gateway("Invalid embeddings request"))?;
+    let resp = forward_request(state, Method::POST, EMBEDDINGS_PATH.to_string(), headers, Bytes::from(body)).await;
+    let (parts, body) = resp.into_parts();
+    let bytes = body::to_bytes(body, usize::MAX)
+        .await
+        .map_err(|_| Failure::bad_gateway("Upstream request failed"))?;
+    if parts.status != StatusCode::OK {
+        return Err(Failure {
+            status: parts.status,
+            headers: Box::new(parts.headers),
+            body: bytes,
+        });
+    }
+    let (vectors, total_tokens, model) = parse_response(&bytes, inputs.len())
+        .ok_or_else(|| Failure::bad_gateway("Unexpected embeddings response from upstream"))?;
+    let lengths: Vec<usize> = inputs.iter().map(String::len).collect();
+    Ok(Embedded {
+        vectors,
+        tokens: apportion(total_tokens, &lengths),
+        model,
+    })
+}
+
+impl EmbeddingCache {
+    pub fn new(config: EmbeddingCacheConfig) -> Self {
+        EmbeddingCache { config }
+    }
+
+    /// Cached entries for `inputs`, read on the blocking pool.
+    async fn lookup(&self, model: &str, inputs: &[String]) -> Result<HashMap<String, Entry>> {
+        let (path, model, inputs) = (self.config.sqlite.clone(), model.to_string(), inputs.to_vec());
+        tokio::task::spawn_blocking(move || sqlite_lookup(&path, &model, &inputs)).await?
+    }
+
+    /// Store fresh entries, and evict beyond the size cap, on the blocking
+    /// pool.
+    async fn store(&self, model: &str, entries: &HashMap<String, Entry>) -> Result<()> {
+        let rows: Vec<(String, String, i64)> = entries
+            .iter()
+            .map(|(text, entry)| (text_hash(text), entry.embedding.to_string(), entry.tokens as i64))
+            .collect();
+        let (path, model, max_bytes) = (self.config.sqlite.clone(), model.to_string(), self.config.max_bytes);
+        tokio::task::spawn_blocking(move || sqlite_store(&path, &model, &rows, max_bytes)).await?
+    }
+}
+
+fn sqlite_lookup(path: &str, model: &str, inputs: &[String]) -> Result<HashMap<String, Entry>> {
+    let mut conn = Connection::open(path)?;
+    ensure_table(&conn)?;
+    let tx = conn.transaction()?;
+    let now = unix_now();
+    let mut found = HashMap::new();
+    for text in inputs {
+        if found.contains_key(text) {
+            continue;
+        }
+        let hash = text_hash(text);
+        let row = tx
+            .query_row(
+                "SELECT embedding, tokens FROM embedding_cache WHERE model = ?1 AND hash = ?2",
+                params![model, hash],
+                |row| Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)? as u64)),
+            )
+            .optional()?;
+        let Some((embedding, tokens)) = row else { continue };
+        let Ok(embedding) = serde_json::from_str(&embedding) else { continue };
+        tx.execute(
+            "UPDATE embedding_cache SET last_used = ?1 WHERE model = ?2 AND hash = ?3",
+            params![now, model, hash],
+        )?;
+        found.insert(text.clone(), Entry { embedding, tokens });
+    }
+    tx.commit()?;
+    Ok(found)
+}
+
+/// Rows are `(hash, embedding JSON, tokens)`.
+fn sqlite_store(path: &str, model: &str, rows: &[(String, String, i64)], max_bytes: u64) -> Result<()> {
+    let mut conn = Connection::open(path)?;
+    ensure_table(&conn)?;
+    let tx = conn.transaction()?;
+    let now = unix_now();
+    for (hash, embedding, tokens) in rows {
+        tx.execute(
+            "INSERT OR REPLACE INTO embedding_cache(model, hash, embedding, tokens, last_used)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![model, hash, embedding, tokens, now],
+        )?;
+    }
+    // evict least recently used rows beyond the size cap, a few at a time
+    let mut total = tx.query_row(
+        "SELECT COALESCE(SUM(length(embedding)), 0) FROM embedding_cache",
+        [],
+        |row| row.get::<_, i64>(0),
+    )? as u64;
+    while total > max_bytes {
+        let oldest: Vec<(i64, u64)> = tx
+            .prepare("SELECT rowid, length(embedding) FROM embedding_cache ORDER BY last_used, rowid LIMIT ?1")?
+            .query_map([EVICT_BATCH], |row| Ok((row.get(0)?, row.get::<_, i64>(1)? as u64)))?
+            .collect::<Result<_, _>>()?;
+        if oldest.is_empty() {
+            break;
+        }
+        for (rowid, len) in oldest {
+            if total <= max_bytes {
+                break;
+            }
+            tx.execute("DELETE FROM embedding_cache WHERE rowid = ?1", [rowid])?;
+            total -= len;
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+fn parse_request(request: &Value) -> Option<Request> {
+    if request
+        .get("encoding_format")
+        .is_some_and(|f| f.as_str() != Some("float"))
+    {
+        return None;
+    }
+    let model = request.get("model")?.as_str()?;
+    let cache_model = match request.get("dimensions").and_then(Value::as_u64) {
+        Some(dims) => format!("{model}@{dims}"),
+        None => model.to_string(),
+    };
+    let inputs = match request.get("input")? {
+        Value::String(text) => vec![text.clone()],
+        Value::Array(items) if !items.is_empty() => items
+            .iter()
+            .map(|item| item.as_str().map(str::to_string))
+            .collect::<Option<_>>()?,
+        _ => return None,
+    };
+    Some(Request { cache_model, inputs })
+}
+
+/// Vectors in input order, the batch's prompt tokens and the model name.
+fn parse_response(bytes: &[u8], expected: usize) -> Option<(Vec<Value>, u64, Option<Value>)> {
+    let resp: Value = serde_json::from_slice(bytes).ok()?;
+    let mut data: Vec<&Value> = resp.get("data")?.as_array()?.iter().collect();
+    if data.len() != expected {
+        return None;
+    }
+    data.sort_by_key(|d| d.get("index").and_then(Value::as_u64).unwrap_or(0));
+    let vectors = data
+        .into_iter()
+        .map(|d| d.get("embedding").cloned())
+        .collect::<Option<Vec<_>>>()?;
+    let tokens = resp
+        .pointer("/usage/prompt_tokens")
+        .and_then(Value::as_u64)
+        .unwrap_or(0);
+    Some((vectors, tokens, resp.get("model").cloned()))
+}
+
+/// Split a batch's token count over its inputs in proportion to their
+/// length, so the parts add up to exactly `total`.  Upstream only reports
+/// the batch total; a single input gets it exactly.
+fn apportion(total: u64, lengths: &[usize]) -> Vec<u64> {
+    let sum: u64 = lengths.iter().map(|&l| l.max(1) as u64).sum();
+    let mut shares: Vec<(usize, u64, u64)> = lengths
+        .iter()
+        .enumerate()
+        .map(|(i, &l)| {
+            let weighted = total * l.max(1) as u64;
+            (i, weighted / sum, weighted % sum)
+        })
+        .collect();
+    let mut left = total - shares.iter().map(|s| s.1).sum::<u64>();
+    // hand out the remainder to the largest fractional parts
+    let mut order: Vec<usize> = (0..shares.len()).collect();
+    order.sort_by_key(|&i| std::cmp::Reverse(shares[i].2));
+    for i in order {
+        if left == 0 {
+            break;
+        }
+        shares[i].1 += 1;
+        left -= 1;
+    }
+    shares.into_iter().map(|s| s.1).collect()
+}
+
+fn text_hash(text: &str) -> String {
+    digest::digest(&digest::SHA256, text.as_bytes())
+        .as_ref()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+fn ensure_table(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS embedding_cache(
+            model TEXT NOT NULL,
+            hash TEXT NOT NULL,
+            embedding TEXT NOT NULL,
+            tokens INTEGER NOT NULL,
+            last_used INTEGER NOT NULL,
+            PRIMARY KEY (model, hash)
+        )",
+        [],
+    )?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS embedding_cache_last_used ON embedding_cache(last_used)",
+        [],
+    )?;
+    Ok(())
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_tokens_exactly() {
+        assert_eq!(apportion(10, &[5]), vec![10]);
+        assert_eq!(apportion(10, &[1, 1, 1]), vec![4, 3, 3]);
+        assert_eq!(apportion(7, &[30, 10]), vec![5, 2]);
+        assert_eq!(apportion(3, &[0, 0, 0]).iter().sum::<u64>(), 3);
+    }
+
+    #[test]
+    fn understands_request_shapes() {
+        let parsed = parse_request(&json!({ "model": "nomic", "input": "hi" })).unwrap();
+        assert_eq!(parsed.inputs, vec!["hi"]);
+        assert_eq!(parsed.cache_model, "nomic");
+        let parsed = parse_request(&json!({ "model": "nomic", "input": ["a", "b"], "dimensions": 256 })).unwrap();
+        assert_eq!(parsed.inputs, vec!["a", "b"]);
+        assert_eq!(parsed.cache_model, "nomic@256");
+        assert!(parse_request(&json!({ "model": "nomic", "input": [[1, 2, 3]] })).is_none());
+        assert!(parse_request(&json!({ "model": "nomic", "input": [] })).is_none());
+        assert!(parse_request(&json!({ "model": "nomic", "input": "a", "encoding_format": "base64" })).is_none());
+    }
+
+    #[tokio::test]
+    async fn bounded_by_size() {
+        let tmp = tempfile::NamedTempFile::new().unwrap();
+        let cache = EmbeddingCache::new(EmbeddingCacheConfig {
+            sqlite: tmp.path().to_str().unwrap().to_string(),
+            max_bytes: 16,
+        });
+        let entry = |v: f64| Entry {
+            embedding: json!([v, v]),
+            tokens: 1,
+        };
+        cache.store("m", &HashMap::from([("a".to_string(), entry(0.5))])).await.unwrap();
+        cache.store("m", &HashMap::from([("b".to_string(), entry(0.25))])).await.unwrap();
+        // `[0.5,0.5]` and `[0.25,0.25]` together exceed the cap
+        let found = cache.lookup("m", &["a".into(), "b".into()]).await.unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found["b"], entry(0.25));
+        assert!(cache.lookup("other", &["b".into()]).await.unwrap().is_empty());
+    }
+}
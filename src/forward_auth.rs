@@ -0,0 +1,251 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::http::{HeaderMap, StatusCode};
+use hyper::Method;
+use reqwest::Client;
+use ring::digest;
+
+use crate::forwarded::ClientInfo;
+use crate::headers::auth_request_headers;
+
+/// Settings for delegating authentication to an external service, in the
+/// style of nginx `auth_request`.
+#[derive(Clone, Debug)]
+pub struct ForwardAuthConfig {
+    /// Called with the client's headers before each request is forwarded.
+    pub url: String,
+    /// How long a decision is reused for the same credentials, route and
+    /// address; zero asks the service every time.
+    pub cache_ttl: Duration,
+    /// Response header naming the user a request is allowed as.
+    pub user_header: String,
+    pub timeout: Duration,
+}
+
+impl ForwardAuthConfig {
+    pub fn new(url: String) -> Self {
+        ForwardAuthConfig {
+            url,
+            cache_ttl: Duration::from_secs(10),
+            user_header: "x-user".to_string(),
+            timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// What the authorization service said about a request.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Decision {
+    /// A 2xx answer, with the user from the configured response header.
+    Allow(Option<String>),
+    /// A 401 or 403 answer, passed on to the client.
+    Deny(StatusCode),
+    /// The service could not be reached or gave any other answer; never
+    /// cached.
+    Unavailable,
+}
+
+struct Cached {
+    expires: Instant,
+    decision: Decision,
+}
+
+/// Cached decisions are pruned once there are this many.
+const CACHE_PRUNE_AT: usize = 10_000;
+
+/// Forward-auth client with its decision cache.  Entries are keyed by a
+/// digest of the method, route, client address and credential headers
+/// (`Authorization`, `Cookie`), so no credentials are kept in memory.
+#[derive(Clone)]
+pub struct ForwardAuth {
+    config: ForwardAuthConfig,
+    cache: Arc<Mutex<HashMap<Vec<u8>, Cached>>>,
+}
+
+impl ForwardAuth {
+    pub fn new(config: ForwardAuthConfig) -> Self {
+        ForwardAuth {
+            config,
+            cache: Arc::default(),
+        }
+    }
+
+    /// Ask the service (or the cache) whether a request may go ahead.
+    pub async fn check(
+        &self,
+        client: &Client,
+        method: &Method,
+        path: &str,
+        headers: &HeaderMap,
+        info: &ClientInfo,
+    ) -> Decision {
+        let key = cache_key(method, path, headers, info);
+        if let Some(decision) = self.cached(&key) {
+            return decision;
+        }
+
+        let mut auth_headers = auth_request_headers(headers);
+        let uri = format!("/v1/{path}");
+        let original = [
+            ("x-original-method", Some(method.as_str().to_string())),
+            ("x-original-uri", Some(uri)),
+            ("x-forwarded-for", info.addr.map(|a| a.to_string())),
+        ];
+        for (name, value) in original {
+            if let Some(Ok(value)) = value.map(|v| reqwest::header::HeaderValue::from_str(&v)) {
+                auth_headers.insert(name, value);
+            }
+        }
+
+        let resp = client
+            .get(&self.config.url)
+            .headers(auth_headers)
+            .timeout(self.config.timeout)
+            .send()
+            .await;
+        let decision = match resp {
+            Ok(resp) if resp.status().is_success() => Decision::Allow(
+                resp.headers()
+                    .get(self.config.user_header.as_str())
+                    .and_then(|v| v.to_str().ok())
+                    .map(str::trim)
+                    .filter(|v| !v.is_empty())
+                    .map(str::to_string),
+            ),
+            Ok(resp) if matches!(resp.status().as_u16(), 401 | 403) => {
+                Decision::Deny(StatusCode::from_u16(resp.status().as_u16()).unwrap_or(StatusCode::FORBIDDEN))
+            }
+            Ok(resp) => {
+                eprintln!("forward-auth service answered {}", resp.status());
+                Decision::Unavailable
+            }
+            Err(e) => {
+                eprintln!("forward-auth request failed: {e}");
+                Decision::Unavailable
+            }
+        };
+        if decision != Decision::Unavailable {
+            self.store(key, decision.clone());
+        }
+        decision
+    }
+
+    fn cached(&self, key: &[u8]) -> Option<Decision> {
+        let cache = self.cache.lock().unwrap();
+        let cached = cache.get(key)?;
+        (cached.expires > Instant::now()).then(|| cached.decision.clone())
+    }
+
+    fn store(&self, key: Vec<u8>, decision: Decision) {
+        if self.config.cache_ttl.is_zero() {
+            return;
+        }
+        let now = Instant::now();
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= CACHE_PRUNE_AT {
+            cache.retain(|_, cached| cached.expires > now);
+        }
+        let expires = now + self.config.cache_ttl;
+        cache.insert(key, Cached { expires, decision });
+    }
+}
+
+fn cache_key(method: &Method, path: &str, headers: &HeaderMap, info: &ClientInfo) -> Vec<u8> {
+    let mut ctx = digest::Context::new(&digest::SHA256);
+    let addr = info.addr.map(|a| a.to_string()).unwrap_or_default();
+    for part in [method.as_str().as_bytes(), path.as_bytes(), addr.as_bytes()] {
+        ctx.update(part);
+        ctx.update(b"\0");
+    }
+    for name in ["authorization", "cookie"] {
+        for value in headers.get_all(name) {
+            ctx.update(value.as_bytes());
+            ctx.update(b"\0");
+        }
+        ctx.update(b"\n");
+    }
+    ctx.finish().as_ref().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use httpmock::MockServer;
+
+    fn info() -> ClientInfo {
+        ClientInfo {
+            addr: Some("192.0.2.7".parse().unwrap()),
+            chain: Vec::new(),
+            proto: "http".into(),
+            host: None,
+        }
+    }
+
+    fn bearer(key: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("authorization", format!("Bearer {key}").parse().unwrap());
+        headers
+    }
+
+    #[tokio::test]
+    async fn asks_the_service_and_caches_decisions() {
+        let server = MockServer::start_async().await;
+        let allow = server.mock(|when, then| {
+            when.method("GET")
+                .path("/auth")
+                .header("authorization", "Bearer good")
+                .header_exists("x-original-method")
+                .header_exists("x-original-uri")
+                .header("x-forwarded-for", "192.0.2.7");
+            then.status(200).header("X-User", "alice");
+        });
+        let deny = server.mock(|when, then| {
+            when.method("GET").path("/auth").header("authorization", "Bearer bad");
+            then.status(403);
+        });
+
+        let auth = ForwardAuth::new(ForwardAuthConfig::new(server.url("/auth")));
+        let client = Client::new();
+        let check = |key: &'static str| {
+            let (auth, client) = (auth.clone(), client.clone());
+            async move {
+                auth.check(&client, &Method::POST, "chat/completions", &bearer(key), &info())
+                    .await
+            }
+        };
+        assert_eq!(check("good").await, Decision::Allow(Some("alice".into())));
+        assert_eq!(check("good").await, Decision::Allow(Some("alice".into())));
+        assert_eq!(check("bad").await, Decision::Deny(StatusCode::FORBIDDEN));
+        assert_eq!(check("bad").await, Decision::Deny(StatusCode::FORBIDDEN));
+        allow.assert_calls(1);
+        deny.assert_calls(1);
+
+        // another route is a separate decision
+        auth.check(&client, &Method::GET, "models", &bearer("good"), &info()).await;
+        allow.assert_calls(2);
+    }
+
+    #[tokio::test]
+    async fn errors_are_not_cached() {
+        let server = MockServer::start_async().await;
+        let broken = server.mock(|when, then| {
+            when.path("/auth");
+            then.status(500);
+        });
+        let mut config = ForwardAuthConfig::new(server.url("/auth"));
+        config.cache_ttl = Duration::from_secs(60);
+        let auth = ForwardAuth::new(config);
+        let client = Client::new();
+        for _ in 0..2 {
+            let decision = auth
+                .check(&client, &Method::GET, "models", &bearer("k"), &info())
+                .await;
+            assert_eq!(decision, Decision::Unavailable);
+        }
+        broken.assert_calls(2);
+    }
+}
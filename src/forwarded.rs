@@ -0,0 +1,348 @@
+use std::net::IpAddr;
+
+use axum::http::{HeaderMap, HeaderValue, header};
+use ipnet::IpNet;
+
+/// Every header that describes the client to Ollama.
+pub const FORWARDING_HEADERS: [&str; 4] = ["x-forwarded-for", "x-forwarded-proto", "x-forwarded-host", "forwarded"];
+
+/// Which forwarding headers are sent to Ollama.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ForwardedHeaders {
+    /// `X-Forwarded-For`, `X-Forwarded-Proto` and `X-Forwarded-Host`.
+    #[default]
+    XForwarded,
+    /// The standard RFC 7239 `Forwarded` header.
+    Forwarded,
+    Both,
+    None,
+}
+
+impl std::str::FromStr for ForwardedHeaders {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "x-forwarded" => Ok(ForwardedHeaders::XForwarded),
+            "forwarded" => Ok(ForwardedHeaders::Forwarded),
+            "both" => Ok(ForwardedHeaders::Both),
+            "none" => Ok(ForwardedHeaders::None),
+            other => anyhow::bail!(
+                "unknown forwarded header mode '{other}' (expected x-forwarded, forwarded, both or none)"
+            ),
+        }
+    }
+}
+
+impl std::fmt::Display for ForwardedHeaders {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ForwardedHeaders::XForwarded => "x-forwarded",
+            ForwardedHeaders::Forwarded => "forwarded",
+            ForwardedHeaders::Both => "both",
+            ForwardedHeaders::None => "none",
+        })
+    }
+}
+
+/// Settings for running behind load balancers or other reverse proxies.
+#[derive(Clone, Debug, Default)]
+pub struct ForwardedConfig {
+    /// Peers whose forwarding headers are believed.  Requests from anyone
+    /// else have those headers ignored, so clients cannot spoof their
+    /// address.
+    pub trusted_proxies: Vec<IpNet>,
+    pub headers: ForwardedHeaders,
+}
+
+/// Parse CIDRs or bare addresses (which become /32 or /128 networks).
+pub fn parse_cidrs(items: &[String]) -> anyhow::Result<Vec<IpNet>> {
+    items
+        .iter()
+        .map(|item| {
+            let item = item.trim();
+            item.parse::<IpNet>()
+                .or_else(|_| item.parse::<IpAddr>().map(IpNet::from))
+                .map_err(|_| anyhow::anyhow!("invalid CIDR or IP address '{item}'"))
+        })
+        .collect()
+}
+
+/// Where a request really came from, after looking through trusted proxies.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ClientInfo {
+    /// The original client's address, when known.
+    pub addr: Option<IpAddr>,
+    /// Addresses of the hops in front of the client as they should be
+    /// reported upstream, oldest first, ending with the direct peer.
+    pub chain: Vec<IpAddr>,
+    /// `http` or `https` as seen by the client.
+    pub proto: String,
+    /// Host the client addressed.
+    pub host: Option<String>,
+}
+
+impl ForwardedConfig {
+    fn is_trusted(&self, addr: IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|net| net.contains(&addr))
+    }
+
+    /// Work out the client behind a request arriving from `peer` over
+    /// `proto`.  Forwarding headers are only honoured when the peer is a
+    /// trusted proxy; the client is then the right-most untrusted address in
+    /// the chain, since anything left of that may have been forged.
+    pub fn client_info(&self, peer: Option<IpAddr>, proto: &str, headers: &HeaderMap) -> ClientInfo {
+        let host = headers
+            .get(header::HOST)
+            .and_then(|h| h.to_str().ok())
+            .map(str::to_string);
+        let mut info = ClientInfo {
+            addr: peer,
+            chain: peer.into_iter().collect(),
+            proto: proto.to_string(),
+            host,
+        };
+        let Some(peer) = peer else { return info };
+        if !self.is_trusted(peer) {
+            return info;
+        }
+
+        let mut hops = claimed_chain(headers);
+        if hops.is_empty() {
+            return info;
+        }
+        hops.push(peer);
+        info.addr = hops
+            .iter()
+            .rev()
+            .find(|addr| !self.is_trusted(**addr))
+            .or(hops.first())
+            .copied();
+        info.chain = hops;
+        if let Some(proto) = header_str(headers, "x-forwarded-proto").or_else(|| forwarded_param(headers, "proto")) {
+            info.proto = proto;
+        }
+        if let Some(host) = header_str(headers, "x-forwarded-host").or_else(|| forwarded_param(headers, "host")) {
+            info.host = Some(host);
+        }
+        info
+    }
+
+    /// Replace whatever forwarding headers the client sent with ones
+    /// describing `client`.
+    pub fn set_upstream_headers(&self, headers: &mut HeaderMap, client: &ClientInfo) {
+        for name in FORWARDING_HEADERS {
+            headers.remove(name);
+        }
+        let xff = matches!(self.headers, ForwardedHeaders::XForwarded | ForwardedHeaders::Both);
+        let rfc = matches!(self.headers, ForwardedHeaders::Forwarded | ForwardedHeaders::Both);
+
+        if xff {
+            let chain = client.chain.iter().map(IpAddr::to_string).collect::<Vec<_>>().join(", ");
+            insert(headers, "x-forwarded-for", &chain);
+            insert(headers, "x-forwarded-proto", &client.proto);
+            if let Some(host) = &client.host {
+                insert(headers, "x-forwarded-host", host);
+            }
+        }
+        if rfc {
+            let mut elements: Vec<String> = client.chain.iter().map(|a| format!("for={}", node(*a))).collect();
+            if elements.is_empty() {
+                elements.push("for=unknown".into());
+            }
+            let last = elements.len() - 1;
+            elements[last].push_str(&format!(";proto={}", client.proto));
+            if let Some(host) = &client.host {
+                elements[last].push_str(&format!(";host=\"{}\"", host.replace('"', "")));
+            }
+            insert(headers, "forwarded", &elements.join(", "));
+        }
+    }
+}
+
+fn insert(headers: &mut HeaderMap, name: &'static str, value: &str) {
+    if let Ok(value) = HeaderValue::from_str(value) {
+        headers.insert(name, value);
+    }
+}
+
+/// RFC 7239 node syntax: IPv6 addresses are bracketed and quoted.
+fn node(addr: IpAddr) -> String {
+    match addr {
+        IpAddr::V4(v4) => v4.to_string(),
+        IpAddr::V6(v6) => format!("\"[{v6}]\""),
+    }
+}
+
+fn header_str(headers: &HeaderMap, name: &str) -> Option<String> {
+    let value = headers.get(name)?.to_str().ok()?;
+    // with several proxies the first value is the one the client saw
+    let first = value.split(',').next()?.trim();
+    (!first.is_empty()).then(|| first.to_string())
+}
+
+/// Addresses claimed by `X-Forwarded-For`, falling back to the `for=`
+/// parameters of `Forwarded`, oldest first.  Unparseable entries (such as
+/// obfuscated identifiers) are skipped.
+fn claimed_chain(headers: &HeaderMap) -> Vec<IpAddr> {
+    let xff: Vec<IpAddr> = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|v| parse_node(v.trim()))
+        .collect();
+    if !xff.is_empty() {
+        return xff;
+    }
+    forwarded_elements(headers)
+        .iter()
+        .filter_map(|params| param(params, "for"))
+        .filter_map(|v| parse_node(&v))
+        .collect()
+}
+
+fn forwarded_elements(headers: &HeaderMap) -> Vec<String> {
+    headers
+        .get_all("forwarded")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|e| e.trim().to_string())
+        .collect()
+}
+
+/// A parameter from the first (client-side) `Forwarded` element.
+fn forwarded_param(headers: &HeaderMap, name: &str) -> Option<String> {
+    forwarded_elements(headers).first().and_then(|e| param(e, name))
+}
+
+fn param(element: &str, name: &str) -> Option<String> {
+    element.split(';').find_map(|pair| {
+        let (k, v) = pair.split_once('=')?;
+        k.trim()
+            .eq_ignore_ascii_case(name)
+            .then(|| v.trim().trim_matches('"').to_string())
+    })
+}
+
+/// Parse an address as it appears in forwarding headers: bare, bracketed
+/// IPv6, or with a port attached.
+fn parse_node(v: &str) -> Option<IpAddr> {
+    if let Ok(addr) = v.parse() {
+        return Some(addr);
+    }
+    if let Some(rest) = v.strip_prefix('[') {
+        return rest.split(']').next()?.parse().ok();
+    }
+    v.rsplit_once(':')?.0.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(trusted: &[&str]) -> ForwardedConfig {
+        ForwardedConfig {
+            trusted_proxies: parse_cidrs(&trusted.iter().map(|s| s.to_string()).collect::<Vec<_>>())
+                .unwrap(),
+            headers: ForwardedHeaders::XForwarded,
+        }
+    }
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut h = HeaderMap::new();
+        for (k, v) in pairs {
+            h.append(*k, HeaderValue::from_static(v));
+        }
+        h
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_cidrs() {
+        let nets = parse_cidrs(&["10.0.0.0/8".into(), "192.168.1.7".into(), "::1".into()]).unwrap();
+        assert_eq!(nets.len(), 3);
+        assert!(nets[1].contains(&ip("192.168.1.7")));
+        assert!(parse_cidrs(&["10.0.0.0/33".into()]).is_err());
+        assert!("both".parse::<ForwardedHeaders>().is_ok());
+        assert!("sideways".parse::<ForwardedHeaders>().is_err());
+    }
+
+    #[test]
+    fn untrusted_peers_cannot_spoof() {
+        let cfg = config(&["10.0.0.0/8"]);
+        let h = headers(&[("x-forwarded-for", "1.2.3.4"), ("x-forwarded-proto", "https")]);
+        let info = cfg.client_info(Some(ip("203.0.113.9")), "http", &h);
+        assert_eq!(info.addr, Some(ip("203.0.113.9")));
+        assert_eq!(info.chain, vec![ip("203.0.113.9")]);
+        assert_eq!(info.proto, "http");
+    }
+
+    #[test]
+    fn trusted_proxy_chain() {
+        let cfg = config(&["10.0.0.0/8"]);
+        // the client forged the first entry; 198.51.100.7 is the address
+        // our own load balancer (10.0.0.2) actually saw
+        let h = headers(&[
+            ("x-forwarded-for", "6.6.6.6, 198.51.100.7"),
+            ("x-forwarded-for", "10.0.0.2"),
+            ("x-forwarded-proto", "https"),
+            ("x-forwarded-host", "llm.example.com"),
+            ("host", "shim:3000"),
+        ]);
+        let info = cfg.client_info(Some(ip("10.0.0.1")), "http", &h);
+        assert_eq!(info.addr, Some(ip("198.51.100.7")));
+        assert_eq!(
+            info.chain,
+            vec![ip("6.6.6.6"), ip("198.51.100.7"), ip("10.0.0.2"), ip("10.0.0.1")]
+        );
+        assert_eq!(info.proto, "https");
+        assert_eq!(info.host.as_deref(), Some("llm.example.com"));
+    }
+
+    #[test]
+    fn rfc7239_input() {
+        let cfg = config(&["10.0.0.1"]);
+        let h = headers(&[(
+            "forwarded",
+            "for=\"[2001:db8::1]:4711\";proto=https;host=llm.example.com, for=10.0.0.1",
+        )]);
+        let info = cfg.client_info(Some(ip("10.0.0.1")), "http", &h);
+        assert_eq!(info.addr, Some(ip("2001:db8::1")));
+        assert_eq!(info.proto, "https");
+        assert_eq!(info.host.as_deref(), Some("llm.example.com"));
+    }
+
+    #[test]
+    fn writes_upstream_headers() {
+        let info = ClientInfo {
+            addr: Some(ip("198.51.100.7")),
+            chain: vec![ip("198.51.100.7"), ip("2001:db8::2")],
+            proto: "https".into(),
+            host: Some("llm.example.com".into()),
+        };
+        let mut h = headers(&[("x-forwarded-for", "6.6.6.6"), ("forwarded", "for=6.6.6.6")]);
+        let mut cfg = config(&[]);
+        cfg.set_upstream_headers(&mut h, &info);
+        assert_eq!(h["x-forwarded-for"], "198.51.100.7, 2001:db8::2");
+        assert_eq!(h["x-forwarded-proto"], "https");
+        assert_eq!(h["x-forwarded-host"], "llm.example.com");
+        assert!(!h.contains_key("forwarded"));
+
+        cfg.headers = ForwardedHeaders::Forwarded;
+        cfg.set_upstream_headers(&mut h, &info);
+        assert!(!h.contains_key("x-forwarded-for"));
+        assert_eq!(
+            h["forwarded"],
+            "for=198.51.100.7, for=\"[2001:db8::2]\";proto=https;host=\"llm.example.com\""
+        );
+
+        cfg.headers = ForwardedHeaders::None;
+        cfg.set_upstream_headers(&mut h, &info);
+        assert!(!h.contains_key("forwarded"));
+    }
+}
@@ -0,0 +1,175 @@
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
+
+/// Connection-specific headers that must not be relayed by a proxy
+/// (RFC 9110 section 7.6.1), plus the long-standing non-standard
+/// `Proxy-Connection`.
+const HOP_BY_HOP: &[&str] = &[
+    "connection",
+    "proxy-connection",
+    "keep-alive",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+    "proxy-authenticate",
+    "proxy-authorization",
+];
+
+/// Headers that are hop-by-hop for this message: the fixed list above plus
+/// every header named in its `Connection` header.
+fn hop_by_hop_names(headers: &HeaderMap) -> Vec<String> {
+    let mut names: Vec<String> = HOP_BY_HOP.iter().map(|n| n.to_string()).collect();
+    for value in headers.get_all("connection") {
+        if let Ok(value) = value.to_str() {
+            names.extend(
+                value
+                    .split(',')
+                    .map(|t| t.trim().to_ascii_lowercase())
+                    .filter(|t| !t.is_empty()),
+            );
+        }
+    }
+    names
+}
+
+/// Build the header map sent to Ollama from the client's headers.
+///
+/// Besides hop-by-hop headers this drops:
+/// * `host` and `content-length`, which reqwest derives from the URL and body;
+/// * `authorization`, which belongs to the proxy, not to Ollama;
+/// * `accept-encoding`, so reqwest negotiates the compression it can decode
+///   itself instead of relaying an encoding it would pass through undecoded.
+///
+/// Values are copied byte for byte, including ones that are not valid
+/// UTF-8, and repeated headers keep all their values in order.
+pub fn upstream_request_headers(headers: &HeaderMap) -> reqwest::header::HeaderMap {
+    let skip = hop_by_hop_names(headers);
+    let mut out = reqwest::header::HeaderMap::new();
+    for (name, value) in headers.iter() {
+        let lower = name.as_str();
+        if matches!(lower, "host" | "content-length" | "authorization" | "accept-encoding")
+            || skip.iter().any(|s| s == lower)
+        {
+            continue;
+        }
+        if let (Ok(name), Ok(value)) = (
+            reqwest::header::HeaderName::from_bytes(name.as_str().as_bytes()),
+            reqwest::header::HeaderValue::from_bytes(value.as_bytes()),
+        ) {
+            out.append(name, value);
+        }
+    }
+    out
+}
+
+/// Build the header map sent to a forward-auth service: the client's
+/// headers, credentials included, minus hop-by-hop headers and the framing
+/// headers of a body that is not sent along.
+pub fn auth_request_headers(headers: &HeaderMap) -> reqwest::header::HeaderMap {
+    let skip = hop_by_hop_names(headers);
+    let mut out = reqwest::header::HeaderMap::new();
+    for (name, value) in headers.iter() {
+        let lower = name.as_str();
+        if matches!(lower, "host" | "content-length" | "content-type" | "content-encoding")
+            || skip.iter().any(|s| s == lower)
+        {
+            continue;
+        }
+        if let (Ok(name), Ok(value)) = (
+            reqwest::header::HeaderName::from_bytes(name.as_str().as_bytes()),
+            reqwest::header::HeaderValue::from_bytes(value.as_bytes()),
+        ) {
+            out.append(name, value);
+        }
+    }
+    out
+}
+
+/// Build the header map returned to the client from Ollama's response.
+///
+/// Hop-by-hop headers are dropped, and so is `content-length`: the body is
+/// streamed through and may have been decompressed by reqwest, so hyper
+/// frames it itself.  Everything else is copied byte for byte with repeated
+/// headers (e.g. `set-cookie`) preserved.
+pub fn downstream_response_headers(headers: &reqwest::header::HeaderMap) -> HeaderMap {
+    let mut connection = HeaderMap::new();
+    for value in headers.get_all("connection") {
+        if let Ok(value) = HeaderValue::from_bytes(value.as_bytes()) {
+            connection.append("connection", value);
+        }
+    }
+    let skip = hop_by_hop_names(&connection);
+
+    let mut out = HeaderMap::new();
+    for (name, value) in headers.iter() {
+        let lower = name.as_str();
+        if lower == "content-length" || skip.iter().any(|s| s == lower) {
+            continue;
+        }
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(name.as_str().as_bytes()),
+            HeaderValue::from_bytes(value.as_bytes()),
+        ) {
+            out.append(name, value);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_hop_by_hop_from_requests() {
+        let mut h = HeaderMap::new();
+        h.insert("host", HeaderValue::from_static("proxy:3000"));
+        h.insert("authorization", HeaderValue::from_static("Bearer k"));
+        h.insert("connection", HeaderValue::from_static("keep-alive, X-Secret"));
+        h.insert("keep-alive", HeaderValue::from_static("timeout=5"));
+        h.insert("te", HeaderValue::from_static("trailers"));
+        h.insert("transfer-encoding", HeaderValue::from_static("chunked"));
+        h.insert("content-length", HeaderValue::from_static("12"));
+        h.insert("accept-encoding", HeaderValue::from_static("br"));
+        h.insert("x-secret", HeaderValue::from_static("hop"));
+        h.insert("content-type", HeaderValue::from_static("application/json"));
+
+        let out = upstream_request_headers(&h);
+        let names: Vec<_> = out.keys().map(|k| k.as_str()).collect();
+        assert_eq!(names, vec!["content-type"]);
+    }
+
+    #[test]
+    fn keeps_opaque_and_repeated_values() {
+        let mut h = HeaderMap::new();
+        let latin1 = HeaderValue::from_bytes(b"caf\xe9").unwrap();
+        h.insert("x-opaque", latin1.clone());
+        h.append("x-multi", HeaderValue::from_static("a"));
+        h.append("x-multi", HeaderValue::from_static("b"));
+
+        let out = upstream_request_headers(&h);
+        assert_eq!(out["x-opaque"].as_bytes(), b"caf\xe9");
+        let multi: Vec<_> = out.get_all("x-multi").iter().collect();
+        assert_eq!(multi, vec!["a", "b"]);
+
+        let mut upstream = reqwest::header::HeaderMap::new();
+        upstream.append("set-cookie", "a=1".parse().unwrap());
+        upstream.append("set-cookie", "b=2".parse().unwrap());
+        upstream.append(
+            "x-opaque",
+            reqwest::header::HeaderValue::from_bytes(b"caf\xe9").unwrap(),
+        );
+        upstream.append("connection", "close, x-internal".parse().unwrap());
+        upstream.append("x-internal", "1".parse().unwrap());
+        upstream.append("transfer-encoding", "chunked".parse().unwrap());
+        upstream.append("content-length", "99".parse().unwrap());
+
+        let out = downstream_response_headers(&upstream);
+        let cookies: Vec<_> = out.get_all("set-cookie").iter().collect();
+        assert_eq!(cookies, vec!["a=1", "b=2"]);
+        assert_eq!(out["x-opaque"], latin1);
+        for gone in ["connection", "x-internal", "transfer-encoding", "content-length"] {
+            assert!(!out.contains_key(gone), "{gone} should be stripped");
+        }
+    }
+}
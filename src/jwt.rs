@@ -0,0 +1,405 @@
+use std::{
+    fs,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use reqwest::Client;
+use ring::signature::{self, RsaPublicKeyComponents, UnparsedPublicKey};
+use serde::Deserialize;
+use serde_json::Value;
+
+/// Settings for accepting OIDC JWTs as bearer credentials.
+#[derive(Clone, Debug)]
+pub struct JwtConfig {
+    /// Path or `http(s)://` URL of the JWKS holding the signing keys.
+    pub jwks: String,
+    /// Required `iss`, if any.
+    pub issuer: Option<String>,
+    /// Required entry in `aud`, if any.
+    pub audience: Option<String>,
+    /// Claim whose value becomes the username, e.g. `sub` or `email`.
+    pub username_claim: String,
+    pub reload_interval: Duration,
+    /// Clock skew tolerated when checking `exp` and `nbf`.
+    pub leeway: Duration,
+}
+
+impl JwtConfig {
+    pub fn new(jwks: String) -> Self {
+        JwtConfig {
+            jwks,
+            issuer: None,
+            audience: None,
+            username_claim: "sub".to_string(),
+            reload_interval: Duration::from_secs(300),
+            leeway: Duration::from_secs(60),
+        }
+    }
+}
+
+/// One key from a JWKS, in the members we use.
+#[derive(Clone, Debug, Deserialize)]
+struct Jwk {
+    kty: String,
+    kid: Option<String>,
+    alg: Option<String>,
+    #[serde(rename = "use")]
+    usage: Option<String>,
+    crv: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+    x: Option<String>,
+    y: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct JwkSet {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Deserialize)]
+struct Header {
+    alg: String,
+    kid: Option<String>,
+}
+
+/// Whether a bearer credential is shaped like a JWT (three base64url parts,
+/// the first a JSON object) rather than an opaque key.
+pub fn looks_like_jwt(token: &str) -> bool {
+    token.starts_with("eyJ") && token.split('.').count() == 3
+}
+
+/// Verifies JWTs against a JWKS that is reloaded periodically, so keys
+/// rotated by the identity provider are picked up without a restart.
+#[derive(Clone)]
+pub struct JwtVerifier {
+    config: JwtConfig,
+    keys: Arc<RwLock<Vec<Jwk>>>,
+}
+
+impl JwtVerifier {
+    /// A verifier with no keys yet; call [`JwtVerifier::reload`] before use.
+    pub fn new(config: JwtConfig) -> Self {
+        JwtVerifier {
+            config,
+            keys: Arc::default(),
+        }
+    }
+
+    /// Fetch or read the JWKS and replace the current keys.  Returns the
+    /// number of keys loaded; on error the previous keys stay in use.
+    pub async fn reload(&self, client: &Client) -> Result<usize> {
+        let source = &self.config.jwks;
+        let text = if source.starts_with("http://") || source.starts_with("https://") {
+            client
+                .get(source)
+                .timeout(Duration::from_secs(10))
+                .send()
+                .await
+                .and_then(|r| r.error_for_status())
+                .with_context(|| format!("failed to fetch JWKS from '{source}'"))?
+                .text()
+                .await
+                .with_context(|| format!("failed to read JWKS from '{source}'"))?
+        } else {
+            fs::read_to_string(source).with_context(|| format!("failed to read JWKS file '{source}'"))?
+        };
+        let set: JwkSet = serde_json::from_str(&text).with_context(|| format!("invalid JWKS in '{source}'"))?;
+        let keys: Vec<Jwk> = set
+            .keys
+            .into_iter()
+            .filter(|k| k.usage.as_deref().is_none_or(|u| u == "sig"))
+            .collect();
+        if keys.is_empty() {
+            anyhow::bail!("JWKS '{source}' has no signing keys");
+        }
+        let count = keys.len();
+        *self.keys.write().unwrap() = keys;
+        Ok(count)
+    }
+
+    /// Reload the JWKS every `reload_interval`.  Failures are logged and the
+    /// previous keys kept.
+    pub async fn watch(self, client: Client) {
+        let mut ticker = tokio::time::interval(self.config.reload_interval);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            if let Err(e) = self.reload(&client).await {
+                eprintln!("failed to reload JWKS: {e:#}");
+            }
+        }
+    }
+
+    /// Check the token's signature and claims and return the username.
+    pub fn verify(&self, token: &str) -> Result<String> {
+        let mut parts = token.split('.');
+        let (Some(header_b64), Some(payload_b64), Some(sig_b64), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            anyhow::bail!("not a JWT");
+        };
+        let header: Header = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(header_b64)?)
+            .context("invalid JWT header")?;
+        let signature = URL_SAFE_NO_PAD.decode(sig_b64).context("invalid JWT signature encoding")?;
+        let message = &token.as_bytes()[..header_b64.len() + 1 + payload_b64.len()];
+
+        let verified = {
+            let keys = self.keys.read().unwrap();
+            keys.iter()
+                .filter(|k| header.kid.is_none() || k.kid == header.kid)
+                .filter(|k| k.alg.as_deref().is_none_or(|alg| alg == header.alg))
+                .any(|k| verify_signature(k, &header.alg, message, &signature))
+        };
+        if !verified {
+            anyhow::bail!("JWT signature not valid for any known key (alg {})", header.alg);
+        }
+
+        let claims: Value = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload_b64)?)
+            .context("invalid JWT payload")?;
+        self.check_claims(&claims)?;
+        match claims.get(&self.config.username_claim) {
+            Some(Value::String(user)) if !user.is_empty() => Ok(user.clone()),
+            _ => anyhow::bail!("JWT has no '{}' claim", self.config.username_claim),
+        }
+    }
+
+    fn check_claims(&self, claims: &Value) -> Result<()> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let leeway = self.config.leeway.as_secs();
+        let Some(exp) = claims.get("exp").and_then(Value::as_u64) else {
+            anyhow::bail!("JWT has no expiry");
+        };
+        if exp + leeway <= now {
+            anyhow::bail!("JWT expired");
+        }
+        if claims
+            .get("nbf")
+            .and_then(Value::as_u64)
+            .is_some_and(|nbf| nbf > now + leeway)
+        {
+            anyhow::bail!("JWT not valid yet");
+        }
+        if let Some(issuer) = &self.config.issuer {
+            if claims.get("iss").and_then(Value::as_str) != Some(issuer) {
+                anyhow::bail!("JWT issuer is not '{issuer}'");
+            }
+        }
+        if let Some(audience) = &self.config.audience {
+            let matches = match claims.get("aud") {
+                Some(Value::String(aud)) => aud == audience,
+                Some(Value::Array(auds)) => auds.iter().any(|a| a.as_str() == Some(audience)),
+                _ => false,
+            };
+            if !matches {
+                anyhow::bail!("JWT audience does not include '{audience}'");
+            }
+        }
+        Ok(())
+    }
+}
+
+fn verify_signature(key: &Jwk, alg: &str, message: &[u8], sig: &[u8]) -> bool {
+    let decode = |field: &Option<String>| field.as_deref().and_then(|v| URL_SAFE_NO_PAD.decode(v).ok());
+    match (key.kty.as_str(), alg) {
+        ("RSA", "RS256" | "RS384" | "RS512" | "PS256" | "PS384" | "PS512") => {
+            let (Some(n), Some(e)) = (decode(&key.n), decode(&key.e)) else {
+                return false;
+            };
+            let params = match alg {
+                "RS256" => &signature::RSA_PKCS1_2048_8192_SHA256,
+                "RS384" => &signature::RSA_PKCS1_2048_8192_SHA384,
+                "RS512" => &signature::RSA_PKCS1_2048_8192_SHA512,
+                "PS256" => &signature::RSA_PSS_2048_8192_SHA256,
+                "PS384" => &signature::RSA_PSS_2048_8192_SHA384,
+                _ => &signature::RSA_PSS_2048_8192_SHA512,
+            };
+            RsaPublicKeyComponents { n, e }.verify(params, message, sig).is_ok()
+        }
+        ("EC", "ES256" | "ES384") => {
+            let (curve, params) = match alg {
+                "ES256" => ("P-256", &signature::ECDSA_P256_SHA256_FIXED),
+                _ => ("P-384", &signature::ECDSA_P384_SHA384_FIXED),
+            };
+            let (Some(x), Some(y)) = (decode(&key.x), decode(&key.y)) else {
+                return false;
+            };
+            if key.crv.as_deref() != Some(curve) {
+                return false;
+            }
+            // uncompressed SEC1 point
+            let point = [&[4u8][..], &x, &y].concat();
+            UnparsedPublicKey::new(params, point).verify(message, sig).is_ok()
+        }
+        ("OKP", "EdDSA") if key.crv.as_deref() == Some("Ed25519") => decode(&key.x)
+            .is_some_and(|x| UnparsedPublicKey::new(&signature::ED25519, x).verify(message, sig).is_ok()),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::rand::SystemRandom;
+    use ring::signature::{EcdsaKeyPair, Ed25519KeyPair, KeyPair, RsaKeyPair};
+    use serde_json::json;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    // 2048-bit RSA test key (PKCS#8 DER); ring cannot generate RSA keys
+    const RSA_PKCS8: &str = concat!(
+        "MIIEvAIBADANBgkqhkiG9w0BAQEFAASCBKYwggSiAgEAAoIBAQCpY1alWpDZLgeW0ZHZ7S40cURKcrnEbv8JPy74Jf5dEIpD",
+        "mnLchX2OFXOpGVVBwAtlcb1MsN1ehw6paOiL64JkQ7dEwirdVlRD/YyKKmii+D+O1ALwz/L/UX9uy8N5YTa/OokNE6b9jPtp",
+        "KOHprUpdKa8V3zb5ThTu1HO4dWrfChDcw/jp8PHUpywEAsAASdsPsqa3XL0gQcvxcDHWYD+PznHA45OTPUNwGEMqv7+lHwpA",
+        "81zJ6vwah3OFGR40J/erS5AJV5s/lq1JTv6G+3kFodRvjLQ/g9974vF0P5kPR8/Au6Vu+/6PPTEzJt5iTzOQ8XVImI5cVqh4",
+        "BDMXkSHLAgMBAAECggEAHfSpcBiyIRZY1T0+s/GUCEY3s4rSe4C9rZ706YGemErCiO4ZfhJFgAkfKTqHr1cNLOWOeq+UlpD/",
+        "8MnSxlvh9WVcVdaYygfoYxXF8vFJC/knFFU+J+Q+rxR0mt2CNkcQ7YSmlB9zmseB9rj6Kt8wlYU3p4CQMkqyvQHpbSemvMa7",
+        "G0fYQO+GJOJt+qLC4nvw0v6st5XID9Upma3vatmBLyx4sr4utSl02do6nB964U86gaHdgpmdqVxDbt8pxXu+v4+MfL16dN60",
+        "7Mgzfgqlqhjt0yIMpppwLY5HuXPn6suYQdhkPQl05ekOhTFjFEFolfb15soBZ2R/rtRMOvAp0QKBgQDfTyTRDqHtkid8NwlE",
+        "MXM0gdvqGDk8aPupw6r80qvmju2PRR10uCTJEUsFsbo6LTQczzPyVq3B5aDnEDQWt1lnDnS4OCdkLntlrOs1PsfOqxblZQB1",
+        "w8E1gCKqIl4uFtCKEJP7myT14q40YbSz8YxIlCKSD5hFN+g1ssZ9Av8V5QKBgQDCL2rckEIuhvV8Lff+AuddSiDIO/PYPLPh",
+        "FsPQO009d3+n97ZcoN41pdAZ8ow8bZP+QaG/nHmFl/HiZefi8I8I4AY+KqVBTtbEa1+r/Ow9REPP5U9rIYdoHfo1kSYUz9Wy",
+        "gYjv5z1vcQKhgDVUU8Vq2MVCmq0kZrKWkWD8ByHd7wKBgEbV/zLFtn3678WKW8ki+o18Z/sxJe7QyowGgpXiah8f1aGt5fMh",
+        "eqHBd4/d2docfrxoyeykrEemhMznKe7OyHBVeJb7URuJVyFgodfpC062nPO1f1jb+Mz00YowjrmGCS7SpW6Zvza0alg5ai3u",
+        "gHaBbxnwEnhajLGD/Uyoh6w1AoGAIRyCxT9X+aULoPQZsFeAahjkxB/s8XdQWNZP52blQdDplJOZpevMWCuA85u3K+qnbJh2",
+        "YzzkP9Jr7WXhB4JrcWD5z4noCd3Oo0mH08TZ54yHX6DDTQo3k5pg+tdYPf4+eyS5W+b7Q9BqWW2KKmnqZNC3uiaz1VwpoaEC",
+        "f5cbwEMCgYBeR2Xkzh13hS2Vbt9lNfLtGqyNTKH2wkZQd0zxUxJ0ezljSalEk6hsFh1inGdoTHmYraCA2w+Nwrt9pShq9ymM",
+        "en98lr8953qHWP2F5B7GR+YBfz6QBhwKEta+IUdXBIqloFstDTm76vwn84tqFrYqbqy7nfXHJqJxRR4avH1Ixg==",
+    );
+
+    fn b64(bytes: &[u8]) -> String {
+        URL_SAFE_NO_PAD.encode(bytes)
+    }
+
+    fn now() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+    }
+
+    fn signing_input(alg: &str, kid: &str, claims: &Value) -> String {
+        let header = json!({ "alg": alg, "kid": kid, "typ": "JWT" });
+        format!(
+            "{}.{}",
+            b64(header.to_string().as_bytes()),
+            b64(claims.to_string().as_bytes())
+        )
+    }
+
+    struct Keys {
+        rsa: RsaKeyPair,
+        ec: EcdsaKeyPair,
+        ed: Ed25519KeyPair,
+    }
+
+    impl Keys {
+        fn new() -> Self {
+            let rng = SystemRandom::new();
+            let rsa_der = base64::engine::general_purpose::STANDARD.decode(RSA_PKCS8).unwrap();
+            let ec_der = EcdsaKeyPair::generate_pkcs8(&signature::ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
+            let ed_der = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+            Keys {
+                rsa: RsaKeyPair::from_pkcs8(&rsa_der).unwrap(),
+                ec: EcdsaKeyPair::from_pkcs8(&signature::ECDSA_P256_SHA256_FIXED_SIGNING, ec_der.as_ref(), &rng)
+                    .unwrap(),
+                ed: Ed25519KeyPair::from_pkcs8(ed_der.as_ref()).unwrap(),
+            }
+        }
+
+        fn jwks(&self) -> Value {
+            let rsa = RsaPublicKeyComponents::<Vec<u8>>::from(self.rsa.public());
+            let point = self.ec.public_key().as_ref();
+            json!({ "keys": [
+                { "kty": "RSA", "kid": "rsa", "use": "sig", "alg": "RS256", "n": b64(&rsa.n), "e": b64(&rsa.e) },
+                { "kty": "EC", "kid": "ec", "crv": "P-256", "x": b64(&point[1..33]), "y": b64(&point[33..]) },
+                { "kty": "OKP", "kid": "ed", "crv": "Ed25519", "x": b64(self.ed.public_key().as_ref()) },
+                { "kty": "RSA", "kid": "enc", "use": "enc", "n": "AQAB", "e": "AQAB" },
+            ]})
+        }
+
+        fn sign(&self, alg: &str, kid: &str, claims: &Value) -> String {
+            let input = signing_input(alg, kid, claims);
+            let sig = match alg {
+                "RS256" => {
+                    let mut sig = vec![0; self.rsa.public().modulus_len()];
+                    self.rsa
+                        .sign(&signature::RSA_PKCS1_SHA256, &SystemRandom::new(), input.as_bytes(), &mut sig)
+                        .unwrap();
+                    sig
+                }
+                "ES256" => self.ec.sign(&SystemRandom::new(), input.as_bytes()).unwrap().as_ref().to_vec(),
+                _ => self.ed.sign(input.as_bytes()).as_ref().to_vec(),
+            };
+            format!("{input}.{}", b64(&sig))
+        }
+    }
+
+    async fn verifier(keys: &Keys) -> (JwtVerifier, NamedTempFile) {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(keys.jwks().to_string().as_bytes()).unwrap();
+        let mut config = JwtConfig::new(file.path().to_str().unwrap().to_string());
+        config.issuer = Some("https://sso.example.com".into());
+        config.audience = Some("ollama".into());
+        config.username_claim = "email".into();
+        let verifier = JwtVerifier::new(config);
+        assert_eq!(verifier.reload(&Client::new()).await.unwrap(), 3);
+        (verifier, file)
+    }
+
+    fn claims(extra: Value) -> Value {
+        let mut claims = json!({
+            "iss": "https://sso.example.com",
+            "aud": ["other", "ollama"],
+            "sub": "1234",
+            "email": "alice@example.com",
+            "exp": now() + 300,
+            "nbf": now() - 10,
+        });
+        claims.as_object_mut().unwrap().extend(extra.as_object().unwrap().clone());
+        claims
+    }
+
+    #[tokio::test]
+    async fn verifies_supported_algorithms() {
+        let keys = Keys::new();
+        let (verifier, _file) = verifier(&keys).await;
+        for (alg, kid) in [("RS256", "rsa"), ("ES256", "ec"), ("EdDSA", "ed")] {
+            let token = keys.sign(alg, kid, &claims(json!({})));
+            assert!(looks_like_jwt(&token));
+            assert_eq!(verifier.verify(&token).unwrap(), "alice@example.com", "{alg}");
+        }
+        // a key id that does not match the signing key
+        let wrong_kid = keys.sign("ES256", "ed", &claims(json!({})));
+        assert!(verifier.verify(&wrong_kid).is_err());
+        // tampered payload
+        let token = keys.sign("EdDSA", "ed", &claims(json!({})));
+        let mut parts: Vec<&str> = token.split('.').collect();
+        let forged = b64(claims(json!({ "email": "root@example.com" })).to_string().as_bytes());
+        parts[1] = &forged;
+        assert!(verifier.verify(&parts.join(".")).is_err());
+        assert!(!looks_like_jwt("sk-0123"));
+    }
+
+    #[tokio::test]
+    async fn checks_claims() {
+        let keys = Keys::new();
+        let (verifier, _file) = verifier(&keys).await;
+        let reject = |extra: Value, reason: &str| {
+            let token = keys.sign("EdDSA", "ed", &claims(extra));
+            let err = verifier.verify(&token).unwrap_err();
+            assert!(format!("{err:#}").contains(reason), "{err:#}");
+        };
+        reject(json!({ "exp": now() - 120 }), "expired");
+        reject(json!({ "nbf": now() + 120 }), "not valid yet");
+        reject(json!({ "iss": "https://evil.example.com" }), "issuer");
+        reject(json!({ "aud": "other" }), "audience");
+        reject(json!({ "email": null }), "'email'");
+        // within the leeway
+        let token = keys.sign("EdDSA", "ed", &claims(json!({ "exp": now() - 10 })));
+        assert!(verifier.verify(&token).is_ok());
+    }
+}
@@ -1,6 +1,33 @@
+mod access;
+mod admin;
+mod auth;
+mod bans;
+mod batch;
+mod cache;
+mod coalesce;
 mod config;
+mod config_file;
+mod context;
+mod cors;
+mod embeddings;
+mod forward_auth;
+mod forwarded;
+mod headers;
+mod jwt;
+mod native;
+mod policy;
 mod proxy;
+mod retry;
+mod roles;
+mod schema;
 mod state;
+mod think;
+mod tls;
+mod tokens;
+mod tools;
+mod usage;
+
+use std::net::SocketAddr;
 
 use axum::{Router, routing::any};
 use axum_server::Server;
@@ -10,10 +37,11 @@ use crate::config::{AppConfig, ConfigOverrides};
 use crate::proxy::proxy_handler;
 use crate::state::AppState;
 
-/// Top-level CLI.  We support two modes of operation:
+/// Top-level CLI.  We support three modes of operation:
 ///
 /// * `server` is the existing behaviour which spins up the proxy.
 /// * `sql` provides helpers to manipulate a sqlite api-keys database.
+/// * `config` inspects the configuration the server would run with.
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None, subcommand_required = false)]
 struct Cli {
@@ -21,6 +49,8 @@ struct Cli {
     command: Option<Command>,
 }
 
+// parsed once at startup, so the size of `ServerOpts` does not matter
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand, Debug)]
 enum Command {
     /// run the proxy server (this is the default behaviour in previous
@@ -36,11 +66,53 @@ enum Command {
         #[arg(long)]
         sqlite: Option<String>,
     },
+
+    /// inspect the configuration
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+
+    /// mint a short-lived scoped token, signed with the configured
+    /// `TOKEN_SECRET`, and print it
+    Token {
+        /// TOML configuration file
+        #[arg(long)]
+        config: Option<String>,
+        /// user the token acts for; must exist in the key store
+        #[arg(long)]
+        user: Option<String>,
+        /// lifetime, e.g. `10m` or `1h` (default: the configured default)
+        #[arg(long)]
+        ttl: Option<String>,
+        /// comma-delimited model names the token may use (`*` globs allowed)
+        #[arg(long, value_delimiter = ',')]
+        models: Vec<String>,
+        /// comma-delimited routes below /v1/ the token may call
+        #[arg(long, value_delimiter = ',')]
+        routes: Vec<String>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigAction {
+    /// validate the configuration file and print the effective settings
+    /// (file, then environment) with secrets masked
+    Check {
+        /// TOML configuration file
+        #[arg(long)]
+        config: Option<String>,
+    },
 }
 
 /// options used when running the proxy server
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Default)]
 struct ServerOpts {
+    /// TOML configuration file; environment variables and flags take
+    /// precedence over its settings.
+    #[arg(long)]
+    config: Option<String>,
+
     /// Base URL for the Ollama service (overrides OLLAMA_URL).
     #[arg(long)]
     ollama_url: Option<String>,
@@ -64,6 +136,118 @@ struct ServerOpts {
     /// Port to bind the proxy to (overrides PROXY_PORT).
     #[arg(long)]
     proxy_port: Option<u16>,
+
+    /// Total attempts per upstream request, 1 disables retries (overrides
+    /// RETRY_MAX_ATTEMPTS).
+    #[arg(long)]
+    retry_max_attempts: Option<u32>,
+
+    /// comma-separated routes that may be retried even for POST requests,
+    /// e.g. `embeddings` (overrides RETRY_SAFE_ROUTES)
+    #[arg(long, value_delimiter = ',')]
+    retry_safe_routes: Option<Vec<String>>,
+
+    /// PEM certificate chain; enables HTTPS together with --tls-key
+    /// (overrides TLS_CERT).
+    #[arg(long)]
+    tls_cert: Option<String>,
+
+    /// PEM private key for --tls-cert (overrides TLS_KEY).
+    #[arg(long)]
+    tls_key: Option<String>,
+
+    /// additionally serve plain HTTP on this port when TLS is enabled
+    /// (overrides TLS_HTTP_PORT)
+    #[arg(long)]
+    http_port: Option<u16>,
+
+    /// make the plain HTTP port redirect to HTTPS instead of serving the
+    /// proxy (overrides TLS_HTTP_REDIRECT)
+    #[arg(long)]
+    http_redirect: bool,
+
+    /// PEM bundle of CAs trusted to issue client certificates; enables
+    /// mutual TLS (overrides TLS_CLIENT_CA)
+    #[arg(long)]
+    tls_client_ca: Option<String>,
+
+    /// reject connections without a client certificate instead of falling
+    /// back to bearer keys (overrides TLS_CLIENT_CERT_REQUIRED)
+    #[arg(long)]
+    tls_client_cert_required: bool,
+
+    /// comma-separated origins allowed to call the proxy from a browser,
+    /// e.g. `https://chat.example.com` or `https://*.example.com`
+    /// (overrides CORS_ALLOWED_ORIGINS)
+    #[arg(long, value_delimiter = ',')]
+    cors_allowed_origins: Option<Vec<String>>,
+
+    /// comma-separated CIDRs of reverse proxies whose X-Forwarded-For and
+    /// Forwarded headers are trusted (overrides TRUSTED_PROXIES)
+    #[arg(long, value_delimiter = ',')]
+    trusted_proxies: Option<Vec<String>>,
+
+    /// comma-separated CIDRs refused before authentication (overrides
+    /// DENIED_IPS)
+    #[arg(long, value_delimiter = ',')]
+    denied_ips: Option<Vec<String>>,
+
+    /// serve the admin API on this port, on loopback unless ADMIN_HOST says
+    /// otherwise (overrides ADMIN_PORT)
+    #[arg(long)]
+    admin_port: Option<u16>,
+
+    /// delegate authentication to this URL (nginx `auth_request` style)
+    /// instead of checking API keys (overrides FORWARD_AUTH_URL)
+    #[arg(long)]
+    forward_auth_url: Option<String>,
+
+    /// cache responses to `temperature: 0` requests (same as
+    /// RESPONSE_CACHE=1)
+    #[arg(long)]
+    response_cache: bool,
+
+    /// cache embeddings per input string in this SQLite database
+    /// (overrides EMBEDDING_CACHE_SQLITE)
+    #[arg(long)]
+    embedding_cache_sqlite: Option<String>,
+
+    /// combine embeddings requests arriving within this many milliseconds
+    /// into one upstream call (overrides EMBEDDING_BATCH_WINDOW_MS)
+    #[arg(long)]
+    embedding_batch_window_ms: Option<u64>,
+
+    /// share one upstream call between identical in-flight requests (same
+    /// as COALESCE=1)
+    #[arg(long)]
+    coalesce: bool,
+
+    /// serve chat completions through Ollama's native /api/chat so native
+    /// options apply (same as NATIVE_CHAT=1)
+    #[arg(long)]
+    native_chat: bool,
+
+    /// reject conversations longer than the model's context window, or
+    /// drop their oldest messages: `reject` or `truncate` (overrides
+    /// CONTEXT_OVERFLOW)
+    #[arg(long)]
+    context_overflow: Option<context::Overflow>,
+
+    /// move `<think>` blocks into `reasoning_content` (`separate`) or drop
+    /// them (`strip`) for models not listed in THINK_MODELS
+    #[arg(long)]
+    think: Option<think::ThinkMode>,
+
+    /// validate structured chat output against its JSON schema, asking the
+    /// model again up to this many times (same as SCHEMA_VALIDATION=1 with
+    /// SCHEMA_RETRIES)
+    #[arg(long)]
+    schema_retries: Option<u32>,
+
+    /// comma-separated model patterns whose tool calling is emulated through
+    /// the system prompt (adds to TOOL_EMULATION)
+    #[arg(long, value_delimiter = ',')]
+    emulate_tools: Option<Vec<String>>,
 }
 
 #[derive(Subcommand, Debug)]
@@ -77,10 +261,70 @@ enum SqlAction {
     DelUser {
         username: String,
     },
+    /// restrict a user's key to browser requests from the given origin;
+    /// may be repeated to allow several origins
+    AllowOrigin {
+        username: String,
+        origin: String,
+    },
+    /// remove an origin previously added with allow-origin
+    RemoveOrigin {
+        username: String,
+        origin: String,
+    },
+    /// only accept a user's key from the given network (e.g. `10.0.0.0/8`)
+    /// or address; may be repeated
+    AllowIp {
+        username: String,
+        cidr: String,
+    },
+    /// remove a network previously added with allow-ip
+    RemoveIp {
+        username: String,
+        cidr: String,
+    },
+    /// issue a new key for a user and print it; the old key keeps working
+    /// for the grace period (e.g. `7d`, `12h`; default: none)
+    Rotate {
+        username: String,
+        #[arg(long, default_value = "0")]
+        grace: String,
+        /// use this key instead of generating one
+        #[arg(long)]
+        key: Option<String>,
+    },
+    /// set a user's role; `admin` grants access to the admin API
+    SetRole {
+        username: String,
+        role: String,
+    },
+    /// set the request policy (defaults and limits applied to request
+    /// bodies) for a user or a model pattern, as JSON such as
+    /// `{"max_tokens": 2048, "system_prompt": "..."}`; omit it to remove
+    /// the policy
+    SetPolicy {
+        #[arg(long, required_unless_present = "model")]
+        user: Option<String>,
+        /// model name or `*` glob, e.g. `llama3*`
+        #[arg(long, conflicts_with = "user")]
+        model: Option<String>,
+        policy: Option<String>,
+    },
+    /// list addresses currently banned for failed authentication
+    Bans,
+    /// lift the ban on an address; a running server notices within seconds
+    Unban {
+        #[arg(required_unless_present = "all")]
+        ip: Option<String>,
+        /// lift every ban
+        #[arg(long, conflicts_with = "ip")]
+        all: bool,
+    },
 }
 
 
 #[cfg(test)]
+#[allow(clippy::items_after_test_module, clippy::option_as_ref_deref)]
 mod tests {
     use super::*;
 
@@ -89,6 +333,8 @@ mod tests {
         let cli = Cli::parse_from([
             "prog",
             "server",
+            "--config",
+            "/etc/shim.toml",
             "--ollama-url",
             "http://example",
             "--api-keys",
@@ -101,14 +347,37 @@ mod tests {
             "1.2.3.4",
             "--proxy-port",
             "5555",
+            "--retry-max-attempts",
+            "4",
+            "--retry-safe-routes",
+            "embeddings",
+            "--tls-cert",
+            "/tmp/cert.pem",
+            "--tls-key",
+            "/tmp/key.pem",
+            "--http-port",
+            "80",
+            "--http-redirect",
+            "--tls-client-ca",
+            "/tmp/ca.pem",
+            "--tls-client-cert-required",
         ]);
         if let Command::Server(opts) = cli.command.unwrap() {
+            assert_eq!(opts.config.as_deref(), Some("/etc/shim.toml"));
             assert_eq!(opts.ollama_url.as_deref(), Some("http://example"));
             assert_eq!(opts.api_keys.as_ref().map(|v| v.as_slice()), Some(&["a".to_string(),"b".to_string(),"c".to_string()][..]));
             assert_eq!(opts.api_keys_file.as_deref(), Some("/tmp/k"));
             assert_eq!(opts.api_keys_sqlite.as_deref(), Some("/tmp/db"));
             assert_eq!(opts.proxy_host.as_deref(), Some("1.2.3.4"));
             assert_eq!(opts.proxy_port, Some(5555));
+            assert_eq!(opts.retry_max_attempts, Some(4));
+            assert_eq!(opts.retry_safe_routes.as_deref(), Some(&["embeddings".to_string()][..]));
+            assert_eq!(opts.tls_cert.as_deref(), Some("/tmp/cert.pem"));
+            assert_eq!(opts.tls_key.as_deref(), Some("/tmp/key.pem"));
+            assert_eq!(opts.http_port, Some(80));
+            assert!(opts.http_redirect);
+            assert_eq!(opts.tls_client_ca.as_deref(), Some("/tmp/ca.pem"));
+            assert!(opts.tls_client_cert_required);
         } else {
             panic!("expected server command");
         }
@@ -145,6 +414,107 @@ mod tests {
         }
     }
 
+    #[test]
+    fn sql_allow_origin_parsing() {
+        let cli = Cli::parse_from(["prog", "sql", "allow-origin", "web", "https://chat.example.com"]);
+        if let Command::Sql { action, .. } = cli.command.unwrap() {
+            match action {
+                SqlAction::AllowOrigin { username, origin } => {
+                    assert_eq!(username, "web");
+                    assert_eq!(origin, "https://chat.example.com");
+                }
+                _ => panic!("wrong subcommand"),
+            }
+        } else {
+            panic!("expected sql command");
+        }
+    }
+
+    #[test]
+    fn sql_allow_ip_parsing() {
+        let cli = Cli::parse_from(["prog", "sql", "--sqlite", "k.db", "allow-ip", "ci", "10.0.0.0/8"]);
+        match cli.command.unwrap() {
+            Command::Sql {
+                action: SqlAction::AllowIp { username, cidr },
+                sqlite,
+            } => {
+                assert_eq!(username, "ci");
+                assert_eq!(cidr, "10.0.0.0/8");
+                assert_eq!(sqlite.as_deref(), Some("k.db"));
+            }
+            _ => panic!("expected sql allow-ip"),
+        }
+    }
+
+    #[test]
+    fn sql_rotate_parsing() {
+        let cli = Cli::parse_from(["prog", "sql", "rotate", "alice", "--grace", "7d"]);
+        match cli.command.unwrap() {
+            Command::Sql {
+                action: SqlAction::Rotate { username, grace, key },
+                ..
+            } => {
+                assert_eq!(username, "alice");
+                assert_eq!(grace, "7d");
+                assert!(key.is_none());
+            }
+            _ => panic!("expected sql rotate"),
+        }
+        let cli = Cli::parse_from(["prog", "sql", "rotate", "alice"]);
+        assert!(matches!(
+            cli.command.unwrap(),
+            Command::Sql { action: SqlAction::Rotate { grace, .. }, .. } if grace == "0"
+        ));
+    }
+
+    #[test]
+    fn token_parsing() {
+        let cli = Cli::parse_from([
+            "prog", "token", "--user", "alice", "--ttl", "10m", "--models", "llama3*,mistral", "--routes", "chat/*",
+        ]);
+        match cli.command.unwrap() {
+            Command::Token {
+                user,
+                ttl,
+                models,
+                routes,
+                ..
+            } => {
+                assert_eq!(user.as_deref(), Some("alice"));
+                assert_eq!(ttl.as_deref(), Some("10m"));
+                assert_eq!(models, vec!["llama3*", "mistral"]);
+                assert_eq!(routes, vec!["chat/*"]);
+            }
+            _ => panic!("expected token command"),
+        }
+    }
+
+    #[test]
+    fn sql_unban_parsing() {
+        let cli = Cli::parse_from(["prog", "sql", "unban", "192.0.2.1"]);
+        assert!(matches!(
+            cli.command.unwrap(),
+            Command::Sql { action: SqlAction::Unban { ip: Some(ip), all: false }, .. } if ip == "192.0.2.1"
+        ));
+        let cli = Cli::parse_from(["prog", "sql", "unban", "--all"]);
+        assert!(matches!(
+            cli.command.unwrap(),
+            Command::Sql { action: SqlAction::Unban { ip: None, all: true }, .. }
+        ));
+        assert!(Cli::try_parse_from(["prog", "sql", "unban"]).is_err());
+    }
+
+    #[test]
+    fn config_check_parsing() {
+        let cli = Cli::parse_from(["prog", "config", "check", "--config", "shim.toml"]);
+        match cli.command.unwrap() {
+            Command::Config {
+                action: ConfigAction::Check { config },
+            } => assert_eq!(config.as_deref(), Some("shim.toml")),
+            _ => panic!("expected config command"),
+        }
+    }
+
     #[test]
     fn default_server_command() {
         let cli = Cli::parse_from(["prog"]);
@@ -156,6 +526,7 @@ mod tests {
             api_keys_sqlite: None,
             proxy_host: None,
             proxy_port: None,
+            ..ServerOpts::default()
         }));
         if let Command::Server(opts) = command {
             assert!(opts.ollama_url.is_none());
@@ -175,17 +546,16 @@ mod tests {
 async fn main() {
     let cli = Cli::parse();
 
-    match cli.command.unwrap_or(Command::Server(ServerOpts {
-        ollama_url: None,
-        api_keys: None,
-        api_keys_file: None,
-        api_keys_sqlite: None,
-        proxy_host: None,
-        proxy_port: None,
-    })) {
+    match cli.command.unwrap_or(Command::Server(ServerOpts::default())) {
         Command::Server(opts) => {
             // build configuration as before
-            let mut config = AppConfig::load().expect("failed to load configuration");
+            let mut config = match AppConfig::load_from(opts.config.as_deref()) {
+                Ok(c) => c,
+                Err(e) => {
+                    eprintln!("error: {e:#}");
+                    std::process::exit(1);
+                }
+            };
             let overrides = ConfigOverrides {
                 ollama_url: opts.ollama_url,
                 proxy_host: opts.proxy_host,
@@ -193,22 +563,156 @@ async fn main() {
                 api_keys_sqlite: opts.api_keys_sqlite,
                 api_keys_file: opts.api_keys_file,
                 api_keys: opts.api_keys,
+                retry_max_attempts: opts.retry_max_attempts,
+                retry_safe_routes: opts.retry_safe_routes,
+                tls_cert: opts.tls_cert,
+                tls_key: opts.tls_key,
+                http_port: opts.http_port,
+                http_redirect: opts.http_redirect.then_some(true),
+                tls_client_ca: opts.tls_client_ca,
+                tls_client_cert_required: opts.tls_client_cert_required.then_some(true),
+                cors_allowed_origins: opts.cors_allowed_origins,
+                trusted_proxies: opts.trusted_proxies,
+                denied_ips: opts.denied_ips,
+                admin_port: opts.admin_port,
+                forward_auth_url: opts.forward_auth_url,
+                response_cache: opts.response_cache.then_some(true),
+                embedding_cache_sqlite: opts.embedding_cache_sqlite,
+                embedding_batch_window_ms: opts.embedding_batch_window_ms,
+                coalesce: opts.coalesce.then_some(true),
+                native_chat: opts.native_chat.then_some(true),
+                context_overflow: opts.context_overflow,
+                think: opts.think,
+                schema_retries: opts.schema_retries,
+                emulate_tools: opts.emulate_tools,
             };
             config.apply_overrides(&overrides).expect("failed to apply overrides");
 
+            for (user, role) in &config.users.roles {
+                if !config.roles.is_defined(role) {
+                    eprintln!("warning: user '{user}' has undefined role '{role}' and will be refused");
+                }
+            }
             let state = AppState::new(&config);
 
+            if let Some(jwt) = &state.jwt {
+                match jwt.reload(&state.client).await {
+                    Ok(n) => println!("loaded {n} JWT signing keys"),
+                    Err(e) => {
+                        eprintln!("error: {e:#}");
+                        std::process::exit(1);
+                    }
+                }
+                tokio::spawn(jwt.clone().watch(state.client.clone()));
+            }
+
+            // pick up `sql` changes to the key store without a restart
+            tokio::spawn(state.clone().watch_directory());
+
+            if let Some(admin_addr) = config.admin_addr {
+                let admin_app = admin::router(state.clone());
+                println!("Admin API listening on http://{}", admin_addr);
+                tokio::spawn(async move {
+                    Server::bind(admin_addr)
+                        .serve(admin_app.into_make_service_with_connect_info::<SocketAddr>())
+                        .await
+                        .unwrap();
+                });
+            }
+
             let app = Router::new()
                 .route("/v1/{*path}", any(proxy_handler))
                 .with_state(state);
 
             let addr = config.proxy_addr;
-            println!("Listening on {}", addr);
-            Server::bind(addr)
-                .serve(app.into_make_service())
+            let Some(tls_config) = config.tls else {
+                println!("Listening on {}", addr);
+                Server::bind(addr)
+                    .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+                    .await
+                    .unwrap();
+                return;
+            };
+
+            let rustls = tls::rustls_config(&tls_config).expect("failed to load TLS certificate");
+            tokio::spawn(tls::watch_certificates(rustls.clone(), tls_config.clone()));
+
+            if let Some(http_port) = tls_config.http_port {
+                let http_addr = SocketAddr::new(addr.ip(), http_port);
+                let http_app = if tls_config.http_redirect {
+                    tls::redirect_router(addr.port())
+                } else {
+                    app.clone()
+                };
+                println!("Listening on http://{}", http_addr);
+                tokio::spawn(async move {
+                    Server::bind(http_addr)
+                        .serve(http_app.into_make_service_with_connect_info::<SocketAddr>())
+                        .await
+                        .unwrap();
+                });
+            }
+
+            println!("Listening on https://{}", addr);
+            axum_server::bind(addr)
+                .acceptor(tls::ClientCertAcceptor::new(rustls))
+                .serve(app.into_make_service_with_connect_info::<SocketAddr>())
                 .await
                 .unwrap();
         }
+        Command::Token {
+            config,
+            user,
+            ttl,
+            models,
+            routes,
+        } => {
+            let minted = AppConfig::load_from(config.as_deref()).and_then(|cfg| {
+                if cfg.tokens.secret.is_none() {
+                    anyhow::bail!("set TOKEN_SECRET (or [tokens] secret) so the server accepts the token");
+                }
+                if let Some(user) = &user {
+                    if !cfg.users.contains(user) {
+                        anyhow::bail!("no such user '{user}'");
+                    }
+                }
+                let ttl = ttl.as_deref().map(config::parse_duration).transpose()?;
+                let signer = tokens::TokenSigner::new(&cfg.tokens);
+                let claims = signer.claims(user, ttl, models, routes)?;
+                Ok((signer.mint(&claims), claims.exp))
+            });
+            match minted {
+                Ok((token, expires_at)) => {
+                    println!("{token}");
+                    eprintln!("expires at {expires_at} (unix time)");
+                }
+                Err(e) => {
+                    eprintln!("error: {e:#}");
+                    std::process::exit(1);
+                }
+            }
+        }
+        Command::Config {
+            action: ConfigAction::Check { config },
+        } => {
+            let checked = AppConfig::load_from(config.as_deref()).and_then(|cfg| {
+                // make sure the certificate files are usable, not just named
+                if let Some(tls_config) = &cfg.tls {
+                    tls::load_server_config(tls_config)?;
+                }
+                cfg.to_file_config().to_toml()
+            });
+            match checked {
+                Ok(rendered) => {
+                    println!("# configuration is valid; effective settings (API keys masked):");
+                    print!("{rendered}");
+                }
+                Err(e) => {
+                    eprintln!("error: {e:#}");
+                    std::process::exit(1);
+                }
+            }
+        }
         Command::Sql { action, sqlite } => {
             let path = if let Some(p) = sqlite {
                 p
@@ -241,6 +745,145 @@ async fn main() {
                     }
                     println!("user '{}' removed", username);
                 }
+                SqlAction::AllowOrigin { username, origin } => {
+                    if let Err(e) = config::add_user_origin(&path, &username, &origin) {
+                        eprintln!("failed to add origin: {}", e);
+                        std::process::exit(1);
+                    }
+                    println!("origin '{}' allowed for user '{}'", origin, username);
+                }
+                SqlAction::RemoveOrigin { username, origin } => {
+                    let removed = match config::remove_user_origin(&path, &username, &origin) {
+                        Ok(r) => r,
+                        Err(e) => {
+                            eprintln!("failed to remove origin: {}", e);
+                            std::process::exit(1);
+                        }
+                    };
+                    if !removed {
+                        eprintln!("no such origin for user");
+                        std::process::exit(2);
+                    }
+                    println!("origin '{}' removed for user '{}'", origin, username);
+                }
+                SqlAction::AllowIp { username, cidr } => {
+                    match config::add_user_ip(&path, &username, &cidr) {
+                        Ok(net) => println!("network '{}' allowed for user '{}'", net, username),
+                        Err(e) => {
+                            eprintln!("failed to add network: {}", e);
+                            std::process::exit(1);
+                        }
+                    }
+                }
+                SqlAction::RemoveIp { username, cidr } => {
+                    let removed = match config::remove_user_ip(&path, &username, &cidr) {
+                        Ok(r) => r,
+                        Err(e) => {
+                            eprintln!("failed to remove network: {}", e);
+                            std::process::exit(1);
+                        }
+                    };
+                    if !removed {
+                        eprintln!("no such network for user");
+                        std::process::exit(2);
+                    }
+                    println!("network '{}' removed for user '{}'", cidr, username);
+                }
+                SqlAction::Rotate { username, grace, key } => {
+                    let rotated = config::parse_duration(&grace).and_then(|grace| {
+                        let key = match key {
+                            Some(key) => key,
+                            None => config::generate_key()?,
+                        };
+                        let found = config::rotate_user_key(&path, &username, &key, grace)?;
+                        Ok(found.then_some((key, grace)))
+                    });
+                    match rotated {
+                        Ok(Some((key, grace))) => {
+                            println!("{}", key);
+                            if grace.is_zero() {
+                                eprintln!(
+                                    "key for user '{}' rotated; a running server drops the old key within 5s",
+                                    username
+                                );
+                            } else {
+                                eprintln!(
+                                    "key for user '{}' rotated; the old key works for another {}s",
+                                    username,
+                                    grace.as_secs()
+                                );
+                            }
+                        }
+                        Ok(None) => {
+                            eprintln!("no such user");
+                            std::process::exit(2);
+                        }
+                        Err(e) => {
+                            eprintln!("failed to rotate key: {:#}", e);
+                            std::process::exit(1);
+                        }
+                    }
+                }
+                SqlAction::SetRole { username, role } => {
+                    if let Err(e) = config::set_user_role(&path, &username, &role) {
+                        eprintln!("failed to set role: {}", e);
+                        std::process::exit(1);
+                    }
+                    println!("user '{}' now has role '{}'", username, role);
+                }
+                SqlAction::SetPolicy { user, model, policy } => {
+                    let policy: Option<policy::Policy> = match policy.as_deref().map(serde_json::from_str).transpose() {
+                        Ok(p) => p,
+                        Err(e) => {
+                            eprintln!("invalid policy: {}", e);
+                            std::process::exit(1);
+                        }
+                    };
+                    let result = match (&user, &model) {
+                        (Some(user), _) => config::set_user_policy(&path, user, policy.as_ref()),
+                        (None, Some(model)) => config::set_model_policy(&path, model, policy.as_ref()),
+                        (None, None) => unreachable!("clap requires --user or --model"),
+                    };
+                    if let Err(e) = result {
+                        eprintln!("failed to set policy: {}", e);
+                        std::process::exit(1);
+                    }
+                    let target = user.map_or_else(|| format!("model '{}'", model.unwrap_or_default()), |u| format!("user '{u}'"));
+                    match policy {
+                        Some(_) => println!("policy set for {}", target),
+                        None => println!("policy removed for {}", target),
+                    }
+                }
+                SqlAction::Bans => {
+                    let bans = match config::list_bans(&path) {
+                        Ok(b) => b,
+                        Err(e) => {
+                            eprintln!("failed to list bans: {}", e);
+                            std::process::exit(1);
+                        }
+                    };
+                    let now = std::time::SystemTime::now()
+                        .duration_since(std::time::UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs();
+                    for (ip, until) in bans {
+                        println!("{}\t{}s remaining", ip, until.saturating_sub(now));
+                    }
+                }
+                SqlAction::Unban { ip, .. } => {
+                    let removed = match config::remove_bans(&path, ip.as_deref()) {
+                        Ok(n) => n,
+                        Err(e) => {
+                            eprintln!("failed to remove ban: {}", e);
+                            std::process::exit(1);
+                        }
+                    };
+                    if removed == 0 && ip.is_some() {
+                        eprintln!("no such ban");
+                        std::process::exit(2);
+                    }
+                    println!("{} ban(s) lifted", removed);
+                }
             }
         }
     }
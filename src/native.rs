@@ -0,0 +1,613 @@
+use std::{
+    collections::BTreeMap,
+    convert::Infallible,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Result, bail};
+use axum::{
+    Json,
+    body::{self, Body, Bytes},
+    http::{HeaderMap, HeaderValue, Response, StatusCode, header},
+    response::IntoResponse,
+};
+use futures_util::{StreamExt, stream};
+use hyper::Method;
+use serde_json::{Map, Value, json};
+
+use crate::proxy::forward_upstream;
+use crate::roles::glob_match;
+use crate::state::AppState;
+
+/// Route translated to Ollama's native `/api/chat`.
+pub const CHAT_PATH: &str = "chat/completions";
+
+/// Default native options for translated chat requests.  Request values win
+/// over per-user ones, which win over per-model ones, which win over
+/// `options`.
+#[derive(Clone, Debug, Default)]
+pub struct NativeChatConfig {
+    /// For every model.
+    pub options: Map<String, Value>,
+    /// Keyed by model name or `*` glob; more specific patterns win.
+    pub models: BTreeMap<String, Map<String, Value>>,
+    /// Keyed by user name.
+    pub users: BTreeMap<String, Map<String, Value>>,
+}
+
+/// Serves `/v1/chat/completions` through Ollama's native `/api/chat`, so
+/// native options such as `num_ctx` and `keep_alive` take effect.  The
+/// native JSON or NDJSON answer is turned back into OpenAI chat completion
+/// objects or SSE chunks.
+#[derive(Clone, Debug)]
+pub struct NativeChat {
+    config: NativeChatConfig,
+}
+
+/// OpenAI sampling parameters and their native option names.
+const MAPPED_OPTIONS: &[(&str, &str)] = &[
+    ("temperature", "temperature"),
+    ("top_p", "top_p"),
+    ("seed", "seed"),
+    ("frequency_penalty", "frequency_penalty"),
+    ("presence_penalty", "presence_penalty"),
+    ("max_tokens", "num_predict"),
+    ("max_completion_tokens", "num_predict"),
+];
+
+impl NativeChat {
+    pub fn new(config: NativeChatConfig) -> Self {
+        NativeChat { config }
+    }
+
+    pub async fn chat(&self, state: &AppState, user: Option<&str>, headers: HeaderMap, body: Bytes) -> Response<Body> {
+        let request: Value = match serde_json::from_slice(&body) {
+            Ok(request) => request,
+            Err(e) => return openai_error(StatusCode::BAD_REQUEST, format!("Invalid JSON body: {e}")),
+        };
+        let native = match self.translate(user, &request) {
+            Ok(native) => native,
+            Err(e) => return openai_error(StatusCode::BAD_REQUEST, format!("{e:#}")),
+        };
+        let stream = native["stream"] == true;
+        let include_usage = request.pointer("/stream_options/include_usage") == Some(&Value::Bool(true));
+        let model = native["model"].clone();
+        let body = Bytes::from(native.to_string());
+
+        let resp = forward_upstream(state, Method::POST, "api/chat", CHAT_PATH, headers, body).await;
+        if !resp.status().is_success() {
+            return translate_error(resp).await;
+        }
+        let converter = Converter::new(model, include_usage);
+        if stream {
+            stream_chunks(converter, resp)
+        } else {
+            match body::to_bytes(resp.into_body(), usize::MAX).await {
+                Ok(bytes) => match serde_json::from_slice(&bytes) {
+                    Ok(native) => Json(converter.completion(&native)).into_response(),
+                    Err(_) => openai_error(StatusCode::BAD_GATEWAY, "Unexpected response from upstream".into()),
+                },
+                Err(_) => openai_error(StatusCode::BAD_GATEWAY, "Upstream request failed".into()),
+            }
+        }
+    }
+
+    /// `user`'s own defaults, serialized, when there are any.  They change
+    /// the answer, so cached answers are kept apart by them.
+    pub fn user_options(&self, user: Option<&str>) -> Option<String> {
+        let options = self.config.users.get(user?)?;
+        Some(Value::Object(options.clone()).to_string())
+    }
+
+    /// The native `/api/chat` request for an OpenAI chat completion request.
+    fn translate(&self, user: Option<&str>, request: &Value) -> Result<Value> {
+        let Some(model) = request.get("model").and_then(Value::as_str) else {
+            bail!("'model' is required");
+        };
+        let Some(messages) = request.get("messages").and_then(Value::as_array) else {
+            bail!("'messages' must be an array");
+        };
+
+        let mut options = self.config.options.clone();
+        let mut patterns: Vec<&String> = self
+            .config
+            .models
+            .keys()
+            .filter(|pattern| glob_match(pattern, model))
+            .collect();
+        patterns.sort_by_key(|pattern| pattern.len());
+        for pattern in patterns {
+            options.extend(self.config.models[pattern].clone());
+        }
+        if let Some(defaults) = user.and_then(|u| self.config.users.get(u)) {
+            options.extend(defaults.clone());
+        }
+        for (openai, native) in MAPPED_OPTIONS {
+            if let Some(value) = request.get(*openai).filter(|v| !v.is_null()) {
+                options.insert(native.to_string(), value.clone());
+            }
+        }
+        match request.get("stop") {
+            Some(Value::String(stop)) => {
+                options.insert("stop".into(), json!([stop]));
+            }
+            Some(stop @ Value::Array(_)) => {
+                options.insert("stop".into(), stop.clone());
+            }
+            _ => {}
+        }
+        match request.get("options") {
+            Some(Value::Object(extra)) => options.extend(extra.clone()),
+            Some(Value::Null) | None => {}
+            Some(_) => bail!("'options' must be an object"),
+        }
+        // keep_alive is a request field rather than a model option natively
+        let keep_alive = request.get("keep_alive").cloned().or_else(|| options.remove("keep_alive"));
+
+        let mut native = json!({
+            "model": model,
+            "messages": messages.iter().map(native_message).collect::<Result<Vec<_>>>()?,
+            "stream": request.get("stream").and_then(Value::as_bool).unwrap_or(false),
+            "options": options,
+        });
+        if let Some(keep_alive) = keep_alive {
+            native["keep_alive"] = keep_alive;
+        }
+        if let Some(tools) = request.get("tools").filter(|t| !t.is_null()) {
+            native["tools"] = tools.clone();
+        }
+        match request.pointer("/response_format/type").and_then(Value::as_str) {
+            Some("json_object") => native["format"] = json!("json"),
+            Some("json_schema") => {
+                if let Some(schema) = request.pointer("/response_format/json_schema/schema") {
+                    native["format"] = schema.clone();
+                }
+            }
+            _ => {}
+        }
+        Ok(native)
+    }
+}
+
+/// An OpenAI message as a native one: content parts are flattened into
+/// text plus base64 `images`, and tool call arguments become objects.
+fn native_message(message: &Value) -> Result<Value> {
+    let role = message.get("role").and_then(Value::as_str).unwrap_or("user");
+    let mut native = json!({ "role": role });
+    match message.get("content") {
+        Some(Value::String(text)) => native["content"] = json!(text),
+        Some(Value::Array(parts)) => {
+            let mut text = Vec::new();
+            let mut images = Vec::new();
+            for part in parts {
+                match part.get("type").and_then(Value::as_str) {
+                    Some("text") => text.push(part.get("text").and_then(Value::as_str).unwrap_or_default()),
+                    Some("image_url") => {
+                        let url = part
+                            .pointer("/image_url/url")
+                            .or_else(|| part.get("image_url"))
+                            .and_then(Value::as_str)
+                            .unwrap_or_default();
+                        match url.strip_prefix("data:").and_then(|u| u.split_once("base64,")) {
+                            Some((_, data)) => images.push(data.to_string()),
+                            None => bail!("only base64 data: URLs are supported for images"),
+                        }
+                    }
+                    other => bail!("unsupported content part type {:?}", other.unwrap_or_default()),
+                }
+            }
+            native["content"] = json!(text.join("\n"));
+            if !images.is_empty() {
+                native["images"] = json!(images);
+            }
+        }
+        _ => native["content"] = json!(""),
+    }
+    if let Some(calls) = message.get("tool_calls").and_then(Value::as_array) {
+        let calls: Vec<Value> = calls
+            .iter()
+            .map(|call| {
+                let arguments = match call.pointer("/function/arguments") {
+                    Some(Value::String(args)) => serde_json::from_str(args).unwrap_or_else(|_| json!({})),
+                    Some(args) => args.clone(),
+                    None => json!({}),
+                };
+                json!({ "function": { "name": call.pointer("/function/name"), "arguments": arguments } })
+            })
+            .collect();
+        native["tool_calls"] = json!(calls);
+    }
+    if let Some(name) = message.get("name").and_then(Value::as_str) {
+        native["tool_name"] = json!(name);
+    }
+    Ok(native)
+}
+
+/// Turns native answers into OpenAI objects for one completion.
+struct Converter {
+    id: String,
+    created: u64,
+    model: Value,
+    include_usage: bool,
+    sent_role: bool,
+    tool_calls: usize,
+}
+
+impl Converter {
+    fn new(model: Value, include_usage: bool) -> Self {
+        let mut bytes = [0u8; 12];
+        getrandom::getrandom(&mut bytes).expect("no randomness available");
+        Converter {
+            id: format!("chatcmpl-{}", bytes.iter().map(|b| format!("{b:02x}")).collect::<String>()),
+            created: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            model,
+            include_usage,
+            sent_role: false,
+            tool_calls: 0,
+        }
+    }
+
+    fn model(&self, native: &Value) -> Value {
+        native.get("model").cloned().unwrap_or_else(|| self.model.clone())
+    }
+
+    /// A non-streaming native answer as a `chat.completion`.
+    fn completion(mut self, native: &Value) -> Value {
+        let mut message = json!({
+            "role": "assistant",
+            "content": native.pointer("/message/content").cloned().unwrap_or(json!("")),
+        });
+        let calls = self.tool_calls(native);
+        if !calls.is_empty() {
+            message["tool_calls"] = json!(calls);
+        }
+        json!({
+            "id": self.id,
+            "object": "chat.completion",
+            "created": self.created,
+            "model": self.model(native),
+            "system_fingerprint": "fp_ollama",
+            "choices": [{
+                "index": 0,
+                "message": message,
+                "finish_reason": self.finish_reason(native),
+            }],
+            "usage": usage(native),
+        })
+    }
+
+    /// The SSE events for one NDJSON line of a native stream.
+    fn chunk_events(&mut self, native: &Value) -> String {
+        if let Some(error) = native.get("error") {
+            let message = error.as_str().map_or_else(|| error.to_string(), str::to_string);
+            return sse(&json!({ "error": { "message": message, "type": "api_error" } }));
+        }
+        let mut delta = Map::new();
+        if !self.sent_role {
+            delta.insert("role".into(), json!("assistant"));
+            self.sent_role = true;
+        }
+        if let Some(content) = native.pointer("/message/content").and_then(Value::as_str) {
+            if !content.is_empty() {
+                delta.insert("content".into(), json!(content));
+            }
+        }
+        let first_call = self.tool_calls;
+        let calls: Vec<Value> = self
+            .tool_calls(native)
+            .into_iter()
+            .enumerate()
+            .map(|(i, mut call)| {
+                call["index"] = json!(first_call + i);
+                call
+            })
+            .collect();
+        if !calls.is_empty() {
+            delta.insert("tool_calls".into(), json!(calls));
+        }
+
+        let done = native.get("done") == Some(&Value::Bool(true));
+        let mut events = String::new();
+        if !delta.is_empty() {
+            events += &sse(&self.chunk(native, Value::Object(delta), Value::Null));
+        }
+        if done {
+            let finish = json!(self.finish_reason(native));
+            events += &sse(&self.chunk(native, json!({}), finish));
+            if self.include_usage {
+                let mut chunk = self.chunk(native, json!({}), Value::Null);
+                chunk["choices"] = json!([]);
+                chunk["usage"] = usage(native);
+                events += &sse(&chunk);
+            }
+            events += "data: [DONE]\n\n";
+        }
+        events
+    }
+
+    fn chunk(&self, native: &Value, delta: Value, finish_reason: Value) -> Value {
+        json!({
+            "id": self.id,
+            "object": "chat.completion.chunk",
+            "created": self.created,
+            "model": self.model(native),
+            "system_fingerprint": "fp_ollama",
+            "choices": [{ "index": 0, "delta": delta, "finish_reason": finish_reason }],
+        })
+    }
+
+    fn tool_calls(&mut self, native: &Value) -> Vec<Value> {
+        let Some(calls) = native.pointer("/message/tool_calls").and_then(Value::as_array) else {
+            return Vec::new();
+        };
+        calls
+            .iter()
+            .map(|call| {
+                self.tool_calls += 1;
+                let arguments = call.pointer("/function/arguments").cloned().unwrap_or(json!({}));
+                json!({
+                    "id": format!("call_{}_{}", &self.id[9..17], self.tool_calls),
+                    "type": "function",
+                    "function": {
+                        "name": call.pointer("/function/name"),
+                        "arguments": arguments.to_string(),
+                    },
+                })
+            })
+            .collect()
+    }
+
+    fn finish_reason(&self, native: &Value) -> &'static str {
+        if self.tool_calls > 0 {
+            return "tool_calls";
+        }
+        match native.get("done_reason").and_then(Value::as_str) {
+            Some("length") => "length",
+            _ => "stop",
+        }
+    }
+}
+
+fn usage(native: &Value) -> Value {
+    let prompt = native.get("prompt_eval_count").and_then(Value::as_u64).unwrap_or(0);
+    let completion = native.get("eval_count").and_then(Value::as_u64).unwrap_or(0);
+    json!({
+        "prompt_tokens": prompt,
+        "completion_tokens": completion,
+        "total_tokens": prompt + completion,
+    })
+}
+
+fn sse(value: &Value) -> String {
+    format!("data: {value}\n\n")
+}
+
+struct Relay {
+    upstream: body::BodyDataStream,
+    buf: Vec<u8>,
+    converter: Converter,
+    finished: bool,
+}
+
+/// Re-frame a native NDJSON stream as OpenAI server-sent events.
+fn stream_chunks(converter: Converter, resp: Response<Body>) -> Response<Body> {
+    let relay = Relay {
+        upstream: resp.into_body().into_data_stream(),
+        buf: Vec::new(),
+        converter,
+        finished: false,
+    };
+    let events = stream::unfold(relay, |mut relay| async move {
+        loop {
+            if let Some(pos) = relay.buf.iter().position(|&b| b == b'\n') {
+                let line: Vec<u8> = relay.buf.drain(..=pos).collect();
+                if let Some(events) = relay.line(&line) {
+                    return Some((Ok::<_, Infallible>(Bytes::from(events)), relay));
+                }
+                continue;
+            }
+            if relay.finished {
+                return None;
+            }
+            match relay.upstream.next().await {
+                Some(Ok(chunk)) => relay.buf.extend_from_slice(&chunk),
+                Some(Err(e)) => {
+                    eprintln!("native chat stream failed: {e}");
+                    return None;
+                }
+                None => {
+                    // a last line without a trailing newline
+                    relay.finished = true;
+                    relay.buf.push(b'\n');
+                }
+            }
+        }
+    });
+    let mut resp = Response::new(Body::from_stream(events));
+    resp.headers_mut()
+        .insert(header::CONTENT_TYPE, HeaderValue::from_static("text/event-stream"));
+    resp.headers_mut()
+        .insert(header::CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+    resp
+}
+
+impl Relay {
+    fn line(&mut self, line: &[u8]) -> Option<String> {
+        let native: Value = serde_json::from_slice(line).ok()?;
+        let events = self.converter.chunk_events(&native);
+        (!events.is_empty()).then_some(events)
+    }
+}
+
+/// Pass an upstream error on in OpenAI's error shape.
+async fn translate_error(resp: Response<Body>) -> Response<Body> {
+    let status = resp.status();
+    let bytes = body::to_bytes(resp.into_body(), usize::MAX).await.unwrap_or_default();
+    let message = match serde_json::from_slice::<Value>(&bytes) {
+        Ok(Value::Object(body)) => match body.get("error") {
+            Some(Value::String(error)) => error.clone(),
+            Some(error) => error.to_string(),
+            None => String::from_utf8_lossy(&bytes).into_owned(),
+        },
+        _ => String::from_utf8_lossy(&bytes).into_owned(),
+    };
+    openai_error(status, message)
+}
+
+/// An error in the shape OpenAI clients expect.
+pub fn openai_error(status: StatusCode, message: String) -> Response<Body> {
+    let kind = if status.is_client_error() { "invalid_request_error" } else { "api_error" };
+    (status, Json(json!({ "error": { "message": message, "type": kind } }))).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use httpmock::MockServer;
+
+    fn config() -> NativeChatConfig {
+        let object = |v: Value| v.as_object().unwrap().clone();
+        NativeChatConfig {
+            options: object(json!({ "num_ctx": 4096, "keep_alive": "5m" })),
+            models: BTreeMap::from([
+                ("llama3*".to_string(), object(json!({ "num_ctx": 8192, "num_gpu": 1 }))),
+                ("llama3:70b".to_string(), object(json!({ "num_gpu": 2 }))),
+            ]),
+            users: BTreeMap::from([("alice".to_string(), object(json!({ "mirostat": 2 })))]),
+        }
+    }
+
+    #[test]
+    fn translates_requests() {
+        let native = NativeChat::new(config());
+        let request = json!({
+            "model": "llama3:70b",
+            "messages": [
+                { "role": "user", "content": [
+                    { "type": "text", "text": "what is this?" },
+                    { "type": "image_url", "image_url": { "url": "data:image/png;base64,AAAA" } },
+                ]},
+                { "role": "assistant", "content": null, "tool_calls": [
+                    { "id": "c1", "type": "function", "function": { "name": "f", "arguments": "{\"x\":1}" } },
+                ]},
+            ],
+            "max_tokens": 10,
+            "stop": "END",
+            "options": { "repeat_penalty": 1.2, "num_ctx": 2048 },
+            "response_format": { "type": "json_object" },
+        });
+        let out = native.translate(Some("alice"), &request).unwrap();
+        assert_eq!(out["stream"], false);
+        assert_eq!(out["keep_alive"], "5m");
+        assert_eq!(out["format"], "json");
+        assert_eq!(
+            out["options"],
+            json!({
+                "num_ctx": 2048, "num_gpu": 2, "mirostat": 2, "num_predict": 10,
+                "stop": ["END"], "repeat_penalty": 1.2,
+            })
+        );
+        assert_eq!(out["messages"][0]["content"], "what is this?");
+        assert_eq!(out["messages"][0]["images"], json!(["AAAA"]));
+        assert_eq!(out["messages"][1]["tool_calls"][0]["function"]["arguments"], json!({ "x": 1 }));
+
+        let out = native.translate(None, &json!({ "model": "qwen", "messages": [] })).unwrap();
+        assert_eq!(out["options"], json!({ "num_ctx": 4096 }));
+        assert!(native.translate(None, &json!({ "messages": [] })).is_err());
+    }
+
+    #[tokio::test]
+    async fn converts_answers_and_streams() {
+        let server = MockServer::start_async().await;
+        let plain = server.mock(|when, then| {
+            when.method("POST")
+                .path("/api/chat")
+                .body_includes(r#""model":"llama3""#)
+                .body_includes(r#""stream":false"#);
+            then.status(200).header("content-type", "application/json").body(
+                r#"{"model":"llama3","message":{"role":"assistant","content":"hi"},
+                    "done":true,"done_reason":"stop","prompt_eval_count":5,"eval_count":2}"#,
+            );
+        });
+        let streamed = server.mock(|when, then| {
+            when.method("POST").path("/api/chat").body_includes(r#""stream":true"#);
+            then.status(200).header("content-type", "application/x-ndjson").body(concat!(
+                r#"{"model":"llama3","message":{"role":"assistant","content":"h"},"done":false}"#,
+                "\n",
+                r#"{"model":"llama3","message":{"role":"assistant","content":"i"},"done":false}"#,
+                "\n",
+                r#"{"model":"llama3","message":{"role":"assistant","content":""},"done":true,"done_reason":"length","prompt_eval_count":5,"eval_count":2}"#,
+            ));
+        });
+        let missing = server.mock(|when, then| {
+            when.method("POST").path("/api/chat").body_includes("nope");
+            then.status(404).body(r#"{"error":"model 'nope' not found"}"#);
+        });
+        let state = AppState::for_tests(&server.url(""), &[]);
+        let native = NativeChat::new(NativeChatConfig::default());
+        let call = |body: &'static str| {
+            let (native, state) = (native.clone(), state.clone());
+            async move {
+                let resp = native.chat(&state, None, HeaderMap::new(), Bytes::from(body)).await;
+                let status = resp.status();
+                let body = body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+                (status, String::from_utf8(body.to_vec()).unwrap())
+            }
+        };
+
+        let (status, body) = call(r#"{"model":"llama3","messages":[]}"#).await;
+        assert_eq!(status, StatusCode::OK);
+        let body: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["object"], "chat.completion");
+        assert_eq!(body["choices"][0]["message"]["content"], "hi");
+        assert_eq!(body["choices"][0]["finish_reason"], "stop");
+        assert_eq!(body["usage"], json!({ "prompt_tokens": 5, "completion_tokens": 2, "total_tokens": 7 }));
+        plain.assert_calls(1);
+
+        let (_, body) = call(r#"{"model":"llama3","messages":[],"stream":true,"stream_options":{"include_usage":true}}"#).await;
+        let events: Vec<Value> = body
+            .split("\n\n")
+            .filter_map(|e| e.strip_prefix("data: "))
+            .filter(|e| *e != "[DONE]")
+            .map(|e| serde_json::from_str(e).unwrap())
+            .collect();
+        assert!(body.ends_with("data: [DONE]\n\n"));
+        assert_eq!(events.len(), 4);
+        assert_eq!(events[0]["choices"][0]["delta"], json!({ "role": "assistant", "content": "h" }));
+        assert_eq!(events[1]["choices"][0]["delta"], json!({ "content": "i" }));
+        assert_eq!(events[2]["choices"][0]["finish_reason"], "length");
+        assert_eq!(events[3]["usage"]["total_tokens"], 7);
+        assert_eq!(events[0]["id"], events[3]["id"]);
+        streamed.assert_calls(1);
+
+        let (status, body) = call(r#"{"model":"nope","messages":[]}"#).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let body: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["error"]["message"], "model 'nope' not found");
+        missing.assert_calls(1);
+    }
+
+    #[test]
+    fn converts_tool_calls() {
+        let mut converter = Converter::new(json!("m"), false);
+        let native = json!({
+            "message": { "role": "assistant", "content": "", "tool_calls": [
+                { "function": { "name": "weather", "arguments": { "city": "Oslo" } } },
+            ]},
+            "done": true,
+        });
+        let events = converter.chunk_events(&native);
+        let first: Value = serde_json::from_str(events.split("\n\n").next().unwrap().strip_prefix("data: ").unwrap()).unwrap();
+        let call = &first["choices"][0]["delta"]["tool_calls"][0];
+        assert_eq!(call["index"], 0);
+        assert_eq!(call["function"]["arguments"], r#"{"city":"Oslo"}"#);
+        assert!(events.contains(r#""finish_reason":"tool_calls""#));
+
+        let completion = Converter::new(json!("m"), false).completion(&native);
+        assert_eq!(completion["choices"][0]["finish_reason"], "tool_calls");
+        assert_eq!(completion["choices"][0]["message"]["tool_calls"][0]["type"], "function");
+    }
+}
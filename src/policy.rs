@@ -0,0 +1,255 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value, json};
+
+use crate::roles::glob_match;
+
+/// Routes whose JSON bodies are rewritten by request policies.
+pub const POLICY_ROUTES: &[&str] = &["chat/completions", "completions"];
+
+/// Rules enforced on generation request bodies, set per user or per model
+/// pattern in the key store.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Policy {
+    /// `max_tokens` added when the client sends neither it nor
+    /// `max_completion_tokens`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_max_tokens: Option<u64>,
+    /// Upper bound on `max_tokens` / `max_completion_tokens`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u64>,
+    /// Upper bound on `n`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_n: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_temperature: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_temperature: Option<f64>,
+    /// System message put before the client's messages on chat requests.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system_prompt: Option<String>,
+    /// Replaces any `keep_alive` the client sent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keep_alive: Option<Value>,
+}
+
+/// Every policy in the key store.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Policies {
+    /// Username → policy.
+    pub users: HashMap<String, Policy>,
+    /// Model name or `*` glob → policy.
+    pub models: BTreeMap<String, Policy>,
+}
+
+impl Policies {
+    /// The policy for `user` calling `model`.  Defaults, the system prompt
+    /// and `keep_alive` come from the user's policy before the model's (and
+    /// a longer model pattern before a shorter one); limits are the
+    /// strictest any of them sets.
+    pub fn resolve(&self, user: Option<&str>, model: &str) -> Policy {
+        let mut matching: Vec<(&String, &Policy)> = self
+            .models
+            .iter()
+            .filter(|(pattern, _)| glob_match(pattern, model))
+            .collect();
+        matching.sort_by_key(|(pattern, _)| std::cmp::Reverse(pattern.len()));
+        let mut layers: Vec<&Policy> = user.and_then(|u| self.users.get(u)).into_iter().collect();
+        layers.extend(matching.into_iter().map(|(_, policy)| policy));
+
+        let mut resolved = Policy::default();
+        for layer in layers {
+            resolved.default_max_tokens = resolved.default_max_tokens.or(layer.default_max_tokens);
+            resolved.system_prompt = resolved.system_prompt.take().or_else(|| layer.system_prompt.clone());
+            resolved.keep_alive = resolved.keep_alive.take().or_else(|| layer.keep_alive.clone());
+            resolved.max_tokens = min_of(resolved.max_tokens, layer.max_tokens);
+            resolved.max_n = min_of(resolved.max_n, layer.max_n);
+            resolved.max_temperature = min_of(resolved.max_temperature, layer.max_temperature);
+            resolved.min_temperature = match (resolved.min_temperature, layer.min_temperature) {
+                (Some(a), Some(b)) => Some(a.max(b)),
+                (a, b) => a.or(b),
+            };
+        }
+        resolved
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty() && self.models.is_empty()
+    }
+}
+
+fn min_of<T: PartialOrd>(a: Option<T>, b: Option<T>) -> Option<T> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(if b < a { b } else { a }),
+        (a, b) => a.or(b),
+    }
+}
+
+impl Policy {
+    /// Rewrite a request body for `path` to follow this policy, returning a
+    /// description of each change made.
+    pub fn apply(&self, path: &str, body: &mut Value) -> Vec<String> {
+        let mut changes = Vec::new();
+        let Some(fields) = body.as_object_mut() else { return changes };
+
+        let token_fields = ["max_tokens", "max_completion_tokens"];
+        if !token_fields.iter().any(|f| fields.get(*f).is_some_and(|v| !v.is_null())) {
+            if let Some(default) = self.default_max_tokens {
+                fields.insert("max_tokens".into(), json!(default));
+                changes.push(format!("max_tokens defaulted to {default}"));
+            }
+        }
+        for field in token_fields {
+            clamp_u64(fields, field, self.max_tokens, &mut changes);
+        }
+        clamp_u64(fields, "n", self.max_n, &mut changes);
+        self.clamp_temperature(fields, "temperature", &mut changes);
+
+        // native options, which native chat mode applies over the fields above
+        if let Some(Value::Object(options)) = fields.get_mut("options") {
+            if let Some(cap) = self.max_tokens {
+                // -1 and -2 ask Ollama for an unlimited answer
+                if let Some(value) = options.get("num_predict").and_then(Value::as_i64).filter(|v| *v < 0) {
+                    options.insert("num_predict".into(), json!(cap));
+                    changes.push(format!("num_predict {value} capped at {cap}"));
+                }
+            }
+            clamp_u64(options, "num_predict", self.max_tokens, &mut changes);
+            self.clamp_temperature(options, "temperature", &mut changes);
+            if let Some(keep_alive) = &self.keep_alive {
+                if options.get("keep_alive").is_some_and(|v| v != keep_alive) {
+                    options.insert("keep_alive".into(), keep_alive.clone());
+                    changes.push(format!("options.keep_alive set to {keep_alive}"));
+                }
+            }
+        }
+
+        if let Some(prompt) = &self.system_prompt {
+            if path == "chat/completions" {
+                if let Some(Value::Array(messages)) = fields.get_mut("messages") {
+                    messages.insert(0, json!({ "role": "system", "content": prompt }));
+                    changes.push("system prompt injected".into());
+                }
+            }
+        }
+        if let Some(keep_alive) = &self.keep_alive {
+            if fields.get("keep_alive") != Some(keep_alive) {
+                fields.insert("keep_alive".into(), keep_alive.clone());
+                changes.push(format!("keep_alive set to {keep_alive}"));
+            }
+        }
+        changes
+    }
+}
+
+impl Policy {
+    fn clamp_temperature(&self, fields: &mut Map<String, Value>, field: &str, changes: &mut Vec<String>) {
+        let Some(temperature) = fields.get(field).and_then(Value::as_f64) else { return };
+        let mut clamped = temperature;
+        if let Some(max) = self.max_temperature {
+            clamped = clamped.min(max);
+        }
+        if let Some(min) = self.min_temperature {
+            clamped = clamped.max(min);
+        }
+        if clamped != temperature {
+            fields.insert(field.into(), json!(clamped));
+            changes.push(format!("{field} {temperature} clamped to {clamped}"));
+        }
+    }
+}
+
+fn clamp_u64(fields: &mut Map<String, Value>, field: &str, cap: Option<u64>, changes: &mut Vec<String>) {
+    let (Some(cap), Some(value)) = (cap, fields.get(field).and_then(Value::as_u64)) else { return };
+    if value > cap {
+        fields.insert(field.into(), json!(cap));
+        changes.push(format!("{field} {value} capped at {cap}"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn applies_defaults_and_clamps() {
+        let policy = Policy {
+            default_max_tokens: Some(256),
+            max_tokens: Some(1024),
+            max_n: Some(1),
+            min_temperature: Some(0.1),
+            max_temperature: Some(1.0),
+            system_prompt: Some("Be brief.".into()),
+            keep_alive: Some(json!("10m")),
+        };
+        let mut body = json!({ "model": "m", "messages": [{ "role": "user", "content": "hi" }], "temperature": 1.7, "n": 3 });
+        let changes = policy.apply("chat/completions", &mut body);
+        assert_eq!(changes.len(), 5);
+        assert_eq!(body["max_tokens"], 256);
+        assert_eq!(body["n"], 1);
+        assert_eq!(body["temperature"], 1.0);
+        assert_eq!(body["messages"][0], json!({ "role": "system", "content": "Be brief." }));
+        assert_eq!(body["messages"][1]["content"], "hi");
+        assert_eq!(body["keep_alive"], "10m");
+
+        let mut body = json!({ "prompt": "x", "max_completion_tokens": 5000, "temperature": 0 });
+        policy.apply("completions", &mut body);
+        assert_eq!(body["max_completion_tokens"], 1024);
+        assert!(body.get("max_tokens").is_none());
+        assert_eq!(body["temperature"], 0.1);
+        assert!(body.get("messages").is_none());
+
+        let mut body = json!({ "max_tokens": 100, "temperature": 0.5, "keep_alive": "10m" });
+        assert!(policy.apply("chat/completions", &mut body).is_empty());
+
+        let mut body = json!({ "max_tokens": 100, "options": { "num_predict": -1, "temperature": 3, "keep_alive": -1 } });
+        assert_eq!(policy.apply("chat/completions", &mut body).len(), 4);
+        assert_eq!(body["options"], json!({ "num_predict": 1024, "temperature": 1.0, "keep_alive": "10m" }));
+    }
+
+    #[test]
+    fn combines_user_and_model_policies() {
+        let policies = Policies {
+            users: HashMap::from([(
+                "alice".to_string(),
+                Policy {
+                    default_max_tokens: Some(100),
+                    max_tokens: Some(4000),
+                    max_temperature: Some(1.5),
+                    ..Default::default()
+                },
+            )]),
+            models: BTreeMap::from([
+                (
+                    "*".to_string(),
+                    Policy {
+                        default_max_tokens: Some(500),
+                        max_tokens: Some(2000),
+                        system_prompt: Some("generic".into()),
+                        ..Default::default()
+                    },
+                ),
+                (
+                    "llama3*".to_string(),
+                    Policy {
+                        max_temperature: Some(1.0),
+                        system_prompt: Some("llama".into()),
+                        ..Default::default()
+                    },
+                ),
+            ]),
+        };
+        let policy = policies.resolve(Some("alice"), "llama3:8b");
+        assert_eq!(policy.default_max_tokens, Some(100));
+        assert_eq!(policy.max_tokens, Some(2000));
+        assert_eq!(policy.max_temperature, Some(1.0));
+        assert_eq!(policy.system_prompt.as_deref(), Some("llama"));
+
+        let policy = policies.resolve(None, "qwen");
+        assert_eq!(policy.default_max_tokens, Some(500));
+        assert_eq!(policy.system_prompt.as_deref(), Some("generic"));
+        assert_eq!(policy.max_temperature, None);
+    }
+}
@@ -1,39 +1,402 @@
 use axum::{
+    Json,
     body::{self, Body, Bytes},
-    extract::{Path, Request, State},
-    http::{HeaderMap, Response, StatusCode},
+    extract::{ConnectInfo, Path, Request, State},
+    http::{HeaderMap, HeaderValue, Response, StatusCode, header},
     response::IntoResponse,
 };
 use hyper::Method;
 
+use std::net::SocketAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::access::{in_any, user_ip_allowed};
+use crate::auth::{ClientCert, Identity, Source, authenticate};
+use crate::cache;
+use crate::coalesce;
+use crate::context::{self, Fit, TRUNCATED_HEADER};
+use crate::cors::{is_preflight, user_origin_allowed};
+use crate::embeddings::{self, EMBEDDINGS_PATH};
+use crate::forward_auth::Decision;
+use crate::forwarded::ClientInfo;
+use crate::headers::{downstream_response_headers, upstream_request_headers};
+use crate::native;
+use crate::policy::POLICY_ROUTES;
 use crate::state::AppState;
+use crate::think;
+use crate::tokens::requested_model;
 
 pub async fn proxy_handler(
     Path(path): Path<String>,
     State(state): State<AppState>,
     req: Request<Body>,
 ) -> impl IntoResponse {
-    let headers = req.headers().clone();
-    let method: Method = req.method().clone();
-    // consume body with an arbitrary max size
-    let body = req.into_body();
+    let Some(cors) = &state.cors else {
+        return handle_request(&state, path, req).await;
+    };
+    // browsers send preflights without credentials, so they are answered
+    // before authentication
+    if is_preflight(req.method(), req.headers()) {
+        return cors.preflight(req.headers());
+    }
+    let origin = req.headers().get(header::ORIGIN).cloned();
+    let mut resp = handle_request(&state, path, req).await;
+    cors.apply(origin.as_ref(), &mut resp);
+    resp
+}
+
+async fn handle_request(state: &AppState, path: String, req: Request<Body>) -> Response<Body> {
+    let (parts, body) = req.into_parts();
+    let mut headers = parts.headers;
+    let method: Method = parts.method;
+
+    let peer = parts
+        .extensions
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+    // TLS connections always carry a (possibly empty) ClientCert
+    let proto = if parts.extensions.get::<ClientCert>().is_some() {
+        "https"
+    } else {
+        "http"
+    };
+    let client = state.forwarded.client_info(peer, proto, &headers);
+
+    if client.addr.is_some_and(|addr| in_any(&state.denied_ips, addr)) {
+        log_denial(state, &client, None, "on the deny list");
+        log_request(&client, None, &method, &path, StatusCode::FORBIDDEN);
+        return (StatusCode::FORBIDDEN, "Forbidden").into_response();
+    }
+    let remaining = match client.addr {
+        Some(addr) => state.guard.ban_remaining(addr).await,
+        None => None,
+    };
+    if let Some(remaining) = remaining {
+        log_request(&client, None, &method, &path, StatusCode::TOO_MANY_REQUESTS);
+        return Response::builder()
+            .status(StatusCode::TOO_MANY_REQUESTS)
+            .header(header::RETRY_AFTER, remaining.as_secs() + u64::from(remaining.subsec_nanos() > 0))
+            .body(Body::from("Too many failed authentication attempts"))
+            .unwrap();
+    }
+    // consume body with an arbitrary max size, once banned addresses are out
     let body_bytes = match body::to_bytes(body, 8 * 1024 * 1024).await {
         Ok(b) => b,
         Err(_) => return (StatusCode::BAD_REQUEST, "Failed to read body").into_response(),
     };
 
-    // simple bearer key check
-    if let Some(auth) = headers.get("authorization") {
-        if let Ok(auth_str) = auth.to_str() {
-            if let Some(key) = auth_str.strip_prefix("Bearer ") {
-                if state.valid_keys.contains(&key.to_string()) {
-                    return forward_request(&state, method, path, headers, body_bytes).await;
+    let identity = match &state.forward_auth {
+        None => authenticate(state, &headers, &parts.extensions).ok_or(StatusCode::UNAUTHORIZED),
+        Some(forward_auth) => match forward_auth.check(&state.client, &method, &path, &headers, &client).await {
+            Decision::Allow(username) => Ok(Identity {
+                username,
+                old_key_expires: None,
+                scope: None,
+                source: Source::ForwardAuth,
+            }),
+            Decision::Deny(status) => Err(status),
+            Decision::Unavailable => {
+                log_request(&client, None, &method, &path, StatusCode::BAD_GATEWAY);
+                return (StatusCode::BAD_GATEWAY, "Authorization service unavailable").into_response();
+            }
+        },
+    };
+    let identity = match identity {
+        Ok(identity) => identity,
+        Err(status) => {
+            if let Some(addr) = client.addr {
+                let failure = state.guard.record_failure(addr).await;
+                if let Some(banned_for) = failure.banned_for {
+                    eprintln!(
+                        "banned {addr} for {}s after repeated failed authentication",
+                        banned_for.as_secs()
+                    );
                 }
+                tokio::time::sleep(failure.delay).await;
             }
+            log_request(&client, None, &method, &path, status);
+            let message = if status == StatusCode::UNAUTHORIZED { "Unauthorized" } else { "Forbidden" };
+            return (status, message).into_response();
         }
+    };
+    if let Some(addr) = client.addr {
+        state.guard.record_success(addr);
     }
 
-    (StatusCode::UNAUTHORIZED, "Unauthorized").into_response()
+    let user = identity.username.as_deref();
+    let (origin_ok, ip_ok, quota, role) = {
+        let directory = state.directory();
+        let users = &directory.users;
+        (
+            user_origin_allowed(user.and_then(|u| users.origins.get(u)), &headers),
+            user_ip_allowed(user.and_then(|u| users.ips.get(u)), client.addr),
+            user.and_then(|u| users.quotas.get(u)).copied(),
+            user.map_or("user", |u| users.role(u)).to_string(),
+        )
+    };
+    if !origin_ok {
+        log_request(&client, Some(&identity), &method, &path, StatusCode::FORBIDDEN);
+        return (StatusCode::FORBIDDEN, "Origin not allowed for this key").into_response();
+    }
+    if !ip_ok {
+        log_denial(state, &client, user, "not in the user's allowlist");
+        log_request(&client, Some(&identity), &method, &path, StatusCode::FORBIDDEN);
+        return (StatusCode::FORBIDDEN, "Address not allowed for this key").into_response();
+    }
+    if !state.roles.allows(&role, &method, &path) {
+        log_request(&client, Some(&identity), &method, &path, StatusCode::FORBIDDEN);
+        return (
+            StatusCode::FORBIDDEN,
+            format!("Role '{role}' may not {method} /v1/{path}"),
+        )
+            .into_response();
+    }
+    if let Some(scope) = &identity.scope {
+        if !scope.allows(&path, requested_model(&body_bytes).as_deref()) {
+            log_request(&client, Some(&identity), &method, &path, StatusCode::FORBIDDEN);
+            return (StatusCode::FORBIDDEN, "Token does not cover this route or model").into_response();
+        }
+    }
+    if let Some(user) = user {
+        if !state.usage.try_record(user, quota) {
+            log_request(&client, Some(&identity), &method, &path, StatusCode::TOO_MANY_REQUESTS);
+            return (StatusCode::TOO_MANY_REQUESTS, "Daily request quota exceeded").into_response();
+        }
+    }
+    // minting is a route like any other, for roles and quotas
+    if path == TOKENS_PATH && method == Method::POST {
+        let resp = mint_token(state, &identity, &body_bytes);
+        log_request(&client, Some(&identity), &method, &path, resp.status());
+        return resp;
+    }
+
+    let mut body_bytes = match apply_policy(state, identity.username.as_deref(), &method, &path, &body_bytes) {
+        Some(rewritten) => rewritten,
+        None => body_bytes,
+    };
+    let mut truncated = None;
+    if let Some(context) = &state.context {
+        if method == Method::POST && POLICY_ROUTES.contains(&path.as_str()) {
+            if let Ok(mut request) = serde_json::from_slice::<serde_json::Value>(&body_bytes) {
+                match context.fit(&path, &mut request) {
+                    Fit::Fits => {}
+                    Fit::Truncated(dropped) => {
+                        println!(
+                            "dropped {dropped} oldest messages for user '{}' to fit the context window",
+                            user.unwrap_or("-")
+                        );
+                        body_bytes = Bytes::from(request.to_string());
+                        truncated = Some(dropped);
+                    }
+                    Fit::Exceeded { limit, tokens } => {
+                        let resp = context::exceeded_response(limit, tokens);
+                        log_request(&client, Some(&identity), &method, &path, resp.status());
+                        return resp;
+                    }
+                }
+            }
+        }
+    }
+
+    // the raw answer is cached, so each request gets its own transform
+    let think = if method == Method::POST && path == native::CHAT_PATH {
+        state.think.mode(&headers, requested_model(&body_bytes).as_deref())
+    } else {
+        None
+    };
+
+    // per-user native options change the answer, so only users with the
+    // same ones share entries
+    let cache_scope = state
+        .native_chat
+        .as_ref()
+        .filter(|_| path == native::CHAT_PATH)
+        .and_then(|native| native.user_options(user));
+    let cache_key = state
+        .cache
+        .as_ref()
+        .and_then(|cache| Some((cache, cache.key(cache_scope.as_deref(), &method, &path, &body_bytes)?)));
+    let directives = cache::directives(&headers);
+    if let Some((cache, key)) = &cache_key {
+        if !directives.no_cache && !directives.no_store {
+            if let Some(hit) = cache.get(key).await {
+                let mut resp = cache::replay(hit);
+                if let Some(mode) = think {
+                    resp = think::transform(mode, resp).await;
+                }
+                log_request(&client, Some(&identity), &method, &path, resp.status());
+                return resp;
+            }
+        }
+    }
+
+    state.forwarded.set_upstream_headers(&mut headers, &client);
+    if (state.embedding_cache.is_some() || state.batcher.is_some())
+        && method == Method::POST
+        && path == EMBEDDINGS_PATH
+    {
+        if let Some(resp) = embeddings::handle(state, headers.clone(), &body_bytes).await {
+            log_request(&client, Some(&identity), &method, &path, resp.status());
+            return resp;
+        }
+    }
+    let coalesce_key = state.coalescer.as_ref().and_then(|coalescer| {
+        let scope = coalesce::scope(identity.username.as_deref(), &headers);
+        Some((coalescer, coalescer.key(&scope, &method, &path, &body_bytes)?))
+    });
+    let user = identity.username.clone();
+    let mut resp = match coalesce_key {
+        Some((coalescer, key)) => coalescer.forward(state, key, user, path.clone(), headers, body_bytes).await,
+        None => send_upstream(state, user.as_deref(), method.clone(), path.clone(), headers, body_bytes).await,
+    };
+    if let Some((cache, key)) = cache_key {
+        if directives.no_store {
+            resp.headers_mut()
+                .insert(cache::CACHE_HEADER, HeaderValue::from_static("MISS"));
+        } else {
+            resp = cache.store_on_completion(key, resp);
+        }
+    }
+    if let Some(dropped) = truncated {
+        resp.headers_mut().insert(TRUNCATED_HEADER, HeaderValue::from(dropped));
+    }
+    if let Some(mode) = think {
+        resp = think::transform(mode, resp).await;
+    }
+    log_request(&client, Some(&identity), &method, &path, resp.status());
+    resp
+}
+
+/// Rewrite a generation request to follow the user's and model's request
+/// policies, logging what changed.  `None` leaves the body as it is.
+fn apply_policy(state: &AppState, user: Option<&str>, method: &Method, path: &str, body: &Bytes) -> Option<Bytes> {
+    if method != Method::POST || !POLICY_ROUTES.contains(&path) {
+        return None;
+    }
+    let policy = {
+        let directory = state.directory();
+        let policies = &directory.users.policies;
+        if policies.is_empty() {
+            return None;
+        }
+        let model = requested_model(body).unwrap_or_default();
+        policies.resolve(user, &model)
+    };
+    let mut request: serde_json::Value = serde_json::from_slice(body).ok()?;
+    let changes = policy.apply(path, &mut request);
+    if changes.is_empty() {
+        return None;
+    }
+    println!(
+        "policy applied for user '{}' on /v1/{path}: {}",
+        user.unwrap_or("-"),
+        changes.join(", ")
+    );
+    serde_json::to_vec(&request).ok().map(Bytes::from)
+}
+
+/// Route below `/v1/` answered by the shim itself with a fresh token.
+const TOKENS_PATH: &str = "shim/tokens";
+
+/// Mint a token for the caller.  Tokens cannot mint further tokens, so a
+/// leaked one can never outlive its own expiry.
+fn mint_token(state: &AppState, identity: &Identity, body: &[u8]) -> Response<Body> {
+    if identity.scope.is_some() {
+        return (StatusCode::FORBIDDEN, "Tokens cannot mint tokens").into_response();
+    }
+    // tokens are checked against the key store, which forward-auth bypasses
+    if state.forward_auth.is_some() {
+        return (StatusCode::NOT_FOUND, "Tokens are not available with forward-auth").into_response();
+    }
+    match state.tokens.mint_for(identity.username.clone(), body) {
+        Ok((token, expires_at)) => {
+            Json(serde_json::json!({ "token": token, "expires_at": expires_at })).into_response()
+        }
+        Err(e) => (StatusCode::BAD_REQUEST, format!("{e:#}")).into_response(),
+    }
+}
+
+fn log_denial(state: &AppState, client: &ClientInfo, user: Option<&str>, reason: &str) {
+    let total = state.denials.record(user);
+    let addr = client.addr.map_or_else(|| "unknown address".to_string(), |a| a.to_string());
+    eprintln!(
+        "denied {addr} for user '{}': {reason} ({total} denials)",
+        user.unwrap_or("-")
+    );
+}
+
+/// One access log line per request, naming the real client rather than the
+/// proxy in front of us.  Requests made with a key that is about to stop
+/// working are flagged with `old-key` and the time left, so stragglers can
+/// be found before a rotation cutoff.
+fn log_request(client: &ClientInfo, identity: Option<&Identity>, method: &Method, path: &str, status: StatusCode) {
+    let addr = client.addr.map_or_else(|| "-".to_string(), |a| a.to_string());
+    let user = identity.and_then(|i| i.username.as_deref()).unwrap_or("-");
+    let flag = identity
+        .and_then(|i| i.old_key_expires)
+        .map(|expires| {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            format!(" old-key(expires in {}s)", expires.saturating_sub(now))
+        })
+        .unwrap_or_default();
+    println!("{addr} {user} {method} /v1/{path} {}{flag}", status.as_u16());
+}
+
+/// Send a request on to Ollama, validating structured chat output when
+/// that is enabled.
+pub async fn send_upstream(
+    state: &AppState,
+    user: Option<&str>,
+    method: Method,
+    path: String,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response<Body> {
+    if let Some(schema) = &state.schema {
+        if method == Method::POST && path == native::CHAT_PATH {
+            if let Some(resp) = schema.complete(state, user, &headers, &body).await {
+                return resp;
+            }
+        }
+    }
+    send_once(state, user, method, path, headers, body).await
+}
+
+/// One upstream call, emulating tool calling for models configured for it.
+pub async fn send_once(
+    state: &AppState,
+    user: Option<&str>,
+    method: Method,
+    path: String,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response<Body> {
+    if method == Method::POST && path == native::CHAT_PATH {
+        if let Some(resp) = state.tools.chat(state, user, &headers, &body).await {
+            return resp;
+        }
+    }
+    send_direct(state, user, method, path, headers, body).await
+}
+
+/// One upstream call, through native `/api/chat` when that is enabled.
+pub async fn send_direct(
+    state: &AppState,
+    user: Option<&str>,
+    method: Method,
+    path: String,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response<Body> {
+    if let Some(native) = &state.native_chat {
+        if method == Method::POST && path == native::CHAT_PATH {
+            return native.chat(state, user, headers, body).await;
+        }
+    }
+    forward_request(state, method, path, headers, body).await
 }
 
 pub async fn forward_request(
@@ -42,54 +405,100 @@ pub async fn forward_request(
     path: String,
     headers: HeaderMap,
     body: Bytes,
+) -> Response<Body> {
+    forward_upstream(state, method, &format!("v1/{path}"), &path, headers, body).await
+}
+
+/// Forward to `upstream_path` on the backend, with the retry policy of the
+/// `/v1/` route the client asked for.
+pub async fn forward_upstream(
+    state: &AppState,
+    method: Method,
+    upstream_path: &str,
+    route: &str,
+    headers: HeaderMap,
+    body: Bytes,
 ) -> Response<Body> {
     let base = state.ollama_url.trim_end_matches('/');
-    let url = format!("{}/v1/{}", base, path);
+    let url = format!("{}/{}", base, upstream_path);
+    let breaker = state.breakers.get(base);
+    // only requests that can safely hit the backend twice are retried; the
+    // rest get exactly one attempt.  Either way the circuit breaker hears
+    // once per client request, about its last attempt.
+    let max_attempts = if state.retry.is_retry_safe(&method, route) {
+        state.retry.max_attempts.max(1)
+    } else {
+        1
+    };
 
     // reqwest expects its own Method type; convert from hyper's.
     let reqwest_method = reqwest::Method::from_bytes(method.as_str().as_bytes())
         .unwrap_or(reqwest::Method::GET);
-    let mut req = state.client.request(reqwest_method, &url).body(body.clone());
 
-    for (name, value) in headers.iter() {
-        if name == "host" || name == "authorization" {
-            continue;
-        }
-        if let Ok(val_str) = value.to_str() {
-            req = req.header(name.as_str(), val_str);
-        }
+    let upstream_headers = upstream_request_headers(&headers);
+
+    if !breaker.try_acquire() {
+        return Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .body(Body::from("Upstream temporarily unavailable"))
+            .unwrap();
     }
 
-    match req.send().await {
-        Ok(resp) => {
-            let status_code =
-                StatusCode::from_u16(resp.status().as_u16()).unwrap_or(StatusCode::OK);
-            let mut response_builder = Response::builder().status(status_code);
-            for (name, value) in resp.headers().iter() {
-                if let Ok(val_str) = value.to_str() {
-                    response_builder = response_builder.header(name.as_str(), val_str);
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let req = state
+            .client
+            .request(reqwest_method.clone(), &url)
+            .headers(upstream_headers.clone())
+            .body(body.clone());
+
+        match req.send().await {
+            Ok(resp) => {
+                // the body has not been read yet, so a 502/503 can still be
+                // retried without the client seeing any of it.
+                let status = resp.status().as_u16();
+                let unavailable = status == 502 || status == 503;
+                if unavailable && attempt < max_attempts {
+                    tokio::time::sleep(state.retry.backoff(attempt)).await;
+                    continue;
+                }
+                if !unavailable {
+                    breaker.record_success();
+                } else if breaker.record_failure() {
+                    eprintln!("circuit breaker opened for {base}");
+                }
+                return relay_response(resp);
+            }
+            Err(err) => {
+                if err.is_connect() && attempt < max_attempts {
+                    tokio::time::sleep(state.retry.backoff(attempt)).await;
+                    continue;
+                }
+                if breaker.record_failure() {
+                    eprintln!("circuit breaker opened for {base}");
                 }
+                eprintln!("error forwarding request (attempt {attempt}): {err}");
+                return Response::builder()
+                    .status(StatusCode::BAD_GATEWAY)
+                    .body(Body::from("Upstream request failed"))
+                    .unwrap();
             }
-            let bytes = resp.bytes().await.unwrap_or_default();
-            response_builder
-                .body(Body::from(bytes))
-                .unwrap_or_else(|_| {
-                    Response::builder()
-                        .status(StatusCode::INTERNAL_SERVER_ERROR)
-                        .body(Body::empty())
-                        .unwrap()
-                })
-        }
-        Err(err) => {
-            eprintln!("error forwarding request: {err}");
-            Response::builder()
-                .status(StatusCode::BAD_GATEWAY)
-                .body(Body::from("Upstream request failed"))
-                .unwrap()
         }
     }
 }
 
+/// Turn an upstream response into ours, streaming the body through as it
+/// arrives instead of buffering it.
+fn relay_response(resp: reqwest::Response) -> Response<Body> {
+    let status_code = StatusCode::from_u16(resp.status().as_u16()).unwrap_or(StatusCode::OK);
+    let headers = downstream_response_headers(resp.headers());
+    let mut response = Response::new(Body::from_stream(resp.bytes_stream()));
+    *response.status_mut() = status_code;
+    *response.headers_mut() = headers;
+    response
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -97,16 +506,55 @@ mod tests {
     use axum::body::Body;
     use axum::http::Request;
     use axum::http::StatusCode;
+    use crate::bans::{AuthGuard, BanConfig};
+    use crate::cache::{CacheConfig, ResponseCache};
+    use crate::native::{NativeChat, NativeChatConfig};
+    use crate::schema::SchemaConfig;
+    use crate::think::ThinkMode;
+    use crate::tools::ToolMode;
+    use crate::embeddings::{EmbeddingCache, EmbeddingCacheConfig};
+    use crate::context::{ContextConfig, Overflow};
+    use crate::policy::Policy;
+    use crate::cors::CorsConfig;
+    use crate::forward_auth::{ForwardAuth, ForwardAuthConfig};
+    use crate::retry::{BreakerConfig, Breakers, RetryPolicy};
+    use crate::roles::Roles;
+    use std::collections::BTreeMap;
     use httpmock::MockServer;
-    use reqwest::Client;
+    use std::time::Duration;
+
+    fn test_state(ollama_url: String, keys: &[&str]) -> AppState {
+        let mut state = AppState::for_tests(&ollama_url, keys);
+        state.retry = RetryPolicy {
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(5),
+            ..Default::default()
+        };
+        state
+    }
+
+    /// A request authenticated with `key`.
+    fn authorized(method: Method, key: &str) -> axum::http::request::Builder {
+        Request::builder()
+            .method(method)
+            .header("authorization", format!("Bearer {key}"))
+    }
+
+    /// Send `req` through the proxy to `path`, as a client connecting from
+    /// `peer` when one is given.
+    async fn send(state: &AppState, path: &str, peer: Option<&str>, mut req: Request<Body>) -> Response<Body> {
+        if let Some(peer) = peer {
+            req.extensions_mut()
+                .insert(ConnectInfo(peer.parse::<SocketAddr>().unwrap()));
+        }
+        proxy_handler(Path(path.into()), State(state.clone()), req)
+            .await
+            .into_response()
+    }
 
     #[tokio::test]
     async fn unauthorized_missing_header() {
-        let state = AppState {
-            client: Client::new(),
-            valid_keys: vec!["secret".into()],
-            ollama_url: "http://localhost".into(),
-        };
+        let state = test_state("http://localhost".into(), &["secret"]);
         let req = Request::builder().body(Body::from("")).unwrap();
         let resp = proxy_handler(Path("foo".into()), State(state), req)
             .await
@@ -122,11 +570,7 @@ mod tests {
             then.status(200).body("ok");
         });
 
-        let state = AppState {
-            client: Client::new(),
-            valid_keys: vec!["goodkey".into()],
-            ollama_url: server.url(""),
-        };
+        let state = test_state(server.url(""), &["goodkey"]);
 
         let req = Request::builder()
             .method(Method::POST)
@@ -149,11 +593,7 @@ mod tests {
             then.status(200).body("okget");
         });
 
-        let state = AppState {
-            client: Client::new(),
-            valid_keys: vec!["goodkey".into()],
-            ollama_url: server.url(""),
-        };
+        let state = test_state(server.url(""), &["goodkey"]);
 
         let req = Request::builder()
             .method(Method::GET)
@@ -167,4 +607,869 @@ mod tests {
         assert_eq!(resp.status(), StatusCode::OK);
         mock.assert();
     }
+
+    #[tokio::test]
+    async fn retries_idempotent_requests_on_503() {
+        let server = MockServer::start_async().await;
+        let mock = server.mock(|when, then| {
+            when.method("GET").path("/v1/models");
+            then.status(503).body("loading");
+        });
+
+        let state = test_state(server.url(""), &["goodkey"]);
+        let resp = forward_request(
+            &state,
+            Method::GET,
+            "models".into(),
+            HeaderMap::new(),
+            Bytes::new(),
+        )
+        .await;
+        // the last upstream response is relayed once attempts run out
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        mock.assert_calls(3);
+    }
+
+    #[tokio::test]
+    async fn does_not_retry_unsafe_requests() {
+        let server = MockServer::start_async().await;
+        let mock = server.mock(|when, then| {
+            when.method("POST").path("/v1/chat/completions");
+            then.status(502);
+        });
+
+        let mut state = test_state(server.url(""), &["goodkey"]);
+        let resp = forward_request(
+            &state,
+            Method::POST,
+            "chat/completions".into(),
+            HeaderMap::new(),
+            Bytes::from("{}"),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::BAD_GATEWAY);
+        mock.assert_calls(1);
+
+        // whitelisting the route makes POSTs retryable
+        state.retry.safe_routes = vec!["chat/completions".into()];
+        forward_request(
+            &state,
+            Method::POST,
+            "chat/completions".into(),
+            HeaderMap::new(),
+            Bytes::from("{}"),
+        )
+        .await;
+        mock.assert_calls(4);
+    }
+
+    #[tokio::test]
+    async fn circuit_breaker_fails_fast() {
+        // nothing listens on this port, so every attempt is a connect error
+        let mut state = test_state("http://127.0.0.1:9".into(), &["goodkey"]);
+        state.breakers = Breakers::new(BreakerConfig {
+            failure_threshold: 2,
+            cooldown: Duration::from_secs(60),
+        });
+
+        let call = || forward_request(&state, Method::GET, "models".into(), HeaderMap::new(), Bytes::new());
+        // all three attempts of one request count as a single failure
+        assert_eq!(call().await.status(), StatusCode::BAD_GATEWAY);
+        assert!(!state.breakers.get("http://127.0.0.1:9").is_open());
+        assert_eq!(call().await.status(), StatusCode::BAD_GATEWAY);
+        assert!(state.breakers.get("http://127.0.0.1:9").is_open());
+        // the open breaker answers without trying
+        assert_eq!(call().await.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn cors_preflight_skips_auth() {
+        let mut state = test_state("http://localhost".into(), &["goodkey"]);
+        state.cors = Some(CorsConfig {
+            allowed_origins: vec!["https://chat.example.com".into()],
+            ..Default::default()
+        });
+        let req = Request::builder()
+            .method(Method::OPTIONS)
+            .header("origin", "https://chat.example.com")
+            .header("access-control-request-method", "POST")
+            .header("access-control-request-headers", "authorization")
+            .body(Body::empty())
+            .unwrap();
+        let resp = proxy_handler(Path("chat/completions".into()), State(state.clone()), req)
+            .await
+            .into_response();
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            resp.headers()["access-control-allow-origin"],
+            "https://chat.example.com"
+        );
+
+        // errors still carry CORS headers so the browser can read them
+        let req = Request::builder()
+            .method(Method::POST)
+            .header("origin", "https://chat.example.com")
+            .body(Body::empty())
+            .unwrap();
+        let resp = proxy_handler(Path("chat/completions".into()), State(state), req)
+            .await
+            .into_response();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            resp.headers()["access-control-allow-origin"],
+            "https://chat.example.com"
+        );
+    }
+
+    #[tokio::test]
+    async fn per_key_origins_are_enforced() {
+        let server = MockServer::start_async().await;
+        let mock = server.mock(|when, then| {
+            when.method("POST").path("/v1/chat/completions");
+            then.status(200).header("access-control-allow-origin", "*").body("ok");
+        });
+
+        let mut state = test_state(server.url(""), &["webkey"]);
+        {
+            let mut dir = state.directory.write().unwrap();
+            dir.users.keys.insert("web".into(), "webkey".into());
+            dir.users.origins.insert("web".into(), vec!["https://chat.example.com".into()]);
+        }
+        state.cors = Some(CorsConfig {
+            allowed_origins: vec!["*".into()],
+            ..Default::default()
+        });
+
+        let call = |origin: Option<&str>| {
+            let mut req = authorized(Method::POST, "webkey");
+            if let Some(origin) = origin {
+                req = req.header("origin", origin);
+            }
+            send(&state, "chat/completions", None, req.body(Body::from("{}")).unwrap())
+        };
+
+        let ok = call(Some("https://chat.example.com")).await;
+        assert_eq!(ok.status(), StatusCode::OK);
+        // Ollama's own CORS header is replaced by ours
+        assert_eq!(ok.headers()["access-control-allow-origin"], "*");
+        assert_eq!(call(Some("https://evil.example")).await.status(), StatusCode::FORBIDDEN);
+        assert_eq!(call(None).await.status(), StatusCode::FORBIDDEN);
+        mock.assert_calls(1);
+    }
+
+    #[tokio::test]
+    async fn forwards_client_address_from_trusted_proxy() {
+        let server = MockServer::start_async().await;
+        let mock = server.mock(|when, then| {
+            when.method("POST")
+                .path("/v1/chat/completions")
+                .header("x-forwarded-for", "198.51.100.7, 10.0.0.5")
+                .header("x-forwarded-proto", "https")
+                .header("x-forwarded-host", "llm.example.com");
+            then.status(200);
+        });
+        let spoofed = server.mock(|when, then| {
+            when.method("POST")
+                .path("/v1/chat/completions")
+                .header("x-forwarded-for", "203.0.113.9")
+                .header("x-forwarded-proto", "http")
+                .header("x-forwarded-host", "shim:3000");
+            then.status(200);
+        });
+
+        let mut state = test_state(server.url(""), &["goodkey"]);
+        state.forwarded.trusted_proxies = vec!["10.0.0.0/8".parse().unwrap()];
+
+        let call = |peer| {
+            let req = authorized(Method::POST, "goodkey")
+                .header("host", "shim:3000")
+                .header("x-forwarded-for", "198.51.100.7")
+                .header("x-forwarded-proto", "https")
+                .header("x-forwarded-host", "llm.example.com")
+                .body(Body::from("{}"))
+                .unwrap();
+            send(&state, "chat/completions", Some(peer), req)
+        };
+
+        assert_eq!(call("10.0.0.5:40000").await.status(), StatusCode::OK);
+        // the same headers from an untrusted peer are replaced, not relayed
+        assert_eq!(call("203.0.113.9:40000").await.status(), StatusCode::OK);
+        mock.assert();
+        spoofed.assert();
+    }
+
+    #[tokio::test]
+    async fn address_allowlists_and_deny_list() {
+        let server = MockServer::start_async().await;
+        let mock = server.mock(|when, then| {
+            when.method("POST").path("/v1/embeddings");
+            then.status(200);
+        });
+
+        let mut state = test_state(server.url(""), &["cikey", "openkey"]);
+        {
+            let mut dir = state.directory.write().unwrap();
+            dir.users.keys.insert("ci".into(), "cikey".into());
+            dir.users.keys.insert("open".into(), "openkey".into());
+            dir.users.ips.insert("ci".into(), vec!["10.1.0.0/16".parse().unwrap()]);
+        }
+        state.denied_ips = vec!["203.0.113.0/24".parse().unwrap()];
+
+        let call = |key, peer| {
+            let req = authorized(Method::POST, key).body(Body::from("{}")).unwrap();
+            send(&state, "embeddings", Some(peer), req)
+        };
+
+        assert_eq!(call("cikey", "10.1.4.2:5000").await.status(), StatusCode::OK);
+        assert_eq!(call("cikey", "10.2.4.2:5000").await.status(), StatusCode::FORBIDDEN);
+        assert_eq!(call("openkey", "10.2.4.2:5000").await.status(), StatusCode::OK);
+        assert_eq!(call("openkey", "203.0.113.50:5000").await.status(), StatusCode::FORBIDDEN);
+        assert_eq!(state.denials.count(Some("ci")), 1);
+        assert_eq!(state.denials.count(None), 1);
+        mock.assert_calls(2);
+    }
+
+    #[tokio::test]
+    async fn roles_restrict_routes_and_methods() {
+        let server = MockServer::start_async().await;
+        let embeddings = server.mock(|when, then| {
+            when.method("POST").path("/v1/embeddings");
+            then.status(200);
+        });
+        let models = server.mock(|when, then| {
+            when.method("GET").path("/v1/models");
+            then.status(200);
+        });
+
+        let mut state = test_state(server.url(""), &["indexer", "dash", "anon"]);
+        state.roles = Roles::with_custom(&BTreeMap::from([(
+            "dashboard".to_string(),
+            vec!["GET models".to_string()],
+        )]))
+        .unwrap();
+        {
+            let mut dir = state.directory.write().unwrap();
+            dir.users.keys.insert("indexer".into(), "indexer".into());
+            dir.users.roles.insert("indexer".into(), "embeddings-only".into());
+            dir.users.keys.insert("dash".into(), "dash".into());
+            dir.users.roles.insert("dash".into(), "dashboard".into());
+        }
+
+        let call = |key, method, path| send(&state, path, None, authorized(method, key).body(Body::empty()).unwrap());
+
+        assert_eq!(call("indexer", Method::POST, "embeddings").await.status(), StatusCode::OK);
+        assert_eq!(call("indexer", Method::GET, "models").await.status(), StatusCode::FORBIDDEN);
+        assert_eq!(call("dash", Method::GET, "models").await.status(), StatusCode::OK);
+        assert_eq!(call("dash", Method::POST, "models").await.status(), StatusCode::FORBIDDEN);
+        assert_eq!(call("dash", Method::POST, "embeddings").await.status(), StatusCode::FORBIDDEN);
+        // keys without a user have the default role
+        assert_eq!(call("anon", Method::POST, "embeddings").await.status(), StatusCode::OK);
+        embeddings.assert_calls(2);
+        models.assert_calls(1);
+    }
+
+    #[tokio::test]
+    async fn scoped_tokens_minted_from_keys() {
+        let server = MockServer::start_async().await;
+        let chat = server.mock(|when, then| {
+            when.method("POST").path("/v1/chat/completions");
+            then.status(200);
+        });
+
+        let state = test_state(server.url(""), &["alice-key", "reader-key", "spent-key"]);
+        {
+            let mut dir = state.directory.write().unwrap();
+            dir.users.keys.insert("alice".into(), "alice-key".into());
+            dir.users.keys.insert("reader".into(), "reader-key".into());
+            dir.users.roles.insert("reader".into(), "readonly".into());
+            dir.users.keys.insert("spent".into(), "spent-key".into());
+            dir.users.quotas.insert("spent".into(), 0);
+        }
+
+        let call = |key: &str, path, body: &'static str| {
+            send(&state, path, None, authorized(Method::POST, key).body(Body::from(body)).unwrap())
+        };
+
+        let resp = call(
+            "alice-key",
+            "shim/tokens",
+            r#"{"ttl_secs": 60, "models": ["llama3*"], "routes": ["chat/*"]}"#,
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let minted: serde_json::Value =
+            serde_json::from_slice(&body::to_bytes(resp.into_body(), usize::MAX).await.unwrap()).unwrap();
+        let token = minted["token"].as_str().unwrap().to_string();
+        assert!(minted["expires_at"].as_u64().is_some());
+
+        let ok = call(&token, "chat/completions", r#"{"model":"llama3:8b"}"#).await;
+        assert_eq!(ok.status(), StatusCode::OK);
+        let wrong_model = call(&token, "chat/completions", r#"{"model":"mistral"}"#).await;
+        assert_eq!(wrong_model.status(), StatusCode::FORBIDDEN);
+        let wrong_route = call(&token, "embeddings", r#"{"model":"llama3"}"#).await;
+        assert_eq!(wrong_route.status(), StatusCode::FORBIDDEN);
+        // tokens cannot be used to mint more tokens
+        assert_eq!(call(&token, "shim/tokens", "").await.status(), StatusCode::FORBIDDEN);
+        let too_long = call("alice-key", "shim/tokens", r#"{"ttl_secs": 86400}"#).await;
+        assert_eq!(too_long.status(), StatusCode::BAD_REQUEST);
+        // nor can users whose role or quota would refuse the route
+        assert_eq!(call("reader-key", "shim/tokens", "").await.status(), StatusCode::FORBIDDEN);
+        assert_eq!(call("spent-key", "shim/tokens", "").await.status(), StatusCode::TOO_MANY_REQUESTS);
+        chat.assert_calls(1);
+    }
+
+    #[tokio::test]
+    async fn forward_auth_decides_access() {
+        let server = MockServer::start_async().await;
+        server.mock(|when, then| {
+            when.path("/auth").header("authorization", "Bearer org-token");
+            then.status(204).header("x-user", "carol");
+        });
+        server.mock(|when, then| {
+            when.path("/auth").header("authorization", "Bearer revoked");
+            then.status(401);
+        });
+        let models = server.mock(|when, then| {
+            when.method("GET").path("/v1/models").header_missing("authorization");
+            then.status(200);
+        });
+
+        let mut state = test_state(server.url(""), &[]);
+        state.forward_auth = Some(ForwardAuth::new(ForwardAuthConfig::new(server.url("/auth"))));
+        {
+            let mut dir = state.directory.write().unwrap();
+            dir.users.quotas.insert("carol".into(), 1);
+        }
+
+        let request = |token| authorized(Method::GET, token).body(Body::empty()).unwrap();
+
+        assert_eq!(send(&state, "models", None, request("org-token")).await.status(), StatusCode::OK);
+        // the service's user is subject to the shim's limits
+        let over_quota = send(&state, "models", None, request("org-token")).await;
+        assert_eq!(over_quota.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(state.usage.get("carol").total_requests, 1);
+        assert_eq!(send(&state, "models", None, request("revoked")).await.status(), StatusCode::UNAUTHORIZED);
+        models.assert_calls(1);
+
+        state.forward_auth = Some(ForwardAuth::new(ForwardAuthConfig::new(server.url("/missing"))));
+        assert_eq!(send(&state, "models", None, request("org-token")).await.status(), StatusCode::BAD_GATEWAY);
+    }
+
+    #[tokio::test]
+    async fn caches_deterministic_responses() {
+        let server = MockServer::start_async().await;
+        let json = server.mock(|when, then| {
+            when.method("POST").path("/v1/chat/completions").body_includes("\"stream\":false");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(r#"{"choices":[{"message":{"content":"4"}}]}"#);
+        });
+        let sse = server.mock(|when, then| {
+            when.method("POST").path("/v1/chat/completions").body_includes("\"stream\":true");
+            then.status(200)
+                .header("content-type", "text/event-stream")
+                .body("data: {\"n\":1}\n\ndata: [DONE]\n\n");
+        });
+
+        let mut state = test_state(server.url(""), &["goodkey"]);
+        state.cache = Some(ResponseCache::new(CacheConfig::default()));
+
+        let state = &state;
+        let call = |body: &'static str, cache_control: Option<&'static str>| async move {
+            let mut req = authorized(Method::POST, "goodkey");
+            if let Some(cc) = cache_control {
+                req = req.header("cache-control", cc);
+            }
+            let resp = send(state, "chat/completions", None, req.body(Body::from(body)).unwrap()).await;
+            let flag = resp
+                .headers()
+                .get("x-shim-cache")
+                .map(|v| v.to_str().unwrap().to_string());
+            let body = body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+            (flag, String::from_utf8(body.to_vec()).unwrap())
+        };
+
+        let q = r#"{"model":"llama3","temperature":0,"stream":false,"messages":[{"role":"user","content":"2+2"}]}"#;
+        let same = r#"{"stream":false, "messages":[{"content":"2+2","role":"user"}], "temperature":0, "model":"llama3"}"#;
+        let (flag, miss) = call(q, None).await;
+        assert_eq!(flag.as_deref(), Some("MISS"));
+        let (flag, hit) = call(same, None).await;
+        assert_eq!(flag.as_deref(), Some("HIT"));
+        assert_eq!(hit, miss);
+        assert_eq!(call(q, Some("no-cache")).await.0.as_deref(), Some("MISS"));
+        json.assert_calls(2);
+
+        let streamed = r#"{"model":"llama3","temperature":0,"stream":true,"messages":[]}"#;
+        assert_eq!(call(streamed, None).await.0.as_deref(), Some("MISS"));
+        let (flag, replayed) = call(streamed, None).await;
+        assert_eq!(flag.as_deref(), Some("HIT"));
+        assert_eq!(replayed, "data: {\"n\":1}\n\ndata: [DONE]\n\n");
+        sse.assert_calls(1);
+
+        // non-deterministic requests are not cached at all
+        let warm = r#"{"model":"llama3","temperature":0.8,"stream":false,"messages":[]}"#;
+        assert_eq!(call(warm, None).await.0, None);
+    }
+
+    #[tokio::test]
+    async fn cache_keeps_per_user_native_options_apart() {
+        let server = MockServer::start_async().await;
+        let tuned = server.mock(|when, then| {
+            when.method("POST").path("/api/chat").body_includes(r#""num_ctx":8192"#);
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(r#"{"model":"llama3","message":{"role":"assistant","content":"tuned"},"done":true}"#);
+        });
+        let plain = server.mock(|when, then| {
+            when.method("POST").path("/api/chat").body_excludes("num_ctx");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(r#"{"model":"llama3","message":{"role":"assistant","content":"plain"},"done":true}"#);
+        });
+
+        let mut state = test_state(server.url(""), &["alice-key", "bob-key"]);
+        {
+            let mut dir = state.directory.write().unwrap();
+            dir.users.keys.insert("alice".into(), "alice-key".into());
+            dir.users.keys.insert("bob".into(), "bob-key".into());
+        }
+        let mut native = NativeChatConfig::default();
+        native.users.insert("bob".into(), serde_json::json!({ "num_ctx": 8192 }).as_object().unwrap().clone());
+        state.native_chat = Some(NativeChat::new(native));
+        state.cache = Some(ResponseCache::new(CacheConfig::default()));
+
+        let state = &state;
+        let call = |key: &'static str| async move {
+            let req = authorized(Method::POST, key)
+                .body(Body::from(r#"{"model":"llama3","temperature":0,"messages":[]}"#))
+                .unwrap();
+            let resp = send(state, "chat/completions", None, req).await;
+            let flag = resp.headers()["x-shim-cache"].to_str().unwrap().to_string();
+            let body = body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+            (flag, String::from_utf8(body.to_vec()).unwrap())
+        };
+        assert_eq!(call("alice-key").await.0, "MISS");
+        let (flag, body) = call("bob-key").await;
+        assert_eq!(flag, "MISS");
+        assert!(body.contains("tuned"));
+        let (flag, body) = call("alice-key").await;
+        assert_eq!(flag, "HIT");
+        assert!(body.contains("plain"));
+        tuned.assert_calls(1);
+        plain.assert_calls(1);
+    }
+
+    #[tokio::test]
+    async fn embeds_only_uncached_inputs() {
+        let server = MockServer::start_async().await;
+        let both = server.mock(|when, then| {
+            when.method("POST").path("/v1/embeddings").body_includes(r#""input":["a","b"]"#);
+            then.status(200).header("content-type", "application/json").body(
+                r#"{"object":"list","model":"nomic","usage":{"prompt_tokens":4,"total_tokens":4},
+                    "data":[{"object":"embedding","index":1,"embedding":[0.2]},
+                            {"object":"embedding","index":0,"embedding":[0.1]}]}"#,
+            );
+        });
+        let third = server.mock(|when, then| {
+            when.method("POST").path("/v1/embeddings").body_includes(r#""input":["c"]"#);
+            then.status(200).header("content-type", "application/json").body(
+                r#"{"object":"list","model":"nomic","usage":{"prompt_tokens":3,"total_tokens":3},
+                    "data":[{"object":"embedding","index":0,"embedding":[0.3]}]}"#,
+            );
+        });
+
+        let tmp = tempfile::NamedTempFile::new().unwrap();
+        let mut state = test_state(server.url(""), &["goodkey"]);
+        state.embedding_cache = Some(EmbeddingCache::new(EmbeddingCacheConfig {
+            sqlite: tmp.path().to_str().unwrap().to_string(),
+            max_bytes: 1024 * 1024,
+        }));
+        let state = &state;
+        let call = |body: &'static str| async move {
+            let req = authorized(Method::POST, "goodkey").body(Body::from(body)).unwrap();
+            let resp = send(state, "embeddings", None, req).await;
+            let flag = resp.headers()["x-shim-cache"].to_str().unwrap().to_string();
+            let body = body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+            (flag, serde_json::from_slice::<serde_json::Value>(&body).unwrap())
+        };
+
+        let (flag, resp) = call(r#"{"model":"nomic","input":["a","b","a"]}"#).await;
+        assert_eq!(flag, "MISS");
+        assert_eq!(resp["data"][2]["embedding"], serde_json::json!([0.1]));
+        assert_eq!(resp["data"][1]["embedding"], serde_json::json!([0.2]));
+        assert_eq!(resp["data"][2]["index"], 2);
+
+        let (flag, resp) = call(r#"{"model":"nomic","input":["c","b"]}"#).await;
+        assert_eq!(flag, "PARTIAL");
+        assert_eq!(resp["data"][0]["embedding"], serde_json::json!([0.3]));
+        assert_eq!(resp["data"][1]["embedding"], serde_json::json!([0.2]));
+        assert_eq!(resp["usage"]["prompt_tokens"], 5);
+
+        let (flag, resp) = call(r#"{"model":"nomic","input":"a"}"#).await;
+        assert_eq!(flag, "HIT");
+        assert_eq!(resp["usage"]["prompt_tokens"], 2);
+        both.assert_calls(1);
+        third.assert_calls(1);
+    }
+
+    #[tokio::test]
+    async fn rewrites_bodies_by_policy() {
+        let server = MockServer::start_async().await;
+        let mock = server.mock(|when, then| {
+            when.method("POST")
+                .path("/v1/chat/completions")
+                .body_includes(r#""max_tokens":64"#)
+                .body_includes(r#"{"content":"Answer in English.","role":"system"}"#);
+            then.status(200).body("ok");
+        });
+
+        let state = test_state(server.url(""), &["alicekey"]);
+        {
+            let mut dir = state.directory.write().unwrap();
+            dir.users.keys.insert("alice".into(), "alicekey".into());
+            dir.users.policies.users.insert(
+                "alice".into(),
+                Policy {
+                    max_tokens: Some(64),
+                    ..Default::default()
+                },
+            );
+            dir.users.policies.models.insert(
+                "llama3*".into(),
+                Policy {
+                    system_prompt: Some("Answer in English.".into()),
+                    ..Default::default()
+                },
+            );
+        }
+        let req = Request::builder()
+            .method(Method::POST)
+            .header("authorization", "Bearer alicekey")
+            .body(Body::from(r#"{"model":"llama3","max_tokens":4096,"messages":[]}"#))
+            .unwrap();
+        let resp = proxy_handler(Path("chat/completions".into()), State(state), req)
+            .await
+            .into_response();
+        assert_eq!(resp.status(), StatusCode::OK);
+        mock.assert_calls(1);
+    }
+
+    #[tokio::test]
+    async fn policies_cap_native_options() {
+        let server = MockServer::start_async().await;
+        let mock = server.mock(|when, then| {
+            when.method("POST")
+                .path("/api/chat")
+                .body_includes(r#""num_predict":64"#)
+                .body_includes(r#""temperature":1.0"#)
+                .body_includes(r#""keep_alive":"5m""#)
+                .body_excludes("100000")
+                .body_excludes("24h");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(r#"{"model":"llama3","message":{"role":"assistant","content":"ok"},"done":true}"#);
+        });
+
+        let mut state = test_state(server.url(""), &["goodkey"]);
+        state.native_chat = Some(NativeChat::new(NativeChatConfig::default()));
+        state.directory.write().unwrap().users.policies.models.insert(
+            "*".into(),
+            Policy {
+                max_tokens: Some(64),
+                max_temperature: Some(1.0),
+                keep_alive: Some(serde_json::json!("5m")),
+                ..Default::default()
+            },
+        );
+        let body = r#"{"model":"llama3","messages":[],"max_tokens":10,
+            "options":{"num_predict":100000,"temperature":2,"keep_alive":"24h"}}"#;
+        let req = Request::builder()
+            .method(Method::POST)
+            .header("authorization", "Bearer goodkey")
+            .body(Body::from(body))
+            .unwrap();
+        let resp = proxy_handler(Path("chat/completions".into()), State(state.clone()), req)
+            .await
+            .into_response();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // Ollama reads a negative num_predict as "no limit"
+        let req = Request::builder()
+            .method(Method::POST)
+            .header("authorization", "Bearer goodkey")
+            .body(Body::from(r#"{"model":"llama3","messages":[],"options":{"num_predict":-1,"temperature":1.5}}"#))
+            .unwrap();
+        let resp = proxy_handler(Path("chat/completions".into()), State(state), req)
+            .await
+            .into_response();
+        assert_eq!(resp.status(), StatusCode::OK);
+        mock.assert_calls(2);
+    }
+
+    #[tokio::test]
+    async fn enforces_context_windows() {
+        let server = MockServer::start_async().await;
+        let mock = server.mock(|when, then| {
+            when.method("POST").path("/v1/chat/completions").body_includes("latest");
+            then.status(200).body("ok");
+        });
+
+        let mut state = test_state(server.url(""), &["goodkey"]);
+        state.context = Some(ContextConfig {
+            limits: [("llama3".to_string(), 20)].into(),
+            overflow: Overflow::Truncate,
+            chars_per_token: 1.0,
+        });
+        let body = r#"{"model":"llama3","messages":[{"role":"user","content":"an old question"},{"role":"user","content":"latest"}]}"#;
+        let request = || authorized(Method::POST, "goodkey").body(Body::from(body)).unwrap();
+        let resp = send(&state, "chat/completions", None, request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()["x-shim-truncated"], "1");
+        mock.assert_calls(1);
+
+        state.context.as_mut().unwrap().overflow = Overflow::Reject;
+        let resp = send(&state, "chat/completions", None, request()).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body = body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"]["code"], "context_length_exceeded");
+        mock.assert_calls(1);
+    }
+
+    #[tokio::test]
+    async fn transforms_thinking_per_request() {
+        let server = MockServer::start_async().await;
+        let mock = server.mock(|when, then| {
+            when.method("POST").path("/v1/chat/completions");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(r#"{"choices":[{"index":0,"message":{"role":"assistant","content":"<think>2+2=4</think>\n4"}}]}"#);
+        });
+
+        let mut state = test_state(server.url(""), &["goodkey"]);
+        state.cache = Some(ResponseCache::new(CacheConfig::default()));
+        state.think.models.insert("deepseek-r1*".into(), ThinkMode::Separate);
+        let state = &state;
+        let call = |think: Option<&'static str>| async move {
+            let body = r#"{"model":"deepseek-r1:8b","temperature":0,"messages":[{"role":"user","content":"2+2"}]}"#;
+            let mut req = authorized(Method::POST, "goodkey");
+            if let Some(mode) = think {
+                req = req.header("x-shim-think", mode);
+            }
+            let resp = send(state, "chat/completions", None, req.body(Body::from(body)).unwrap()).await;
+            let body = body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            body["choices"][0]["message"].clone()
+        };
+
+        let message = call(None).await;
+        assert_eq!(message["content"], "4");
+        assert_eq!(message["reasoning_content"], "2+2=4");
+        // the cache holds the raw answer, so later requests choose again
+        let message = call(Some("strip")).await;
+        assert_eq!(message["content"], "4");
+        assert!(message.get("reasoning_content").is_none());
+        assert_eq!(call(Some("keep")).await["content"], "<think>2+2=4</think>\n4");
+        mock.assert_calls(1);
+    }
+
+    #[tokio::test]
+    async fn retries_invalid_structured_output() {
+        let server = MockServer::start_async().await;
+        let first = server.mock(|when, then| {
+            when.method("POST").path("/v1/chat/completions").body_excludes("does not match");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(r#"{"choices":[{"index":0,"message":{"role":"assistant","content":"{\"age\": \"three\"}"}}]}"#);
+        });
+        let retry = server.mock(|when, then| {
+            when.method("POST")
+                .path("/v1/chat/completions")
+                .body_includes("$.age should be integer, got string");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(r#"{"choices":[{"index":0,"message":{"role":"assistant","content":"```json\n{\"age\": 3}\n```"}}]}"#);
+        });
+
+        let mut state = test_state(server.url(""), &["goodkey"]);
+        state.schema = Some(SchemaConfig { retries: 1 });
+        let body = r#"{"model":"llama3","messages":[{"role":"user","content":"how old?"}],
+            "response_format":{"type":"json_schema","json_schema":{"name":"age","schema":{"type":"object","properties":{"age":{"type":"integer"}},"required":["age"]}}}}"#;
+        let request = || authorized(Method::POST, "goodkey").body(Body::from(body)).unwrap();
+        let read = |resp: Response<Body>| async move {
+            let status = resp.status();
+            let attempts = resp.headers()["x-shim-schema-attempts"].to_str().unwrap().to_string();
+            let body = body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+            (status, attempts, serde_json::from_slice::<serde_json::Value>(&body).unwrap())
+        };
+
+        let (status, attempts, body) = read(send(&state, "chat/completions", None, request()).await).await;
+        assert_eq!((status, attempts.as_str()), (StatusCode::OK, "2"));
+        assert_eq!(body["choices"][0]["message"]["content"], r#"{"age":3}"#);
+        first.assert_calls(1);
+        retry.assert_calls(1);
+
+        state.schema = Some(SchemaConfig { retries: 0 });
+        let (status, attempts, body) = read(send(&state, "chat/completions", None, request()).await).await;
+        assert_eq!((status, attempts.as_str()), (StatusCode::UNPROCESSABLE_ENTITY, "1"));
+        assert_eq!(body["error"]["code"], "json_schema_validation_failed");
+        first.assert_calls(2);
+    }
+
+    #[tokio::test]
+    async fn emulates_tool_calls_when_streaming() {
+        let server = MockServer::start_async().await;
+        let mock = server.mock(|when, then| {
+            when.method("POST")
+                .path("/v1/chat/completions")
+                .body_includes("You can call these tools")
+                .body_includes("\"stream\":false")
+                .body_excludes("\"tools\"");
+            then.status(200)
+                .header("content-type", "application/json")
+                .body(r#"{"id":"chatcmpl-1","model":"gemma2","choices":[{"index":0,"message":{"role":"assistant","content":"{\"tool_calls\":[{\"name\":\"get_time\",\"arguments\":{}}]}"},"finish_reason":"stop"}]}"#);
+        });
+
+        let mut state = test_state(server.url(""), &["goodkey"]);
+        state.tools.models.insert("gemma*".into(), ToolMode::Emulate);
+        let body = r#"{"model":"gemma2","stream":true,"messages":[{"role":"user","content":"time?"}],
+            "tools":[{"type":"function","function":{"name":"get_time","parameters":{"type":"object"}}}]}"#;
+        let req = Request::builder()
+            .method(Method::POST)
+            .header("authorization", "Bearer goodkey")
+            .body(Body::from(body))
+            .unwrap();
+        let resp = proxy_handler(Path("chat/completions".into()), State(state), req)
+            .await
+            .into_response();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()["content-type"], "text/event-stream");
+        let body = body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+        let events: Vec<serde_json::Value> = String::from_utf8(body.to_vec())
+            .unwrap()
+            .split("\n\n")
+            .filter_map(|e| e.strip_prefix("data: "))
+            .filter_map(|e| serde_json::from_str(e).ok())
+            .collect();
+        assert_eq!(events[0]["object"], "chat.completion.chunk");
+        assert_eq!(events[0]["choices"][0]["delta"]["tool_calls"][0]["function"]["name"], "get_time");
+        assert_eq!(events[1]["choices"][0]["finish_reason"], "tool_calls");
+        mock.assert_calls(1);
+    }
+
+    #[tokio::test]
+    async fn bans_addresses_guessing_keys() {
+        let server = MockServer::start_async().await;
+        let mock = server.mock(|when, then| {
+            when.method("GET").path("/v1/models");
+            then.status(200);
+        });
+
+        let mut state = test_state(server.url(""), &["goodkey"]);
+        state.guard = AuthGuard::new(
+            BanConfig {
+                threshold: 2,
+                delay_step: Duration::from_millis(1),
+                max_delay: Duration::from_millis(2),
+                ..Default::default()
+            },
+            None,
+        );
+
+        let call = |key, peer| {
+            send(&state, "models", Some(peer), authorized(Method::GET, key).body(Body::empty()).unwrap())
+        };
+
+        assert_eq!(call("guess1", "192.0.2.1:1").await.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(call("guess2", "192.0.2.1:1").await.status(), StatusCode::UNAUTHORIZED);
+        // banned now, even with the right key
+        let banned = call("goodkey", "192.0.2.1:1").await;
+        assert_eq!(banned.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(banned.headers()["retry-after"], "900");
+        assert_eq!(call("goodkey", "192.0.2.2:1").await.status(), StatusCode::OK);
+        mock.assert_calls(1);
+    }
+
+    #[tokio::test]
+    async fn request_header_matrix() {
+        let server = MockServer::start_async().await;
+        let mock = server.mock(|when, then| {
+            when.method("POST")
+                .path("/v1/embeddings")
+                .header("content-type", "application/json")
+                .header_count("x-multi", ".*", 2)
+                .header("x-multi", "b")
+                .header_missing("authorization")
+                .header_missing("keep-alive")
+                .header_missing("te")
+                .header_missing("x-hop")
+                .header_missing("proxy-authorization");
+            then.status(200).body("ok");
+        });
+
+        let state = test_state(server.url(""), &["goodkey"]);
+        let req = Request::builder()
+            .method(Method::POST)
+            .header("authorization", "Bearer goodkey")
+            .header("content-type", "application/json")
+            .header("connection", "keep-alive, x-hop")
+            .header("keep-alive", "timeout=5")
+            .header("te", "trailers")
+            .header("x-hop", "1")
+            .header("proxy-authorization", "Basic Zm9v")
+            .header("x-multi", "a")
+            .header("x-multi", "b")
+            .body(Body::from("{}"))
+            .unwrap();
+        let resp = proxy_handler(Path("embeddings".into()), State(state), req)
+            .await
+            .into_response();
+        assert_eq!(resp.status(), StatusCode::OK);
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn response_header_matrix() {
+        use std::io::Write;
+
+        let payload = br#"{"object":"list","data":[]}"#;
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gz.write_all(payload).unwrap();
+        let compressed = gz.finish().unwrap();
+
+        let server = MockServer::start_async().await;
+        server.mock(|when, then| {
+            when.method("GET").path("/v1/models");
+            then.status(200)
+                .header("content-encoding", "gzip")
+                .header("content-type", "application/json")
+                .header("connection", "x-internal")
+                .header("x-internal", "secret")
+                .header("set-cookie", "a=1")
+                .header("set-cookie", "b=2")
+                .body(compressed);
+        });
+
+        let state = test_state(server.url(""), &["goodkey"]);
+        let req = Request::builder()
+            .header("authorization", "Bearer goodkey")
+            .header("accept-encoding", "br")
+            .body(Body::empty())
+            .unwrap();
+        let resp = proxy_handler(Path("models".into()), State(state), req)
+            .await
+            .into_response();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let h = resp.headers();
+        // reqwest decoded the body, so neither the encoding nor the
+        // compressed length may reach the client
+        assert!(!h.contains_key("content-encoding"));
+        assert!(!h.contains_key("content-length"));
+        assert!(!h.contains_key("connection"));
+        assert!(!h.contains_key("x-internal"));
+        let cookies: Vec<_> = h.get_all("set-cookie").iter().collect();
+        assert_eq!(cookies, vec!["a=1", "b=2"]);
+        let body = body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], payload);
+    }
 }
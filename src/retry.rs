@@ -0,0 +1,261 @@
+use std::{
+    collections::{HashMap, hash_map::RandomState},
+    hash::{BuildHasher, Hasher},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use hyper::Method;
+
+/// Settings controlling how `forward_request` retries failed upstream calls.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first one.  `1` disables
+    /// retries entirely.
+    pub max_attempts: u32,
+    /// Backoff before the first retry; doubled for every further attempt.
+    pub base_delay: Duration,
+    /// Upper bound for a single backoff period.
+    pub max_delay: Duration,
+    /// Routes (relative to `/v1/`) that may be retried even though the HTTP
+    /// method is not idempotent, e.g. `embeddings`.
+    pub safe_routes: Vec<String>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(2),
+            safe_routes: Vec::new(),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Whether a request is safe to send more than once.  Idempotent methods
+    /// always are; anything else only when the route was whitelisted.
+    pub fn is_retry_safe(&self, method: &Method, path: &str) -> bool {
+        let idempotent = matches!(
+            *method,
+            Method::GET | Method::HEAD | Method::OPTIONS | Method::PUT | Method::DELETE
+        );
+        let path = path.trim_matches('/');
+        idempotent || self.safe_routes.iter().any(|r| r.trim_matches('/') == path)
+    }
+
+    /// Backoff to wait before retry number `retry` (starting at 1), using
+    /// "full jitter": a random value between zero and the exponential cap.
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let exp = self
+            .base_delay
+            .saturating_mul(1u32 << retry.saturating_sub(1).min(16));
+        jitter(exp.min(self.max_delay))
+    }
+}
+
+/// Pick a uniformly distributed duration in `[0, max]`.  The randomness comes
+/// from the per-process random keys of `RandomState`, which is plenty for
+/// spreading out retries and saves pulling in a dedicated RNG crate.
+fn jitter(max: Duration) -> Duration {
+    let mut hasher = RandomState::new().build_hasher();
+    if let Ok(now) = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+        hasher.write_u128(now.as_nanos());
+    }
+    let nanos = max.as_nanos() as u64;
+    if nanos == 0 {
+        return Duration::ZERO;
+    }
+    Duration::from_nanos(hasher.finish() % (nanos + 1))
+}
+
+/// Thresholds for the per-backend circuit breaker.
+#[derive(Clone, Debug)]
+pub struct BreakerConfig {
+    /// Consecutive failures after which the breaker opens.
+    pub failure_threshold: u32,
+    /// How long the breaker stays open before letting a probe through.
+    pub cooldown: Duration,
+}
+
+impl Default for BreakerConfig {
+    fn default() -> Self {
+        BreakerConfig {
+            failure_threshold: 5,
+            cooldown: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BreakerState {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    /// The cooldown has elapsed and a single probe request is in flight.  A
+    /// probe that never reports back (e.g. the client went away) is replaced
+    /// by a new one after another cooldown.
+    HalfOpen { since: Instant },
+}
+
+/// Classic three-state circuit breaker guarding one upstream backend.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    config: BreakerConfig,
+    state: Mutex<BreakerState>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: BreakerConfig) -> Self {
+        CircuitBreaker {
+            config,
+            state: Mutex::new(BreakerState::Closed { failures: 0 }),
+        }
+    }
+
+    /// Ask for permission to send a request.  Returns `false` while the
+    /// breaker is open, or while another request is already probing a
+    /// half-open backend.
+    pub fn try_acquire(&self) -> bool {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        match *state {
+            BreakerState::Closed { .. } => true,
+            BreakerState::Open { until } if now >= until => {
+                *state = BreakerState::HalfOpen { since: now };
+                true
+            }
+            BreakerState::HalfOpen { since } if now >= since + self.config.cooldown => {
+                *state = BreakerState::HalfOpen { since: now };
+                true
+            }
+            BreakerState::Open { .. } | BreakerState::HalfOpen { .. } => false,
+        }
+    }
+
+    pub fn record_success(&self) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        *state = BreakerState::Closed { failures: 0 };
+    }
+
+    /// Count a failed request.  Returns `true` when this failure tripped the
+    /// breaker open.
+    pub fn record_failure(&self) -> bool {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        match *state {
+            BreakerState::Closed { failures } if failures + 1 < self.config.failure_threshold => {
+                *state = BreakerState::Closed {
+                    failures: failures + 1,
+                };
+                false
+            }
+            BreakerState::Open { .. } => false,
+            _ => {
+                *state = BreakerState::Open {
+                    until: Instant::now() + self.config.cooldown,
+                };
+                true
+            }
+        }
+    }
+
+    #[cfg(test)]
+    pub fn is_open(&self) -> bool {
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        matches!(*state, BreakerState::Open { until } if Instant::now() < until)
+    }
+}
+
+/// Lazily created circuit breakers, one per backend base URL.
+#[derive(Clone, Debug, Default)]
+pub struct Breakers {
+    config: BreakerConfig,
+    inner: Arc<Mutex<HashMap<String, Arc<CircuitBreaker>>>>,
+}
+
+impl Breakers {
+    pub fn new(config: BreakerConfig) -> Self {
+        Breakers {
+            config,
+            inner: Arc::default(),
+        }
+    }
+
+    pub fn get(&self, backend: &str) -> Arc<CircuitBreaker> {
+        let mut map = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        map.entry(backend.to_string())
+            .or_insert_with(|| Arc::new(CircuitBreaker::new(self.config.clone())))
+            .clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_safety() {
+        let policy = RetryPolicy {
+            safe_routes: vec!["embeddings".into()],
+            ..Default::default()
+        };
+        assert!(policy.is_retry_safe(&Method::GET, "models"));
+        assert!(policy.is_retry_safe(&Method::DELETE, "models/foo"));
+        assert!(!policy.is_retry_safe(&Method::POST, "chat/completions"));
+        assert!(policy.is_retry_safe(&Method::POST, "embeddings"));
+        assert!(policy.is_retry_safe(&Method::POST, "/embeddings/"));
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        let policy = RetryPolicy {
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(250),
+            ..Default::default()
+        };
+        for retry in 1..40 {
+            let d = policy.backoff(retry);
+            assert!(d <= Duration::from_millis(250), "retry {retry}: {d:?}");
+        }
+        assert!(policy.backoff(1) <= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn breaker_opens_and_half_opens() {
+        let breaker = CircuitBreaker::new(BreakerConfig {
+            failure_threshold: 2,
+            cooldown: Duration::from_millis(20),
+        });
+        assert!(breaker.try_acquire());
+        breaker.record_failure();
+        assert!(!breaker.is_open());
+        breaker.record_failure();
+        assert!(breaker.is_open());
+        assert!(!breaker.try_acquire());
+
+        std::thread::sleep(Duration::from_millis(30));
+        // first caller after the cooldown gets to probe, the rest wait
+        assert!(breaker.try_acquire());
+        assert!(!breaker.try_acquire());
+        // failed probe re-opens immediately
+        breaker.record_failure();
+        assert!(breaker.is_open());
+
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(breaker.try_acquire());
+        breaker.record_success();
+        assert!(breaker.try_acquire());
+        assert!(breaker.try_acquire());
+    }
+
+    #[test]
+    fn breakers_are_per_backend() {
+        let breakers = Breakers::new(BreakerConfig {
+            failure_threshold: 1,
+            cooldown: Duration::from_secs(60),
+        });
+        breakers.get("http://a").record_failure();
+        assert!(breakers.get("http://a").is_open());
+        assert!(!breakers.get("http://b").is_open());
+    }
+}
@@ -0,0 +1,200 @@
+use std::collections::BTreeMap;
+
+use anyhow::Context;
+use hyper::Method;
+
+/// One allowed combination of methods and routes, written as
+/// `"METHOD pattern"`: e.g. `"GET models"`, `"GET,HEAD *"` or
+/// `"* chat/*"`.  Patterns are matched against the path below `/v1/` and
+/// `*` matches any run of characters, including `/`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Permission {
+    /// `None` allows every method.
+    methods: Option<Vec<Method>>,
+    pattern: String,
+}
+
+impl std::str::FromStr for Permission {
+    type Err = anyhow::Error;
+
+    fn from_str(rule: &str) -> Result<Self, Self::Err> {
+        let (methods, pattern) = rule
+            .trim()
+            .split_once(char::is_whitespace)
+            .with_context(|| format!("permission '{rule}' must look like \"METHOD route\""))?;
+        let methods = match methods {
+            "*" => None,
+            list => Some(
+                list.split(',')
+                    .map(|m| {
+                        Method::from_bytes(m.trim().to_ascii_uppercase().as_bytes())
+                            .with_context(|| format!("invalid method '{m}' in permission '{rule}'"))
+                    })
+                    .collect::<anyhow::Result<_>>()?,
+            ),
+        };
+        let pattern = pattern.trim();
+        let pattern = pattern
+            .strip_prefix("/v1/")
+            .or_else(|| pattern.strip_prefix('/'))
+            .unwrap_or(pattern);
+        Ok(Permission {
+            methods,
+            pattern: pattern.to_string(),
+        })
+    }
+}
+
+impl std::fmt::Display for Permission {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.methods {
+            None => write!(f, "* {}", self.pattern),
+            Some(methods) => {
+                let methods: Vec<_> = methods.iter().map(Method::as_str).collect();
+                write!(f, "{} {}", methods.join(","), self.pattern)
+            }
+        }
+    }
+}
+
+impl Permission {
+    pub fn allows(&self, method: &Method, path: &str) -> bool {
+        self.methods.as_ref().is_none_or(|m| m.contains(method)) && glob_match(&self.pattern, path)
+    }
+}
+
+/// `*`-only glob matching, as used by role rules and token scopes.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = text.strip_prefix(first) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        // no `*` at all
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+/// What each role may do.  Named users carry a role from the key store;
+/// keys without a user (from `API_KEYS` or a keys file) are treated as role
+/// `user`.  A role that is not defined here allows nothing.
+#[derive(Clone, Debug)]
+pub struct Roles {
+    roles: BTreeMap<String, Vec<Permission>>,
+}
+
+impl Default for Roles {
+    fn default() -> Self {
+        let builtin = [
+            ("admin", &["* *"][..]),
+            ("user", &["* *"]),
+            ("readonly", &["GET,HEAD *"]),
+            ("embeddings-only", &["POST embeddings", "POST embed"]),
+        ];
+        Roles {
+            roles: builtin
+                .iter()
+                .map(|(name, rules)| {
+                    let rules = rules.iter().map(|r| r.parse().expect("valid builtin rule")).collect();
+                    (name.to_string(), rules)
+                })
+                .collect(),
+        }
+    }
+}
+
+impl Roles {
+    /// The built-in roles with `custom` added; a custom role with a built-in
+    /// name replaces it.
+    pub fn with_custom(custom: &BTreeMap<String, Vec<String>>) -> anyhow::Result<Self> {
+        let mut roles = Roles::default();
+        for (name, rules) in custom {
+            let rules = rules
+                .iter()
+                .map(|r| r.parse())
+                .collect::<anyhow::Result<_>>()
+                .with_context(|| format!("invalid permissions for role '{name}'"))?;
+            roles.roles.insert(name.clone(), rules);
+        }
+        Ok(roles)
+    }
+
+    pub fn allows(&self, role: &str, method: &Method, path: &str) -> bool {
+        self.roles
+            .get(role)
+            .is_some_and(|rules| rules.iter().any(|p| p.allows(method, path)))
+    }
+
+    pub fn is_defined(&self, role: &str) -> bool {
+        self.roles.contains_key(role)
+    }
+
+    /// All roles as rule strings, e.g. for `config check`.
+    pub fn to_rules(&self) -> BTreeMap<String, Vec<String>> {
+        self.roles
+            .iter()
+            .map(|(name, rules)| (name.clone(), rules.iter().map(|r| r.to_string()).collect()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn globs() {
+        assert!(glob_match("models", "models"));
+        assert!(!glob_match("models", "models/x"));
+        assert!(glob_match("*", "chat/completions"));
+        assert!(glob_match("chat/*", "chat/completions"));
+        assert!(!glob_match("chat/*", "embeddings"));
+        assert!(glob_match("*/completions", "chat/completions"));
+        assert!(glob_match("a*b*c", "a-b-b-c"));
+        assert!(!glob_match("a*bc", "abc-bc-"));
+        assert!(!glob_match("ab*ba", "aba"));
+    }
+
+    #[test]
+    fn parses_rules() {
+        let p: Permission = "get,HEAD /v1/models".parse().unwrap();
+        assert_eq!(p.to_string(), "GET,HEAD models");
+        assert!(p.allows(&Method::HEAD, "models"));
+        assert!(!p.allows(&Method::POST, "models"));
+        assert!("models".parse::<Permission>().is_err());
+        assert!("G@T models".parse::<Permission>().is_err());
+    }
+
+    #[test]
+    fn builtin_and_custom_roles() {
+        let custom = BTreeMap::from([
+            ("dashboard".to_string(), vec!["GET models".to_string()]),
+            ("user".to_string(), vec!["POST chat/completions".to_string()]),
+        ]);
+        let roles = Roles::with_custom(&custom).unwrap();
+        assert!(roles.allows("admin", &Method::DELETE, "anything"));
+        assert!(roles.allows("readonly", &Method::GET, "models"));
+        assert!(!roles.allows("readonly", &Method::POST, "chat/completions"));
+        assert!(roles.allows("embeddings-only", &Method::POST, "embeddings"));
+        assert!(!roles.allows("embeddings-only", &Method::GET, "models"));
+        assert!(roles.allows("dashboard", &Method::GET, "models"));
+        assert!(!roles.allows("dashboard", &Method::GET, "models/llama3"));
+        // overridden built-in
+        assert!(!roles.allows("user", &Method::GET, "models"));
+        // undefined roles allow nothing
+        assert!(!roles.allows("typo", &Method::GET, "models"));
+
+        let bad = BTreeMap::from([("x".to_string(), vec!["nonsense".to_string()])]);
+        let err = Roles::with_custom(&bad).unwrap_err();
+        assert!(format!("{err:#}").contains("role 'x'"), "{err:#}");
+    }
+}
@@ -0,0 +1,420 @@
+use std::cell::Cell;
+
+use axum::{
+    Json,
+    body::{self, Body, Bytes},
+    http::{HeaderMap, HeaderValue, Method, Response, StatusCode},
+    response::IntoResponse,
+};
+use serde_json::{Value, json};
+
+use crate::native::CHAT_PATH;
+use crate::proxy::send_once;
+use crate::state::AppState;
+
+/// Response header with the number of upstream calls a validated request
+/// took.
+pub const ATTEMPTS_HEADER: &str = "x-shim-schema-attempts";
+
+/// Validation of structured chat output against the request's schema.
+#[derive(Clone, Debug)]
+pub struct SchemaConfig {
+    /// Extra upstream calls made after an answer fails validation.
+    pub retries: u32,
+}
+
+impl Default for SchemaConfig {
+    fn default() -> Self {
+        SchemaConfig { retries: 2 }
+    }
+}
+
+/// The schema a non-streaming chat request asks its answer to follow.
+fn requested_schema(request: &Value) -> Option<Value> {
+    if request.get("stream").and_then(Value::as_bool) == Some(true) {
+        return None;
+    }
+    let format = request.get("response_format")?;
+    match format.get("type").and_then(Value::as_str)? {
+        "json_schema" => format.get("json_schema")?.get("schema").cloned(),
+        "json_object" => Some(json!({ "type": "object" })),
+        _ => None,
+    }
+}
+
+/// Parse a model's answer as JSON, tolerating a Markdown code fence around
+/// it.
+fn parse_content(content: &str) -> Result<Value, String> {
+    let mut text = content.trim();
+    if let Some(fenced) = text.strip_prefix("```") {
+        let fenced = fenced.strip_prefix("json").unwrap_or(fenced);
+        text = fenced.strip_suffix("```").unwrap_or(fenced).trim();
+    }
+    serde_json::from_str(text).map_err(|e| format!("the answer is not valid JSON ({e})"))
+}
+
+/// Check every choice of a completion, rewriting each valid answer to bare
+/// JSON.  Returns the first failing answer and its error.
+fn check_completion(schema: &Value, completion: &mut Value) -> Result<(), (String, String)> {
+    let choices = completion.get_mut("choices").and_then(Value::as_array_mut);
+    for choice in choices.into_iter().flatten() {
+        let Some(message) = choice.get_mut("message").and_then(Value::as_object_mut) else { continue };
+        if message.get("tool_calls").and_then(Value::as_array).is_some_and(|calls| !calls.is_empty()) {
+            continue;
+        }
+        let content = message.get("content").and_then(Value::as_str).unwrap_or_default().to_string();
+        let checked = parse_content(&content).and_then(|value| {
+            validate(schema, &value)?;
+            Ok(value)
+        });
+        match checked {
+            Ok(value) => {
+                message.insert("content".into(), json!(value.to_string()));
+            }
+            Err(error) => return Err((content, error)),
+        }
+    }
+    Ok(())
+}
+
+impl SchemaConfig {
+    /// Send a chat request whose `response_format` carries a schema, asking
+    /// again with the validation error while retries remain.  `None` when
+    /// the request has no schema to check.
+    pub async fn complete(
+        &self,
+        state: &AppState,
+        user: Option<&str>,
+        headers: &HeaderMap,
+        body: &Bytes,
+    ) -> Option<Response<Body>> {
+        let request: Value = serde_json::from_slice(body).ok()?;
+        let schema = requested_schema(&request)?;
+        let messages = request.get("messages").and_then(Value::as_array)?.clone();
+
+        let attempts = self.retries + 1;
+        let mut body = body.clone();
+        for attempt in 1..=attempts {
+            let resp = send_once(state, user, Method::POST, CHAT_PATH.to_string(), headers.clone(), body).await;
+            if !resp.status().is_success() {
+                return Some(with_attempts(resp, attempt));
+            }
+            let (mut parts, upstream) = resp.into_parts();
+            let bytes = match body::to_bytes(upstream, usize::MAX).await {
+                Ok(bytes) => bytes,
+                Err(_) => return Some(with_attempts(Response::from_parts(parts, Body::empty()), attempt)),
+            };
+            let Ok(mut completion) = serde_json::from_slice::<Value>(&bytes) else {
+                return Some(with_attempts(Response::from_parts(parts, Body::from(bytes)), attempt));
+            };
+            let (content, error) = match check_completion(&schema, &mut completion) {
+                Ok(()) => {
+                    parts.headers.remove(axum::http::header::CONTENT_LENGTH);
+                    let resp = Response::from_parts(parts, Body::from(completion.to_string()));
+                    return Some(with_attempts(resp, attempt));
+                }
+                Err(failure) => failure,
+            };
+            println!(
+                "structured output from upstream failed validation for user '{}' (attempt {attempt} of {attempts}): {error}",
+                user.unwrap_or("-")
+            );
+            if attempt == attempts {
+                return Some(with_attempts(invalid_response(attempts, &error), attempt));
+            }
+            let mut retry = request.clone();
+            let mut conversation = messages.clone();
+            conversation.push(json!({ "role": "assistant", "content": content }));
+            conversation.push(json!({
+                "role": "user",
+                "content": format!(
+                    "Your answer does not match the required JSON schema: {error}. \
+                     Reply again with only the corrected JSON."
+                ),
+            }));
+            retry["messages"] = Value::Array(conversation);
+            body = Bytes::from(retry.to_string());
+        }
+        unreachable!("the last attempt always returns")
+    }
+}
+
+fn with_attempts(mut resp: Response<Body>, attempts: u32) -> Response<Body> {
+    resp.headers_mut().insert(ATTEMPTS_HEADER, HeaderValue::from(attempts));
+    resp
+}
+
+fn invalid_response(attempts: u32, error: &str) -> Response<Body> {
+    let message = format!("The model's answer did not match the JSON schema after {attempts} attempts: {error}");
+    (
+        StatusCode::UNPROCESSABLE_ENTITY,
+        Json(json!({
+            "error": {
+                "message": message,
+                "type": "invalid_response_error",
+                "param": "response_format",
+                "code": "json_schema_validation_failed",
+            }
+        })),
+    )
+        .into_response()
+}
+
+/// Check `value` against a JSON Schema, reporting the first violation with
+/// its location.  Covers the keywords structured output uses: `type`,
+/// `enum`, `const`, object, array, string and number bounds, `anyOf`,
+/// `oneOf`, `allOf`, `not` and local `$ref`s.  `pattern` and `format` are
+/// not checked.
+pub fn validate(schema: &Value, value: &Value) -> Result<(), String> {
+    let validator = Validator {
+        root: schema,
+        refs: Cell::new(0),
+        steps: Cell::new(0),
+    };
+    validator.check(schema, value, "$")
+}
+
+/// How many `$ref`s may be followed inside each other; client schemas can
+/// refer to themselves.
+const MAX_REF_DEPTH: usize = 32;
+
+/// Subschema checks allowed for one answer, against `anyOf` branches that
+/// multiply through references.
+const MAX_STEPS: usize = 100_000;
+
+struct Validator<'a> {
+    root: &'a Value,
+    refs: Cell<usize>,
+    steps: Cell<usize>,
+}
+
+impl Validator<'_> {
+    fn check(&self, schema: &Value, value: &Value, at: &str) -> Result<(), String> {
+        self.steps.set(self.steps.get() + 1);
+        if self.steps.get() > MAX_STEPS {
+            return Err("schema too complex to check".into());
+        }
+        let schema = match schema {
+            Value::Bool(true) => return Ok(()),
+            Value::Bool(false) => return Err(format!("{at} is not allowed")),
+            Value::Object(schema) => schema,
+            _ => return Ok(()),
+        };
+
+        if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+            let target = reference
+                .strip_prefix('#')
+                .and_then(|pointer| self.root.pointer(pointer))
+                .ok_or_else(|| format!("unsupported schema reference '{reference}'"))?;
+            if self.refs.get() >= MAX_REF_DEPTH {
+                return Err("schema reference nesting too deep".into());
+            }
+            self.refs.set(self.refs.get() + 1);
+            let checked = self.check(target, value, at);
+            self.refs.set(self.refs.get() - 1);
+            checked?;
+        }
+        if let Some(types) = schema.get("type") {
+            let names: Vec<&str> = match types {
+                Value::String(name) => vec![name.as_str()],
+                Value::Array(names) => names.iter().filter_map(Value::as_str).collect(),
+                _ => Vec::new(),
+            };
+            if !names.is_empty() && !names.iter().any(|name| has_type(value, name)) {
+                return Err(format!("{at} should be {}, got {}", names.join(" or "), type_name(value)));
+            }
+        }
+        if let Some(Value::Array(allowed)) = schema.get("enum") {
+            if !allowed.contains(value) {
+                return Err(format!("{at} should be one of {}", Value::Array(allowed.clone())));
+            }
+        }
+        if let Some(constant) = schema.get("const") {
+            if constant != value {
+                return Err(format!("{at} should be {constant}"));
+            }
+        }
+
+        match value {
+            Value::Object(fields) => {
+                let properties = schema.get("properties").and_then(Value::as_object);
+                for name in schema.get("required").and_then(Value::as_array).into_iter().flatten() {
+                    let Some(name) = name.as_str() else { continue };
+                    if !fields.contains_key(name) {
+                        return Err(format!("{at} is missing required property '{name}'"));
+                    }
+                }
+                for (name, field) in fields {
+                    let path = format!("{at}.{name}");
+                    match properties.and_then(|p| p.get(name)) {
+                        Some(property) => self.check(property, field, &path)?,
+                        None => match schema.get("additionalProperties") {
+                            Some(Value::Bool(false)) => return Err(format!("{at} has unexpected property '{name}'")),
+                            Some(extra) => self.check(extra, field, &path)?,
+                            None => {}
+                        },
+                    }
+                }
+            }
+            Value::Array(items) => {
+                bound(schema, "minItems", items.len(), at, "items", |n, min| n >= min)?;
+                bound(schema, "maxItems", items.len(), at, "items", |n, max| n <= max)?;
+                if let Some(item) = schema.get("items") {
+                    for (i, element) in items.iter().enumerate() {
+                        self.check(item, element, &format!("{at}[{i}]"))?;
+                    }
+                }
+            }
+            Value::String(text) => {
+                let chars = text.chars().count();
+                bound(schema, "minLength", chars, at, "characters", |n, min| n >= min)?;
+                bound(schema, "maxLength", chars, at, "characters", |n, max| n <= max)?;
+            }
+            Value::Number(number) => {
+                let n = number.as_f64().unwrap_or_default();
+                for (keyword, op) in [("minimum", ">="), ("maximum", "<="), ("exclusiveMinimum", ">"), ("exclusiveMaximum", "<")] {
+                    let Some(limit) = schema.get(keyword).and_then(Value::as_f64) else { continue };
+                    let ok = match op {
+                        ">=" => n >= limit,
+                        "<=" => n <= limit,
+                        ">" => n > limit,
+                        _ => n < limit,
+                    };
+                    if !ok {
+                        return Err(format!("{at} should be {op} {limit}, got {n}"));
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        for sub in schema.get("allOf").and_then(Value::as_array).into_iter().flatten() {
+            self.check(sub, value, at)?;
+        }
+        if let Some(Value::Array(options)) = schema.get("anyOf") {
+            let errors: Vec<String> = options.iter().filter_map(|s| self.check(s, value, at).err()).collect();
+            if errors.len() == options.len() && !options.is_empty() {
+                return Err(format!("{at} matches none of the allowed shapes ({})", errors.join("; ")));
+            }
+        }
+        if let Some(Value::Array(options)) = schema.get("oneOf") {
+            let matching = options.iter().filter(|s| self.check(s, value, at).is_ok()).count();
+            if matching != 1 {
+                return Err(format!("{at} should match exactly one allowed shape, matches {matching}"));
+            }
+        }
+        if let Some(not) = schema.get("not") {
+            if self.check(not, value, at).is_ok() {
+                return Err(format!("{at} matches a disallowed shape"));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn bound(
+    schema: &serde_json::Map<String, Value>,
+    keyword: &str,
+    n: usize,
+    at: &str,
+    unit: &str,
+    ok: fn(usize, usize) -> bool,
+) -> Result<(), String> {
+    match schema.get(keyword).and_then(Value::as_u64) {
+        Some(limit) if !ok(n, limit as usize) => Err(format!("{at} has {n} {unit}, {keyword} is {limit}")),
+        _ => Ok(()),
+    }
+}
+
+fn has_type(value: &Value, name: &str) -> bool {
+    match name {
+        "integer" => value.as_f64().is_some_and(|n| n.fract() == 0.0),
+        "number" => value.is_number(),
+        other => type_name(value) == other,
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_common_keywords() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "name": { "type": "string", "minLength": 1 },
+                "age": { "type": "integer", "minimum": 0 },
+                "tags": { "type": "array", "items": { "$ref": "#/$defs/tag" }, "maxItems": 2 },
+                "kind": { "enum": ["cat", "dog"] },
+                "note": { "anyOf": [{ "type": "string" }, { "type": "null" }] },
+            },
+            "required": ["name", "age"],
+            "additionalProperties": false,
+            "$defs": { "tag": { "type": "string" } },
+        });
+        let ok = json!({ "name": "Rex", "age": 3, "tags": ["good"], "kind": "dog", "note": null });
+        assert_eq!(validate(&schema, &ok), Ok(()));
+        assert_eq!(validate(&schema, &json!({ "name": "Rex", "age": 3.0 })), Ok(()));
+
+        let fails = |value: Value| validate(&schema, &value).unwrap_err();
+        assert_eq!(fails(json!({ "name": "Rex" })), "$ is missing required property 'age'");
+        assert_eq!(fails(json!({ "name": "Rex", "age": 2.5 })), "$.age should be integer, got number");
+        assert_eq!(fails(json!({ "name": "Rex", "age": -1 })), "$.age should be >= 0, got -1");
+        assert_eq!(fails(json!({ "name": "Rex", "age": 1, "tags": [1] })), "$.tags[0] should be string, got number");
+        assert_eq!(fails(json!({ "name": "Rex", "age": 1, "x": 1 })), "$ has unexpected property 'x'");
+        assert_eq!(fails(json!({ "name": "", "age": 1 })), "$.name has 0 characters, minLength is 1");
+        assert!(fails(json!({ "name": "Rex", "age": 1, "kind": "cow" })).starts_with("$.kind should be one of"));
+        assert!(fails(json!({ "name": "Rex", "age": 1, "note": 5 })).starts_with("$.note matches none"));
+        assert_eq!(fails(json!([])), "$ should be object, got array");
+    }
+
+    #[test]
+    fn stops_on_recursive_schemas() {
+        let too_deep = Err("schema reference nesting too deep".to_string());
+        assert_eq!(validate(&json!({ "$ref": "#" }), &json!(1)), too_deep);
+        let schema = json!({ "$ref": "#/$defs/a", "$defs": { "a": { "allOf": [{ "$ref": "#/$defs/a" }] } } });
+        assert_eq!(validate(&schema, &json!({})), too_deep);
+        let schema = json!({ "$defs": { "a": { "anyOf": [{ "$ref": "#/$defs/a" }, { "$ref": "#/$defs/a" }] } }, "$ref": "#/$defs/a" });
+        assert!(validate(&schema, &json!(1)).is_err());
+
+        // recursion that follows the value is fine
+        let tree = json!({
+            "type": "object",
+            "properties": { "children": { "type": "array", "items": { "$ref": "#" } } },
+        });
+        assert_eq!(validate(&tree, &json!({ "children": [{ "children": [{}] }] })), Ok(()));
+    }
+
+    #[test]
+    fn checks_completions() {
+        let request = json!({
+            "response_format": { "type": "json_schema", "json_schema": { "name": "n", "schema": { "type": "object", "required": ["a"] } } },
+        });
+        let schema = requested_schema(&request).unwrap();
+        let mut streamed = request.clone();
+        streamed["stream"] = json!(true);
+        assert!(requested_schema(&streamed).is_none());
+        assert!(requested_schema(&json!({ "response_format": { "type": "text" } })).is_none());
+
+        let mut completion = json!({ "choices": [{ "message": { "content": "```json\n{\"a\": 1}\n```" } }] });
+        assert_eq!(check_completion(&schema, &mut completion), Ok(()));
+        assert_eq!(completion["choices"][0]["message"]["content"], "{\"a\":1}");
+
+        let mut completion = json!({ "choices": [{ "message": { "content": "{\"b\": 1" } }] });
+        let (content, error) = check_completion(&schema, &mut completion).unwrap_err();
+        assert_eq!(content, "{\"b\": 1");
+        assert!(error.starts_with("the answer is not valid JSON"));
+    }
+}
@@ -1,20 +1,236 @@
-use crate::config::AppConfig;
+use std::{
+    fs,
+    sync::{Arc, RwLock, RwLockReadGuard},
+    time::{Duration, SystemTime},
+};
+
+use crate::access::Denials;
+use crate::bans::AuthGuard;
+use crate::batch::EmbeddingBatcher;
+use crate::cache::ResponseCache;
+use crate::coalesce::Coalescer;
+use crate::config::{self, AppConfig, UserDirectory};
+use crate::context::ContextConfig;
+use crate::cors::CorsConfig;
+use crate::embeddings::EmbeddingCache;
+use crate::forward_auth::ForwardAuth;
+use crate::forwarded::ForwardedConfig;
+use crate::jwt::JwtVerifier;
+use crate::native::NativeChat;
+use crate::retry::{Breakers, RetryPolicy};
+use crate::roles::Roles;
+use crate::schema::SchemaConfig;
+use crate::think::ThinkConfig;
+use crate::tokens::TokenSigner;
+use crate::tools::ToolsConfig;
+use crate::usage::Usage;
+use ipnet::IpNet;
 use reqwest::Client;
 
+/// How often the key store is checked for changes made by other processes,
+/// e.g. `sql rotate`.
+const STORE_RECHECK: Duration = Duration::from_secs(5);
+
+/// Keys and users as currently loaded.  Replaced as a whole when the key
+/// store changes, so requests always see a consistent view.
+#[derive(Clone, Debug, Default)]
+pub struct Directory {
+    pub valid_keys: Vec<String>,
+    /// Named users from the key database, used to resolve identities.
+    pub users: UserDirectory,
+}
+
 /// Shared state that is stored in `axum::Extension`/`State`.
 #[derive(Clone)]
 pub struct AppState {
     pub client: Client,
-    pub valid_keys: Vec<String>,
+    pub directory: Arc<RwLock<Directory>>,
+    /// SQLite key store backing `directory`, if any.
+    pub sqlite_path: Option<String>,
     pub ollama_url: String,
+    pub retry: RetryPolicy,
+    pub breakers: Breakers,
+    pub cors: Option<CorsConfig>,
+    pub forwarded: ForwardedConfig,
+    pub denied_ips: Vec<IpNet>,
+    /// Requests refused because of their address.
+    pub denials: Denials,
+    /// Failed-authentication tracking and bans per client address.
+    pub guard: AuthGuard,
+    pub usage: Usage,
+    pub roles: Roles,
+    /// Mints and verifies short-lived scoped tokens.
+    pub tokens: TokenSigner,
+    /// External authorization service, replacing key checks when set.
+    pub forward_auth: Option<ForwardAuth>,
+    /// Accepts OIDC JWTs alongside keys when configured.
+    pub jwt: Option<JwtVerifier>,
+    pub cache: Option<ResponseCache>,
+    pub embedding_cache: Option<EmbeddingCache>,
+    pub batcher: Option<EmbeddingBatcher>,
+    pub coalescer: Option<Coalescer>,
+    pub native_chat: Option<NativeChat>,
+    pub context: Option<ContextConfig>,
+    pub think: ThinkConfig,
+    pub schema: Option<SchemaConfig>,
+    pub tools: ToolsConfig,
 }
 
 impl AppState {
     pub fn new(cfg: &AppConfig) -> Self {
         AppState {
             client: Client::new(),
-            valid_keys: cfg.valid_keys.clone(),
+            directory: Arc::new(RwLock::new(Directory {
+                valid_keys: cfg.valid_keys.clone(),
+                users: cfg.users.clone(),
+            })),
+            sqlite_path: cfg.sqlite_path.clone(),
             ollama_url: cfg.ollama_url.clone(),
+            retry: cfg.retry.clone(),
+            breakers: Breakers::new(cfg.breaker.clone()),
+            cors: cfg.cors.clone(),
+            forwarded: cfg.forwarded.clone(),
+            denied_ips: cfg.denied_ips.clone(),
+            denials: Denials::default(),
+            guard: AuthGuard::new(cfg.bans.clone(), cfg.sqlite_path.clone()),
+            usage: Usage::default(),
+            roles: cfg.roles.clone(),
+            tokens: TokenSigner::new(&cfg.tokens),
+            forward_auth: cfg.forward_auth.clone().map(ForwardAuth::new),
+            jwt: cfg.jwt.clone().map(JwtVerifier::new),
+            cache: cfg.cache.clone().map(ResponseCache::new),
+            embedding_cache: cfg.embedding_cache.clone().map(EmbeddingCache::new),
+            batcher: cfg.embedding_batch.clone().map(EmbeddingBatcher::new),
+            coalescer: cfg.coalesce.clone().map(Coalescer::new),
+            native_chat: cfg.native_chat.clone().map(NativeChat::new),
+            context: cfg.context.clone(),
+            think: cfg.think.clone(),
+            schema: cfg.schema.clone(),
+            tools: cfg.tools.clone(),
+        }
+    }
+
+    /// The current keys and users.  Do not hold on to the guard across an
+    /// `.await`.
+    pub fn directory(&self) -> RwLockReadGuard<'_, Directory> {
+        self.directory.read().unwrap()
+    }
+
+    /// Re-read keys and users from the SQLite store so changes take effect
+    /// for the next request.
+    pub fn reload_directory(&self) -> anyhow::Result<()> {
+        let Some(path) = &self.sqlite_path else {
+            anyhow::bail!("no SQLite key store configured");
+        };
+        let (valid_keys, users) = config::load_directory_from_sqlite(path)?;
+        *self.directory.write().unwrap() = Directory { valid_keys, users };
+        Ok(())
+    }
+
+    /// Reload the directory whenever the SQLite store changes on disk, so
+    /// `sql` commands run against it take effect without a restart.  A failed
+    /// reload is logged and retried on the next check.
+    pub async fn watch_directory(self) {
+        self.watch_store(STORE_RECHECK).await
+    }
+
+    async fn watch_store(self, every: Duration) {
+        let Some(path) = self.sqlite_path.clone() else { return };
+        let mut seen = modified(&path);
+        let mut ticker = tokio::time::interval(every);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            let current = modified(&path);
+            if current == seen {
+                continue;
+            }
+            let state = self.clone();
+            match tokio::task::spawn_blocking(move || state.reload_directory()).await {
+                Ok(Ok(())) => seen = current,
+                Ok(Err(e)) => eprintln!("failed to reload keys: {e:#}"),
+                Err(e) => eprintln!("failed to reload keys: {e}"),
+            }
+        }
+    }
+}
+
+/// Modification time and size of the store, which change with every write.
+fn modified(path: &str) -> Option<(SystemTime, u64)> {
+    let meta = fs::metadata(path).ok()?;
+    Some((meta.modified().ok()?, meta.len()))
+}
+
+#[cfg(test)]
+impl AppState {
+    /// State for unit tests: the given backend and keys, defaults for
+    /// everything else.
+    pub fn for_tests(ollama_url: &str, keys: &[&str]) -> Self {
+        AppState {
+            client: Client::new(),
+            directory: Arc::new(RwLock::new(Directory {
+                valid_keys: keys.iter().map(|k| k.to_string()).collect(),
+                users: UserDirectory::default(),
+            })),
+            sqlite_path: None,
+            ollama_url: ollama_url.to_string(),
+            retry: RetryPolicy::default(),
+            breakers: Breakers::default(),
+            cors: None,
+            forwarded: ForwardedConfig::default(),
+            denied_ips: Vec::new(),
+            denials: Denials::default(),
+            guard: AuthGuard::default(),
+            usage: Usage::default(),
+            roles: Roles::default(),
+            tokens: TokenSigner::new(&crate::tokens::TokenConfig::default()),
+            forward_auth: None,
+            jwt: None,
+            cache: None,
+            embedding_cache: None,
+            batcher: None,
+            coalescer: None,
+            native_chat: None,
+            context: None,
+            think: ThinkConfig::default(),
+            schema: None,
+            tools: ToolsConfig::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::authenticate;
+    use axum::http::{Extensions, HeaderMap};
+    use tempfile::NamedTempFile;
+
+    #[tokio::test]
+    async fn picks_up_store_changes() {
+        let db = NamedTempFile::new().unwrap();
+        let path = db.path().to_str().unwrap().to_string();
+        config::add_key_to_sqlite(&path, "alice", "old-key").unwrap();
+        let mut state = AppState::for_tests("http://localhost", &[]);
+        state.sqlite_path = Some(path.clone());
+        state.reload_directory().unwrap();
+        tokio::spawn(state.clone().watch_store(Duration::from_millis(10)));
+        tokio::time::sleep(Duration::from_millis(30)).await;
+
+        // as `sql rotate alice` would from another process
+        config::rotate_user_key(&path, "alice", "new-key", Duration::ZERO).unwrap();
+        let bearer = |key: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert("authorization", format!("Bearer {key}").parse().unwrap());
+            headers
+        };
+        for _ in 0..100 {
+            if authenticate(&state, &bearer("new-key"), &Extensions::new()).is_some() {
+                assert!(authenticate(&state, &bearer("old-key"), &Extensions::new()).is_none());
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
         }
+        panic!("key store change was not picked up");
     }
 }
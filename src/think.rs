@@ -0,0 +1,380 @@
+use std::{collections::BTreeMap, convert::Infallible};
+
+use anyhow::bail;
+use axum::{
+    body::{self, Body, Bytes},
+    http::{HeaderMap, Response, header},
+};
+use futures_util::{StreamExt, stream};
+use serde_json::{Map, Value, json};
+
+use crate::roles::glob_match;
+
+/// Request header choosing the transform for one request, overriding the
+/// per-model setting.
+pub const THINK_HEADER: &str = "x-shim-think";
+
+const OPEN: &str = "<think>";
+const CLOSE: &str = "</think>";
+
+/// What to do with `<think>…</think>` blocks in answers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ThinkMode {
+    /// Leave the content as the model wrote it.
+    Keep,
+    /// Move thinking into `reasoning_content`.
+    Separate,
+    /// Drop thinking altogether.
+    Strip,
+}
+
+impl std::str::FromStr for ThinkMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "keep" => Ok(ThinkMode::Keep),
+            "separate" => Ok(ThinkMode::Separate),
+            "strip" => Ok(ThinkMode::Strip),
+            _ => bail!("invalid think mode '{s}' (use keep, separate or strip)"),
+        }
+    }
+}
+
+impl ThinkMode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ThinkMode::Keep => "keep",
+            ThinkMode::Separate => "separate",
+            ThinkMode::Strip => "strip",
+        }
+    }
+}
+
+/// Which models' chat answers have their thinking moved or stripped.
+#[derive(Clone, Debug, Default)]
+pub struct ThinkConfig {
+    /// Model name or `*` glob → mode; the longest matching pattern wins.
+    pub models: BTreeMap<String, ThinkMode>,
+}
+
+impl ThinkConfig {
+    /// The transform for a request: its `X-Shim-Think` header, else the
+    /// model's setting.  `None` leaves the answer alone.
+    pub fn mode(&self, headers: &HeaderMap, model: Option<&str>) -> Option<ThinkMode> {
+        let requested = headers
+            .get(THINK_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok());
+        let configured = || {
+            let model = model?;
+            self.models
+                .iter()
+                .filter(|(pattern, _)| glob_match(pattern, model))
+                .max_by_key(|(pattern, _)| pattern.len())
+                .map(|(_, mode)| *mode)
+        };
+        requested.or_else(configured).filter(|mode| *mode != ThinkMode::Keep)
+    }
+}
+
+/// Incremental splitter of text into thinking and answer, for tags that
+/// may arrive split across chunks.
+#[derive(Debug, Default)]
+struct Splitter {
+    inside: bool,
+    /// Tail that could be the start of the next tag.
+    pending: String,
+    /// Whitespace after a closing tag is not part of the answer.
+    trim_answer: bool,
+}
+
+impl Splitter {
+    /// Feed a piece of text; returns the thinking and answer parts of it
+    /// that are settled so far.
+    fn push(&mut self, text: &str) -> (String, String) {
+        let mut buf = std::mem::take(&mut self.pending);
+        buf.push_str(text);
+        let (mut thinking, mut answer) = (String::new(), String::new());
+        let mut rest = buf.as_str();
+        loop {
+            let tag = if self.inside { CLOSE } else { OPEN };
+            match rest.find(tag) {
+                Some(at) => {
+                    self.emit(&rest[..at], &mut thinking, &mut answer);
+                    rest = &rest[at + tag.len()..];
+                    self.inside = !self.inside;
+                    if !self.inside {
+                        self.trim_answer = true;
+                    }
+                }
+                None => {
+                    let keep = partial_tag_len(rest, tag);
+                    self.emit(&rest[..rest.len() - keep], &mut thinking, &mut answer);
+                    self.pending = rest[rest.len() - keep..].to_string();
+                    return (thinking, answer);
+                }
+            }
+        }
+    }
+
+    /// Whatever was held back, at the end of the text.
+    fn finish(&mut self) -> (String, String) {
+        let pending = std::mem::take(&mut self.pending);
+        let (mut thinking, mut answer) = (String::new(), String::new());
+        self.emit(&pending, &mut thinking, &mut answer);
+        (thinking, answer)
+    }
+
+    fn emit(&mut self, text: &str, thinking: &mut String, answer: &mut String) {
+        if self.inside {
+            thinking.push_str(text);
+            return;
+        }
+        let text = if self.trim_answer { text.trim_start() } else { text };
+        if !text.is_empty() {
+            self.trim_answer = false;
+        }
+        answer.push_str(text);
+    }
+}
+
+/// Length of the longest suffix of `text` that is a proper prefix of `tag`.
+fn partial_tag_len(text: &str, tag: &str) -> usize {
+    (1..tag.len())
+        .rev()
+        .find(|&n| {
+            text.len() >= n && text.is_char_boundary(text.len() - n) && tag.starts_with(&text[text.len() - n..])
+        })
+        .unwrap_or(0)
+}
+
+/// Apply `mode` to one message or delta object.
+fn rewrite(mode: ThinkMode, message: &mut Map<String, Value>, thinking: String, answer: String) {
+    message.insert("content".into(), json!(answer));
+    if mode == ThinkMode::Separate && !thinking.is_empty() {
+        let reasoning = match message.get("reasoning_content").and_then(Value::as_str) {
+            Some(earlier) => format!("{earlier}{thinking}"),
+            None => thinking,
+        };
+        message.insert("reasoning_content".into(), json!(reasoning));
+    }
+}
+
+/// Transform a chat completion response, JSON or SSE, according to `mode`.
+/// Error responses and other content types pass through untouched.
+pub async fn transform(mode: ThinkMode, resp: Response<Body>) -> Response<Body> {
+    if !resp.status().is_success() {
+        return resp;
+    }
+    let content_type = resp
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+    if content_type.starts_with("text/event-stream") {
+        transform_stream(mode, resp)
+    } else if content_type.starts_with("application/json") {
+        let (mut parts, body) = resp.into_parts();
+        let Ok(bytes) = body::to_bytes(body, usize::MAX).await else {
+            return Response::from_parts(parts, Body::empty());
+        };
+        let bytes = match serde_json::from_slice::<Value>(&bytes) {
+            Ok(mut completion) => {
+                transform_completion(mode, &mut completion);
+                Bytes::from(completion.to_string())
+            }
+            Err(_) => bytes,
+        };
+        parts.headers.remove(header::CONTENT_LENGTH);
+        Response::from_parts(parts, Body::from(bytes))
+    } else {
+        resp
+    }
+}
+
+fn transform_completion(mode: ThinkMode, completion: &mut Value) {
+    let Some(choices) = completion.get_mut("choices").and_then(Value::as_array_mut) else { return };
+    for choice in choices {
+        let Some(message) = choice.get_mut("message").and_then(Value::as_object_mut) else { continue };
+        let Some(content) = message.get("content").and_then(Value::as_str) else { continue };
+        let mut splitter = Splitter::default();
+        let (mut thinking, mut answer) = splitter.push(content);
+        let (more_thinking, more_answer) = splitter.finish();
+        thinking.push_str(&more_thinking);
+        answer.push_str(&more_answer);
+        rewrite(mode, message, thinking.trim().to_string(), answer);
+    }
+}
+
+/// Per-stream state: a splitter for each choice.
+struct Events {
+    upstream: body::BodyDataStream,
+    buf: Vec<u8>,
+    mode: ThinkMode,
+    splitters: BTreeMap<u64, Splitter>,
+    finished: bool,
+}
+
+impl Events {
+    fn event(&mut self, event: &[u8]) -> Vec<u8> {
+        let text = String::from_utf8_lossy(event);
+        let Some(data) = text.strip_prefix("data: ").or_else(|| text.strip_prefix("data:")) else {
+            return event.to_vec();
+        };
+        let Ok(mut chunk) = serde_json::from_str::<Value>(data.trim_end()) else {
+            return event.to_vec();
+        };
+        let Some(choices) = chunk.get_mut("choices").and_then(Value::as_array_mut) else {
+            return event.to_vec();
+        };
+        for choice in choices {
+            let index = choice.get("index").and_then(Value::as_u64).unwrap_or(0);
+            let finishing = choice.get("finish_reason").is_some_and(|f| !f.is_null());
+            let Some(delta) = choice.get_mut("delta").and_then(Value::as_object_mut) else { continue };
+            let splitter = self.splitters.entry(index).or_default();
+            let content = delta.get("content").and_then(Value::as_str);
+            if content.is_none() && !finishing {
+                continue;
+            }
+            let (mut thinking, mut answer) = splitter.push(content.unwrap_or_default());
+            if finishing {
+                let (more_thinking, more_answer) = splitter.finish();
+                thinking.push_str(&more_thinking);
+                answer.push_str(&more_answer);
+            }
+            if content.is_some() || !thinking.is_empty() || !answer.is_empty() {
+                rewrite(self.mode, delta, thinking, answer);
+            }
+        }
+        format!("data: {chunk}").into_bytes()
+    }
+}
+
+fn transform_stream(mode: ThinkMode, resp: Response<Body>) -> Response<Body> {
+    let (mut parts, body) = resp.into_parts();
+    let events = Events {
+        upstream: body.into_data_stream(),
+        buf: Vec::new(),
+        mode,
+        splitters: BTreeMap::new(),
+        finished: false,
+    };
+    let stream = stream::unfold(events, |mut events| async move {
+        loop {
+            if let Some(pos) = events.buf.windows(2).position(|w| w == b"\n\n") {
+                let event: Vec<u8> = events.buf.drain(..pos + 2).collect();
+                let mut out = events.event(&event[..pos]);
+                out.extend_from_slice(b"\n\n");
+                return Some((Ok::<_, Infallible>(Bytes::from(out)), events));
+            }
+            if events.finished {
+                return None;
+            }
+            match events.upstream.next().await {
+                Some(Ok(chunk)) => events.buf.extend_from_slice(&chunk),
+                Some(Err(e)) => {
+                    eprintln!("think transform stream failed: {e}");
+                    return None;
+                }
+                None => {
+                    events.finished = true;
+                    if events.buf.is_empty() {
+                        return None;
+                    }
+                    let rest = std::mem::take(&mut events.buf);
+                    return Some((Ok(Bytes::from(events.event(&rest))), events));
+                }
+            }
+        }
+    });
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Response::from_parts(parts, Body::from_stream(stream))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split_all(chunks: &[&str]) -> (String, String) {
+        let mut splitter = Splitter::default();
+        let (mut thinking, mut answer) = (String::new(), String::new());
+        for chunk in chunks {
+            let (t, a) = splitter.push(chunk);
+            thinking += &t;
+            answer += &a;
+        }
+        let (t, a) = splitter.finish();
+        (thinking + &t, answer + &a)
+    }
+
+    #[test]
+    fn splits_tags_across_chunks() {
+        let whole = ("hmm, 2+2".to_string(), "It is 4 <b>.".to_string());
+        assert_eq!(split_all(&["<think>hmm, 2+2</think>\n\nIt is 4 <b>."]), whole);
+        assert_eq!(split_all(&["<thi", "nk>hmm, 2", "+2</th", "ink>", "\n\n", "It is 4 <", "b>."]), whole);
+        assert_eq!(split_all(&["no thinking here"]), (String::new(), "no thinking here".into()));
+        // an unfinished tag at the very end is just text
+        assert_eq!(split_all(&["ends with <thi"]), (String::new(), "ends with <thi".into()));
+        assert_eq!(split_all(&["<think>cut off"]), ("cut off".into(), String::new()));
+        assert_eq!(partial_tag_len("é<", OPEN), 1);
+    }
+
+    #[test]
+    fn picks_the_mode() {
+        let config = ThinkConfig {
+            models: BTreeMap::from([
+                ("deepseek-r1*".to_string(), ThinkMode::Separate),
+                ("deepseek-r1:70b".to_string(), ThinkMode::Strip),
+            ]),
+        };
+        let mut headers = HeaderMap::new();
+        assert_eq!(config.mode(&headers, Some("deepseek-r1:8b")), Some(ThinkMode::Separate));
+        assert_eq!(config.mode(&headers, Some("deepseek-r1:70b")), Some(ThinkMode::Strip));
+        assert_eq!(config.mode(&headers, Some("llama3")), None);
+        headers.insert(THINK_HEADER, "strip".parse().unwrap());
+        assert_eq!(config.mode(&headers, Some("llama3")), Some(ThinkMode::Strip));
+        headers.insert(THINK_HEADER, "keep".parse().unwrap());
+        assert_eq!(config.mode(&headers, Some("deepseek-r1:8b")), None);
+    }
+
+    #[tokio::test]
+    async fn rewrites_json_and_sse() {
+        let json_resp = |body: &'static str, content_type: &'static str| {
+            Response::builder()
+                .header("content-type", content_type)
+                .body(Body::from(body))
+                .unwrap()
+        };
+        let read = |resp: Response<Body>| async move {
+            String::from_utf8(body::to_bytes(resp.into_body(), usize::MAX).await.unwrap().to_vec()).unwrap()
+        };
+
+        let completion = r#"{"choices":[{"index":0,"message":{"role":"assistant","content":"<think>\nhmm\n</think>\n\n4"}}]}"#;
+        let out = read(transform(ThinkMode::Separate, json_resp(completion, "application/json")).await).await;
+        let out: Value = serde_json::from_str(&out).unwrap();
+        assert_eq!(out["choices"][0]["message"]["content"], "4");
+        assert_eq!(out["choices"][0]["message"]["reasoning_content"], "hmm");
+        let out = read(transform(ThinkMode::Strip, json_resp(completion, "application/json")).await).await;
+        assert!(!out.contains("reasoning_content") && !out.contains("hmm"));
+
+        let sse = concat!(
+            "data: {\"choices\":[{\"index\":0,\"delta\":{\"role\":\"assistant\",\"content\":\"<th\"}}]}\n\n",
+            "data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\"ink>hm\"}}]}\n\n",
+            "data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\"m</think>\\n\\n4\"}}]}\n\n",
+            "data: {\"choices\":[{\"index\":0,\"delta\":{},\"finish_reason\":\"stop\"}]}\n\n",
+            "data: [DONE]\n\n",
+        );
+        let out = read(transform(ThinkMode::Separate, json_resp(sse, "text/event-stream")).await).await;
+        let (mut thinking, mut answer) = (String::new(), String::new());
+        for event in out.split("\n\n").filter_map(|e| e.strip_prefix("data: ")) {
+            let Ok(chunk) = serde_json::from_str::<Value>(event) else { continue };
+            let delta = &chunk["choices"][0]["delta"];
+            thinking += delta["reasoning_content"].as_str().unwrap_or_default();
+            answer += delta["content"].as_str().unwrap_or_default();
+        }
+        assert_eq!((thinking.as_str(), answer.as_str()), ("hmm", "4"));
+        assert!(out.ends_with("data: [DONE]\n\n"));
+    }
+}
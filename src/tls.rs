@@ -0,0 +1,505 @@
+use std::{
+    fs, future::Future, io, pin::Pin, sync::Arc, time::Duration, time::SystemTime,
+};
+
+use anyhow::{Context, Result};
+use axum::{
+    Extension, Router,
+    extract::{Request, State},
+    http::header::HOST,
+    middleware::AddExtension,
+    response::{IntoResponse, Redirect},
+};
+use axum_server::{
+    accept::Accept,
+    tls_rustls::{RustlsAcceptor, RustlsConfig},
+};
+use rustls::{RootCertStore, ServerConfig, server::WebPkiClientVerifier};
+use rustls_pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::server::TlsStream;
+use tower::Layer;
+use x509_parser::{extensions::GeneralName, prelude::FromDer};
+
+use crate::auth::ClientCert;
+
+/// HTTPS settings for the listener.
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    /// PEM file with the certificate chain, leaf first.
+    pub cert_path: String,
+    /// PEM file with the private key.
+    pub key_path: String,
+    /// Optional extra plain-HTTP port served alongside HTTPS.
+    pub http_port: Option<u16>,
+    /// Whether the plain-HTTP port only redirects to HTTPS instead of serving
+    /// the proxy itself.
+    pub http_redirect: bool,
+    /// How often the certificate files are checked for changes.
+    pub reload_interval: Duration,
+    /// PEM bundle of CAs whose client certificates are accepted; `None`
+    /// disables client certificate authentication.
+    pub client_ca: Option<String>,
+    /// Whether the handshake fails without a client certificate.  When
+    /// `false` clients may still fall back to a bearer key.
+    pub client_cert_required: bool,
+}
+
+/// Build a rustls server configuration from the PEM files referenced by
+/// `tls`.
+pub fn load_server_config(tls: &TlsConfig) -> Result<ServerConfig> {
+    let certs = CertificateDer::pem_file_iter(&tls.cert_path)
+        .with_context(|| format!("failed to read TLS certificate '{}'", tls.cert_path))?
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("invalid PEM in TLS certificate '{}'", tls.cert_path))?;
+    if certs.is_empty() {
+        anyhow::bail!("no certificates found in '{}'", tls.cert_path);
+    }
+    let key = PrivateKeyDer::from_pem_file(&tls.key_path)
+        .with_context(|| format!("failed to read TLS private key '{}'", tls.key_path))?;
+
+    let builder = match &tls.client_ca {
+        None => ServerConfig::builder().with_no_client_auth(),
+        Some(ca_path) => {
+            let mut roots = RootCertStore::empty();
+            for ca in CertificateDer::pem_file_iter(ca_path)
+                .with_context(|| format!("failed to read client CA bundle '{}'", ca_path))?
+            {
+                let ca = ca.with_context(|| format!("invalid PEM in client CA bundle '{}'", ca_path))?;
+                roots
+                    .add(ca)
+                    .with_context(|| format!("unusable certificate in '{}'", ca_path))?;
+            }
+            let mut verifier = WebPkiClientVerifier::builder(Arc::new(roots));
+            if !tls.client_cert_required {
+                verifier = verifier.allow_unauthenticated();
+            }
+            let verifier = verifier
+                .build()
+                .with_context(|| format!("cannot verify clients against '{}'", ca_path))?;
+            ServerConfig::builder().with_client_cert_verifier(verifier)
+        }
+    };
+    let mut config = builder
+        .with_single_cert(certs, key)
+        .context("TLS certificate and key do not match")?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+/// Load the initial configuration in the form `axum_server` expects.
+pub fn rustls_config(tls: &TlsConfig) -> Result<RustlsConfig> {
+    Ok(RustlsConfig::from_config(Arc::new(load_server_config(tls)?)))
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Modification times of every file the server configuration is built from.
+fn stamps(tls: &TlsConfig) -> [Option<SystemTime>; 3] {
+    [
+        modified(&tls.cert_path),
+        modified(&tls.key_path),
+        tls.client_ca.as_deref().and_then(modified),
+    ]
+}
+
+/// Poll the certificate, key and client CA files and swap in a fresh
+/// configuration when any changes, e.g. after a certbot renewal or a CA
+/// rotation.  A broken or half-written pair is logged and the previous
+/// certificate stays in use; the next change is retried.
+pub async fn watch_certificates(rustls: RustlsConfig, tls: TlsConfig) {
+    let mut seen = stamps(&tls);
+    let mut ticker = tokio::time::interval(tls.reload_interval);
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        let current = stamps(&tls);
+        if current == seen {
+            continue;
+        }
+        match load_server_config(&tls) {
+            Ok(config) => {
+                rustls.reload_from_config(Arc::new(config));
+                seen = current;
+                println!("reloaded TLS certificate from '{}'", tls.cert_path);
+            }
+            Err(e) => eprintln!("failed to reload TLS certificate: {e:#}"),
+        }
+    }
+}
+
+/// Wraps the rustls acceptor and attaches the names from a verified client
+/// certificate to every request on the connection as a [`ClientCert`]
+/// extension.  rustls has already checked the chain against the configured
+/// CA by the time the handshake completes, so the names can be trusted.
+#[derive(Clone)]
+pub struct ClientCertAcceptor {
+    inner: RustlsAcceptor,
+}
+
+impl ClientCertAcceptor {
+    pub fn new(config: RustlsConfig) -> Self {
+        ClientCertAcceptor {
+            inner: RustlsAcceptor::new(config),
+        }
+    }
+}
+
+impl<I, S> Accept<I, S> for ClientCertAcceptor
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    S: Send + 'static,
+{
+    type Stream = TlsStream<I>;
+    type Service = AddExtension<S, ClientCert>;
+    type Future = Pin<Box<dyn Future<Output = io::Result<(Self::Stream, Self::Service)>> + Send>>;
+
+    fn accept(&self, stream: I, service: S) -> Self::Future {
+        let handshake = self.inner.accept(stream, service);
+        Box::pin(async move {
+            let (stream, service) = handshake.await?;
+            let names = stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|chain| chain.first())
+                .map(|leaf| certificate_names(leaf))
+                .unwrap_or_default();
+            Ok((stream, Extension(ClientCert { names }).layer(service)))
+        })
+    }
+}
+
+/// Names a client certificate vouches for: the subject common names followed
+/// by DNS and email subject alternative names.
+pub fn certificate_names(der: &CertificateDer<'_>) -> Vec<String> {
+    let Ok((_, cert)) = x509_parser::certificate::X509Certificate::from_der(der) else {
+        return Vec::new();
+    };
+    let mut names: Vec<String> = cert
+        .subject()
+        .iter_common_name()
+        .filter_map(|cn| cn.as_str().ok())
+        .map(str::to_string)
+        .collect();
+    if let Ok(Some(san)) = cert.subject_alternative_name() {
+        for name in &san.value.general_names {
+            match name {
+                GeneralName::DNSName(n) | GeneralName::RFC822Name(n) => names.push(n.to_string()),
+                _ => {}
+            }
+        }
+    }
+    names
+}
+
+/// Router for the plain-HTTP port when `http_redirect` is enabled: every
+/// request is permanently redirected to the same path on the HTTPS port.
+pub fn redirect_router(https_port: u16) -> Router {
+    Router::new()
+        .fallback(redirect_to_https)
+        .with_state(https_port)
+}
+
+async fn redirect_to_https(State(https_port): State<u16>, req: Request) -> impl IntoResponse {
+    let host = req
+        .headers()
+        .get(HOST)
+        .and_then(|h| h.to_str().ok())
+        .unwrap_or("localhost");
+    let host = strip_port(host);
+    let authority = if https_port == 443 {
+        host.to_string()
+    } else {
+        format!("{host}:{https_port}")
+    };
+    let path = req
+        .uri()
+        .path_and_query()
+        .map(|p| p.as_str())
+        .unwrap_or("/");
+    Redirect::permanent(&format!("https://{authority}{path}"))
+}
+
+/// Remove a `:port` suffix from a Host header, keeping IPv6 literals intact.
+fn strip_port(host: &str) -> &str {
+    if host.starts_with('[') {
+        return host.find(']').map(|i| &host[..=i]).unwrap_or(host);
+    }
+    host.split(':').next().unwrap_or(host)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::http::StatusCode;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+    use tower::ServiceExt;
+
+    fn write_temp(contents: &str) -> NamedTempFile {
+        let mut f = NamedTempFile::new().unwrap();
+        f.write_all(contents.as_bytes()).unwrap();
+        f
+    }
+
+    fn self_signed() -> (NamedTempFile, NamedTempFile) {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        (
+            write_temp(&cert.cert.pem()),
+            write_temp(&cert.key_pair.serialize_pem()),
+        )
+    }
+
+    fn tls_for(cert: &NamedTempFile, key: &NamedTempFile) -> TlsConfig {
+        TlsConfig {
+            cert_path: cert.path().to_str().unwrap().into(),
+            key_path: key.path().to_str().unwrap().into(),
+            http_port: None,
+            http_redirect: false,
+            reload_interval: Duration::from_millis(10),
+            client_ca: None,
+            client_cert_required: false,
+        }
+    }
+
+    #[test]
+    fn loads_matching_pair() {
+        let (cert, key) = self_signed();
+        let config = load_server_config(&tls_for(&cert, &key)).expect("valid pair");
+        assert!(config.alpn_protocols.contains(&b"h2".to_vec()));
+    }
+
+    #[test]
+    fn rejects_mismatched_or_missing_files() {
+        let (cert, _) = self_signed();
+        let (_, other_key) = self_signed();
+        assert!(load_server_config(&tls_for(&cert, &other_key)).is_err());
+
+        let empty = write_temp("");
+        let err = load_server_config(&tls_for(&empty, &other_key)).unwrap_err();
+        assert!(err.to_string().contains("no certificates"));
+    }
+
+    #[tokio::test]
+    async fn reloads_changed_certificate() {
+        let (cert, key) = self_signed();
+        let tls = tls_for(&cert, &key);
+        let rustls = rustls_config(&tls).unwrap();
+        let before = rustls.get_inner();
+        tokio::spawn(watch_certificates(rustls.clone(), tls.clone()));
+        // let the watcher record the original timestamps first
+        tokio::time::sleep(Duration::from_millis(30)).await;
+
+        // replace both files with a new pair
+        let fresh = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        fs::write(&tls.cert_path, fresh.cert.pem()).unwrap();
+        fs::write(&tls.key_path, fresh.key_pair.serialize_pem()).unwrap();
+        touch(&[&tls.cert_path, &tls.key_path]);
+        assert!(reloaded(&rustls, &before).await, "certificate was not reloaded");
+    }
+
+    #[tokio::test]
+    async fn reloads_changed_client_ca() {
+        let (cert, key) = self_signed();
+        let ca_file = write_temp(&Pki::new().ca.pem());
+        let mut tls = tls_for(&cert, &key);
+        tls.client_ca = Some(ca_file.path().to_str().unwrap().into());
+        let rustls = rustls_config(&tls).unwrap();
+        let before = rustls.get_inner();
+        tokio::spawn(watch_certificates(rustls.clone(), tls.clone()));
+        tokio::time::sleep(Duration::from_millis(30)).await;
+
+        // a rotated CA alone must be picked up
+        let ca_path = tls.client_ca.clone().unwrap();
+        fs::write(&ca_path, Pki::new().ca.pem()).unwrap();
+        touch(&[&ca_path]);
+        assert!(reloaded(&rustls, &before).await, "client CA was not reloaded");
+    }
+
+    /// Bump the mtime explicitly, since a rewrite may land within the
+    /// filesystem's timestamp granularity.
+    fn touch(paths: &[&String]) {
+        let later = SystemTime::now() + Duration::from_secs(5);
+        for path in paths {
+            fs::File::options()
+                .write(true)
+                .open(path)
+                .unwrap()
+                .set_modified(later)
+                .unwrap();
+        }
+    }
+
+    async fn reloaded(rustls: &RustlsConfig, before: &Arc<ServerConfig>) -> bool {
+        for _ in 0..100 {
+            if !Arc::ptr_eq(before, &rustls.get_inner()) {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        false
+    }
+
+    #[tokio::test]
+    async fn redirects_to_https_port() {
+        let app = redirect_router(8443);
+        let req = axum::http::Request::builder()
+            .uri("/v1/models?x=1")
+            .header("host", "example.com:8080")
+            .body(Body::empty())
+            .unwrap();
+        let resp = app.oneshot(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(
+            resp.headers()["location"],
+            "https://example.com:8443/v1/models?x=1"
+        );
+    }
+
+    #[test]
+    fn strips_ports() {
+        assert_eq!(strip_port("example.com:80"), "example.com");
+        assert_eq!(strip_port("example.com"), "example.com");
+        assert_eq!(strip_port("[::1]:80"), "[::1]");
+    }
+
+    struct Pki {
+        ca: rcgen::Certificate,
+        ca_key: rcgen::KeyPair,
+    }
+
+    impl Pki {
+        fn new() -> Self {
+            let mut params = rcgen::CertificateParams::new(Vec::new()).unwrap();
+            params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+            params
+                .distinguished_name
+                .push(rcgen::DnType::CommonName, "test ca");
+            let ca_key = rcgen::KeyPair::generate().unwrap();
+            let ca = params.self_signed(&ca_key).unwrap();
+            Pki { ca, ca_key }
+        }
+
+        fn issue(&self, cn: &str, sans: &[&str], client: bool) -> (rcgen::Certificate, rcgen::KeyPair) {
+            let mut params =
+                rcgen::CertificateParams::new(sans.iter().map(|s| s.to_string()).collect::<Vec<_>>())
+                    .unwrap();
+            params.distinguished_name.push(rcgen::DnType::CommonName, cn);
+            params.extended_key_usages = vec![if client {
+                rcgen::ExtendedKeyUsagePurpose::ClientAuth
+            } else {
+                rcgen::ExtendedKeyUsagePurpose::ServerAuth
+            }];
+            let key = rcgen::KeyPair::generate().unwrap();
+            let cert = params.signed_by(&key, &self.ca, &self.ca_key).unwrap();
+            (cert, key)
+        }
+    }
+
+    #[test]
+    fn extracts_cn_and_sans() {
+        let pki = Pki::new();
+        let (cert, _) = pki.issue("alice", &["alice.svc.internal"], true);
+        assert_eq!(
+            certificate_names(cert.der()),
+            vec!["alice".to_string(), "alice.svc.internal".to_string()]
+        );
+    }
+
+    /// Start an HTTPS server requiring client certificates whose only route
+    /// echoes the names the acceptor extracted, and return its address.
+    async fn serve_names(tls: &TlsConfig) -> std::net::SocketAddr {
+        let app = Router::new().route(
+            "/",
+            axum::routing::get(|Extension(cert): Extension<ClientCert>| async move {
+                cert.names.join(",")
+            }),
+        );
+        let handle = axum_server::Handle::new();
+        let server = axum_server::bind("127.0.0.1:0".parse().unwrap())
+            .acceptor(ClientCertAcceptor::new(rustls_config(tls).unwrap()))
+            .handle(handle.clone());
+        tokio::spawn(server.serve(app.into_make_service()));
+        handle.listening().await.unwrap()
+    }
+
+    async fn https_get(
+        addr: std::net::SocketAddr,
+        pki: &Pki,
+        client: Option<(&rcgen::Certificate, &rcgen::KeyPair)>,
+    ) -> io::Result<String> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mut roots = RootCertStore::empty();
+        roots.add(pki.ca.der().clone()).unwrap();
+        let builder = rustls::ClientConfig::builder().with_root_certificates(roots);
+        let config = match client {
+            Some((cert, key)) => builder
+                .with_client_auth_cert(
+                    vec![cert.der().clone()],
+                    PrivateKeyDer::try_from(key.serialize_der()).unwrap(),
+                )
+                .unwrap(),
+            None => builder.with_no_client_auth(),
+        };
+        let connector = tokio_rustls::TlsConnector::from(Arc::new(config));
+        let tcp = tokio::net::TcpStream::connect(addr).await?;
+        let mut tls = connector
+            .connect("localhost".try_into().unwrap(), tcp)
+            .await?;
+        tls.write_all(b"GET / HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n")
+            .await?;
+        let mut out = String::new();
+        tls.read_to_string(&mut out).await?;
+        Ok(out)
+    }
+
+    #[tokio::test]
+    async fn mutual_tls_exposes_client_names() {
+        let pki = Pki::new();
+        let (server_cert, server_key) = pki.issue("localhost", &["localhost"], false);
+        let (client_cert, client_key) = pki.issue("alice", &[], true);
+        let ca_file = write_temp(&pki.ca.pem());
+        let cert_file = write_temp(&server_cert.pem());
+        let key_file = write_temp(&server_key.serialize_pem());
+
+        let mut tls = tls_for(&cert_file, &key_file);
+        tls.client_ca = Some(ca_file.path().to_str().unwrap().into());
+        tls.client_cert_required = true;
+        let addr = serve_names(&tls).await;
+
+        let body = https_get(addr, &pki, Some((&client_cert, &client_key)))
+            .await
+            .unwrap();
+        assert!(body.starts_with("HTTP/1.1 200"), "{body}");
+        assert!(body.ends_with("alice"), "{body}");
+
+        // without a certificate the handshake is refused
+        assert!(https_get(addr, &pki, None).await.is_err());
+
+        // certificates from another CA are refused as well
+        let rogue = Pki::new();
+        let (rogue_cert, rogue_key) = rogue.issue("alice", &[], true);
+        assert!(https_get(addr, &pki, Some((&rogue_cert, &rogue_key))).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn optional_client_certificate() {
+        let pki = Pki::new();
+        let (server_cert, server_key) = pki.issue("localhost", &["localhost"], false);
+        let ca_file = write_temp(&pki.ca.pem());
+        let cert_file = write_temp(&server_cert.pem());
+        let key_file = write_temp(&server_key.serialize_pem());
+
+        let mut tls = tls_for(&cert_file, &key_file);
+        tls.client_ca = Some(ca_file.path().to_str().unwrap().into());
+        let addr = serve_names(&tls).await;
+
+        let body = https_get(addr, &pki, None).await.unwrap();
+        assert!(body.starts_with("HTTP/1.1 200"), "{body}");
+    }
+}
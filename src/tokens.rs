@@ -0,0 +1,229 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+
+use crate::roles::glob_match;
+
+/// Prefix that tells a token apart from an API key.
+pub const TOKEN_PREFIX: &str = "st.";
+
+/// Shortest accepted `TOKEN_SECRET`.
+pub const MIN_SECRET_LEN: usize = 16;
+
+/// Settings for short-lived tokens.
+#[derive(Clone, Debug)]
+pub struct TokenConfig {
+    /// HMAC secret.  When unset a random one is generated at startup, so
+    /// tokens stop working on restart and are only accepted by the instance
+    /// that minted them.
+    pub secret: Option<String>,
+    pub default_ttl: Duration,
+    pub max_ttl: Duration,
+}
+
+impl Default for TokenConfig {
+    fn default() -> Self {
+        TokenConfig {
+            secret: None,
+            default_ttl: Duration::from_secs(300),
+            max_ttl: Duration::from_secs(3600),
+        }
+    }
+}
+
+/// What a token grants.  It never grants more than its user's role allows;
+/// the lists only narrow that down further.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Claims {
+    /// User the token acts for; `None` for keys without a user.
+    pub user: Option<String>,
+    /// Expiry in seconds since the Unix epoch.
+    pub exp: u64,
+    /// Model names (`*` globs allowed) the token may use; empty means any.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub models: Vec<String>,
+    /// Routes below `/v1/` (`*` globs allowed) the token may call; empty
+    /// means any.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub routes: Vec<String>,
+}
+
+impl Claims {
+    /// Whether the token covers a request to `path` for `model`.  Requests
+    /// that name no model are only limited by route.
+    pub fn allows(&self, path: &str, model: Option<&str>) -> bool {
+        let route_ok = self.routes.is_empty() || self.routes.iter().any(|r| glob_match(r, path));
+        let model_ok = match model {
+            Some(model) if !self.models.is_empty() => self.models.iter().any(|m| glob_match(m, model)),
+            _ => true,
+        };
+        route_ok && model_ok
+    }
+}
+
+/// Body of `POST /v1/shim/tokens`.
+#[derive(Debug, Default, Deserialize)]
+pub struct MintRequest {
+    pub ttl_secs: Option<u64>,
+    #[serde(default)]
+    pub models: Vec<String>,
+    #[serde(default)]
+    pub routes: Vec<String>,
+}
+
+/// Mints and checks tokens of the form `st.<claims>.<signature>`, both
+/// parts base64url-encoded, signed with HMAC-SHA256.  Validation needs no
+/// lookup, so any number of tokens can be outstanding.
+#[derive(Clone)]
+pub struct TokenSigner {
+    key: hmac::Key,
+    pub default_ttl: Duration,
+    pub max_ttl: Duration,
+}
+
+impl TokenSigner {
+    pub fn new(config: &TokenConfig) -> Self {
+        let secret = match &config.secret {
+            Some(secret) => secret.as_bytes().to_vec(),
+            None => {
+                let mut bytes = vec![0u8; 32];
+                getrandom::getrandom(&mut bytes).expect("no randomness available");
+                bytes
+            }
+        };
+        TokenSigner {
+            key: hmac::Key::new(hmac::HMAC_SHA256, &secret),
+            default_ttl: config.default_ttl,
+            max_ttl: config.max_ttl,
+        }
+    }
+
+    /// Sign `claims`.
+    pub fn mint(&self, claims: &Claims) -> String {
+        let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(claims).expect("claims serialize"));
+        let tag = hmac::sign(&self.key, payload.as_bytes());
+        format!("{TOKEN_PREFIX}{payload}.{}", URL_SAFE_NO_PAD.encode(tag.as_ref()))
+    }
+
+    /// Claims for a token lasting `ttl` (the default when `None`), checked
+    /// against the maximum.
+    pub fn claims(
+        &self,
+        user: Option<String>,
+        ttl: Option<Duration>,
+        models: Vec<String>,
+        routes: Vec<String>,
+    ) -> anyhow::Result<Claims> {
+        let ttl = ttl.unwrap_or(self.default_ttl);
+        if ttl.is_zero() || ttl > self.max_ttl {
+            anyhow::bail!(
+                "token lifetime must be between 1 and {} seconds",
+                self.max_ttl.as_secs()
+            );
+        }
+        let routes = routes
+            .into_iter()
+            .map(|r| r.trim_start_matches("/v1/").trim_start_matches('/').to_string())
+            .collect();
+        Ok(Claims {
+            user,
+            exp: unix_now() + ttl.as_secs(),
+            models,
+            routes,
+        })
+    }
+
+    /// The claims of a genuine, unexpired token.
+    pub fn verify(&self, token: &str) -> Option<Claims> {
+        let (payload, tag) = token.strip_prefix(TOKEN_PREFIX)?.split_once('.')?;
+        let tag = URL_SAFE_NO_PAD.decode(tag).ok()?;
+        hmac::verify(&self.key, payload.as_bytes(), &tag).ok()?;
+        let claims: Claims = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload).ok()?).ok()?;
+        (claims.exp > unix_now()).then_some(claims)
+    }
+
+    /// Handle a mint request from an already authenticated caller.
+    pub fn mint_for(&self, user: Option<String>, body: &[u8]) -> anyhow::Result<(String, u64)> {
+        let req: MintRequest = if body.is_empty() {
+            MintRequest::default()
+        } else {
+            serde_json::from_slice(body).context("invalid token request")?
+        };
+        let claims = self.claims(user, req.ttl_secs.map(Duration::from_secs), req.models, req.routes)?;
+        Ok((self.mint(&claims), claims.exp))
+    }
+}
+
+/// The `model` field of a JSON request body, if there is one.
+pub fn requested_model(body: &[u8]) -> Option<String> {
+    let value: serde_json::Value = serde_json::from_slice(body).ok()?;
+    value.get("model")?.as_str().map(str::to_string)
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signer(secret: &str) -> TokenSigner {
+        TokenSigner::new(&TokenConfig {
+            secret: Some(secret.into()),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn round_trip_and_tampering() {
+        let signer = signer("0123456789abcdef");
+        let claims = signer
+            .claims(Some("alice".into()), None, vec!["llama3*".into()], vec!["/v1/chat/*".into()])
+            .unwrap();
+        assert_eq!(claims.routes, vec!["chat/*"]);
+        let token = signer.mint(&claims);
+        assert!(token.starts_with(TOKEN_PREFIX));
+        assert_eq!(signer.verify(&token), Some(claims.clone()));
+
+        // another secret, a forged payload and an expired token all fail
+        assert!(self::signer("fedcba9876543210").verify(&token).is_none());
+        let (_, tag) = token.rsplit_once('.').unwrap();
+        let forged = Claims {
+            user: Some("root".into()),
+            ..claims.clone()
+        };
+        let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&forged).unwrap());
+        assert!(signer.verify(&format!("{TOKEN_PREFIX}{payload}.{tag}")).is_none());
+        let expired = signer.mint(&Claims { exp: 1, ..claims });
+        assert!(signer.verify(&expired).is_none());
+        assert!(signer.verify("st.garbage").is_none());
+    }
+
+    #[test]
+    fn lifetimes_and_scopes() {
+        let signer = signer("0123456789abcdef");
+        assert!(signer.claims(None, Some(Duration::from_secs(7200)), vec![], vec![]).is_err());
+        assert!(signer.claims(None, Some(Duration::ZERO), vec![], vec![]).is_err());
+
+        let claims = Claims {
+            models: vec!["llama3*".into()],
+            routes: vec!["chat/*".into(), "models".into()],
+            ..Default::default()
+        };
+        assert!(claims.allows("chat/completions", Some("llama3:8b")));
+        assert!(!claims.allows("chat/completions", Some("mistral")));
+        assert!(claims.allows("models", None));
+        assert!(!claims.allows("embeddings", Some("llama3")));
+        assert!(Claims::default().allows("anything", Some("any")));
+
+        assert_eq!(requested_model(br#"{"model":"llama3","messages":[]}"#).as_deref(), Some("llama3"));
+        assert_eq!(requested_model(b"not json"), None);
+    }
+}
@@ -0,0 +1,437 @@
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::bail;
+use axum::{
+    body::{self, Body, Bytes},
+    http::{HeaderMap, HeaderValue, Method, Response, StatusCode, header},
+};
+use serde_json::{Map, Value, json};
+
+use crate::native::{CHAT_PATH, openai_error};
+use crate::proxy::send_direct;
+use crate::roles::glob_match;
+use crate::state::AppState;
+
+/// How a model is given the `tools` of a chat request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ToolMode {
+    /// Pass `tools` through for models with native tool support.
+    Native,
+    /// Describe the tools in the system prompt and parse calls out of the
+    /// answer.
+    Emulate,
+}
+
+impl std::str::FromStr for ToolMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "native" => Ok(ToolMode::Native),
+            "emulate" => Ok(ToolMode::Emulate),
+            _ => bail!("invalid tool mode '{s}' (use native or emulate)"),
+        }
+    }
+}
+
+impl ToolMode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ToolMode::Native => "native",
+            ToolMode::Emulate => "emulate",
+        }
+    }
+}
+
+/// Which models get tool calling emulated.
+#[derive(Clone, Debug, Default)]
+pub struct ToolsConfig {
+    /// Model name or `*` glob → mode; the longest matching pattern wins and
+    /// unlisted models are `native`.
+    pub models: BTreeMap<String, ToolMode>,
+}
+
+impl ToolsConfig {
+    fn mode(&self, model: &str) -> ToolMode {
+        self.models
+            .iter()
+            .filter(|(pattern, _)| glob_match(pattern, model))
+            .max_by_key(|(pattern, _)| pattern.len())
+            .map_or(ToolMode::Native, |(_, mode)| *mode)
+    }
+
+    /// Serve a chat request with `tools` for a model configured for
+    /// emulation.  `None` when the request should go upstream unchanged.
+    pub async fn chat(
+        &self,
+        state: &AppState,
+        user: Option<&str>,
+        headers: &HeaderMap,
+        body: &Bytes,
+    ) -> Option<Response<Body>> {
+        let mut request: Value = serde_json::from_slice(body).ok()?;
+        let model = request.get("model").and_then(Value::as_str)?;
+        if self.mode(model) != ToolMode::Emulate {
+            return None;
+        }
+        let tools = request.get("tools").and_then(Value::as_array).filter(|t| !t.is_empty())?.clone();
+        let names: Vec<String> = tools
+            .iter()
+            .filter_map(|t| t.pointer("/function/name").and_then(Value::as_str).map(str::to_string))
+            .collect();
+        let stream = request.get("stream").and_then(Value::as_bool) == Some(true);
+        let include_usage = request.pointer("/stream_options/include_usage") == Some(&Value::Bool(true));
+        emulated_request(&mut request, &tools, &names);
+
+        let body = Bytes::from(request.to_string());
+        let resp = send_direct(state, user, Method::POST, CHAT_PATH.to_string(), headers.clone(), body).await;
+        if !resp.status().is_success() {
+            return Some(resp);
+        }
+        let (mut parts, upstream) = resp.into_parts();
+        let Ok(bytes) = body::to_bytes(upstream, usize::MAX).await else {
+            return Some(openai_error(StatusCode::BAD_GATEWAY, "Upstream request failed".into()));
+        };
+        let Ok(mut completion) = serde_json::from_slice::<Value>(&bytes) else {
+            return Some(Response::from_parts(parts, Body::from(bytes)));
+        };
+        extract_tool_calls(&mut completion, &names);
+
+        parts.headers.remove(header::CONTENT_LENGTH);
+        let body = if stream {
+            parts
+                .headers
+                .insert(header::CONTENT_TYPE, HeaderValue::from_static("text/event-stream"));
+            parts.headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+            Body::from(chunk_events(&completion, include_usage))
+        } else {
+            Body::from(completion.to_string())
+        };
+        Some(Response::from_parts(parts, body))
+    }
+}
+
+/// Rewrite a chat request with `tools` into one a model without tool
+/// support can answer: tools described in the system prompt, earlier calls
+/// and results as plain messages, and no streaming.
+fn emulated_request(request: &mut Value, tools: &[Value], names: &[String]) {
+    let Some(fields) = request.as_object_mut() else { return };
+    let choice = fields.remove("tool_choice").unwrap_or(json!("auto"));
+    fields.remove("tools");
+    fields.remove("parallel_tool_calls");
+    fields.remove("stream_options");
+    fields.insert("stream".into(), json!(false));
+
+    let messages = fields
+        .get("messages")
+        .and_then(Value::as_array)
+        .map(|messages| plain_messages(messages))
+        .unwrap_or_default();
+    let forced = match &choice {
+        Value::String(choice) if choice == "none" => {
+            fields.insert("messages".into(), Value::Array(messages));
+            return;
+        }
+        Value::String(choice) if choice == "required" => Some(None),
+        Value::Object(_) => Some(choice.pointer("/function/name").and_then(Value::as_str)),
+        _ => None,
+    };
+
+    let mut prompt = String::from("You can call these tools:\n");
+    for tool in tools {
+        let function = tool.get("function").unwrap_or(tool);
+        prompt += &format!(
+            "\n- {}: {}\n  Parameters (JSON Schema): {}\n",
+            function.get("name").and_then(Value::as_str).unwrap_or_default(),
+            function.get("description").and_then(Value::as_str).unwrap_or_default(),
+            function.get("parameters").unwrap_or(&json!({ "type": "object" })),
+        );
+    }
+    prompt += "\nTo call tools, reply with only this JSON and nothing else:\n\
+        {\"tool_calls\": [{\"name\": \"<tool name>\", \"arguments\": {<arguments>}}]}\n";
+    match forced {
+        None => prompt += "If no tool is needed, answer normally without any JSON.",
+        Some(None) => prompt += "You must call at least one tool.",
+        Some(Some(name)) => prompt += &format!("You must call the tool `{name}`."),
+    }
+    if forced.is_some() && !fields.contains_key("response_format") {
+        let allowed: Vec<&str> = match forced {
+            Some(Some(name)) => vec![name],
+            _ => names.iter().map(String::as_str).collect(),
+        };
+        fields.insert("response_format".into(), call_format(&allowed));
+    }
+
+    let mut messages = messages;
+    match messages.first_mut() {
+        Some(first) if first.get("role").and_then(Value::as_str) == Some("system") && first["content"].is_string() => {
+            let content = format!("{}\n\n{prompt}", first["content"].as_str().unwrap_or_default());
+            first["content"] = json!(content);
+        }
+        _ => messages.insert(0, json!({ "role": "system", "content": prompt })),
+    }
+    fields.insert("messages".into(), Value::Array(messages));
+}
+
+/// A schema for the call format, so a forced call comes back well formed.
+fn call_format(names: &[&str]) -> Value {
+    json!({
+        "type": "json_schema",
+        "json_schema": {
+            "name": "tool_calls",
+            "schema": {
+                "type": "object",
+                "properties": {
+                    "tool_calls": {
+                        "type": "array",
+                        "minItems": 1,
+                        "items": {
+                            "type": "object",
+                            "properties": {
+                                "name": { "enum": names },
+                                "arguments": { "type": "object" },
+                            },
+                            "required": ["name", "arguments"],
+                        },
+                    },
+                },
+                "required": ["tool_calls"],
+            },
+        },
+    })
+}
+
+/// Earlier tool calls and their results, written as ordinary assistant and
+/// user messages.
+fn plain_messages(messages: &[Value]) -> Vec<Value> {
+    let mut names: HashMap<String, String> = HashMap::new();
+    messages
+        .iter()
+        .map(|message| match message.get("role").and_then(Value::as_str) {
+            Some("assistant") if message.get("tool_calls").is_some_and(|c| !c.is_null()) => {
+                let calls: Vec<Value> = message["tool_calls"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .map(|call| {
+                        let name = call.pointer("/function/name").cloned().unwrap_or(Value::Null);
+                        if let (Some(id), Some(name)) = (call.get("id").and_then(Value::as_str), name.as_str()) {
+                            names.insert(id.to_string(), name.to_string());
+                        }
+                        let arguments = match call.pointer("/function/arguments") {
+                            Some(Value::String(text)) => serde_json::from_str(text).unwrap_or(json!(text)),
+                            Some(arguments) => arguments.clone(),
+                            None => json!({}),
+                        };
+                        json!({ "name": name, "arguments": arguments })
+                    })
+                    .collect();
+                let calls = json!({ "tool_calls": calls }).to_string();
+                let content = match message.get("content").and_then(Value::as_str) {
+                    Some(text) if !text.is_empty() => format!("{text}\n{calls}"),
+                    _ => calls,
+                };
+                json!({ "role": "assistant", "content": content })
+            }
+            Some("tool") => {
+                let id = message.get("tool_call_id").and_then(Value::as_str).unwrap_or_default();
+                let name = names.get(id).map_or("tool", String::as_str);
+                let result = match message.get("content") {
+                    Some(Value::String(text)) => text.clone(),
+                    Some(other) => other.to_string(),
+                    None => String::new(),
+                };
+                json!({ "role": "user", "content": format!("Result of {name} (call {id}):\n{result}") })
+            }
+            _ => message.clone(),
+        })
+        .collect()
+}
+
+/// Parse the calls out of an answer in the prompted format, if it is one.
+fn parse_calls(content: &str, names: &[String]) -> Option<Vec<(String, Value)>> {
+    let mut text = content.trim();
+    if let Some(fenced) = text.strip_prefix("```") {
+        let fenced = fenced.strip_prefix("json").unwrap_or(fenced);
+        text = fenced.strip_suffix("```").unwrap_or(fenced).trim();
+    }
+    let answer: Value = serde_json::from_str(text).ok()?;
+    let calls = match answer.get("tool_calls") {
+        Some(Value::Array(calls)) => calls.clone(),
+        // a single call without the wrapper
+        _ if answer.get("name").is_some() => vec![answer],
+        _ => return None,
+    };
+    let calls: Vec<(String, Value)> = calls
+        .into_iter()
+        .map(|call| {
+            let name = call.get("name").and_then(Value::as_str)?.to_string();
+            if !names.contains(&name) {
+                return None;
+            }
+            let arguments = match call.get("arguments") {
+                Some(Value::String(text)) => serde_json::from_str(text).ok()?,
+                Some(arguments) => arguments.clone(),
+                None => json!({}),
+            };
+            Some((name, arguments))
+        })
+        .collect::<Option<_>>()?;
+    (!calls.is_empty()).then_some(calls)
+}
+
+/// Turn answers in the call format into OpenAI `tool_calls`.
+fn extract_tool_calls(completion: &mut Value, names: &[String]) {
+    let Some(choices) = completion.get_mut("choices").and_then(Value::as_array_mut) else { return };
+    for choice in choices {
+        let content = choice.pointer("/message/content").and_then(Value::as_str).unwrap_or_default();
+        let Some(calls) = parse_calls(content, names) else { continue };
+        let calls: Vec<Value> = calls
+            .into_iter()
+            .map(|(name, arguments)| {
+                json!({
+                    "id": call_id(),
+                    "type": "function",
+                    "function": { "name": name, "arguments": arguments.to_string() },
+                })
+            })
+            .collect();
+        choice["message"]["content"] = Value::Null;
+        choice["message"]["tool_calls"] = json!(calls);
+        choice["finish_reason"] = json!("tool_calls");
+    }
+}
+
+fn call_id() -> String {
+    let mut bytes = [0u8; 8];
+    getrandom::getrandom(&mut bytes).expect("no randomness available");
+    format!("call_{}", bytes.iter().map(|b| format!("{b:02x}")).collect::<String>())
+}
+
+/// A whole completion as `chat.completion.chunk` server-sent events.
+fn chunk_events(completion: &Value, include_usage: bool) -> String {
+    let chunk = |choices: Value| {
+        let mut chunk = Map::new();
+        for field in ["id", "created", "model", "system_fingerprint"] {
+            if let Some(value) = completion.get(field) {
+                chunk.insert(field.into(), value.clone());
+            }
+        }
+        chunk.insert("object".into(), json!("chat.completion.chunk"));
+        chunk.insert("choices".into(), choices);
+        Value::Object(chunk)
+    };
+    let mut events = String::new();
+    let choices = completion.get("choices").and_then(Value::as_array).cloned().unwrap_or_default();
+    for (i, choice) in choices.iter().enumerate() {
+        let index = choice.get("index").cloned().unwrap_or(json!(i));
+        let message = choice.get("message").cloned().unwrap_or(json!({}));
+        let mut delta = json!({ "role": "assistant" });
+        match message.get("tool_calls").and_then(Value::as_array) {
+            Some(calls) => {
+                let calls: Vec<Value> = calls
+                    .iter()
+                    .enumerate()
+                    .map(|(n, call)| {
+                        let mut call = call.clone();
+                        call["index"] = json!(n);
+                        call
+                    })
+                    .collect();
+                delta["tool_calls"] = json!(calls);
+            }
+            None => delta["content"] = message.get("content").cloned().unwrap_or(json!("")),
+        }
+        let finish = choice.get("finish_reason").cloned().unwrap_or(json!("stop"));
+        events += &format!("data: {}\n\n", chunk(json!([{ "index": index, "delta": delta, "finish_reason": null }])));
+        events += &format!("data: {}\n\n", chunk(json!([{ "index": index, "delta": {}, "finish_reason": finish }])));
+    }
+    if include_usage {
+        let mut last = chunk(json!([]));
+        last["usage"] = completion.get("usage").cloned().unwrap_or(Value::Null);
+        events += &format!("data: {last}\n\n");
+    }
+    events + "data: [DONE]\n\n"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tools() -> Vec<Value> {
+        vec![json!({
+            "type": "function",
+            "function": {
+                "name": "get_weather",
+                "description": "Current weather for a city",
+                "parameters": { "type": "object", "properties": { "city": { "type": "string" } } },
+            },
+        })]
+    }
+
+    #[test]
+    fn renders_tools_and_history() {
+        let mut request = json!({
+            "model": "gemma2",
+            "stream": true,
+            "tools": tools(),
+            "tool_choice": "required",
+            "messages": [
+                { "role": "system", "content": "Be brief." },
+                { "role": "user", "content": "Weather in Oslo?" },
+                { "role": "assistant", "content": null, "tool_calls": [
+                    { "id": "call_1", "type": "function", "function": { "name": "get_weather", "arguments": "{\"city\":\"Oslo\"}" } },
+                ] },
+                { "role": "tool", "tool_call_id": "call_1", "content": "-3C, snow" },
+            ],
+        });
+        let names = vec!["get_weather".to_string()];
+        emulated_request(&mut request, &tools(), &names);
+        assert!(request.get("tools").is_none() && request.get("tool_choice").is_none());
+        assert_eq!(request["stream"], false);
+        let system = request["messages"][0]["content"].as_str().unwrap();
+        assert!(system.starts_with("Be brief.\n\nYou can call these tools:"));
+        assert!(system.contains("get_weather: Current weather for a city"));
+        assert!(system.ends_with("You must call at least one tool."));
+        assert_eq!(
+            request["messages"][2]["content"],
+            r#"{"tool_calls":[{"arguments":{"city":"Oslo"},"name":"get_weather"}]}"#
+        );
+        assert_eq!(request["messages"][3], json!({ "role": "user", "content": "Result of get_weather (call call_1):\n-3C, snow" }));
+        assert_eq!(request["response_format"]["json_schema"]["schema"]["properties"]["tool_calls"]["items"]["properties"]["name"]["enum"], json!(["get_weather"]));
+
+        let mut request = json!({ "model": "gemma2", "tools": tools(), "tool_choice": "none", "messages": [] });
+        emulated_request(&mut request, &tools(), &names);
+        assert_eq!(request["messages"], json!([]));
+    }
+
+    #[test]
+    fn parses_calls_out_of_answers() {
+        let names = vec!["get_weather".to_string()];
+        let mut completion = json!({
+            "id": "chatcmpl-1",
+            "choices": [{
+                "index": 0,
+                "message": { "role": "assistant", "content": "```json\n{\"tool_calls\": [{\"name\": \"get_weather\", \"arguments\": {\"city\": \"Oslo\"}}]}\n```" },
+                "finish_reason": "stop",
+            }],
+        });
+        extract_tool_calls(&mut completion, &names);
+        let message = &completion["choices"][0]["message"];
+        assert_eq!(message["content"], Value::Null);
+        assert_eq!(message["tool_calls"][0]["function"]["name"], "get_weather");
+        assert_eq!(message["tool_calls"][0]["function"]["arguments"], r#"{"city":"Oslo"}"#);
+        assert!(message["tool_calls"][0]["id"].as_str().unwrap().starts_with("call_"));
+        assert_eq!(completion["choices"][0]["finish_reason"], "tool_calls");
+
+        let events = chunk_events(&completion, false);
+        assert!(events.contains("\"tool_calls\":[{\"function\""));
+        assert!(events.contains("\"finish_reason\":\"tool_calls\""));
+        assert!(events.ends_with("data: [DONE]\n\n"));
+
+        assert_eq!(parse_calls(r#"{"name": "get_weather", "arguments": "{}"}"#, &names), Some(vec![("get_weather".into(), json!({}))]));
+        assert_eq!(parse_calls(r#"{"name": "rm_rf", "arguments": {}}"#, &names), None);
+        assert_eq!(parse_calls("It is cold.", &names), None);
+        assert_eq!(parse_calls(r#"{"city": "Oslo"}"#, &names), None);
+    }
+}
@@ -0,0 +1,86 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+
+/// Request counters for one user.
+#[derive(Clone, Debug, Default, Serialize, PartialEq, Eq)]
+pub struct UserUsage {
+    /// Requests since the proxy started.
+    pub total_requests: u64,
+    /// Requests during the current UTC day, counted against the quota.
+    pub requests_today: u64,
+    /// Seconds since the Unix epoch of the latest request.
+    pub last_request: Option<u64>,
+    #[serde(skip)]
+    day: u64,
+}
+
+/// Per-user request accounting.  Counters are held in memory, so they start
+/// from zero when the proxy restarts.
+#[derive(Clone, Default)]
+pub struct Usage {
+    users: Arc<Mutex<HashMap<String, UserUsage>>>,
+}
+
+impl Usage {
+    /// Count a request by `username` unless it would exceed `daily_quota`.
+    /// Returns `false` when the quota is exhausted.
+    pub fn try_record(&self, username: &str, daily_quota: Option<u64>) -> bool {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        self.record_at(username, daily_quota, now)
+    }
+
+    fn record_at(&self, username: &str, daily_quota: Option<u64>, now: u64) -> bool {
+        let mut users = self.users.lock().unwrap();
+        let usage = users.entry(username.to_string()).or_default();
+        let today = now / 86_400;
+        if usage.day != today {
+            usage.day = today;
+            usage.requests_today = 0;
+        }
+        if daily_quota.is_some_and(|quota| usage.requests_today >= quota) {
+            return false;
+        }
+        usage.total_requests += 1;
+        usage.requests_today += 1;
+        usage.last_request = Some(now);
+        true
+    }
+
+    pub fn get(&self, username: &str) -> UserUsage {
+        self.users.lock().unwrap().get(username).cloned().unwrap_or_default()
+    }
+
+    pub fn all(&self) -> HashMap<String, UserUsage> {
+        self.users.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quota_resets_daily() {
+        let usage = Usage::default();
+        let day = 20_000 * 86_400;
+        assert!(usage.record_at("alice", Some(2), day));
+        assert!(usage.record_at("alice", Some(2), day + 10));
+        assert!(!usage.record_at("alice", Some(2), day + 20));
+        assert!(usage.record_at("bob", Some(2), day + 20));
+        assert_eq!(usage.get("alice").requests_today, 2);
+
+        assert!(usage.record_at("alice", Some(2), day + 86_400));
+        let alice = usage.get("alice");
+        assert_eq!((alice.total_requests, alice.requests_today), (3, 1));
+        assert_eq!(alice.last_request, Some(day + 86_400));
+        assert!(usage.record_at("alice", None, day + 86_401));
+    }
+}